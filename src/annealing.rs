@@ -2,12 +2,10 @@
 /// Math is shamelessly taken from: http://mkweb.bcgsc.ca/carpalx/?simulated_annealing
 /// This code is written to be generic and can be reused for other applications.
 
-extern crate rand;
-
 use std::f64;
 use std::ops::Range;
-use self::rand::thread_rng;
-use self::rand::Rng;
+
+use rng;
 
 // These values are taken from Carpalx, with T0 adjusted for the scale that our
 // penalty model outputs.
@@ -17,36 +15,111 @@ const P0: f64   = 1.0;
 const N:  usize = 15000;
 const KN: f64   = K / (N as f64);
 
+// Heats a frozen schedule back up. After `after` iterations without a new
+// lowest penalty, the schedule steps back to `factor` of the way through it had
+// got, at most `max` times.
+#[derive(Clone, Copy)]
+pub struct Reheat
+{
+	pub after:  usize,
+	pub max:    usize,
+	pub factor: f64,
+}
+
+impl Reheat
+{
+	// Where in the schedule to continue from after reheating at step `i`.
+	pub fn reheated(&self, i: usize)
+	-> usize
+	{
+		(i as f64 * self.factor) as usize
+	}
+}
+
 // T(i) = T0 exp(-ik/N)
-fn temperature(i: usize)
+pub fn temperature(i: usize)
 -> f64
 {
 	T0 * f64::exp(-(i as f64) * KN)
 }
 
-// p(dE, i) = p0 exp(-dE/T(i))
-fn cutoff_p(de: f64, i: usize)
+// p(dE, T) = p0 exp(-dE/T)
+fn cutoff_p(de: f64, t: f64)
 -> f64
 {
-	let t = temperature(i);
 	P0 * f64::exp(-de / t)
 }
 
 // For positive dE, accept if r < p_dE where r ~ Uniform(0, 1)
 pub fn accept_transition(de: f64, i: usize)
 -> bool
+{
+	accept_transition_at(de, temperature(i))
+}
+
+// As above, but at a fixed temperature rather than one from the schedule.
+pub fn accept_transition_at(de: f64, t: f64)
+-> bool
 {
 	if de < 0.0 {
 		true
 	} else {
-		let p_de = cutoff_p(de, i);
-		let r = thread_rng().next_f64();
+		let p_de = cutoff_p(de, t);
+		let r = rng::random::<f64>();
 		r < p_de
 	}
 }
 
+// Temperatures for parallel tempering, spaced geometrically from T0 down to the
+// final temperature of the schedule, hottest first.
+pub fn chain_temperatures(chains: usize)
+-> Vec<f64>
+{
+	if chains < 2 {
+		return vec![T0];
+	}
+	(0..chains)
+		.map(|c| temperature(c * N / (chains - 1)))
+		.collect()
+}
+
+// Accept exchanging the layouts of two chains with the Metropolis criterion:
+// p = min(1, exp((E_a - E_b)(1/T_a - 1/T_b)))
+pub fn accept_exchange(e_a: f64, t_a: f64, e_b: f64, t_b: f64)
+-> bool
+{
+	let p = f64::exp((e_a - e_b) * (1.0 / t_a - 1.0 / t_b));
+	rng::random::<f64>() < p
+}
+
 pub fn get_simulation_range()
 -> Range<usize>
 {
 	1..(N+1)
 }
+
+#[cfg(test)]
+mod tests
+{
+	use super::*;
+
+	#[test]
+	fn reheating_steps_back_through_the_schedule()
+	{
+		let reheat = Reheat { after: 100, max: 1, factor: 0.5 };
+		assert_eq!(reheat.reheated(12000), 6000);
+		assert!(temperature(reheat.reheated(12000)) > temperature(12000));
+	}
+
+	#[test]
+	fn chain_temperatures_span_the_schedule()
+	{
+		let temperatures = chain_temperatures(4);
+		assert_eq!(temperatures.len(), 4);
+		assert_eq!(temperatures[0], T0);
+		assert_eq!(temperatures[3], temperature(N));
+		// Each chain is colder than the last by the same factor.
+		let ratio = temperatures[1] / temperatures[0];
+		assert!((temperatures[3] / temperatures[2] - ratio).abs() < 1e-9);
+	}
+}
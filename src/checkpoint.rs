@@ -0,0 +1,291 @@
+/// Saves where a run has got to every so often, so that one cut short by a
+/// crash or a flat battery can carry on from there. A checkpoint replaces the
+/// last one atomically, by writing a temporary file and renaming it over the
+/// old one.
+
+use std::fmt;
+use std::fs;
+use std::io;
+
+use json;
+use json::Value;
+use layout;
+use simulator::{BestLayoutsEntry, SearchState};
+
+const VERSION: f64 = 1.0;
+
+pub struct Checkpoint
+{
+	pub path:       String,
+	// Iterations between saves.
+	pub every:      usize,
+	pub options:    u64,
+	pub corpus:     u64,
+	pub round:      usize,
+	pub iterations: usize,
+	error:          Option<io::Error>,
+}
+
+// The contents of a checkpoint file.
+pub struct Saved
+{
+	pub options:    u64,
+	pub corpus:     u64,
+	pub round:      usize,
+	pub iterations: usize,
+	pub state:      Option<SearchState>,
+	pub best:       Vec<BestLayoutsEntry>,
+}
+
+#[derive(Debug)]
+pub enum CheckpointParseError
+{
+	Json(json::ParseError),
+	Version,
+	Field(&'static str),
+	Layout(layout::LayoutParseError),
+}
+
+impl Checkpoint
+{
+	// `options` and `corpus` are hashes of the options and the corpora that a
+	// resumed run must match.
+	pub fn new(path: &str, every: usize, options: u64, corpus: u64)
+	-> Checkpoint
+	{
+		Checkpoint { path: path.to_string(), every, options, corpus, round: 1, iterations: 0, error: None }
+	}
+
+	// Saves `state` and the best layouts, keeping the first error for
+	// `status` to report so that the optimiser doesn't have to stop for it.
+	// The file holds a JSON object:
+	//
+	//     {"version":1,"options":"<hash>","corpus":"<hash>","round":2,"iterations":150000,
+	//      "state":{"iteration":4000,"layout":"...","penalty":0.71,"history":["ab","cd"],...},
+	//      "best":[{"layout":"...","penalty":0.70,"history":["ab"]}]}
+	//
+	// where `iterations` counts those of earlier rounds, and `state` is null
+	// if the round hasn't started.
+	pub fn save(&mut self, state: Option<&SearchState>, best: &[BestLayoutsEntry])
+	{
+		if self.error.is_some() {
+			return;
+		}
+		let state = state.map_or(String::from("null"), |state| format!(
+			"{{\"iteration\":{},\"layout\":{},\"penalty\":{},\"history\":{},\"lowest\":{},\"stale\":{},\"reheats\":{},\"rewound\":{},\"idle\":{},\"failures\":{},\"rng\":[{}]}}",
+			state.iteration, json::string(&state.layout.to_string()), json::number(state.penalty), history(&state.history),
+			json::number(state.lowest), state.stale, state.reheats, state.rewound, state.idle, state.failures,
+			state.rng.iter().map(|n| n.to_string()).collect::<Vec<String>>().join(",")));
+		let best: Vec<String> = best.iter()
+			.map(|entry| format!("{{\"layout\":{},\"penalty\":{},\"history\":{}}}",
+				json::string(&entry.layout.to_string()), json::number(entry.penalty), history(&entry.history)))
+			.collect();
+		let contents = format!("{{\"version\":{},\"options\":\"{:016x}\",\"corpus\":\"{:016x}\",\"round\":{},\"iterations\":{},\n\"state\":{},\n\"best\":[{}]}}\n",
+			VERSION, self.options, self.corpus, self.round, self.iterations, state, best.join(",\n"));
+
+		let temporary = format!("{}.tmp", self.path);
+		if let Err(e) = fs::write(&temporary, contents).and_then(|_| fs::rename(&temporary, &self.path)) {
+			self.error = Some(e);
+		}
+	}
+
+	// Reports the first save that failed, if any.
+	pub fn status(&mut self)
+	-> io::Result<()>
+	{
+		match self.error.take() {
+			Some(e) => Err(e),
+			None => Ok(()),
+		}
+	}
+}
+
+fn history(history: &[(char, char)])
+-> String
+{
+	let swaps: Vec<String> = history.iter().map(|&(a, b)| json::string(&format!("{}{}", a, b))).collect();
+	format!("[{}]", swaps.join(","))
+}
+
+// Reads a checkpoint, whose layouts are on `board`.
+pub fn parse_checkpoint(s: &str, board: layout::Board)
+-> Result<Saved, CheckpointParseError>
+{
+	let value = json::parse(s).map_err(CheckpointParseError::Json)?;
+	if value.get("version") != Some(&Value::Number(VERSION)) {
+		return Err(CheckpointParseError::Version);
+	}
+
+	let state = match field(&value, "state")? {
+		Value::Null => None,
+		state => {
+			let rng = match field(state, "rng")? {
+				Value::Array(items) if items.len() == 4 => {
+					let mut rng = [0; 4];
+					for (n, item) in rng.iter_mut().zip(items.iter()) {
+						*n = match *item {
+							Value::Number(x) if x >= 0.0 && x <= u32::MAX as f64 && x.fract() == 0.0 => x as u32,
+							_ => { return Err(CheckpointParseError::Field("rng")) },
+						};
+					}
+					rng
+				},
+				_ => { return Err(CheckpointParseError::Field("rng")) },
+			};
+			Some(SearchState {
+				iteration: count(state, "iteration")?,
+				layout:    parse_layout(state, board)?,
+				penalty:   number(state, "penalty")?,
+				history:   parse_history(state)?,
+				lowest:    number(state, "lowest")?,
+				stale:     count(state, "stale")?,
+				reheats:   count(state, "reheats")?,
+				rewound:   count(state, "rewound")?,
+				idle:      count(state, "idle")?,
+				failures:  count(state, "failures")?,
+				rng,
+			})
+		},
+	};
+
+	let best = match field(&value, "best")? {
+		Value::Array(entries) => entries.iter().map(|entry| Ok(BestLayoutsEntry {
+			layout:  parse_layout(entry, board)?,
+			penalty: number(entry, "penalty")?,
+			history: parse_history(entry)?,
+		})).collect::<Result<Vec<BestLayoutsEntry>, CheckpointParseError>>()?,
+		_ => { return Err(CheckpointParseError::Field("best")) },
+	};
+
+	Ok(Saved {
+		options:    hash(&value, "options")?,
+		corpus:     hash(&value, "corpus")?,
+		round:      count(&value, "round")?,
+		iterations: count(&value, "iterations")?,
+		state,
+		best,
+	})
+}
+
+fn field<'a>(value: &'a Value, key: &'static str)
+-> Result<&'a Value, CheckpointParseError>
+{
+	value.get(key).ok_or(CheckpointParseError::Field(key))
+}
+
+fn number(value: &Value, key: &'static str)
+-> Result<f64, CheckpointParseError>
+{
+	match *field(value, key)? {
+		Value::Number(x) => Ok(x),
+		_ => Err(CheckpointParseError::Field(key)),
+	}
+}
+
+fn count(value: &Value, key: &'static str)
+-> Result<usize, CheckpointParseError>
+{
+	match number(value, key)? {
+		x if x >= 0.0 && x.fract() == 0.0 => Ok(x as usize),
+		_ => Err(CheckpointParseError::Field(key)),
+	}
+}
+
+fn hash(value: &Value, key: &'static str)
+-> Result<u64, CheckpointParseError>
+{
+	match *field(value, key)? {
+		Value::String(ref s) => u64::from_str_radix(s, 16).map_err(|_| CheckpointParseError::Field(key)),
+		_ => Err(CheckpointParseError::Field(key)),
+	}
+}
+
+fn parse_layout(value: &Value, board: layout::Board)
+-> Result<layout::Layout, CheckpointParseError>
+{
+	match *field(value, "layout")? {
+		Value::String(ref s) => layout::Layout::from_string(s, board).map_err(CheckpointParseError::Layout),
+		_ => Err(CheckpointParseError::Field("layout")),
+	}
+}
+
+fn parse_history(value: &Value)
+-> Result<Vec<(char, char)>, CheckpointParseError>
+{
+	let bad = CheckpointParseError::Field("history");
+	let swaps = match *field(value, "history")? {
+		Value::Array(ref swaps) => swaps,
+		_ => { return Err(bad) },
+	};
+	let mut history = Vec::new();
+	for swap in swaps.iter() {
+		let chars: Vec<char> = match *swap {
+			Value::String(ref s) => s.chars().collect(),
+			_ => Vec::new(),
+		};
+		if chars.len() != 2 {
+			return Err(bad);
+		}
+		history.push((chars[0], chars[1]));
+	}
+	Ok(history)
+}
+
+impl fmt::Display for CheckpointParseError
+{
+	fn fmt(&self, f: &mut fmt::Formatter)
+	-> fmt::Result
+	{
+		match *self {
+			CheckpointParseError::Json(ref e) =>
+				write!(f, "{}", e),
+			CheckpointParseError::Version =>
+				write!(f, "not a checkpoint from this version of keygen"),
+			CheckpointParseError::Field(key) =>
+				write!(f, "missing or invalid {:?}", key),
+			CheckpointParseError::Layout(ref e) =>
+				write!(f, "invalid layout: {}", e),
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests
+{
+	use std::env;
+	use super::*;
+
+	#[test]
+	fn saves_and_reads_back()
+	{
+		let path = env::temp_dir().join("keygen-checkpoint-test.json");
+		let path = path.to_str().unwrap();
+		let mut checkpoint = Checkpoint::new(path, 100, 0xfedcba9876543210, 7);
+		checkpoint.round = 3;
+		checkpoint.iterations = 12345;
+		let mut swapped = layout::INIT_LAYOUT.clone();
+		swapped.swap_keys(0, 12);
+		let state = SearchState {
+			iteration: 400, layout: swapped, penalty: 0.1 + 0.2, history: vec![('"', '\\')],
+			lowest: 0.25, stale: 1, reheats: 2, rewound: 3, idle: 4, failures: 5, rng: [u32::MAX, 0, 1, 2],
+		};
+		let best = vec![BestLayoutsEntry { layout: layout::INIT_LAYOUT.clone(), penalty: 0.25, history: vec![('a', 'b'), ('c', ' ')] }];
+		checkpoint.save(Some(&state), &best);
+		checkpoint.status().unwrap();
+
+		let saved = parse_checkpoint(&fs::read_to_string(path).unwrap(), *layout::INIT_LAYOUT.board()).unwrap();
+		fs::remove_file(path).unwrap();
+		assert_eq!((saved.options, saved.corpus, saved.round, saved.iterations), (0xfedcba9876543210, 7, 3, 12345));
+		let restored = saved.state.unwrap();
+		assert!(restored.layout == state.layout);
+		assert_eq!((restored.iteration, restored.penalty, restored.history.clone()), (400, state.penalty, state.history.clone()));
+		assert_eq!((restored.stale, restored.reheats, restored.rewound, restored.idle, restored.failures, restored.rng), (1, 2, 3, 4, 5, state.rng));
+		assert!(saved.best.len() == 1 && saved.best[0].layout == layout::INIT_LAYOUT);
+		assert_eq!(saved.best[0].history, best[0].history);
+
+		match parse_checkpoint("{\"version\":1,\"state\":null}", *layout::INIT_LAYOUT.board()) {
+			Err(CheckpointParseError::Field("best")) => (),
+			_ => panic!("expected a missing field"),
+		}
+	}
+}
@@ -0,0 +1,625 @@
+/// The commands and the options that each of them takes, for parsing the
+/// command line and printing each command's help.
+
+use std::fmt;
+use std::hash::Hasher;
+use std::str::FromStr;
+
+use getopts;
+use getopts::Options;
+use keygen::{annealing, penalty, simulator};
+use keygen::hash::FnvHasher;
+
+// Options come in groups, and each command takes some of the groups.
+#[derive(Clone, Copy, PartialEq)]
+pub enum Group
+{
+	Common,
+	Output,
+	Json,
+	Export,
+	Reference,
+	Refs,
+	Baseline,
+	Effort,
+	Model,
+	Corpus,
+	Top,
+	Keys,
+	Search,
+	Anneal,
+	Moves,
+	Optimize,
+	Verify,
+}
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum Kind
+{
+	Flag,
+	Opt,
+	Multi,
+	FlagOpt,
+}
+
+pub struct Opt
+{
+	pub group: Group,
+	pub short: &'static str,
+	pub long:  &'static str,
+	pub kind:  Kind,
+	pub hint:  &'static str,
+	pub help:  &'static str,
+}
+
+// The value given to an option that it can't take.
+#[derive(Debug)]
+pub struct OptionValueError
+{
+	pub option:   &'static str,
+	pub value:    String,
+	pub expected: &'static str,
+}
+
+// The numbers and modes given by options, checked before any work starts.
+pub struct Settings
+{
+	pub top:              usize,
+	pub swaps:            usize,
+	pub refine_swaps:     Option<usize>,
+	pub distribution:     simulator::SwapDistribution,
+	pub min_distance:     usize,
+	pub keys:             usize,
+	pub strategy:         simulator::Strategy,
+	pub max_failures:     usize,
+	pub reheat:           Option<annealing::Reheat>,
+	pub patience:         Option<usize>,
+	pub rounds:           usize,
+	pub runs:             usize,
+	pub threads:          usize,
+	pub max_seconds:      Option<f64>,
+	pub chains:           usize,
+	pub exchange_every:   usize,
+	pub progress_every:   usize,
+	pub checkpoint_every: usize,
+	pub seed:             Option<u64>,
+	pub space:            penalty::SpaceMode,
+	pub strict_coverage:  f64,
+	pub hand_balance:     f64,
+	pub context:          usize,
+	pub same_finger_rows: f64,
+	pub off_hand_factor:  f64,
+	pub rows:             Option<usize>,
+}
+
+pub struct Command
+{
+	pub name:    &'static str,
+	pub args:    &'static str,
+	pub about:   &'static str,
+	pub example: &'static str,
+	pub groups:  &'static [Group],
+}
+
+const SCORING: &[Group] = &[Group::Common, Group::Effort, Group::Model, Group::Corpus];
+
+pub static COMMANDS: [Command; 21] = [
+	Command { name: "run", args: "<corpus>[:WEIGHT]... [layout]",
+	          about: "search for a better layout by simulated annealing, starting from the layout or RSTHD",
+	          example: "run corpus/books.short.txt --rounds 3 --seed 1",
+	          groups: &[Group::Common, Group::Effort, Group::Model, Group::Corpus, Group::Json, Group::Baseline, Group::Top, Group::Keys, Group::Search, Group::Anneal, Group::Moves] },
+	Command { name: "optimize", args: "<corpus>[:WEIGHT]... [layout] [-o FILE]",
+	          about: "anneal as run does, then refine the best layout it finds",
+	          example: "optimize corpus/books.short.txt --seed 1 --refine-swaps 2 -o best.layout",
+	          groups: &[Group::Common, Group::Effort, Group::Model, Group::Corpus, Group::Output, Group::Baseline, Group::Top, Group::Keys, Group::Search, Group::Anneal, Group::Moves, Group::Optimize] },
+	Command { name: "run-ref", args: "<corpus>[:WEIGHT]... [layout]...",
+	          about: "score the built-in layouts and any others given",
+	          example: "run-ref corpus/books.short.txt --refs qwerty,colemak",
+	          groups: &[Group::Common, Group::Effort, Group::Model, Group::Corpus, Group::Reference, Group::Refs, Group::Baseline, Group::Keys] },
+	Command { name: "refine", args: "<corpus>[:WEIGHT]... [layout]",
+	          about: "improve a layout by trying every combination of a few swaps, until none helps",
+	          example: "refine corpus/books.short.txt my.layout -s 2",
+	          groups: &[Group::Common, Group::Effort, Group::Model, Group::Corpus, Group::Baseline, Group::Top, Group::Keys, Group::Search, Group::Moves] },
+	Command { name: "compare", args: "<corpus>[:WEIGHT]... <layout> <layout>",
+	          about: "score two layouts side by side",
+	          example: "compare corpus/books.short.txt qwerty colemak",
+	          groups: SCORING },
+	Command { name: "suggest", args: "<corpus>[:WEIGHT]... [layout]",
+	          about: "list the swaps that would improve a layout the most",
+	          example: "suggest corpus/books.short.txt qwerty -t 5",
+	          groups: &[Group::Common, Group::Effort, Group::Model, Group::Corpus, Group::Top, Group::Moves] },
+	Command { name: "render", args: "<corpus>[:WEIGHT]... [layout] -o <file.svg>",
+	          about: "draw a layout's keystrokes and penalties per key",
+	          example: "render corpus/books.short.txt colemak -o colemak.svg",
+	          groups: &[Group::Common, Group::Effort, Group::Model, Group::Corpus, Group::Output] },
+	Command { name: "rank", args: "<corpus>[:WEIGHT]... <directory>",
+	          about: "score every layout file in a directory",
+	          example: "rank corpus/books.short.txt layouts/ --json",
+	          groups: &[Group::Common, Group::Effort, Group::Model, Group::Corpus, Group::Json, Group::Baseline] },
+	Command { name: "tui", args: "<corpus>[:WEIGHT]... <layout> [-o FILE]",
+	          about: "swap keys of a layout by hand in the terminal, seeing the score change with each swap",
+	          example: "tui corpus/books.short.txt my.layout",
+	          groups: &[Group::Common, Group::Effort, Group::Model, Group::Corpus, Group::Output] },
+	Command { name: "explain", args: "<layout> <ngram>",
+	          about: "show the penalties of each keystroke of an n-gram of 2 to 4 characters",
+	          example: "explain qwerty the",
+	          groups: &[Group::Common, Group::Effort, Group::Model] },
+	Command { name: "type", args: "<layout> [text|-]",
+	          about: "annotate the keystrokes of some text, or of standard input",
+	          example: "type colemak \"hello world\"",
+	          groups: &[Group::Common, Group::Effort, Group::Model] },
+	Command { name: "export", args: "(qmk|xkb|kanata) <layout> [-o FILE]",
+	          about: "write a layout for a keyboard firmware or operating system",
+	          example: "export qmk colemak --template corne -o keymap.c",
+	          groups: &[Group::Common, Group::Output, Group::Export] },
+	Command { name: "mirror", args: "<layout> [-o FILE]",
+	          about: "swap the hands of a layout",
+	          example: "mirror colemak -o colemak.mirrored",
+	          groups: &[Group::Common, Group::Output] },
+	Command { name: "count", args: "<corpus>[:WEIGHT]... [layout] -o <file.qd>",
+	          about: "save the quartad counts of corpora to score from later",
+	          example: "count corpus/books.txt -o books.qd",
+	          groups: &[Group::Common, Group::Model, Group::Corpus, Group::Refs, Group::Output] },
+	Command { name: "corpus-stats", args: "<corpus>[:WEIGHT]... [layout]",
+	          about: "list the most frequent characters, bigrams and trigrams of corpora, and how much of them a layout types",
+	          example: "corpus-stats corpus/books.short.txt qwerty --json",
+	          groups: &[Group::Common, Group::Model, Group::Corpus, Group::Json] },
+	Command { name: "bench", args: "<corpus>[:WEIGHT]... [layout]",
+	          about: "time how long preparing the corpora and scoring a layout take",
+	          example: "bench corpus/books.short.txt --json",
+	          groups: &[Group::Common, Group::Effort, Group::Model, Group::Corpus, Group::Json] },
+	Command { name: "swap", args: "<layout> <a:b>... [--corpus FILE[:WEIGHT]]... [--ngrams FILE[:WEIGHT]]... [-o FILE]",
+	          about: "swap keys of a layout by character or position, scoring it before and after on any corpora",
+	          example: "swap qwerty e:i --corpus corpus/books.short.txt",
+	          groups: &[Group::Common, Group::Effort, Group::Model, Group::Corpus, Group::Output] },
+	Command { name: "calibrate", args: "<timings.csv> <layout> -o <effort.txt>",
+	          about: "fit the base penalties to key press timings typed on the layout",
+	          example: "calibrate timings.csv qwerty -o effort.txt",
+	          groups: &[Group::Common, Group::Effort, Group::Output] },
+	Command { name: "check-symmetry", args: "<corpus>[:WEIGHT]... [layout]",
+	          about: "score a layout and its mirror image, listing the penalties that differ between them",
+	          example: "check-symmetry corpus/books.short.txt qwerty",
+	          groups: SCORING },
+	Command { name: "verify", args: "[directory]",
+	          about: "score the reference layouts on the fixture corpus and check the scores against those saved with it",
+	          example: "verify --bless",
+	          groups: &[Group::Verify] },
+	Command { name: "help", args: "[command]",
+	          about: "list the commands, or show a command's arguments and options",
+	          example: "help run",
+	          groups: &[] },
+];
+
+pub static OPTIONS: [Opt; 74] = [
+	Opt { group: Group::Common, short: "h", long: "help", kind: Kind::Flag, hint: "",
+	      help: "print this help menu" },
+	Opt { group: Group::Common, short: "d", long: "debug", kind: Kind::Flag, hint: "",
+	      help: "show debug logging" },
+	Opt { group: Group::Common, short: "f", long: "force", kind: Kind::Flag, hint: "",
+	      help: "use layouts with duplicate characters anyway" },
+	Opt { group: Group::Common, short: "", long: "config", kind: Kind::Opt, hint: "FILE",
+	      help: "read default options from this file (default: keygen.toml, if there is one)" },
+	Opt { group: Group::Common, short: "", long: "board", kind: Kind::Opt, hint: "BOARD",
+	      help: "keyboard: matrix34, matrix33 or ansi30 (default: matrix34)" },
+	Opt { group: Group::Common, short: "", long: "geometry", kind: Kind::Opt, hint: "FILE",
+	      help: "read the keyboard from a file of key coordinates, to score travel and same finger distances" },
+	Opt { group: Group::Common, short: "", long: "rows", kind: Kind::Opt, hint: "N",
+	      help: "rows of keys to lay out: 3, or 4 to add the number row (default: 4 for a geometry file with a number row, otherwise 3)" },
+	Opt { group: Group::Common, short: "", long: "pairs", kind: Kind::Opt, hint: "FILE",
+	      help: "read the character shift gives with each key from lines like \",\" = \";\", for one-layer layout files and to check layouts against" },
+	Opt { group: Group::Output, short: "o", long: "output", kind: Kind::Opt, hint: "FILE",
+	      help: "file for render or export to write to (default for export: standard output)" },
+	Opt { group: Group::Json, short: "", long: "json", kind: Kind::Flag, hint: "",
+	      help: "print the table from rank, the results of run --runs or bench timings as JSON" },
+	Opt { group: Group::Export, short: "", long: "template", kind: Kind::Opt, hint: "NAME",
+	      help: "keyboard for export qmk: keymap, corne or lily58 (default: keymap)" },
+	Opt { group: Group::Export, short: "", long: "thumb-key", kind: Kind::Opt, hint: "KEYCODE",
+	      help: "QMK keycode for thumb keys that the layout leaves empty (default: KC_NO)" },
+	Opt { group: Group::Reference, short: "", long: "heatmap", kind: Kind::Flag, hint: "",
+	      help: "with run-ref, draw each layout's keystrokes and penalties per key" },
+	Opt { group: Group::Reference, short: "", long: "watch", kind: Kind::Flag, hint: "",
+	      help: "with run-ref and one layout file, score the file again whenever it changes, showing what moved" },
+	Opt { group: Group::Reference, short: "", long: "words", kind: Kind::Flag, hint: "",
+	      help: "with run-ref, list the words of the corpora that cost each layout the most" },
+	Opt { group: Group::Reference, short: "", long: "hand-runs", kind: Kind::Flag, hint: "",
+	      help: "with run-ref, show how many keystrokes in a row each layout types on one hand" },
+	Opt { group: Group::Refs, short: "", long: "refs", kind: Kind::Opt, hint: "NAME,FILE,...",
+	      help: "layouts for run-ref to compare, as built-in names or layout files (default: all built-in layouts)" },
+	Opt { group: Group::Baseline, short: "", long: "baseline", kind: Kind::Opt, hint: "NAME|FILE",
+	      help: "layout to give scores as a percentage of, as a built-in name or a layout file (default: qwerty)" },
+	Opt { group: Group::Effort, short: "", long: "preset", kind: Kind::Opt, hint: "NAME",
+	      help: "penalty model for a kind of corpus: prose or code, which weighs symbols and shift more and lets the top right corner move (default: prose)" },
+	Opt { group: Group::Effort, short: "", long: "effort", kind: Kind::Opt, hint: "FILE",
+	      help: "read the base penalty of each key from a file shaped like a layout" },
+	Opt { group: Group::Effort, short: "", long: "dominant-hand", kind: Kind::Opt, hint: "HAND",
+	      help: "raise the base penalty of the other hand's keys, for a left or right handed typist" },
+	Opt { group: Group::Effort, short: "", long: "off-hand-factor", kind: Kind::Opt, hint: "FACTOR",
+	      help: "what --dominant-hand multiplies the other hand's base penalties by (default: 1.2)" },
+	Opt { group: Group::Model, short: "", long: "model-shift", kind: Kind::Flag, hint: "",
+	      help: "type upper layer characters with a shift key on the opposite pinky" },
+	Opt { group: Group::Model, short: "", long: "space", kind: Kind::Opt, hint: "MODE",
+	      help: "how space is typed: key, left, right, alternate (the other hand's thumb, or the right one after no keystroke) or reset (default: key)" },
+	Opt { group: Group::Model, short: "", long: "disable-penalty", kind: Kind::Multi, hint: "NAME",
+	      help: "turn off a penalty, e.g. \"alternating hand\"" },
+	Opt { group: Group::Model, short: "", long: "penalty-weight", kind: Kind::Multi, hint: "NAME=WEIGHT",
+	      help: "change the weight of a penalty, e.g. \"roll in=-0.5\"" },
+	Opt { group: Group::Model, short: "", long: "weights", kind: Kind::Opt, hint: "FILE",
+	      help: "read penalty weights from a file, one `NAME = WEIGHT` a line, and the finger load target as `finger load target = SHARES`" },
+	Opt { group: Group::Model, short: "", long: "finger-load", kind: Kind::Opt, hint: "LOADS",
+	      help: "target share of keystrokes per finger from left pinky to right pinky (default: 8,11,15,16,16,15,11,8)" },
+	Opt { group: Group::Model, short: "", long: "hand-balance", kind: Kind::Opt, hint: "RATIO",
+	      help: "target share of keystrokes typed by the left hand, which turns the hand balance penalty on (default: 0.5)" },
+	Opt { group: Group::Model, short: "", long: "bigram-penalties", kind: Kind::Opt, hint: "FILE",
+	      help: "read extra penalties for pairs of positions from a file, one `FROM [>] TO PENALTY` a line" },
+	Opt { group: Group::Model, short: "", long: "same-finger-rows", kind: Kind::Opt, hint: "FACTOR",
+	      help: "how much of the same finger penalty's weight each row between the keys adds (default: 0.5)" },
+	Opt { group: Group::Model, short: "", long: "context", kind: Kind::Opt, hint: "N",
+	      help: "score each keystroke with n-grams of this many characters, from 3 to 5 (default: 4)" },
+	Opt { group: Group::Corpus, short: "", long: "corpus", kind: Kind::Multi, hint: "FILE[:WEIGHT]",
+	      help: "score swap's layout on this corpus before and after swapping; for other commands, a corpus to read, so that every argument names a layout" },
+	Opt { group: Group::Corpus, short: "", long: "ngrams", kind: Kind::Multi, hint: "FILE[:WEIGHT]",
+	      help: "a corpus to read as lines of n-gram<TAB>count instead of text, so that every argument names a layout" },
+	Opt { group: Group::Corpus, short: "", long: "strict-coverage", kind: Kind::Opt, hint: "RATIO",
+	      help: "abort if less than this fraction of the corpus can be typed" },
+	Opt { group: Group::Corpus, short: "", long: "fold-case", kind: Kind::Flag, hint: "",
+	      help: "type each upper layer character of the corpora as the lower layer one it pairs with, e.g. 'A' as 'a' and '<' as ','" },
+	Opt { group: Group::Corpus, short: "", long: "no-normalize", kind: Kind::Flag, hint: "",
+	      help: "keep typographic characters like curly quotes, dashes and ellipses instead of typing their ASCII equivalents" },
+	Opt { group: Group::Corpus, short: "", long: "normalize", kind: Kind::Multi, hint: "CHAR=TEXT",
+	      help: "also type this character as the text after the '=', e.g. \u{ab}=\"" },
+	Opt { group: Group::Corpus, short: "", long: "compose", kind: Kind::Opt, hint: "FILE",
+	      help: "type characters of the corpora as the keystrokes this file gives them, like \"é\" = \"'e\" for a dead key" },
+	Opt { group: Group::Corpus, short: "", long: "reset-on", kind: Kind::Opt, hint: "CHARS",
+	      help: "characters that reset the context without being scored, e.g. \"\\n\\t \"" },
+	Opt { group: Group::Corpus, short: "", long: "penalty-runs", kind: Kind::Flag, hint: "",
+	      help: "penalise runs of five or more keystrokes on one hand, which needs each corpus's text" },
+	Opt { group: Group::Top, short: "t", long: "top", kind: Kind::Opt, hint: "TOP_LAYOUTS",
+	      help: "number of top layouts or suggestions to print (default: 1, or 10 for suggest)" },
+	Opt { group: Group::Keys, short: "k", long: "show-keys", kind: Kind::Opt, hint: "KEYS",
+	      help: "number of high keys to print per penalty (default: 5)" },
+	Opt { group: Group::Search, short: "s", long: "swaps-per-iteration", kind: Kind::Opt, hint: "SWAPS",
+	      help: "maximum number of swaps per iteration, from 1 to 10 (default: 3)" },
+	Opt { group: Group::Optimize, short: "", long: "refine-swaps", kind: Kind::Opt, hint: "SWAPS",
+	      help: "most swaps that optimize's refinement combines (default: --swaps-per-iteration)" },
+	Opt { group: Group::Verify, short: "", long: "bless", kind: Kind::Flag, hint: "",
+	      help: "with verify, save the scores as the expected ones instead of checking them, after a change to the model that is meant to move them" },
+	Opt { group: Group::Search, short: "", long: "threads", kind: Kind::Opt, hint: "N",
+	      help: "threads to share --runs, or the layouts refine tries, between (default: 1)" },
+	Opt { group: Group::Search, short: "m", long: "min-distance", kind: Kind::Opt, hint: "KEYS",
+	      help: "minimum number of differing keys between top layouts (default: 1)" },
+	Opt { group: Group::Anneal, short: "", long: "strategy", kind: Kind::Opt, hint: "STRATEGY",
+	      help: "how run moves between layouts: anneal, hill-climb or steepest (default: anneal)" },
+	Opt { group: Group::Anneal, short: "", long: "swap-distribution", kind: Kind::Opt, hint: "DISTRIBUTION",
+	      help: "how many swaps run makes per iteration: uniform, any number up to --swaps-per-iteration equally often, or geometric, half as often for each swap more (default: uniform)" },
+	Opt { group: Group::Anneal, short: "", long: "max-failures", kind: Kind::Opt, hint: "ITERATIONS",
+	      help: "number of failed swaps in a row after which hill climbing stops (default: 1000)" },
+	Opt { group: Group::Anneal, short: "", long: "reheat-after", kind: Kind::Opt, hint: "ITERATIONS",
+	      help: "when annealing, reheat after this many iterations without a new lowest penalty (default: never)" },
+	Opt { group: Group::Anneal, short: "", long: "max-reheats", kind: Kind::Opt, hint: "REHEATS",
+	      help: "maximum number of reheats per round (default: 3)" },
+	Opt { group: Group::Anneal, short: "", long: "reheat-factor", kind: Kind::Opt, hint: "RATIO",
+	      help: "fraction of the way through the annealing schedule to step back to when reheating (default: 0.5)" },
+	Opt { group: Group::Anneal, short: "", long: "patience", kind: Kind::Opt, hint: "ITERATIONS",
+	      help: "stop annealing after this many iterations without improvement (default: never)" },
+	Opt { group: Group::Anneal, short: "", long: "runs", kind: Kind::Opt, hint: "N",
+	      help: "run this many independent optimisations with different seeds and summarise their best penalties" },
+	Opt { group: Group::Anneal, short: "", long: "max-seconds", kind: Kind::Opt, hint: "SECONDS",
+	      help: "stop run after this many seconds, keeping the best layouts found so far" },
+	Opt { group: Group::Anneal, short: "", long: "rounds", kind: Kind::Opt, hint: "ROUNDS",
+	      help: "number of rounds for run to optimise for (default: until interrupted)" },
+	Opt { group: Group::Anneal, short: "", long: "tempering", kind: Kind::Opt, hint: "N",
+	      help: "run anneals N chains in parallel at fixed temperatures, exchanging layouts between them, instead of following --strategy" },
+	Opt { group: Group::Anneal, short: "", long: "exchange-every", kind: Kind::Opt, hint: "ITERATIONS",
+	      help: "iterations between exchanges of layouts in parallel tempering (default: 100)" },
+	Opt { group: Group::Anneal, short: "", long: "trace", kind: Kind::Opt, hint: "FILE",
+	      help: "write every iteration of run to a CSV file for plotting" },
+	Opt { group: Group::Anneal, short: "", long: "progress-json", kind: Kind::FlagOpt, hint: "FILE",
+	      help: "also write progress as JSON events, one per line, to standard error or to --progress-json=FILE; see the events below" },
+	Opt { group: Group::Anneal, short: "", long: "progress-every", kind: Kind::Opt, hint: "ITERATIONS",
+	      help: "iterations between progress events from --progress-json (default: 1000)" },
+	Opt { group: Group::Anneal, short: "", long: "checkpoint", kind: Kind::Opt, hint: "FILE",
+	      help: "save where run has got to in FILE every --checkpoint-every iterations and between rounds, for --resume to carry on from" },
+	Opt { group: Group::Anneal, short: "", long: "checkpoint-every", kind: Kind::Opt, hint: "ITERATIONS",
+	      help: "iterations between checkpoints (default: 10000)" },
+	Opt { group: Group::Anneal, short: "", long: "resume", kind: Kind::Opt, hint: "FILE",
+	      help: "carry on from a checkpoint, saving later ones to the same file unless --checkpoint names another; refuses if the corpora or the options that change the search differ, unless --force" },
+	Opt { group: Group::Anneal, short: "", long: "seed", kind: Kind::Opt, hint: "SEED",
+	      help: "seed the random number generator to make a run reproducible" },
+	Opt { group: Group::Moves, short: "", long: "swap-thumb", kind: Kind::Flag, hint: "",
+	      help: "let the optimiser move the key on the left thumb" },
+	Opt { group: Group::Moves, short: "", long: "groups", kind: Kind::FlagOpt, hint: "FILE",
+	      help: "only swap letters with letters, punctuation with punctuation and the rest with the rest, optionally regrouping characters as listed in FILE" },
+	Opt { group: Group::Moves, short: "", long: "only-punctuation", kind: Kind::Flag, hint: "",
+	      help: "only move keys that aren't letters, including the outer column" },
+	Opt { group: Group::Moves, short: "", long: "only-letters", kind: Kind::Flag, hint: "",
+	      help: "only move keys that are letters" },
+	Opt { group: Group::Moves, short: "", long: "hand", kind: Kind::Opt, hint: "HAND",
+	      help: "only move keys on this hand, left or right, and show the hand balance" },
+	Opt { group: Group::Moves, short: "", long: "keep", kind: Kind::Opt, hint: "CHARS",
+	      help: "never move the keys that type these characters" },
+	Opt { group: Group::Moves, short: "", long: "constraints", kind: Kind::Opt, hint: "FILE",
+	      help: "keep characters on a hand, finger or row, with rules like: chars = \"aeiou\", hand = \"right\"" },
+];
+
+// Options that a run resumed from a checkpoint may change, since they don't
+// change the search. The corpora are checked separately, by their contents.
+const RESUMABLE: [&str; 20] = [
+	"help", "debug", "force", "config", "output", "json", "baseline", "show-keys", "corpus", "ngrams", "threads", "max-seconds", "rounds",
+	"seed", "trace", "progress-json", "progress-every", "checkpoint", "checkpoint-every", "resume",
+];
+
+// The events written by --progress-json. Penalties are scaled, as printed, and
+// new fields may be added to an event but none will be removed or renamed.
+const PROGRESS_EVENTS: &str = "
+Progress events, one JSON object per line:
+    {\"event\":\"round\",\"round\":R,\"iterations\":N}
+        a round starts; N is how many iterations it will run, or null if unknown
+    {\"event\":\"iteration\",\"round\":R,\"chain\":C,\"iteration\":I,\"temperature\":T,\"penalty\":P,\"best\":B}
+        every --progress-every iterations of each chain (0 unless --tempering):
+        the temperature, the penalty of the layout it holds and the lowest so far
+    {\"event\":\"accepted\",...}
+        a chain moved to a new layout, with the same fields as iteration
+    {\"event\":\"result\",\"round\":R,\"iterations\":I,\"stop\":S,\"layouts\":[{\"penalty\":P,\"layout\":L}]}
+        a round ended, why, and the best layouts so far, best first
+    {\"event\":\"done\",\"rounds\":R,\"iterations\":I,\"reason\":S}
+        the last round ended
+";
+
+// Help for an option that means something else to one command.
+static HELP_FOR_COMMAND: [(&str, &str, &str); 2] = [
+	("refine", "swaps-per-iteration", "most swaps to combine when trying the layouts around each one, trying more only when fewer find nothing better (default: 3)"),
+	("suggest", "top", "number of suggestions to print (default: 10)"),
+];
+
+pub fn command(name: &str)
+-> Option<&'static Command>
+{
+	COMMANDS.iter().find(|command| command.name == name)
+}
+
+impl Command
+{
+	pub fn takes(&self, opt: &Opt)
+	-> bool
+	{
+		opt.group == Group::Common || self.groups.contains(&opt.group)
+	}
+}
+
+// Whether a config file's option should be passed to `command`. Names that
+// aren't options are passed on to be reported.
+pub fn applies(command: &Command, name: &str)
+-> bool
+{
+	OPTIONS.iter().find(|opt| opt.long == name).is_none_or(|opt| command.takes(opt))
+}
+
+// The options that `command` takes, or every option if None.
+pub fn options(command: Option<&Command>)
+-> Options
+{
+	let mut opts = Options::new();
+	for opt in OPTIONS.iter().filter(|opt| command.is_none_or(|command| command.takes(opt))) {
+		let help = command
+			.and_then(|command| HELP_FOR_COMMAND.iter().find(|&&(name, long, _)| name == command.name && long == opt.long))
+			.map_or(opt.help, |&(_, _, help)| help);
+		match opt.kind {
+			Kind::Flag    => opts.optflag(opt.short, opt.long, help),
+			Kind::Opt     => opts.optopt(opt.short, opt.long, help, opt.hint),
+			Kind::Multi   => opts.optmulti(opt.short, opt.long, help, opt.hint),
+			Kind::FlagOpt => opts.optflagopt(opt.short, opt.long, help, opt.hint),
+		};
+	}
+	opts
+}
+
+// The first option given that `command` doesn't take, if any.
+pub fn misplaced(matches: &getopts::Matches, command: &Command)
+-> Option<&'static str>
+{
+	OPTIONS.iter().find(|opt| !command.takes(opt) && matches.opt_present(opt.long)).map(|opt| opt.long)
+}
+
+pub fn settings(matches: &getopts::Matches, command: &Command)
+-> Result<Settings, OptionValueError>
+{
+	const COUNT: &str = "a whole number";
+	const POSITIVE: &str = "a whole number of at least 1";
+	const FRACTION: &str = "a number from 0 to 1";
+	let any = |_: &usize| true;
+	let positive = |&n: &usize| n >= 1;
+	let fraction = |&x: &f64| (0.0..=1.0).contains(&x);
+
+	let reheat = match value(matches, "reheat-after", POSITIVE, positive)? {
+		Some(after) => Some(annealing::Reheat {
+			after,
+			max:    value(matches, "max-reheats", COUNT, any)?.unwrap_or(3),
+			factor: value(matches, "reheat-factor", FRACTION, fraction)?.unwrap_or(0.5),
+		}),
+		None => None,
+	};
+	Ok(Settings {
+		top:              value(matches, "top", POSITIVE, positive)?.unwrap_or(if command.name == "suggest" { 10 } else { 1 }),
+		swaps:            value(matches, "swaps-per-iteration", "a whole number from 1 to 10", |&n: &usize| (1..=simulator::MAX_SWAPS).contains(&n))?.unwrap_or(3),
+		refine_swaps:     value(matches, "refine-swaps", "a whole number from 1 to 10", |&n: &usize| (1..=simulator::MAX_SWAPS).contains(&n))?,
+		distribution:     value(matches, "swap-distribution", "uniform or geometric", |_: &_| true)?.unwrap_or(simulator::SwapDistribution::Uniform),
+		min_distance:     value(matches, "min-distance", COUNT, any)?.unwrap_or(1),
+		keys:             value(matches, "show-keys", COUNT, any)?.unwrap_or(5),
+		strategy:         value(matches, "strategy", "anneal, hill-climb or steepest", |_: &_| true)?.unwrap_or(simulator::Strategy::Anneal),
+		max_failures:     value(matches, "max-failures", POSITIVE, positive)?.unwrap_or(1000),
+		reheat,
+		patience:         value(matches, "patience", POSITIVE, positive)?,
+		rounds:           value(matches, "rounds", COUNT, any)?.unwrap_or(0),
+		runs:             value(matches, "runs", COUNT, any)?.unwrap_or(0),
+		threads:          value(matches, "threads", POSITIVE, positive)?.unwrap_or(1),
+		max_seconds:      value(matches, "max-seconds", "a number of at least 0", |&x: &f64| x >= 0.0)?,
+		chains:           value(matches, "tempering", COUNT, any)?.unwrap_or(0),
+		exchange_every:   value(matches, "exchange-every", POSITIVE, positive)?.unwrap_or(100),
+		progress_every:   value(matches, "progress-every", POSITIVE, positive)?.unwrap_or(1000),
+		checkpoint_every: value(matches, "checkpoint-every", POSITIVE, positive)?.unwrap_or(10000),
+		seed:             value(matches, "seed", COUNT, |_: &_| true)?,
+		space:            value(matches, "space", "key, left, right, alternate or reset", |_: &_| true)?.unwrap_or(penalty::SpaceMode::Key),
+		strict_coverage:  value(matches, "strict-coverage", FRACTION, fraction)?.unwrap_or(0.0),
+		hand_balance:     value(matches, "hand-balance", FRACTION, fraction)?.unwrap_or(0.5),
+		context:          value(matches, "context", "a whole number from 3 to 5",
+		                        |&n: &usize| (penalty::MIN_CONTEXT..=penalty::MAX_CONTEXT).contains(&n))?.unwrap_or(penalty::DEFAULT_CONTEXT),
+		same_finger_rows: value(matches, "same-finger-rows", "a number of at least 0", |&x: &f64| x >= 0.0)?.unwrap_or(penalty::DEFAULT_SAME_FINGER_ROWS),
+		off_hand_factor:  value(matches, "off-hand-factor", "a number greater than 0", |&x: &f64| x > 0.0)?.unwrap_or(1.2),
+		rows:             value(matches, "rows", "3 or 4", |&n: &usize| n == 3 || n == 4)?,
+	})
+}
+
+// A hash of the options given that a resumed run must repeat, for a checkpoint
+// to record.
+pub fn search_hash(matches: &getopts::Matches, command: &Command)
+-> u64
+{
+	let mut hasher = FnvHasher::default();
+	for opt in OPTIONS.iter().filter(|opt| command.takes(opt) && !RESUMABLE.contains(&opt.long)) {
+		hasher.write(opt.long.as_bytes());
+		hasher.write_usize(matches.opt_count(opt.long));
+		for value in matches.opt_strs(opt.long) {
+			hasher.write(value.as_bytes());
+			hasher.write_u8(0);
+		}
+	}
+	hasher.finish()
+}
+
+// The value of an option if it was given, as long as it parses and `valid`
+// accepts it.
+fn value<T, F>(matches: &getopts::Matches, option: &'static str, expected: &'static str, valid: F)
+-> Result<Option<T>, OptionValueError>
+where T: FromStr, F: Fn(&T) -> bool
+{
+	let s = match matches.opt_str(option) {
+		Some(s) => s,
+		None => { return Ok(None) }
+	};
+	match s.trim().parse::<T>() {
+		Ok(v) => if valid(&v) { Ok(Some(v)) } else { Err(OptionValueError { option, value: s, expected }) },
+		Err(_) => Err(OptionValueError { option, value: s, expected }),
+	}
+}
+
+pub fn usage(progname: &str, command: &Command)
+-> String
+{
+	let brief = format!("Usage: {} {} {} [OPTIONS]\n\n{}.\n\nExample: {} {}",
+		progname, command.name, command.args, capitalise(command.about), progname, command.example);
+	let mut usage = options(Some(command)).usage(&brief);
+	if command.groups.contains(&Group::Anneal) {
+		usage.push_str(PROGRESS_EVENTS);
+	}
+	usage
+}
+
+pub fn command_list(progname: &str)
+-> String
+{
+	let width = COMMANDS.iter().map(|command| command.name.len()).max().unwrap_or(0);
+	let mut list = format!("Usage: {} <command> [arguments] [OPTIONS]\n\nCommands:\n", progname);
+	for command in COMMANDS.iter() {
+		list.push_str(&format!("    {:width$}  {}\n", command.name, command.about, width = width));
+	}
+	list.push_str(&format!("\nRun `{} help <command>` for a command's arguments and options.\n", progname));
+	list
+}
+
+fn capitalise(s: &str)
+-> String
+{
+	let mut chars = s.chars();
+	match chars.next() {
+		Some(c) => c.to_uppercase().chain(chars).collect(),
+		None => String::new(),
+	}
+}
+
+impl fmt::Display for OptionValueError
+{
+	fn fmt(&self, f: &mut fmt::Formatter)
+	-> fmt::Result
+	{
+		write!(f, "invalid value {:?} for --{}, expected {}", self.value, self.option, self.expected)
+	}
+}
+
+#[cfg(test)]
+mod tests
+{
+	use super::*;
+
+	#[test]
+	fn options_are_unique()
+	{
+		for (i, opt) in OPTIONS.iter().enumerate() {
+			assert!(OPTIONS[..i].iter().all(|other| other.long != opt.long && (opt.short.is_empty() || other.short != opt.short)), "{}", opt.long);
+		}
+		for &(name, long, _) in HELP_FOR_COMMAND.iter() {
+			let command = command(name).unwrap();
+			assert!(OPTIONS.iter().any(|opt| opt.long == long && command.takes(opt)), "{} {}", name, long);
+		}
+	}
+
+	#[test]
+	fn commands_take_their_options()
+	{
+		let args: Vec<String> = vec!["corpus.txt".to_string(), "--runs".to_string(), "4".to_string()];
+		let matches = options(None).parse(&args).unwrap();
+		assert_eq!(misplaced(&matches, command("run").unwrap()), None);
+		assert_eq!(misplaced(&matches, command("refine").unwrap()), Some("runs"));
+		assert!(options(command("refine")).parse(&args).is_err());
+
+		assert!(usage("keygen", command("optimize").unwrap()).contains("\"event\":\"accepted\""));
+		let usage = usage("keygen", command("refine").unwrap());
+		assert!(usage.contains("--swaps-per-iteration") && usage.contains("most swaps to combine"));
+		assert!(!usage.contains("--tempering") && !usage.contains("Progress events"));
+		assert!(command_list("keygen").contains("calibrate"));
+	}
+
+	fn parse(args: &[&str])
+	-> getopts::Matches
+	{
+		let args: Vec<String> = args.iter().map(|arg| arg.to_string()).collect();
+		options(None).parse(&args).unwrap()
+	}
+
+	#[test]
+	fn settings_default_and_parse()
+	{
+		let defaults = settings(&parse(&[]), command("suggest").unwrap()).unwrap();
+		assert_eq!((defaults.top, defaults.swaps, defaults.threads), (10, 3, 1));
+		assert!(defaults.reheat.is_none() && defaults.seed.is_none());
+
+		let given = settings(&parse(&["-s", "2", "--threads", "4", "--reheat-after", "500", "--space", "left"]), command("run").unwrap()).unwrap();
+		assert_eq!((given.top, given.swaps, given.threads), (1, 2, 4));
+		assert_eq!(given.reheat.map(|reheat| (reheat.after, reheat.max)), Some((500, 3)));
+		assert!(given.space == penalty::SpaceMode::Left);
+	}
+
+	#[test]
+	fn search_hash_ignores_resumable_options()
+	{
+		let run = command("run").unwrap();
+		let hash = search_hash(&parse(&["-s", "2", "--threads", "4"]), run);
+		assert_eq!(hash, search_hash(&parse(&["--max-seconds", "60", "-s", "2", "--resume", "state.json"]), run));
+		assert!(hash != search_hash(&parse(&["-s", "3"]), run));
+		assert!(hash != search_hash(&parse(&["-s", "2", "--swap-thumb"]), run));
+	}
+
+	#[test]
+	fn settings_reject_bad_values()
+	{
+		let run = command("run").unwrap();
+		for &(option, value) in [("top", "1O"), ("swaps-per-iteration", "0"), ("swaps-per-iteration", "50"), ("swap-distribution", "poisson"), ("threads", "-1"), ("hand-balance", "1.5"),
+		                         ("context", "6"), ("space", "sideways"), ("max-seconds", "-5"), ("off-hand-factor", "0"), ("progress-every", "0"),
+		                         ("rows", "5")].iter() {
+			let flag = format!("--{}", option);
+			match settings(&parse(&[&flag, value]), run) {
+				Err(e) => assert_eq!((e.option, &e.value[..]), (option, value)),
+				Ok(_) => panic!("{} {} was accepted", flag, value),
+			}
+		}
+	}
+}
@@ -0,0 +1,226 @@
+/// Default options read from a config file, `keygen.toml` in the current
+/// directory or the file named by `--config`. Each key is the long name of an
+/// option, and options given on the command line override the file's:
+///
+///     # Scoring
+///     corpus = "corpus/books.short.txt"
+///     penalty-weight = ["roll in=-0.5", "redirect=3"]
+///     model-shift = true
+///     threads = 4
+
+use std::fmt;
+use std::path::Path;
+
+use getopts;
+use getopts::Options;
+
+pub const DEFAULT_PATH: &str = "keygen.toml";
+
+// One option from the file, with the values to give it. A flag that is on has
+// none.
+pub struct ConfigOption
+{
+	pub line:   usize,
+	pub name:   String,
+	pub values: Vec<String>,
+}
+
+#[derive(Debug)]
+pub enum ConfigParseError
+{
+	Syntax { line: usize },
+	BadValue { line: usize, key: String },
+	UnknownKey { line: usize, key: String },
+}
+
+// The config file to read: the one named by --config, or keygen.toml if there
+// is one.
+pub fn path(args: &[String])
+-> Option<String>
+{
+	for (i, arg) in args.iter().enumerate() {
+		if arg == "--config" {
+			return args.get(i + 1).cloned();
+		}
+		if let Some(path) = arg.strip_prefix("--config=") {
+			return Some(path.to_string());
+		}
+	}
+	if Path::new(DEFAULT_PATH).exists() {
+		Some(DEFAULT_PATH.to_string())
+	} else {
+		None
+	}
+}
+
+// Reads `key = value` lines, where a value is a quoted string, a number, true
+// or false, or a list of those in brackets. Blank lines and those starting
+// with `#` are skipped, and a key set to false is left out.
+pub fn parse_config(s: &str)
+-> Result<Vec<ConfigOption>, ConfigParseError>
+{
+	let mut options = Vec::new();
+	for (i, line) in s.lines().enumerate() {
+		let line_num = i + 1;
+		let line = line.trim();
+		if line.is_empty() || line.starts_with('#') {
+			continue;
+		}
+
+		let mut kv = line.splitn(2, '=');
+		let key = kv.next().unwrap_or("").trim().trim_matches('"');
+		let value = match kv.next() {
+			Some(value) if !key.is_empty() => value.trim(),
+			_ => { return Err(ConfigParseError::Syntax { line: line_num }) }
+		};
+		let bad = || ConfigParseError::BadValue { line: line_num, key: key.to_string() };
+		let values = if value.starts_with('[') && value.ends_with(']') {
+			let mut values = Vec::new();
+			let mut rest = value[1..(value.len() - 1)].trim();
+			while !rest.is_empty() {
+				let (item, after) = scalar(rest).ok_or_else(bad)?;
+				values.push(item.ok_or_else(bad)?);
+				rest = after.trim_start();
+				if rest.starts_with(',') {
+					rest = rest[1..].trim_start();
+				} else if !rest.is_empty() {
+					return Err(bad());
+				}
+			}
+			values
+		} else {
+			match scalar(value) {
+				Some((Some(value), "")) => vec![value],
+				Some((None, "")) if value == "true" => Vec::new(),
+				Some((None, "")) => continue,
+				_ => { return Err(bad()) }
+			}
+		};
+		options.push(ConfigOption { line: line_num, name: key.to_string(), values });
+	}
+
+	Ok(options)
+}
+
+// Reads one value from the start of `s`, returning it and the rest of `s`.
+// The value is None for true and false, which aren't passed on.
+fn scalar(s: &str)
+-> Option<(Option<String>, &str)>
+{
+	if s.starts_with('"') {
+		let mut value = String::new();
+		let mut chars = s.char_indices().skip(1);
+		while let Some((i, c)) = chars.next() {
+			match c {
+				'"' => return Some((Some(value), &s[(i + 1)..])),
+				'\\' => match chars.next() {
+					Some((_, 'n')) => value.push('\n'),
+					Some((_, 't')) => value.push('\t'),
+					Some((_, c)) => value.push(c),
+					None => return None,
+				},
+				c => value.push(c),
+			}
+		}
+		return None;
+	}
+
+	let end = s.find(|c: char| c == ',' || c.is_whitespace()).unwrap_or(s.len());
+	let (token, rest) = s.split_at(end);
+	match token {
+		"true" | "false" => Some((None, rest)),
+		_ if token.parse::<f64>().is_ok() => Some((Some(token.to_string()), rest)),
+		_ => None,
+	}
+}
+
+// The command line with the file's options added, except those the command
+// line already gives.
+pub fn merge(opts: &Options, options: &[ConfigOption], args: &[String])
+-> Result<Vec<String>, ConfigParseError>
+{
+	let matches = opts.parse(args).ok();
+	let mut merged = args.to_vec();
+	for option in options {
+		let flag = format!("--{}", option.name);
+		if let Err(getopts::Fail::UnrecognizedOption(_)) = opts.parse(&[&flag]) {
+			return Err(ConfigParseError::UnknownKey { line: option.line, key: option.name.clone() });
+		}
+		if option.name == "config" || matches.as_ref().is_some_and(|m| m.opt_present(&option.name)) {
+			continue;
+		}
+		if option.values.is_empty() {
+			merged.push(flag.clone());
+		}
+		for value in option.values.iter() {
+			merged.push(format!("{}={}", flag, value));
+		}
+	}
+
+	Ok(merged)
+}
+
+impl fmt::Display for ConfigParseError
+{
+	fn fmt(&self, f: &mut fmt::Formatter)
+	-> fmt::Result
+	{
+		match *self {
+			ConfigParseError::Syntax { line } =>
+				write!(f, "line {} is not a key = value pair", line),
+			ConfigParseError::BadValue { line, ref key } =>
+				write!(f, "line {}: invalid value for {:?}, expected a quoted string, a number, true, false or a list of them", line, key),
+			ConfigParseError::UnknownKey { line, ref key } =>
+				write!(f, "line {} has unknown key {:?}, expected the long name of an option", line, key),
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests
+{
+	use super::*;
+
+	fn options()
+	-> Options
+	{
+		let mut opts = Options::new();
+		opts.optflag("", "json", "");
+		opts.optopt("", "threads", "", "N");
+		opts.optopt("", "reset-on", "", "CHARS");
+		opts.optmulti("", "penalty-weight", "", "NAME=WEIGHT");
+		opts
+	}
+
+	#[test]
+	fn command_line_overrides_file()
+	{
+		let config = parse_config("# defaults\nthreads = 4\njson = true\nreset-on = \"\\n \"\npenalty-weight = [\"roll in=-0.5\", \"redirect=3\"]\n").unwrap();
+		assert_eq!(config.len(), 4);
+		assert_eq!(config[2].values, vec!["\n ".to_string()]);
+
+		let args: Vec<String> = vec!["corpus.txt".to_string(), "--threads".to_string(), "2".to_string()];
+		let merged = merge(&options(), &config, &args).unwrap();
+		let matches = options().parse(&merged).unwrap();
+		assert_eq!(matches.opt_str("threads"), Some("2".to_string()));
+		assert!(matches.opt_present("json"));
+		assert_eq!(matches.opt_str("reset-on"), Some("\n ".to_string()));
+		assert_eq!(matches.opt_strs("penalty-weight"), vec!["roll in=-0.5", "redirect=3"]);
+		assert_eq!(matches.free, vec!["corpus.txt"]);
+	}
+
+	#[test]
+	fn rejects_unknown_keys()
+	{
+		let config = parse_config("json = false\nthread = 4\n").unwrap();
+		assert_eq!(config.len(), 1);
+		match merge(&options(), &config, &[]) {
+			Err(ConfigParseError::UnknownKey { line: 2, .. }) => (),
+			_ => panic!("expected an unknown key"),
+		}
+		match parse_config("threads = four\n") {
+			Err(ConfigParseError::BadValue { line: 1, .. }) => (),
+			_ => panic!("expected a bad value"),
+		}
+	}
+}
@@ -0,0 +1,223 @@
+/// Errors that are reported to the user instead of panicking.
+
+use std::fmt;
+use std::io;
+
+use getopts;
+
+use cli::OptionValueError;
+use config::ConfigParseError;
+use keygen::checkpoint::CheckpointParseError;
+use keygen::layout::GeometryParseError;
+use keygen::layout::GroupsParseError;
+use keygen::layout::ComposeParseError;
+use keygen::layout::ConstraintParseError;
+use keygen::layout::KleParseError;
+use keygen::layout::LayoutParseError;
+use keygen::layout::PairsParseError;
+use keygen::layout::SwapError;
+use keygen::penalty::BigramParseError;
+use keygen::penalty::CountsParseError;
+use keygen::penalty::EffortParseError;
+use keygen::penalty::NgramParseError;
+use keygen::penalty::TimingsParseError;
+use keygen::penalty::WeightsParseError;
+use keygen::verify::ScoresParseError;
+
+pub enum Error
+{
+	Options(getopts::Fail),
+	ReadCorpus(String, io::Error),
+	ReadLayout(String, io::Error),
+	ReadEffort(String, io::Error),
+	ReadGeometry(String, io::Error),
+	ReadBigrams(String, io::Error),
+	ReadWeights(String, io::Error),
+	ReadTimings(String, io::Error),
+	ReadConfig(String, io::Error),
+	ReadGroups(String, io::Error),
+	ReadConstraints(String, io::Error),
+	ReadPairs(String, io::Error),
+	ReadCompose(String, io::Error),
+	ReadCheckpoint(String, io::Error),
+	ReadFixtures(String, io::Error),
+	WriteTrace(String, io::Error),
+	WriteCheckpoint(String, io::Error),
+	WriteSvg(String, io::Error),
+	WriteExport(String, io::Error),
+	WriteLayout(String, io::Error),
+	Terminal(io::Error),
+	WriteCounts(String, io::Error),
+	WriteEffort(String, io::Error),
+	WriteScores(String, io::Error),
+	ParseLayout(String, LayoutParseError),
+	ParseKle(String, KleParseError),
+	ParseNgrams(String, NgramParseError),
+	ParseCounts(String, CountsParseError),
+	CountsContext(String, usize, usize),
+	ParseEffort(String, EffortParseError),
+	ParseGeometry(String, GeometryParseError),
+	ParseBigrams(String, BigramParseError),
+	ParseWeights(String, WeightsParseError),
+	ParseTimings(String, TimingsParseError),
+	ParseConfig(String, ConfigParseError),
+	ParseGroups(String, GroupsParseError),
+	ParseConstraints(String, ConstraintParseError),
+	ParsePairs(String, PairsParseError),
+	ParseCompose(String, ComposeParseError),
+	ParseCheckpoint(String, CheckpointParseError),
+	ParseScores(String, ScoresParseError),
+	CheckpointMismatch(String, &'static str),
+	ScoresChanged(usize),
+	NoNumberRow(String),
+	Swap(String, SwapError),
+	InvalidLayout(String),
+	ComposeMissing(String, Vec<char>),
+	Coverage(String, f64, f64),
+	UnknownPenalty(String, Vec<String>),
+	UnknownPreset(String, Vec<String>),
+	UnknownCommand(String),
+	UnknownBoard(String),
+	OptionNotForCommand(String, String),
+	OptionConflict(&'static str, &'static str),
+	OptionValue(OptionValueError),
+	PenaltyWeight(String),
+	FingerLoad(String),
+	Normalize(String),
+	Hand(String),
+	ExportFormat(String),
+	ExportTemplate(String),
+}
+
+impl fmt::Display for Error
+{
+	fn fmt(&self, f: &mut fmt::Formatter)
+	-> fmt::Result
+	{
+		match *self {
+			Error::Options(ref e) =>
+				write!(f, "{}", e),
+			Error::ReadCorpus(ref path, ref e) =>
+				write!(f, "could not read corpus {}: {}", path, e),
+			Error::ReadLayout(ref path, ref e) =>
+				write!(f, "could not read layout {}: {}", path, e),
+			Error::ReadEffort(ref path, ref e) =>
+				write!(f, "could not read effort grid {}: {}", path, e),
+			Error::ReadGeometry(ref path, ref e) =>
+				write!(f, "could not read geometry {}: {}", path, e),
+			Error::ReadBigrams(ref path, ref e) =>
+				write!(f, "could not read bigram penalties {}: {}", path, e),
+			Error::ReadWeights(ref path, ref e) =>
+				write!(f, "could not read weights {}: {}", path, e),
+			Error::ReadTimings(ref path, ref e) =>
+				write!(f, "could not read timings {}: {}", path, e),
+			Error::ReadConfig(ref path, ref e) =>
+				write!(f, "could not read config {}: {}", path, e),
+			Error::ReadGroups(ref path, ref e) =>
+				write!(f, "could not read groups {}: {}", path, e),
+			Error::ReadConstraints(ref path, ref e) =>
+				write!(f, "could not read constraints {}: {}", path, e),
+			Error::ReadPairs(ref path, ref e) =>
+				write!(f, "could not read shift pairs {}: {}", path, e),
+			Error::ReadCompose(ref path, ref e) =>
+				write!(f, "could not read compose rules {}: {}", path, e),
+			Error::ReadCheckpoint(ref path, ref e) =>
+				write!(f, "could not read checkpoint {}: {}", path, e),
+			Error::ReadFixtures(ref path, ref e) =>
+				write!(f, "could not read fixture {}: {}", path, e),
+			Error::WriteTrace(ref path, ref e) =>
+				write!(f, "could not write trace {}: {}", path, e),
+			Error::WriteCheckpoint(ref path, ref e) =>
+				write!(f, "could not write checkpoint {}: {}", path, e),
+			Error::WriteSvg(ref path, ref e) =>
+				write!(f, "could not write picture {}: {}", path, e),
+			Error::WriteExport(ref path, ref e) =>
+				write!(f, "could not write export {}: {}", path, e),
+			Error::Terminal(ref e) =>
+				write!(f, "could not put the terminal into raw mode: {}", e),
+			Error::WriteLayout(ref path, ref e) =>
+				write!(f, "could not write layout {}: {}", path, e),
+			Error::WriteCounts(ref path, ref e) =>
+				write!(f, "could not write quartad counts {}: {}", path, e),
+			Error::WriteEffort(ref path, ref e) =>
+				write!(f, "could not write effort grid {}: {}", path, e),
+			Error::WriteScores(ref path, ref e) =>
+				write!(f, "could not write scores {}: {}", path, e),
+			Error::ParseLayout(ref path, ref e) =>
+				write!(f, "invalid layout {}: {}", path, e),
+			Error::ParseKle(ref path, ref e) =>
+				write!(f, "invalid keyboard-layout-editor layout {}: {}", path, e),
+			Error::ParseNgrams(ref path, ref e) =>
+				write!(f, "invalid n-gram table {}: {}", path, e),
+			Error::ParseCounts(ref path, ref e) =>
+				write!(f, "invalid quartad counts {}: {}", path, e),
+			Error::CountsContext(ref path, found, expected) =>
+				write!(f, "quartad counts {} were made with a context of {}, not {}", path, found, expected),
+			Error::ParseEffort(ref path, ref e) =>
+				write!(f, "invalid effort grid {}: {}", path, e),
+			Error::ParseBigrams(ref path, ref e) =>
+				write!(f, "invalid bigram penalties {}: {}", path, e),
+			Error::ParseWeights(ref path, ref e) =>
+				write!(f, "invalid weights {}: {}", path, e),
+			Error::ParseTimings(ref path, ref e) =>
+				write!(f, "invalid timings {}: {}", path, e),
+			Error::ParseConfig(ref path, ref e) =>
+				write!(f, "invalid config {}: {}", path, e),
+			Error::ParseGeometry(ref path, ref e) =>
+				write!(f, "invalid geometry {}: {}", path, e),
+			Error::ParseGroups(ref path, ref e) =>
+				write!(f, "invalid groups {}: {}", path, e),
+			Error::ParseConstraints(ref path, ref e) =>
+				write!(f, "invalid constraints {}: {}", path, e),
+			Error::ParsePairs(ref path, ref e) =>
+				write!(f, "invalid shift pairs {}: {}", path, e),
+			Error::ParseCompose(ref path, ref e) =>
+				write!(f, "invalid compose rules {}: {}", path, e),
+			Error::ParseCheckpoint(ref path, ref e) =>
+				write!(f, "invalid checkpoint {}: {}", path, e),
+			Error::ParseScores(ref path, ref e) =>
+				write!(f, "invalid scores {}: {}", path, e),
+			Error::CheckpointMismatch(ref path, what) =>
+				write!(f, "checkpoint {} was saved with different {} (use --force to resume from it anyway)", path, what),
+			Error::ScoresChanged(n) =>
+				write!(f, "{} score(s) changed (use verify --bless if the change is intended)", n),
+			Error::NoNumberRow(ref path) =>
+				write!(f, "geometry {} has no number row for --rows 4; add a line of coordinates above the top row", path),
+			Error::Swap(ref arg, ref e) =>
+				write!(f, "invalid swap {:?}: {}", arg, e),
+			Error::InvalidLayout(ref name) =>
+				write!(f, "layout {} has duplicate or missing keys (use --force to score it anyway)", name),
+			Error::ComposeMissing(ref name, ref missing) =>
+				write!(f, "layout {} has no key for {:?}, which compose rules type (use --force to score it anyway)", name, missing),
+			Error::Coverage(ref path, coverage, threshold) =>
+				write!(f, "only {:.2}% of corpus {} can be typed, below the required {:.2}%",
+					coverage * 100.0, path, threshold * 100.0),
+			Error::UnknownPenalty(ref name, ref names) =>
+				write!(f, "unknown penalty {:?}; valid penalties are: {}", name, names.join(", ")),
+			Error::UnknownPreset(ref name, ref names) =>
+				write!(f, "unknown preset {:?}; valid presets are: {}", name, names.join(", ")),
+			Error::UnknownCommand(ref name) =>
+				write!(f, "unknown command {:?}", name),
+			Error::UnknownBoard(ref name) =>
+				write!(f, "unknown board {:?}, expected matrix34, matrix33 or ansi30", name),
+			Error::OptionValue(ref e) =>
+				write!(f, "{}", e),
+			Error::OptionNotForCommand(ref option, ref command) =>
+				write!(f, "--{} does not apply to {}; see `help {}` for its options", option, command, command),
+			Error::OptionConflict(a, b) =>
+				write!(f, "--{} can't be used with --{}", a, b),
+			Error::PenaltyWeight(ref arg) =>
+				write!(f, "invalid penalty weight {:?}, expected NAME=WEIGHT", arg),
+			Error::FingerLoad(ref arg) =>
+				write!(f, "invalid finger load {:?}, expected 8 comma-separated shares", arg),
+			Error::Normalize(ref arg) =>
+				write!(f, "invalid replacement {:?}, expected a character, '=' and the text to type for it", arg),
+			Error::Hand(ref arg) =>
+				write!(f, "unknown hand {:?}, expected left or right", arg),
+			Error::ExportFormat(ref format) =>
+				write!(f, "unknown export format {:?}, expected qmk, xkb or kanata", format),
+			Error::ExportTemplate(ref name) =>
+				write!(f, "unknown QMK template {:?}, expected keymap, corne or lily58", name),
+		}
+	}
+}
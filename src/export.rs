@@ -0,0 +1,403 @@
+/// Writes layouts out in the formats of the tools that people type with.
+
+use layout::{us_shift, Layout};
+
+// Where each key of a QMK LAYOUT macro comes from: a position of the layout,
+// the configurable thumb key, or nothing.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum QmkSlot
+{
+	Pos(usize),
+	Thumb,
+	None,
+}
+
+// A keyboard's LAYOUT macro and the order of its arguments, row by row.
+pub struct QmkTemplate
+{
+	pub name:  String,
+	pub rows:  Vec<Vec<QmkSlot>>,
+}
+
+// Built-in templates: a grid of positions (`T` for an extra thumb key, `-` for
+// a key the layout doesn't fill) for each macro.
+static QMK_TEMPLATES: [(&str, &str, &str); 3] = [
+	("keymap", "LAYOUT",
+		"0 1 2 3 4 5 6 7 8 9 10\n11 12 13 14 15 16 17 18 19 20 21\n22 23 24 25 26 27 28 29 30 31\n32 33"),
+	("corne", "LAYOUT_split_3x6_3",
+		"- 0 1 2 3 4 5 6 7 8 9 10\n- 11 12 13 14 15 16 17 18 19 20 21\n- 22 23 24 25 26 27 28 29 30 31 -\nT T 32 33 T T"),
+	("lily58", "LAYOUT",
+		"- - - - - - - - - - - -\n- 0 1 2 3 4 5 6 7 8 9 10\n- 11 12 13 14 15 16 17 18 19 20 21\n- 22 23 24 25 26 - - 27 28 29 30 31 -\nT T T 32 33 T T T"),
+];
+
+impl QmkTemplate
+{
+	// Looks up a built-in template: keymap (the positions in order), corne or
+	// lily58.
+	pub fn from_name(name: &str)
+	-> Option<QmkTemplate>
+	{
+		QMK_TEMPLATES.iter().find(|t| t.0 == name.to_lowercase()).map(|&(_, macro_name, grid)| {
+			let rows = grid.lines().map(|line| line.split_whitespace().map(|token| match token {
+				"T" => QmkSlot::Thumb,
+				"-" => QmkSlot::None,
+				n   => QmkSlot::Pos(n.parse().unwrap()),
+			}).collect()).collect();
+			QmkTemplate { name: macro_name.to_string(), rows }
+		})
+	}
+}
+
+// Writes a keymap.c with the layout as its only layer. Positions the layout
+// leaves empty get `thumb_key`, like the template's extra thumb keys. Where
+// the upper layer doesn't hold the US shifted character of the lower layer,
+// a comment gives the key override that would type it.
+pub fn qmk(layout: &Layout, template: &QmkTemplate, thumb_key: &str)
+-> String
+{
+	let mut out = String::from("// Generated by keygen.\n#include QMK_KEYBOARD_H\n\n");
+
+	let mut overrides = Vec::new();
+	let mut missing = Vec::new();
+	for pos in 0..34 {
+		let (lower, upper) = layout.keys_at(pos);
+		if lower == '\0' {
+			continue;
+		}
+		let keycode = match qmk_keycode(lower) {
+			Some(k) => k,
+			None => { missing.push((pos, lower)); continue }
+		};
+		if upper != '\0' && upper != lower && Some(upper) != us_shift(lower) {
+			match qmk_keycode(upper) {
+				Some(shifted) => overrides.push(format!("//     ko_make_basic(MOD_MASK_SHIFT, {}, {}), // {:?} -> {:?}", keycode, shifted, lower, upper)),
+				None => missing.push((pos, upper)),
+			}
+		}
+	}
+	for &(pos, c) in missing.iter() {
+		out.push_str(&format!("// No keycode for {:?} at position {}.\n", c, pos));
+	}
+	if !overrides.is_empty() {
+		out.push_str("// Shifted characters that differ from US QWERTY need key overrides:\n");
+		for o in overrides.iter() {
+			out.push_str(o);
+			out.push('\n');
+		}
+	}
+	if !missing.is_empty() || !overrides.is_empty() {
+		out.push('\n');
+	}
+
+	let slot = |s: QmkSlot| match s {
+		QmkSlot::Pos(pos) => match layout.keys_at(pos).0 {
+			'\0' => thumb_key.to_string(),
+			c => qmk_keycode(c).unwrap_or("KC_NO").to_string(),
+		},
+		QmkSlot::Thumb => thumb_key.to_string(),
+		QmkSlot::None => "KC_NO".to_string(),
+	};
+	let rows: Vec<String> = template.rows.iter().map(|row| {
+		let cells: Vec<String> = row.iter().map(|&s| format!("{:<8}", slot(s) + ",")).collect();
+		format!("        {}", cells.join(" ").trim_end())
+	}).collect();
+	let body = rows.join("\n");
+	out.push_str("const uint16_t PROGMEM keymaps[][MATRIX_ROWS][MATRIX_COLS] = {\n");
+	out.push_str(&format!("    [0] = {}(\n{}\n    ),\n}};\n", template.name, body.trim_end_matches(',')));
+	out
+}
+
+// Writes an xkb_symbols stanza that replaces the letter rows of US QWERTY, the
+// lower layer on level 1 and the upper layer on level 2. The thumb keys are
+// only described in a comment, since moving space around in xkb does more harm
+// than good.
+pub fn xkb(layout: &Layout, name: &str)
+-> String
+{
+	let mut out = String::from("// Generated by keygen.\n");
+	out.push_str("default partial alphanumeric_keys\n");
+	out.push_str("xkb_symbols \"basic\" {\n");
+	out.push_str("    include \"us(basic)\"\n");
+	out.push_str(&format!("    name[Group1] = {};\n\n", ::json::string(name)));
+	for pos in 0..32 {
+		let (lower, upper) = layout.keys_at(pos);
+		if lower == '\0' {
+			continue;
+		}
+		let upper = if upper == '\0' { lower } else { upper };
+		out.push_str(&format!("    key <{}> {{ [ {}, {} ] }};\n", xkb_key_name(pos), xkb_keysym(lower), xkb_keysym(upper)));
+	}
+	for &(pos, thumb) in [(32, "left thumb"), (33, "right thumb")].iter() {
+		let (lower, upper) = layout.keys_at(pos);
+		if lower != '\0' {
+			out.push_str(&format!("    // {}: {:?} / {:?}\n", thumb, lower, upper));
+		}
+	}
+	out.push_str("};\n");
+	out
+}
+
+// The xkb name of the key at a position of the letter rows.
+fn xkb_key_name(pos: usize)
+-> String
+{
+	match pos {
+		0..=10  => format!("AD{:02}", pos + 1),
+		11..=21 => format!("AC{:02}", pos - 10),
+		_       => format!("AB{:02}", pos - 21),
+	}
+}
+
+// Keysyms for the printable ASCII characters that aren't their own name.
+static XKB_KEYSYMS: [(char, &str); 33] = [
+	(' ', "space"), ('!', "exclam"), ('"', "quotedbl"), ('#', "numbersign"),
+	('$', "dollar"), ('%', "percent"), ('&', "ampersand"), ('\'', "apostrophe"),
+	('(', "parenleft"), (')', "parenright"), ('*', "asterisk"), ('+', "plus"),
+	(',', "comma"), ('-', "minus"), ('.', "period"), ('/', "slash"),
+	(':', "colon"), (';', "semicolon"), ('<', "less"), ('=', "equal"),
+	('>', "greater"), ('?', "question"), ('@', "at"), ('[', "bracketleft"),
+	('\\', "backslash"), (']', "bracketright"), ('^', "asciicircum"), ('_', "underscore"),
+	('`', "grave"), ('{', "braceleft"), ('|', "bar"), ('}', "braceright"),
+	('~', "asciitilde")];
+
+// The keysym of a character: letters and digits are their own, other ASCII
+// has a name, and anything else is given by its code point.
+fn xkb_keysym(c: char)
+-> String
+{
+	if c.is_ascii_alphanumeric() {
+		return c.to_string();
+	}
+	match XKB_KEYSYMS.iter().find(|k| k.0 == c) {
+		Some(&(_, name)) => name.to_string(),
+		None => format!("U{:04X}", c as u32),
+	}
+}
+
+// The key of a standard ANSI keyboard at each position, by kanata's names. The
+// left thumb has none.
+pub static KANATA_ANSI: [Option<&str>; 34] = [
+	Some("q"), Some("w"), Some("e"), Some("r"), Some("t"),    Some("y"), Some("u"), Some("i"), Some("o"), Some("p"), Some("["),
+	Some("a"), Some("s"), Some("d"), Some("f"), Some("g"),    Some("h"), Some("j"), Some("k"), Some("l"), Some(";"), Some("'"),
+	Some("z"), Some("x"), Some("c"), Some("v"), Some("b"),    Some("n"), Some("m"), Some(","), Some("."), Some("/"),
+	                                               None,       Some("spc")];
+
+// Writes a kanata configuration that types the layout on an ANSI keyboard. If
+// any upper layer character isn't the US shift of its lower layer character,
+// shift is held along with a layer that types the upper layer instead,
+// releasing shift for the characters typed without it on a US host.
+pub fn kanata(layout: &Layout)
+-> String
+{
+	let positions: Vec<usize> = (0..34).filter(|&i| KANATA_ANSI[i].is_some()).collect();
+	let shifted = positions.iter().any(|&i| {
+		let (lower, upper) = layout.keys_at(i);
+		upper != '\0' && upper != lower && Some(upper) != us_shift(lower)
+	});
+
+	let mut out = String::from(";; Generated by keygen.\n");
+	let (lower, upper) = layout.keys_at(32);
+	if lower != '\0' {
+		out.push_str(&format!(";; The left thumb key ({:?} / {:?}) has no place on an ANSI keyboard.\n", lower, upper));
+	}
+	if shifted {
+		out.push_str(";; Shift also switches to the shifted layer, which releases it where needed.\n");
+		out.push_str("(defalias shf (multi lsft (layer-while-held shifted)))\n");
+	}
+
+	let row = |cells: Vec<String>| {
+		let cells: Vec<String> = cells.iter().map(|c| format!("{:<6}", c)).collect();
+		format!("  {}\n", cells.join(" ").trim_end())
+	};
+	let block = |head: &str, key: &dyn Fn(usize) -> String, shift_keys: (&str, &str)| {
+		let mut block = format!("({}\n", head);
+		for range in [0..11, 11..22, 22..32, 32..34].iter() {
+			block.push_str(&row(range.clone().filter(|i| positions.contains(i)).map(key).collect()));
+		}
+		if shifted {
+			block.push_str(&row(vec![shift_keys.0.to_string(), shift_keys.1.to_string()]));
+		}
+		block.push_str(")\n");
+		block
+	};
+	let layer = |upper: bool| move |i: usize| {
+		let (lower, shifted) = layout.keys_at(i);
+		let c = if upper && shifted != '\0' { shifted } else { lower };
+		if c == '\0' {
+			return String::from("_");
+		}
+		// Shift is held on the shifted layer, so a remapped character that
+		// a US host types without it has to release it.
+		let action = kanata_action(c);
+		let remapped = upper && shifted != '\0' && Some(shifted) != us_shift(lower);
+		if remapped && !action.starts_with("S-") && !action.starts_with('(') {
+			format!("(unshift {})", action)
+		} else {
+			action
+		}
+	};
+
+	out.push_str(&block("defsrc", &|i| KANATA_ANSI[i].unwrap().to_string(), ("lsft", "rsft")));
+	out.push_str(&block("deflayer base", &layer(false), ("@shf", "@shf")));
+	if shifted {
+		out.push_str(&block("deflayer shifted", &layer(true), ("_", "_")));
+	}
+	out
+}
+
+// The kanata action that types a character on a US host.
+fn kanata_action(c: char)
+-> String
+{
+	if c == ' ' {
+		return String::from("spc");
+	}
+	if c == '`' {
+		return String::from("grv");
+	}
+	if c.is_ascii_uppercase() {
+		return format!("S-{}", c.to_ascii_lowercase());
+	}
+	if c.is_ascii_alphanumeric() || "-=[]\\;',./".contains(c) {
+		return c.to_string();
+	}
+	match "`1234567890-=[]\\;',./".chars().find(|&u| us_shift(u) == Some(c)) {
+		Some(u) => format!("S-{}", kanata_action(u)),
+		None => format!("(unicode {})", c),
+	}
+}
+
+// The QMK keycode that types a character on a US host.
+fn qmk_keycode(c: char)
+-> Option<&'static str>
+{
+	static LETTERS: [&str; 26] = [
+		"KC_A", "KC_B", "KC_C", "KC_D", "KC_E", "KC_F", "KC_G", "KC_H", "KC_I",
+		"KC_J", "KC_K", "KC_L", "KC_M", "KC_N", "KC_O", "KC_P", "KC_Q", "KC_R",
+		"KC_S", "KC_T", "KC_U", "KC_V", "KC_W", "KC_X", "KC_Y", "KC_Z"];
+	static DIGITS: [&str; 10] = [
+		"KC_0", "KC_1", "KC_2", "KC_3", "KC_4", "KC_5", "KC_6", "KC_7", "KC_8", "KC_9"];
+	let keycode = match c {
+		'a'..='z' => LETTERS[c as usize - 'a' as usize],
+		'A'..='Z' => LETTERS[c as usize - 'A' as usize],
+		'0'..='9' => DIGITS[c as usize - '0' as usize],
+		' '  => "KC_SPC",
+		'-'  => "KC_MINS",
+		'='  => "KC_EQL",
+		'['  => "KC_LBRC",
+		']'  => "KC_RBRC",
+		'\\' => "KC_BSLS",
+		';'  => "KC_SCLN",
+		'\'' => "KC_QUOT",
+		'`'  => "KC_GRV",
+		','  => "KC_COMM",
+		'.'  => "KC_DOT",
+		'/'  => "KC_SLSH",
+		'!'  => "KC_EXLM",
+		'@'  => "KC_AT",
+		'#'  => "KC_HASH",
+		'$'  => "KC_DLR",
+		'%'  => "KC_PERC",
+		'^'  => "KC_CIRC",
+		'&'  => "KC_AMPR",
+		'*'  => "KC_ASTR",
+		'('  => "KC_LPRN",
+		')'  => "KC_RPRN",
+		'_'  => "KC_UNDS",
+		'+'  => "KC_PLUS",
+		'{'  => "KC_LCBR",
+		'}'  => "KC_RCBR",
+		'|'  => "KC_PIPE",
+		':'  => "KC_COLN",
+		'"'  => "KC_DQUO",
+		'~'  => "KC_TILD",
+		'<'  => "KC_LABK",
+		'>'  => "KC_RABK",
+		'?'  => "KC_QUES",
+		_ => { return None },
+	};
+	Some(keycode)
+}
+
+#[cfg(test)]
+mod tests
+{
+	use layout::{Layout, MATRIX34_BOARD};
+	use super::*;
+
+	#[test]
+	fn qmk_keymap()
+	{
+		// RSTHD, with ',' shifting to ';' instead of '<'.
+		let rsthd = "jcyfk zl,uq=\nrsthd mnaio'\n/vgpb xw.;-e \nJCYFK ZL;UQ+\nRSTHD MNAIO\"\n?VGPB XW>:_E \n";
+		let layout = Layout::from_string(rsthd, MATRIX34_BOARD).unwrap();
+		let keymap = qmk(&layout, &QmkTemplate::from_name("corne").unwrap(), "KC_BSPC");
+		assert!(keymap.contains("//     ko_make_basic(MOD_MASK_SHIFT, KC_COMM, KC_SCLN), // ',' -> ';'\n"), "{}", keymap);
+		assert!(keymap.contains("    [0] = LAYOUT_split_3x6_3(\n        KC_NO,   KC_J,    KC_C,"), "{}", keymap);
+		assert!(keymap.contains("        KC_BSPC, KC_BSPC, KC_E,    KC_SPC,  KC_BSPC, KC_BSPC\n    ),\n};\n"), "{}", keymap);
+		assert_eq!(keymap.matches("KC_").count(), 42 + 2);
+	}
+
+	#[test]
+	fn kanata_positions_are_ansi()
+	{
+		let rows: Vec<String> = [0..11, 11..22, 22..32, 32..34].iter()
+			.map(|r| r.clone().map(|i| KANATA_ANSI[i].unwrap_or("-")).collect::<Vec<&str>>().join(" "))
+			.collect();
+		assert_eq!(rows, vec!["q w e r t y u i o p [", "a s d f g h j k l ; '", "z x c v b n m , . /", "- spc"]);
+	}
+
+	#[test]
+	fn kanata_config()
+	{
+		let rsthd = "jcyfk zl,uq=\nrsthd mnaio'\n/vgpb xw.;-e \nJCYFK ZL<UQ+\nRSTHD MNAIO\"\n?VGPB XW>:_E \n";
+		let layout = Layout::from_string(rsthd, MATRIX34_BOARD).unwrap();
+		let config = kanata(&layout);
+		assert!(config.contains(";; The left thumb key ('e' / 'E') has no place on an ANSI keyboard.\n"), "{}", config);
+		assert!(!config.contains("shifted"), "{}", config);
+		assert!(config.contains("(defsrc\n  q      w      e      r      t      y      u      i      o      p      [\n"), "{}", config);
+		assert!(config.contains("(deflayer base\n  j      c      y      f      k      z      l      ,      u      q      =\n"), "{}", config);
+
+		// ',' shifting to ';' needs its own layer.
+		let layout = Layout::from_string(&rsthd.replace('<', ";"), MATRIX34_BOARD).unwrap();
+		let config = kanata(&layout);
+		assert!(config.contains("(defalias shf (multi lsft (layer-while-held shifted)))\n"), "{}", config);
+		assert!(config.contains("  lsft   rsft\n)\n(deflayer base"), "{}", config);
+		assert!(config.contains("(deflayer shifted\n  S-j    S-c    S-y    S-f    S-k    S-z    S-l    (unshift ;) S-u    S-q    S-=\n"), "{}", config);
+		assert!(config.contains("  @shf   @shf\n"), "{}", config);
+	}
+
+	// Reads the keys of an xkb_symbols stanza back into characters.
+	fn parse_xkb(symbols: &str)
+	-> Vec<(String, char, char)>
+	{
+		let keysym = |sym: &str| match XKB_KEYSYMS.iter().find(|k| k.1 == sym) {
+			Some(&(c, _)) => c,
+			None if sym.len() == 1 => sym.chars().next().unwrap(),
+			None => ::std::char::from_u32(u32::from_str_radix(&sym[1..], 16).unwrap()).unwrap(),
+		};
+		symbols.lines().map(|l| l.trim()).filter(|l| l.starts_with("key <")).map(|l| {
+			let name = l[5..9].to_string();
+			let syms: Vec<&str> = l[l.find('[').unwrap() + 1..l.find(']').unwrap()].split(',').map(|s| s.trim()).collect();
+			(name, keysym(syms[0]), keysym(syms[1]))
+		}).collect()
+	}
+
+	#[test]
+	fn xkb_round_trip()
+	{
+		let layout_str = "jcyfk zl,uq=\nrsthd mnaio'\n/vgpb xw.;-e \nJCYFK ZL<UQ\u{c9}\nRSTHD MNAIO\"\n?VGPB XW>:_E \n";
+		let layout = Layout::from_string(layout_str, MATRIX34_BOARD).unwrap();
+		let symbols = xkb(&layout, "rsthd");
+		assert!(symbols.contains("    key <AB10> { [ minus, underscore ] };\n"), "{}", symbols);
+		assert!(symbols.contains("    key <AD11> { [ equal, U00C9 ] };\n"), "{}", symbols);
+		assert!(symbols.contains("    // left thumb: 'e' / 'E'\n"), "{}", symbols);
+
+		let keys = parse_xkb(&symbols);
+		assert_eq!(keys.len(), 32);
+		for (pos, (name, lower, upper)) in keys.into_iter().enumerate() {
+			assert_eq!(name, xkb_key_name(pos));
+			assert_eq!((lower, upper), layout.keys_at(pos));
+		}
+	}
+}
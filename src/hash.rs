@@ -0,0 +1,58 @@
+/// A fast hash map for counting and scoring n-grams, whose keys are short
+/// strings that nobody chooses adversarially, so SipHash's protection isn't
+/// worth its cost.
+
+use std::collections::HashMap;
+use std::hash::{BuildHasherDefault, Hasher};
+
+pub type FastHashMap<K, V> = HashMap<K, V, BuildHasherDefault<FnvHasher>>;
+
+const FNV_OFFSET: u64 = 0xcbf29ce484222325;
+const FNV_PRIME: u64 = 0x100000001b3;
+
+// 64-bit FNV-1a.
+pub struct FnvHasher(u64);
+
+impl Default for FnvHasher
+{
+	fn default()
+	-> FnvHasher
+	{
+		FnvHasher(FNV_OFFSET)
+	}
+}
+
+impl Hasher for FnvHasher
+{
+	fn write(&mut self, bytes: &[u8])
+	{
+		for &b in bytes {
+			self.0 = (self.0 ^ b as u64).wrapping_mul(FNV_PRIME);
+		}
+	}
+
+	fn finish(&self)
+	-> u64
+	{
+		self.0
+	}
+}
+
+#[cfg(test)]
+mod tests
+{
+	use super::*;
+
+	#[test]
+	fn fnv_matches_reference()
+	{
+		let mut hasher = FnvHasher::default();
+		hasher.write(b"a");
+		assert_eq!(hasher.finish(), 0xaf63dc4c8601ec8c);
+
+		let mut counts: FastHashMap<&str, usize> = FastHashMap::default();
+		*counts.entry("th").or_insert(0) += 1;
+		*counts.entry("th").or_insert(0) += 1;
+		assert_eq!(counts["th"], 2);
+	}
+}
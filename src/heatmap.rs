@@ -0,0 +1,76 @@
+/// Draws a value per key position over a layout in the terminal, as colours
+/// where the terminal has them and as percentages where it doesn't.
+
+use std::ops::Range;
+
+use layout::{KeyMap, Layout, NUM_KEYS};
+
+// ANSI 256-colour backgrounds from cold to hot.
+static PALETTE: [u8; 10] = [17, 19, 26, 31, 36, 71, 142, 214, 208, 196];
+
+// Renders `values` on the keys of `layout`. In colour each key shows its
+// character on a background scaled to the largest value; otherwise each shows
+// its character and its share of the sum of the values.
+pub fn render(layout: &Layout, values: &KeyMap<f64>, color: bool)
+-> String
+{
+	let KeyMap(ref values) = *values;
+	let board = layout.board();
+	let max = values.iter().cloned().fold(0.0, f64::max);
+	let sum: f64 = values.iter().sum();
+
+	let cell = |pos: usize| {
+		let c = match layout.keys_at(pos).0 {
+			' ' => '_',
+			'\0' => ' ',
+			c => c,
+		};
+		if color {
+			let heat = if max > 0.0 { values[pos].max(0.0) / max } else { 0.0 };
+			let level = (heat * (PALETTE.len() - 1) as f64).round() as usize;
+			let fg = if level < PALETTE.len() / 2 { 231 } else { 16 };
+			format!("\x1b[48;5;{}m\x1b[38;5;{}m {} \x1b[0m", PALETTE[level], fg, c)
+		} else {
+			let share = if sum != 0.0 { values[pos] / sum * 100.0 } else { 0.0 };
+			format!("{} {:>4.1}", c, share)
+		}
+	};
+	let width = if color { 3 } else { 6 };
+	let keys = |range: Range<usize>| range
+		.filter(|&i| board.exists(i))
+		.map(&cell)
+		.collect::<Vec<String>>()
+		.join(" ");
+
+	let mut out = String::new();
+	if board.has_number_row() {
+		out.push_str(&format!("{} | {}\n", keys(34..39), keys(39..NUM_KEYS)));
+	}
+	out.push_str(&format!("{} | {}\n", keys(0..5), keys(5..11)));
+	out.push_str(&format!("{} | {}\n", keys(11..16), keys(16..22)));
+	out.push_str(&format!("{} | {}", keys(22..27), keys(27..32)));
+	if board.exists(32) || board.exists(33) {
+		let left = if board.exists(32) { cell(32) } else { " ".repeat(width) };
+		out.push_str(&format!("\n{}{} | {}", " ".repeat(4 * (width + 1)), left, keys(33..34)));
+	}
+	out
+}
+
+#[cfg(test)]
+mod tests
+{
+	use layout::QWERTY_LAYOUT;
+	use super::*;
+
+	#[test]
+	fn plain_heatmap_shows_shares()
+	{
+		let mut values = KeyMap([0.0; NUM_KEYS]);
+		values.0[13] = 3.0;
+		values.0[33] = 1.0;
+		let lines: Vec<String> = render(&QWERTY_LAYOUT, &values, false).lines().map(|l| l.to_string()).collect();
+		assert_eq!(lines[1], "a  0.0 s  0.0 d 75.0 f  0.0 g  0.0 | h  0.0 j  0.0 k  0.0 l  0.0 ;  0.0 '  0.0");
+		assert_eq!(lines[3], format!("{}| _ 25.0", " ".repeat(35)));
+		assert!(render(&QWERTY_LAYOUT, &values, true).contains("\x1b[48;5;196m\x1b[38;5;16m d \x1b[0m"));
+	}
+}
@@ -0,0 +1,294 @@
+/// Just enough JSON to print results and read keyboard-layout-editor files
+/// without a serialisation library.
+
+use std::fmt;
+use std::iter::Peekable;
+use std::str::CharIndices;
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum Value
+{
+	Null,
+	Bool(bool),
+	Number(f64),
+	String(String),
+	Array(Vec<Value>),
+	// Members in the order they were written.
+	Object(Vec<(String, Value)>),
+}
+
+impl Value
+{
+	// The member named `key`, if this is an object with one.
+	pub fn get(&self, key: &str)
+	-> Option<&Value>
+	{
+		match *self {
+			Value::Object(ref members) => members.iter().find(|&(name, _)| name == key).map(|(_, value)| value),
+			_ => None,
+		}
+	}
+}
+
+#[derive(Debug)]
+pub struct ParseError
+{
+	pub offset:   usize,
+	pub expected: &'static str,
+}
+
+// Quotes a string for JSON output.
+pub fn string(s: &str)
+-> String
+{
+	let mut quoted = String::from("\"");
+	for c in s.chars() {
+		match c {
+			'"'  => quoted.push_str("\\\""),
+			'\\' => quoted.push_str("\\\\"),
+			c if (c as u32) < 0x20 => quoted.push_str(&format!("\\u{:04x}", c as u32)),
+			c => quoted.push(c),
+		}
+	}
+	quoted.push('"');
+	quoted
+}
+
+// Formats a number for JSON, which has no infinities or NaN.
+pub fn number(n: f64)
+-> String
+{
+	if n.is_finite() { n.to_string() } else { String::from("null") }
+}
+
+// Parses a JSON document. Object keys may also be left unquoted, as in the raw
+// data of keyboard-layout-editor.
+pub fn parse(s: &str)
+-> Result<Value, ParseError>
+{
+	let mut parser = Parser { s, chars: s.char_indices().peekable() };
+	let value = parser.value()?;
+	parser.skip_whitespace();
+	match parser.chars.peek() {
+		None => Ok(value),
+		Some(&(offset, _)) => Err(ParseError { offset, expected: "end of input" }),
+	}
+}
+
+struct Parser<'a>
+{
+	s:     &'a str,
+	chars: Peekable<CharIndices<'a>>,
+}
+
+impl <'a> Parser<'a>
+{
+	fn offset(&mut self)
+	-> usize
+	{
+		let len = self.s.len();
+		self.chars.peek().map_or(len, |&(i, _)| i)
+	}
+
+	fn error<T>(&mut self, expected: &'static str)
+	-> Result<T, ParseError>
+	{
+		Err(ParseError { offset: self.offset(), expected })
+	}
+
+	fn skip_whitespace(&mut self)
+	{
+		while self.chars.peek().is_some_and(|&(_, c)| c.is_whitespace()) {
+			self.chars.next();
+		}
+	}
+
+	// Consumes `c` after any whitespace, if it's next.
+	fn eat(&mut self, c: char)
+	-> bool
+	{
+		self.skip_whitespace();
+		if self.chars.peek().is_some_and(|&(_, next)| next == c) {
+			self.chars.next();
+			true
+		} else {
+			false
+		}
+	}
+
+	fn value(&mut self)
+	-> Result<Value, ParseError>
+	{
+		self.skip_whitespace();
+		match self.chars.peek().map(|&(_, c)| c) {
+			Some('[') => {
+				self.chars.next();
+				let mut items = Vec::new();
+				if self.eat(']') {
+					return Ok(Value::Array(items));
+				}
+				loop {
+					items.push(self.value()?);
+					if self.eat(']') {
+						return Ok(Value::Array(items));
+					}
+					if !self.eat(',') {
+						return self.error("',' or ']'");
+					}
+				}
+			},
+			Some('{') => {
+				self.chars.next();
+				let mut members = Vec::new();
+				if self.eat('}') {
+					return Ok(Value::Object(members));
+				}
+				loop {
+					let key = self.key()?;
+					if !self.eat(':') {
+						return self.error("':'");
+					}
+					members.push((key, self.value()?));
+					if self.eat('}') {
+						return Ok(Value::Object(members));
+					}
+					if !self.eat(',') {
+						return self.error("',' or '}'");
+					}
+				}
+			},
+			Some('"') => self.string().map(Value::String),
+			Some(c) if c == '-' || c.is_ascii_digit() => {
+				let word = self.word();
+				match word.parse() {
+					Ok(n) => Ok(Value::Number(n)),
+					Err(_) => self.error("a number"),
+				}
+			},
+			Some(c) if c.is_ascii_alphabetic() => match &self.word()[..] {
+				"null"  => Ok(Value::Null),
+				"true"  => Ok(Value::Bool(true)),
+				"false" => Ok(Value::Bool(false)),
+				_ => self.error("a value"),
+			},
+			_ => self.error("a value"),
+		}
+	}
+
+	fn key(&mut self)
+	-> Result<String, ParseError>
+	{
+		self.skip_whitespace();
+		match self.chars.peek().map(|&(_, c)| c) {
+			Some('"') => self.string(),
+			Some(c) if c.is_ascii_alphanumeric() || c == '_' => Ok(self.word()),
+			_ => self.error("a key"),
+		}
+	}
+
+	// A run of characters that can make up a number, literal or bare key.
+	fn word(&mut self)
+	-> String
+	{
+		let mut word = String::new();
+		while let Some(&(_, c)) = self.chars.peek() {
+			if !(c.is_ascii_alphanumeric() || c == '_' || c == '-' || c == '+' || c == '.') {
+				break;
+			}
+			word.push(c);
+			self.chars.next();
+		}
+		word
+	}
+
+	fn string(&mut self)
+	-> Result<String, ParseError>
+	{
+		self.chars.next();
+		let mut string = String::new();
+		loop {
+			let c = match self.chars.next() {
+				Some((_, c)) => c,
+				None => { return self.error("'\"'") },
+			};
+			match c {
+				'"' => { return Ok(string) },
+				'\\' => {
+					let escaped = match self.chars.next() {
+						Some((_, 'n')) => '\n',
+						Some((_, 't')) => '\t',
+						Some((_, 'r')) => '\r',
+						Some((_, 'b')) => '\u{8}',
+						Some((_, 'f')) => '\u{c}',
+						Some((_, 'u')) => self.code_point()?,
+						Some((_, c)) => c,
+						None => { return self.error("an escape") },
+					};
+					string.push(escaped);
+				},
+				c => string.push(c),
+			}
+		}
+	}
+
+	// The character of a \u escape, which may be the first of a surrogate
+	// pair.
+	fn code_point(&mut self)
+	-> Result<char, ParseError>
+	{
+		let hex = |parser: &mut Parser| {
+			let digits: String = (0..4).filter_map(|_| parser.chars.next().map(|(_, c)| c)).collect();
+			match u32::from_str_radix(&digits, 16) {
+				Ok(n) => Ok(n),
+				Err(_) => parser.error("four hex digits"),
+			}
+		};
+		let mut n = hex(self)?;
+		if (0xd800..0xdc00).contains(&n) && self.eat('\\') && self.eat('u') {
+			let low = hex(self)?;
+			n = 0x10000 + ((n - 0xd800) << 10) + (low.wrapping_sub(0xdc00) & 0x3ff);
+		}
+		Ok(::std::char::from_u32(n).unwrap_or('\u{fffd}'))
+	}
+}
+
+impl fmt::Display for ParseError
+{
+	fn fmt(&self, f: &mut fmt::Formatter)
+	-> fmt::Result
+	{
+		write!(f, "expected {} at byte {}", self.expected, self.offset)
+	}
+}
+
+#[cfg(test)]
+mod tests
+{
+	use super::*;
+
+	#[test]
+	fn escapes_strings()
+	{
+		assert_eq!(string("a\"b\\c\n"), "\"a\\\"b\\\\c\\u000a\"");
+		assert_eq!(number(1.5), "1.5");
+		assert_eq!(number(1.0 / 0.0), "null");
+	}
+
+	#[test]
+	fn parses_documents()
+	{
+		let value = parse("[{a:7, \"w\": 1.5}, \"!\\n1\", [], true, null, -2e1, \"\\u00e9\\ud83d\\ude00\"]").unwrap();
+		assert_eq!(value, Value::Array(vec![
+			Value::Object(vec![("a".to_string(), Value::Number(7.0)), ("w".to_string(), Value::Number(1.5))]),
+			Value::String("!\n1".to_string()),
+			Value::Array(vec![]),
+			Value::Bool(true),
+			Value::Null,
+			Value::Number(-20.0),
+			Value::String("\u{e9}\u{1f600}".to_string()),
+		]));
+		assert_eq!(parse("{\"a\": [1]}").unwrap().get("a"), Some(&Value::Array(vec![Value::Number(1.0)])));
+		assert_eq!(parse("[1 2]").unwrap_err().to_string(), "expected ',' or ']' at byte 3");
+		assert_eq!(parse("[1] x").unwrap_err().to_string(), "expected end of input at byte 4");
+	}
+}
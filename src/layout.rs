@@ -1,9 +1,13 @@
 /// Data structures and methods for creating and shuffling keyboard layouts.
 
-extern crate rand;
-
+use std::cmp;
 use std::fmt;
-use self::rand::random;
+use std::iter;
+use std::ops::Range;
+use std::collections::HashMap;
+
+use json;
+use rng::random;
 
 /* ----- *
  * TYPES *
@@ -11,42 +15,210 @@ use self::rand::random;
 
 // KeyMap format:
 //    LEFT HAND   |    RIGHT HAND
+// 34 35 36 37 38 | 39 40 41 42 43 (number row, with --rows 4)
 //  0  1  2  3  4 |  5  6  7  8  9 10
 // 11 12 13 14 15 | 16 17 18 19 20 21 
 // 22 23 24 25 26 | 27 28 29 30 31
 //
 //             32 | 33 (thumb keys)
 
-pub struct KeyMap<T>(pub [T; 34]);
+pub const NUM_KEYS: usize = 44;
+
+// The positions of the number row, which only four-row boards have. It comes
+// after the thumb keys so that the other positions are numbered the same
+// either way.
+pub const NUMBER_ROW: Range<usize> = 34..NUM_KEYS;
+
+pub struct KeyMap<T>(pub [T; NUM_KEYS]);
 
 impl <T: Copy> Clone for KeyMap<T>
 {
 	fn clone(&self)
 	-> KeyMap<T>
 	{
-		KeyMap(self.0)
+		*self
 	}
 }
 
-#[derive(Clone)]
+impl <T: Copy> Copy for KeyMap<T> {}
+
+impl <T: PartialEq> PartialEq for KeyMap<T>
+{
+	fn eq(&self, other: &KeyMap<T>)
+	-> bool
+	{
+		self.0[..] == other.0[..]
+	}
+}
+
+#[derive(Clone, PartialEq)]
 pub struct Layer(KeyMap<char>);
 
-#[derive(Clone)]
-pub struct Layout(Layer, Layer);
+#[derive(Clone, PartialEq)]
+pub struct Layout(Layer, Layer, Board);
+
+// Which key positions a keyboard has, and which of those may be swapped.
+// Positions that it lacks hold no character and are never swapped.
+#[derive(Clone, Copy, PartialEq)]
+pub struct Board
+{
+	keys:   [bool; NUM_KEYS],
+	mask:   LayoutShuffleMask,
+	// Keys are only swapped with keys in the same group.
+	groups: [u8; NUM_KEYS],
+}
 
+// Every distinct layout within `depth` swaps of a layout, starting with the
+// layout itself. Each is reached by the fewest swaps that make it, written
+// cycle by cycle: a cycle through keys a < b, c, ... is the swaps (a, b),
+// (a, c), ... and cycles come in order of their first key.
 pub struct LayoutPermutations
 {
 	orig_layout: Layout,
-	swap_idx: Vec<usize>,
+	swappable: Vec<usize>,
+	groups: Vec<u8>,
+	depth: usize,
+	// Indices into `swappable`, two per swap, of the swaps last made.
+	slots: Vec<usize>,
 	started: bool,
 }
 
-pub struct LayoutPosMap([Option<KeyPress>; 128]);
+// Where each ASCII character is typed, and whether characters on both layers
+// are typed from the lower one.
+#[derive(Clone, Copy)]
+pub struct LayoutPosMap([Option<KeyPress>; 128], bool);
+
+#[derive(Debug)]
+pub enum LayoutParseError
+{
+	LineCount { found: usize },
+	KeyCount { line: usize, found: usize, expected: usize },
+	LayerMismatch { lower: usize, upper: usize },
+	MissingSeparator { line: usize },
+	LongKey { line: usize, key: String },
+}
+
+#[derive(Debug)]
+pub enum KleParseError
+{
+	Json(json::ParseError),
+	NotRows,
+	NoAlphaBlock,
+}
+
+#[derive(Debug)]
+pub enum GeometryParseError
+{
+	LineCount { found: usize },
+	KeyCount { line: usize, found: usize, expected: usize },
+	BadCoordinate { line: usize, column: usize, found: String },
+	HomeCount { hand: Hand, finger: Finger, found: usize },
+}
 
+// The physical position of each key, in key widths, and of the key that each
+// key's finger rests on.
 #[derive(Clone)]
-pub struct LayoutShuffleMask(KeyMap<bool>);
+pub struct Geometry
+{
+	pub coords: KeyMap<(f64, f64)>,
+	pub homes:  KeyMap<(f64, f64)>,
+	pub board:  Board,
+}
+
+pub enum LayoutProblem
+{
+	DuplicateChar { layer: &'static str, kc: char, first: usize, second: usize },
+	CrossLayer { kc: char, lower: usize, upper: usize },
+	Unshifted { kc: char, pos: usize },
+	Hole { pos: usize },
+	Missing { kc: char, pos: usize },
+	Unpaired { kc: char, pos: usize, found: char, expected: char },
+}
+
+// The character that shift gives with each lower layer character, for filling
+// in upper layers that layout files leave out and for checking those they
+// don't.
+#[derive(Clone)]
+pub struct ShiftPairs(HashMap<char, char>);
+
+#[derive(Debug)]
+pub enum PairsParseError
+{
+	Syntax { line: usize },
+	NotOneChar { line: usize, found: String },
+}
+
+// Characters typed as a sequence of keystrokes, like an accented letter after
+// a dead key, each keystroke being a character of the layout.
+#[derive(Clone)]
+pub struct Compose(HashMap<char, String>);
+
+#[derive(Debug)]
+pub enum ComposeParseError
+{
+	Syntax { line: usize },
+	NotOneChar { line: usize, found: String },
+	NoKeystrokes { line: usize },
+}
+
+// The kind of key that `Layout::move_only` lets the optimiser move.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum KeyKind
+{
+	Letters,
+	Punctuation,
+}
+
+// Keeps characters on a hand, finger or row, wherever they are within it.
+pub struct Constraint
+{
+	pub line:   usize,
+	pub chars:  Vec<char>,
+	pub hand:   Option<Hand>,
+	pub finger: Option<Finger>,
+	pub row:    Option<Row>,
+}
+
+#[derive(Debug)]
+pub enum ConstraintParseError
+{
+	Syntax { line: usize },
+	UnknownKey { line: usize, key: String },
+	BadValue { line: usize, key: String, value: String },
+	MissingChars { line: usize },
+	MissingPlace { line: usize },
+}
+
+#[derive(Debug)]
+pub enum GroupsParseError
+{
+	MissingChars { line: usize },
+	Duplicate { line: usize, kc: char },
+}
+
+pub type Swap = (usize, usize);
+
+// One side of a swap given on the command line: a character or a position.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum SwapKey
+{
+	Char(char),
+	Position(usize),
+}
+
+#[derive(Debug)]
+pub enum SwapError
+{
+	Syntax,
+	NoPosition { pos: usize },
+	Missing { kc: char },
+	Repeated { kc: char, count: usize },
+}
 
 #[derive(Clone, Copy, PartialEq)]
+pub struct LayoutShuffleMask(KeyMap<bool>);
+
+#[derive(Clone, Copy, PartialEq, Debug)]
 pub enum Finger 
 {
 	Thumb,
@@ -56,16 +228,17 @@ pub enum Finger
 	Pinky,
 }
 
-#[derive(Clone, Copy, PartialEq)]
+#[derive(Clone, Copy, PartialEq, Debug)]
 pub enum Hand
 {
 	Left,
 	Right,
 }
 
-#[derive(Clone, Copy, PartialEq)]
+#[derive(Clone, Copy, PartialEq, Debug)]
 pub enum Row
 {
+	Number,
 	Top,
 	Home,
 	Bottom,
@@ -80,152 +253,370 @@ pub struct KeyPress
 	pub finger: Finger,
 	pub hand:   Hand,
 	pub row:    Row,
+	// Counting from the left of the row, 0 to 10.
+	pub col:    u8,
 	pub center: bool,
+	pub shifted: bool,
 }
 
 /* ------- *
  * STATICS *
  * ------- */
 
+// Everything but the top right corner, which is kept for a symbol, and the
+// thumb keys. The number row only moves if the board has one; see
+// `Board::add_number_row`.
+const LAYOUT_MASK: LayoutShuffleMask = LayoutShuffleMask(KeyMap([
+	true,  true,  true,  true,  true,     true,  true,  true,  true,  true,  false,
+	true,  true,  true,  true,  true,     true,  true,  true,  true,  true,  true,
+	true,  true,  true,  true,  true,     true,  true,  true,  true,  true,
+	                            false,    false,
+	false, false, false, false, false,    false, false, false, false, false]));
+
+// Every position of the three rows, including a letter on the left thumb.
+pub const MATRIX34_BOARD: Board = Board {
+	keys:   three_rows([true; 34], false),
+	mask:   LAYOUT_MASK,
+	groups: [0; NUM_KEYS],
+};
+
+// No letter on the left thumb.
+pub const MATRIX33_BOARD: Board = Board {
+	keys: three_rows([
+		true, true, true, true, true,    true, true, true, true, true, true,
+		true, true, true, true, true,    true, true, true, true, true, true,
+		true, true, true, true, true,    true, true, true, true, true,
+		                        false,   true], false),
+	mask:   LAYOUT_MASK,
+	groups: [0; NUM_KEYS],
+};
+
+// The 30 keys of a plain ANSI alpha block, plus the space bar.
+pub const ANSI30_BOARD: Board = Board {
+	keys: three_rows([
+		true, true, true, true, true,    true, true, true, true, true, false,
+		true, true, true, true, true,    true, true, true, true, true, false,
+		true, true, true, true, true,    true, true, true, true, true,
+		                        false,   true], false),
+	mask:   LAYOUT_MASK,
+	groups: [0; NUM_KEYS],
+};
+
+// The groups that characters are put in by `--groups`, unless listed in a
+// groups file.
+static GROUP_NAMES: [&str; 3] = ["letters", "punctuation", "rest"];
+
 pub static INIT_LAYOUT: Layout = Layout(
-	Layer(KeyMap(['j', 'c', 'y', 'f', 'k',   'z', 'l', ',', 'u', 'q', '=',
+	Layer(keys34(['j', 'c', 'y', 'f', 'k',   'z', 'l', ',', 'u', 'q', '=',
 	              'r', 's', 't', 'h', 'd',   'm', 'n', 'a', 'i', 'o',  '\'',
 	              '/', 'v', 'g', 'p', 'b',   'x', 'w', '.', ';', '-',
 	              'e', ' '])),
-	Layer(KeyMap(['J', 'C', 'Y', 'F', 'K',   'Z', 'L', '<', 'U', 'Q', '+',
+	Layer(keys34(['J', 'C', 'Y', 'F', 'K',   'Z', 'L', '<', 'U', 'Q', '+',
 	              'R', 'S', 'T', 'H', 'D',   'M', 'N', 'A', 'I', 'O', '"',
 	              '?', 'V', 'G', 'P', 'B',   'X', 'W', '>', ':', '_',
-	              'E', ' '])));
+	              'E', ' '])),
+	MATRIX34_BOARD);
 
 pub static QWERTY_LAYOUT: Layout = Layout(
-	Layer(KeyMap(['q', 'w', 'e', 'r', 't',   'y', 'u', 'i', 'o', 'p', '-',
+	Layer(keys34(['q', 'w', 'e', 'r', 't',   'y', 'u', 'i', 'o', 'p', '-',
 	              'a', 's', 'd', 'f', 'g',   'h', 'j', 'k', 'l', ';', '\'',
 	              'z', 'x', 'c', 'v', 'b',   'n', 'm', ',', '.', '/',
 	              '\0', ' '])),
-	Layer(KeyMap(['Q', 'W', 'E', 'R', 'T',   'Y', 'U', 'I', 'O', 'P', '_',
+	Layer(keys34(['Q', 'W', 'E', 'R', 'T',   'Y', 'U', 'I', 'O', 'P', '_',
 	              'A', 'S', 'D', 'F', 'G',   'H', 'J', 'K', 'L', ':', '"',
 	              'Z', 'X', 'C', 'V', 'B',   'N', 'M', '<', '>', '?',
-	              '\0', ' '])));
+	              '\0', ' '])),
+	MATRIX33_BOARD);
 
 pub static DVORAK_LAYOUT: Layout = Layout(
-	Layer(KeyMap(['\'', ',', '.', 'p', 'y',   'f', 'g', 'c', 'r', 'l', '/',
+	Layer(keys34(['\'', ',', '.', 'p', 'y',   'f', 'g', 'c', 'r', 'l', '/',
 	              'a', 'o', 'e', 'u', 'i',   'd', 'h', 't', 'n', 's', '-',
 	              ';', 'q', 'j', 'k', 'x',   'b', 'm', 'w', 'v', 'z',
 	              '\0', ' '])),
-	Layer(KeyMap(['"', ',', '.', 'P', 'Y',   'F', 'G', 'C', 'R', 'L', '?',
+	Layer(keys34(['"', ',', '.', 'P', 'Y',   'F', 'G', 'C', 'R', 'L', '?',
 	              'A', 'O', 'E', 'U', 'I',   'D', 'H', 'T', 'N', 'S', '_',
 	              ':', 'Q', 'J', 'K', 'X',   'B', 'M', 'W', 'V', 'Z',
-	              '\0', ' '])));
+	              '\0', ' '])),
+	MATRIX33_BOARD);
 
 pub static COLEMAK_LAYOUT: Layout = Layout(
-	Layer(KeyMap(['q', 'w', 'f', 'p', 'g',   'j', 'l', 'u', 'y', ';', '-',
+	Layer(keys34(['q', 'w', 'f', 'p', 'g',   'j', 'l', 'u', 'y', ';', '-',
 	              'a', 'r', 's', 't', 'd',   'h', 'n', 'e', 'i', 'o', '\'',
 	              'z', 'x', 'c', 'v', 'b',   'k', 'm', ',', '.', '/',
 	              '\0', ' '])),
-	Layer(KeyMap(['Q', 'W', 'F', 'P', 'G',   'J', 'L', 'U', 'Y', ':', '_',
+	Layer(keys34(['Q', 'W', 'F', 'P', 'G',   'J', 'L', 'U', 'Y', ':', '_',
 	              'A', 'R', 'S', 'T', 'D',   'H', 'N', 'E', 'I', 'O', '"',
 	              'Z', 'X', 'C', 'V', 'B',   'K', 'M', '<', '>', '?',
-	              '\0', ' '])));
+	              '\0', ' '])),
+	MATRIX33_BOARD);
 
 pub static QGMLWY_LAYOUT: Layout = Layout(
-	Layer(KeyMap(['q', 'g', 'm', 'l', 'w',   'y', 'f', 'u', 'b', ';', '-',
+	Layer(keys34(['q', 'g', 'm', 'l', 'w',   'y', 'f', 'u', 'b', ';', '-',
 	              'd', 's', 't', 'n', 'r',   'i', 'a', 'e', 'o', 'h', '\'',
 	              'z', 'x', 'c', 'v', 'j',   'k', 'p', ',', '.', '/',
 	              '\0', ' '])),
-	Layer(KeyMap(['Q', 'G', 'M', 'L', 'W',   'Y', 'F', 'U', 'B', ':', '_',
+	Layer(keys34(['Q', 'G', 'M', 'L', 'W',   'Y', 'F', 'U', 'B', ':', '_',
 	              'D', 'S', 'T', 'N', 'R',   'I', 'A', 'E', 'O', 'H', '"',
 	              'Z', 'X', 'C', 'V', 'J',   'K', 'P', '<', '>', '?',
-	              '\0', ' '])));
+	              '\0', ' '])),
+	MATRIX33_BOARD);
 
 pub static WORKMAN_LAYOUT: Layout = Layout(
-	Layer(KeyMap(['q', 'd', 'r', 'w', 'b',   'j', 'f', 'u', 'p', ';', '-',
+	Layer(keys34(['q', 'd', 'r', 'w', 'b',   'j', 'f', 'u', 'p', ';', '-',
 	              'a', 's', 'h', 't', 'g',   'y', 'n', 'e', 'o', 'i', '\'',
 	              'z', 'x', 'm', 'c', 'v',   'k', 'l', ',', '.', '/',
 	              '\0', ' '])),
-	Layer(KeyMap(['Q', 'D', 'R', 'W', 'B',   'J', 'F', 'U', 'P', ':', '_',
+	Layer(keys34(['Q', 'D', 'R', 'W', 'B',   'J', 'F', 'U', 'P', ':', '_',
 	              'A', 'S', 'H', 'T', 'G',   'Y', 'N', 'E', 'O', 'I', '"',
 	              'Z', 'X', 'M', 'C', 'V',   'K', 'L', '<', '>', '?',
-	              '\0', ' '])));
+	              '\0', ' '])),
+	MATRIX33_BOARD);
 
 pub static MALTRON_LAYOUT: Layout = Layout(
-	Layer(KeyMap(['q', 'p', 'y', 'c', 'b',   'v', 'm', 'u', 'z', 'l', '=',
+	Layer(keys34(['q', 'p', 'y', 'c', 'b',   'v', 'm', 'u', 'z', 'l', '=',
 	              'a', 'n', 'i', 's', 'f',   'd', 't', 'h', 'o', 'r', '\'',
 	              ',', '.', 'j', 'g', '/',   ';', 'w', 'k', '-', 'x',
 	              'e', ' '])),
-	Layer(KeyMap(['Q', 'P', 'Y', 'C', 'B',   'V', 'M', 'U', 'Z', 'L', '+',
+	Layer(keys34(['Q', 'P', 'Y', 'C', 'B',   'V', 'M', 'U', 'Z', 'L', '+',
 	              'A', 'N', 'I', 'S', 'F',   'D', 'T', 'H', 'O', 'R', '"',
 	              '<', '>', 'J', 'G', '?',   ':', 'W', 'K', '_', 'X',
-	              'E', ' '])));
+	              'E', ' '])),
+	MATRIX34_BOARD);
 
 pub static MTGAP_LAYOUT: Layout = Layout(
-	Layer(KeyMap(['y', 'p', 'o', 'u', '-',   'b', 'd', 'l', 'c', 'k', 'j',
+	Layer(keys34(['y', 'p', 'o', 'u', '-',   'b', 'd', 'l', 'c', 'k', 'j',
 	              'i', 'n', 'e', 'a', ',',   'm', 'h', 't', 's', 'r', 'v',
 	              '(', '"', '\'', '.', '_',   ')', 'f', 'w', 'g', 'x',
 	              'z', ' '])),
-	Layer(KeyMap(['Y', 'P', 'O', 'U', ':',   'B', 'D', 'L', 'C', 'K', 'J',
+	Layer(keys34(['Y', 'P', 'O', 'U', ':',   'B', 'D', 'L', 'C', 'K', 'J',
 	              'I', 'N', 'E', 'A', ';',   'M', 'H', 'T', 'S', 'R', 'V',
 	              '&', '?', '*', '=', '<',   '>', 'F', 'W', 'G', 'X',
-	              'Z', ' '])));
+	              'Z', ' '])),
+	MATRIX34_BOARD);
 
 pub static CAPEWELL_LAYOUT: Layout = Layout(
-	Layer(KeyMap(['.', 'y', 'w', 'd', 'f',   'j', 'p', 'l', 'u', 'q', '/',
+	Layer(keys34(['.', 'y', 'w', 'd', 'f',   'j', 'p', 'l', 'u', 'q', '/',
 	              'a', 'e', 'r', 's', 'g',   'b', 't', 'n', 'i', 'o', '-',
-	              'x', 'z', 'c', 'v', ';',   'k', 'w', 'h', ',', '\'',
+	              'x', 'z', 'c', 'v', ';',   'k', 'm', 'h', ',', '\'',
 	              '\0', ' '])),
-	Layer(KeyMap(['>', 'Y', 'W', 'D', 'F',   'J', 'P', 'L', 'U', 'Q', '?',
+	Layer(keys34(['>', 'Y', 'W', 'D', 'F',   'J', 'P', 'L', 'U', 'Q', '?',
 	              'A', 'E', 'R', 'S', 'G',   'B', 'T', 'N', 'I', 'O', '_',
-	              'X', 'Z', 'C', 'V', ':',   'K', 'W', 'H', '<', '"',
-	              '\0', ' '])));
+	              'X', 'Z', 'C', 'V', ':',   'K', 'M', 'H', '<', '"',
+	              '\0', ' '])),
+	MATRIX33_BOARD);
 
 pub static ARENSITO_LAYOUT: Layout = Layout(
-	Layer(KeyMap(['q', 'l', ',', 'p', '\0',  '\0', 'f', 'u', 'd', 'k', '\0',
+	Layer(keys34(['q', 'l', ',', 'p', '\0',  '\0', 'f', 'u', 'd', 'k', '\0',
 	              'a', 'r', 'e', 'n', 'b',   'g', 's', 'i', 't', 'o', '\0',
 	              'z', 'w', '.', 'h', 'j',   'v', 'c', 'y', 'm', 'x',
 	              '\0', ' '])),
-	Layer(KeyMap(['Q', 'L', '<', 'P', '\0',  '\0', 'F', 'U', 'D', 'K', '\0',
+	Layer(keys34(['Q', 'L', '<', 'P', '\0',  '\0', 'F', 'U', 'D', 'K', '\0',
 	              'A', 'R', 'E', 'N', 'B',   'G', 'S', 'I', 'T', 'O', '\0',
 	              'Z', 'W', '>', 'H', 'J',   'V', 'C', 'Y', 'M', 'X',
-	              '\0', ' '])));
-
-// static LAYOUT_MASK: LayoutShuffleMask = LayoutShuffleMask(KeyMap([
-// 	true,  true,  true,  true,  true,  true,  true,  true,  true,  true,  false,
-// 	true,  true,  true,  true,  true,  true,  true,  true,  true,  true,  true,
-// 	true,  true,  true,  true,  true,  true,  true,  true,  true,  true,
-// 	false]));
-static LAYOUT_MASK_SWAP_OFFSETS: [usize; 33] = [
-	0, 0, 0, 0, 0,    0, 0, 0, 0, 0,
-	1, 1, 1, 1, 1,    1, 1, 1, 1, 1, 1,
-	1, 1, 1, 1, 1,    1, 1, 1, 1, 1,
-	1, 1];
-static LAYOUT_MASK_NUM_SWAPPABLE: usize = 33;
-
-static KEY_FINGERS: KeyMap<Finger> = KeyMap([
+	              '\0', ' '])),
+	MATRIX33_BOARD);
+
+pub static COLEMAK_DH_LAYOUT: Layout = Layout(
+	Layer(keys34(['q', 'w', 'f', 'p', 'b',   'j', 'l', 'u', 'y', ';', '-',
+	              'a', 'r', 's', 't', 'g',   'm', 'n', 'e', 'i', 'o', '\'',
+	              'z', 'x', 'c', 'd', 'v',   'k', 'h', ',', '.', '/',
+	              '\0', ' '])),
+	Layer(keys34(['Q', 'W', 'F', 'P', 'B',   'J', 'L', 'U', 'Y', ':', '_',
+	              'A', 'R', 'S', 'T', 'G',   'M', 'N', 'E', 'I', 'O', '"',
+	              'Z', 'X', 'C', 'D', 'V',   'K', 'H', '<', '>', '?',
+	              '\0', ' '])),
+	MATRIX33_BOARD);
+
+pub static COLEMAK_DH_ANSI_LAYOUT: Layout = Layout(
+	Layer(keys34(['q', 'w', 'f', 'p', 'b',   'j', 'l', 'u', 'y', ';', '-',
+	              'a', 'r', 's', 't', 'g',   'm', 'n', 'e', 'i', 'o', '\'',
+	              'x', 'c', 'd', 'v', 'z',   'k', 'h', ',', '.', '/',
+	              '\0', ' '])),
+	Layer(keys34(['Q', 'W', 'F', 'P', 'B',   'J', 'L', 'U', 'Y', ':', '_',
+	              'A', 'R', 'S', 'T', 'G',   'M', 'N', 'E', 'I', 'O', '"',
+	              'X', 'C', 'D', 'V', 'Z',   'K', 'H', '<', '>', '?',
+	              '\0', ' '])),
+	MATRIX33_BOARD);
+
+pub static NORMAN_LAYOUT: Layout = Layout(
+	Layer(keys34(['q', 'w', 'd', 'f', 'k',   'j', 'u', 'r', 'l', ';', '-',
+	              'a', 's', 'e', 't', 'g',   'y', 'n', 'i', 'o', 'h', '\'',
+	              'z', 'x', 'c', 'v', 'b',   'p', 'm', ',', '.', '/',
+	              '\0', ' '])),
+	Layer(keys34(['Q', 'W', 'D', 'F', 'K',   'J', 'U', 'R', 'L', ':', '_',
+	              'A', 'S', 'E', 'T', 'G',   'Y', 'N', 'I', 'O', 'H', '"',
+	              'Z', 'X', 'C', 'V', 'B',   'P', 'M', '<', '>', '?',
+	              '\0', ' '])),
+	MATRIX33_BOARD);
+
+pub static HALMAK_LAYOUT: Layout = Layout(
+	Layer(keys34(['w', 'l', 'r', 'b', 'z',   ';', 'q', 'u', 'd', 'j', '-',
+	              's', 'h', 'n', 't', ',',   '.', 'a', 'e', 'o', 'i', '\'',
+	              'f', 'm', 'v', 'c', '/',   'g', 'p', 'x', 'k', 'y',
+	              '\0', ' '])),
+	Layer(keys34(['W', 'L', 'R', 'B', 'Z',   ':', 'Q', 'U', 'D', 'J', '_',
+	              'S', 'H', 'N', 'T', '<',   '>', 'A', 'E', 'O', 'I', '"',
+	              'F', 'M', 'V', 'C', '?',   'G', 'P', 'X', 'K', 'Y',
+	              '\0', ' '])),
+	MATRIX33_BOARD);
+
+pub static ENGRAM_LAYOUT: Layout = Layout(
+	Layer(keys34(['b', 'y', 'o', 'u', '\'',   '"', 'l', 'd', 'w', 'v', 'z',
+	              'c', 'i', 'e', 'a', ',',   '.', 'h', 't', 's', 'n', 'q',
+	              'g', 'x', 'j', 'k', '-',   '?', 'r', 'm', 'f', 'p',
+	              '\0', ' '])),
+	Layer(keys34(['B', 'Y', 'O', 'U', '(',   ')', 'L', 'D', 'W', 'V', 'Z',
+	              'C', 'I', 'E', 'A', ';',   ':', 'H', 'T', 'S', 'N', 'Q',
+	              'G', 'X', 'J', 'K', '_',   '!', 'R', 'M', 'F', 'P',
+	              '\0', ' '])),
+	MATRIX33_BOARD);
+
+pub static SEMIMAK_LAYOUT: Layout = Layout(
+	Layer(keys34(['f', 'l', 'h', 'v', 'z',   'q', 'w', 'u', 'o', 'y', ';',
+	              's', 'r', 'n', 't', 'k',   'c', 'd', 'e', 'a', 'i', '-',
+	              'x', '\'', 'b', 'm', 'j',   'p', 'g', ',', '.', '/',
+	              '\0', ' '])),
+	Layer(keys34(['F', 'L', 'H', 'V', 'Z',   'Q', 'W', 'U', 'O', 'Y', ':',
+	              'S', 'R', 'N', 'T', 'K',   'C', 'D', 'E', 'A', 'I', '_',
+	              'X', '"', 'B', 'M', 'J',   'P', 'G', '<', '>', '?',
+	              '\0', ' '])),
+	MATRIX33_BOARD);
+
+// Finds a built-in layout by name, ignoring case.
+pub fn reference_layout(name: &str)
+-> Option<&'static Layout>
+{
+	REFERENCE_LAYOUTS.iter()
+		.find(|&&(n, _)| n.eq_ignore_ascii_case(name))
+		.map(|&(_, layout)| layout)
+}
+
+// RSTHD is the layout this program found, so it is also where it starts.
+pub static REFERENCE_LAYOUTS: [(&str, &Layout); 17] = [
+	("QWERTY",          &QWERTY_LAYOUT),
+	("DVORAK",          &DVORAK_LAYOUT),
+	("COLEMAK",         &COLEMAK_LAYOUT),
+	("QGMLWY",          &QGMLWY_LAYOUT),
+	("WORKMAN",         &WORKMAN_LAYOUT),
+	("MALTRON",         &MALTRON_LAYOUT),
+	("MTGAP",           &MTGAP_LAYOUT),
+	("CAPEWELL",        &CAPEWELL_LAYOUT),
+	("ARENSITO",        &ARENSITO_LAYOUT),
+	("COLEMAK-DH",      &COLEMAK_DH_LAYOUT),
+	("COLEMAK-DH-ANSI", &COLEMAK_DH_ANSI_LAYOUT),
+	("NORMAN",          &NORMAN_LAYOUT),
+	("HALMAK",          &HALMAK_LAYOUT),
+	("ENGRAM",          &ENGRAM_LAYOUT),
+	("SEMIMAK",         &SEMIMAK_LAYOUT),
+	("RSTHD",           &INIT_LAYOUT),
+	("INITIAL",         &INIT_LAYOUT),
+];
+
+pub static KEY_FINGERS: KeyMap<Finger> = KeyMap([
 	Finger::Pinky, Finger::Ring, Finger::Middle, Finger::Index, Finger::Index,    Finger::Index, Finger::Index, Finger::Middle, Finger::Ring, Finger::Pinky, Finger::Pinky,
 	Finger::Pinky, Finger::Ring, Finger::Middle, Finger::Index, Finger::Index,    Finger::Index, Finger::Index, Finger::Middle, Finger::Ring, Finger::Pinky, Finger::Pinky,
 	Finger::Pinky, Finger::Ring, Finger::Middle, Finger::Index, Finger::Index,    Finger::Index, Finger::Index, Finger::Middle, Finger::Ring, Finger::Pinky,
-	Finger::Thumb, Finger::Thumb]);
-static KEY_HANDS: KeyMap<Hand> = KeyMap([
+	Finger::Thumb, Finger::Thumb,
+	Finger::Pinky, Finger::Ring, Finger::Middle, Finger::Index, Finger::Index,    Finger::Index, Finger::Index, Finger::Middle, Finger::Ring, Finger::Pinky]);
+pub static KEY_HANDS: KeyMap<Hand> = KeyMap([
 	Hand::Left, Hand::Left, Hand::Left, Hand::Left, Hand::Left,    Hand::Right, Hand::Right, Hand::Right, Hand::Right, Hand::Right, Hand::Right,
 	Hand::Left, Hand::Left, Hand::Left, Hand::Left, Hand::Left,    Hand::Right, Hand::Right, Hand::Right, Hand::Right, Hand::Right, Hand::Right,
 	Hand::Left, Hand::Left, Hand::Left, Hand::Left, Hand::Left,    Hand::Right, Hand::Right, Hand::Right, Hand::Right, Hand::Right,
-	Hand::Left, Hand::Right]);
+	Hand::Left, Hand::Right,
+	Hand::Left, Hand::Left, Hand::Left, Hand::Left, Hand::Left,    Hand::Right, Hand::Right, Hand::Right, Hand::Right, Hand::Right]);
 static KEY_ROWS: KeyMap<Row> = KeyMap([
 	Row::Top,    Row::Top,    Row::Top,    Row::Top,    Row::Top,       Row::Top,    Row::Top,    Row::Top,    Row::Top,    Row::Top,    Row::Top,
 	Row::Home,   Row::Home,   Row::Home,   Row::Home,   Row::Home,      Row::Home,   Row::Home,   Row::Home,   Row::Home,   Row::Home,   Row::Home,
 	Row::Bottom, Row::Bottom, Row::Bottom, Row::Bottom, Row::Bottom,    Row::Bottom, Row::Bottom, Row::Bottom, Row::Bottom, Row::Bottom,
-	Row::Thumb, Row::Thumb]);
+	Row::Thumb, Row::Thumb,
+	Row::Number, Row::Number, Row::Number, Row::Number, Row::Number,    Row::Number, Row::Number, Row::Number, Row::Number, Row::Number]);
+// The position across the hand divider from each position. The outer column of
+// the right hand and the thumbs have no counterpart, so they map to themselves.
+static MIRROR_POSITIONS: KeyMap<usize> = KeyMap([
+	9,  8,  7,  6,  5,     4,  3,  2,  1,  0,  10,
+	20, 19, 18, 17, 16,    15, 14, 13, 12, 11, 21,
+	31, 30, 29, 28, 27,    26, 25, 24, 23, 22,
+	32, 33,
+	43, 42, 41, 40, 39,    38, 37, 36, 35, 34]);
+// The position across the hand divider from `pos`, if it has one.
+pub fn mirror_position(pos: usize)
+-> Option<usize>
+{
+	match MIRROR_POSITIONS.0[pos] {
+		mirror if mirror != pos => Some(mirror),
+		_ => None,
+	}
+}
+
+// Names a position by the hand, finger and row that reach it, like "left
+// index top", with "inner" for the centre columns and "outer" for the column
+// beyond the right pinky's.
+pub fn position_name(pos: usize)
+-> String
+{
+	let hand = format!("{:?}", KEY_HANDS.0[pos]).to_lowercase();
+	let finger = format!("{:?}", KEY_FINGERS.0[pos]).to_lowercase();
+	let column = if KEY_CENTER_COLUMN.0[pos] {
+		"inner "
+	} else if KEY_COLUMNS.0[pos] == 10 {
+		"outer "
+	} else {
+		""
+	};
+	match KEY_ROWS.0[pos] {
+		Row::Thumb => format!("{} thumb", hand),
+		row => format!("{} {}{} {}", hand, column, finger, format!("{:?}", row).to_lowercase()),
+	}
+}
+
+// The column of each position, counting from the left of its row. The thumb
+// keys sit below the centre columns.
+pub static KEY_COLUMNS: KeyMap<u8> = KeyMap([
+	0,  1,  2,  3,  4,     5,  6,  7,  8,  9,  10,
+	0,  1,  2,  3,  4,     5,  6,  7,  8,  9,  10,
+	0,  1,  2,  3,  4,     5,  6,  7,  8,  9,
+	4,  5,
+	0,  1,  2,  3,  4,     5,  6,  7,  8,  9]);
 static KEY_CENTER_COLUMN: KeyMap<bool> = KeyMap([
 	false, false, false, false, true,    true, false, false, false, false, false,
 	false, false, false, false, true,    true, false, false, false, false, false,
 	false, false, false, false, true,    true, false, false, false, false,
-	false, false]);
+	false, false,
+	false, false, false, false, true,    true, false, false, false, false]);
 
 pub static KP_NONE: Option<KeyPress> = None;
 
-static LAYOUT_FILE_IDXS: KeyMap<usize> = KeyMap([
-	0,  1,  2,  3,  4,     6,  7,  8,  9,  10, 11,
-	13, 14, 15, 16, 17,    19, 20, 21, 22, 23, 24,
-	26, 27, 28, 29, 30,    32, 33, 34, 35, 36, 37, 38]);
+// Positions 0 to 33 as given, and an empty number row.
+const fn three_rows<T: Copy>(keys: [T; 34], none: T)
+-> [T; NUM_KEYS]
+{
+	let mut all = [none; NUM_KEYS];
+	let mut i = 0;
+	while i < 34 {
+		all[i] = keys[i];
+		i += 1;
+	}
+	all
+}
+
+const fn keys34(keys: [char; 34])
+-> KeyMap<char>
+{
+	KeyMap(three_rows(keys, '\0'))
+}
+
+// The positions on each line of a layout file, or of a file shaped like one,
+// with or without a number row above the rest.
+pub fn layout_file_lines(number_row: bool)
+-> Vec<Range<usize>>
+{
+	let rows = vec![0..11, 11..22, 22..34];
+	if number_row {
+		iter::once(NUMBER_ROW).chain(rows).collect()
+	} else {
+		rows
+	}
+}
 
 /* ----- *
  * IMPLS *
@@ -233,199 +624,2076 @@ static LAYOUT_FILE_IDXS: KeyMap<usize> = KeyMap([
 
 impl Layout
 {
-	pub fn from_string(s: &str)
-	-> Layout
+	// Layout files hold the lower layer on three lines followed by the upper
+	// layer on three more. Each line separates the hands with one character,
+	// and the third line of each layer ends with the two thumb keys (here 'e'
+	// and space):
+	//
+	//     jcyfk zl,uq=
+	//     rsthd mnaio'
+	//     /vgpb xw.;-e 
+	//
+	// A layout with a number row has four lines a layer, starting with the
+	// number row's ten keys, e.g. `12345 67890`.
+	//
+	// On a board that lacks some keys, lines may also leave those keys out.
+	// Layout files from before the second thumb key may leave it out too, in
+	// which case it is empty. A file with only the lower layer gets the upper
+	// layer from the US QWERTY shift pairs; see `from_string_with_pairs`.
+	//
+	// The way layouts are printed is accepted too, so that a printed layout
+	// can be pasted into a file; see `from_printed_string`.
+	pub fn from_string(s: &str, board: Board)
+	-> Result<Layout, LayoutParseError>
 	{
-		let s: Vec<char> = s.chars().collect();
-		let mut lower: [char; 34] = ['\0'; 34];
-		let mut upper: [char; 34] = ['\0'; 34];
-		
-		for i in 0..34 {
-			let file_i = LAYOUT_FILE_IDXS.0[i];
-			lower[i] = *s.get(file_i).unwrap_or(&'\0');
-			upper[i] = *s.get(file_i + 40).unwrap_or(&'\0');
-		}
-
-		Layout(Layer(KeyMap(lower)), Layer(KeyMap(upper)))
+		Layout::from_string_with_pairs(s, board, &ShiftPairs::us())
 	}
 
-	pub fn shuffle(&mut self, times: usize)
+	// Like `from_string`, filling in a missing upper layer from `pairs`.
+	pub fn from_string_with_pairs(s: &str, board: Board, pairs: &ShiftPairs)
+	-> Result<Layout, LayoutParseError>
 	{
-		for _ in 0..times {
-			let (i, j) = Layout::shuffle_position();
-			let Layout(ref mut lower, ref mut upper) = *self;
-			lower.swap(i, j);
-			upper.swap(i, j);
+		if s.trim_start().starts_with("lower:") {
+			return Layout::from_printed_string(s, board, pairs);
+		}
+
+		let mut lines: Vec<Vec<char>> = s.trim_end_matches(['\n', '\r'])
+			.split('\n')
+			.map(|line| line.trim_end_matches('\r').chars().collect())
+			.collect();
+		if lines.len() > 8 || lines.len() == 7 {
+			return Err(LayoutParseError::LineCount { found: lines.len() });
 		}
+		// Layers of four lines start with the number row.
+		let rows = layout_file_lines(lines.len().is_multiple_of(4));
+		let num_layers = if lines.len() > rows.len() { 2 } else { 1 };
+		lines.resize(rows.len() * num_layers, Vec::new());
+
+		let line_positions = |l: usize| rows[l % rows.len()].clone();
+		let line_keys = |line: &Vec<char>| line.len().saturating_sub(1);
+		let one_thumb = |l: usize, found: usize| line_positions(l).end == 34 && found == line_positions(l).len() - 1;
+		let check_line = |l: usize| {
+			let found = line_keys(&lines[l]);
+			let expected = line_positions(l).filter(|&i| board.exists(i)).count();
+			if found != expected && found != line_positions(l).len() && !one_thumb(l, found) {
+				Err(LayoutParseError::KeyCount { line: l + 1, found, expected })
+			} else {
+				Ok(())
+			}
+		};
+
+		// Both layers must describe the same keys.
+		let (lower, upper) = lines.split_at(rows.len());
+		for l in 0..rows.len() {
+			check_line(l)?;
+		}
+		if num_layers == 2 {
+			let lower_keys: usize = lower.iter().map(&line_keys).sum();
+			let upper_keys: usize = upper.iter().map(&line_keys).sum();
+			if lower_keys != upper_keys {
+				return Err(LayoutParseError::LayerMismatch { lower: lower_keys, upper: upper_keys });
+			}
+			for l in rows.len()..lines.len() {
+				check_line(l)?;
+			}
+		}
+
+		let mut layers = [['\0'; NUM_KEYS]; 2];
+		for (l, line) in lines.iter().enumerate() {
+			// Skip the hand separator, and any keys the board doesn't have if
+			// the line leaves them out.
+			let keys = line.iter().take(5).chain(line.iter().skip(6));
+			let existing: Vec<usize> = line_positions(l).filter(|&i| board.exists(i)).collect();
+			let positions: Vec<usize> = if line_keys(line) == existing.len() {
+				existing
+			} else {
+				line_positions(l).collect()
+			};
+			for (&i, c) in positions.iter().zip(keys) {
+				layers[l / rows.len()][i] = *c;
+			}
+		}
+		if num_layers == 1 {
+			layers[1] = layers[0].map(|c| pairs.upper(c));
+		}
+
+		Ok(Layout(Layer(KeyMap(layers[0])), Layer(KeyMap(layers[1])), board))
 	}
 
-	pub fn get_position_map(&self)
-	-> LayoutPosMap
+	// Reads a layout as `Display` writes it: a `lower:` line, a row of keys
+	// separated by whitespace per line with `|` between the hands, and the
+	// same again after an `upper:` line. The keys the board lacks are left out,
+	// as is the thumb row if the board has no thumb keys. On a board with a
+	// number row, a layer with a line more than that starts with the number
+	// row. An empty right thumb is the space bar, since a space doesn't show,
+	// and without an `upper:` layer the upper layer comes from `pairs`.
+	fn from_printed_string(s: &str, board: Board, pairs: &ShiftPairs)
+	-> Result<Layout, LayoutParseError>
 	{
-		let Layout(ref lower, ref upper) = *self;
-		let mut map = [None; 128];
-		lower.fill_position_map(&mut map);
-		upper.fill_position_map(&mut map);
+		static ROWS: [(Range<usize>, Range<usize>); 4] = [(0..5, 5..11), (11..16, 16..22), (22..27, 27..32), (32..33, 33..34)];
+		let number_row = (34..39, 39..NUM_KEYS);
+		let thumbs = board.exists(32) || board.exists(33);
+
+		let mut layer_lines: Vec<Vec<(usize, &str)>> = Vec::new();
+		for (l, line) in s.lines().enumerate() {
+			match line.trim() {
+				"lower:" | "upper:" => layer_lines.push(Vec::new()),
+				"" => (),
+				_ => if let Some(lines) = layer_lines.last_mut() {
+					lines.push((l + 1, line.trim_end_matches('\r')));
+				},
+			}
+		}
+		if layer_lines.len() > 2 || layer_lines.iter().any(|lines| lines.len() > 4 + board.has_number_row() as usize) {
+			return Err(LayoutParseError::LineCount { found: s.lines().count() });
+		}
+
+		let mut layers = [['\0'; NUM_KEYS]; 2];
+		for (layer, lines) in layers.iter_mut().zip(layer_lines.iter()) {
+			let rows: Vec<&(Range<usize>, Range<usize>)> = if board.has_number_row() && lines.len() > 3 + thumbs as usize {
+				iter::once(&number_row).chain(ROWS.iter()).collect()
+			} else {
+				ROWS.iter().collect()
+			};
+			for (&(line, text), rows) in lines.iter().zip(rows) {
+				let mut hands = text.splitn(2, '|');
+				let halves = [hands.next().unwrap(), hands.next().ok_or(LayoutParseError::MissingSeparator { line })?];
+				for (half, range) in halves.iter().zip([rows.0.clone(), rows.1.clone()].iter()) {
+					let keys: Vec<&str> = half.split_whitespace().collect();
+					let positions: Vec<usize> = range.clone().filter(|&i| board.exists(i)).collect();
+					// Space and an empty left thumb both print as blanks.
+					if keys.is_empty() && positions == [33] {
+						layer[33] = ' ';
+						continue;
+					}
+					if keys.is_empty() && positions == [32] {
+						continue;
+					}
+					if keys.len() != positions.len() {
+						return Err(LayoutParseError::KeyCount { line, found: keys.len(), expected: positions.len() });
+					}
+					for (&pos, key) in positions.iter().zip(keys.iter()) {
+						let mut chars = key.chars();
+						match (chars.next(), chars.next()) {
+							(Some(c), None) => layer[pos] = c,
+							_ => { return Err(LayoutParseError::LongKey { line, key: key.to_string() }) },
+						}
+					}
+				}
+			}
+		}
+		if layer_lines.len() < 2 {
+			layers[1] = layers[0].map(|c| pairs.upper(c));
+		}
 
-		LayoutPosMap(map)
+		Ok(Layout(Layer(KeyMap(layers[0])), Layer(KeyMap(layers[1])), board))
 	}
 
-	fn shuffle_position() 
-	-> (usize, usize)
+	// Reads a layout from keyboard-layout-editor JSON, an array of rows of
+	// keys. The alpha block is the last three rows in a row with at least ten
+	// character keys each: the first eleven character keys of the top two rows
+	// and the first ten of the bottom row fill their rows. The first character
+	// key below the alpha block goes on the left thumb, and space on the right.
+	// A key with two legends types the bottom one and, shifted, the top one;
+	// a key with one is shifted as `pairs` shift it. Returns warnings listing
+	// the legends of character keys that found no place on the layout.
+	pub fn from_kle_json(s: &str, board: Board, pairs: &ShiftPairs)
+	-> Result<(Layout, Vec<String>), KleParseError>
 	{
-		let mut i = random::<usize>() % LAYOUT_MASK_NUM_SWAPPABLE;
-		let mut j = random::<usize>() % (LAYOUT_MASK_NUM_SWAPPABLE - 1);
-		if j >= i {
-			j += 1;
+		let rows = match json::parse(s) {
+			Ok(json::Value::Array(rows)) => rows,
+			Ok(_) => { return Err(KleParseError::NotRows) },
+			Err(e) => { return Err(KleParseError::Json(e)) },
+		};
+
+		// Key legends; objects in a row change the properties of the keys
+		// after them, and objects outside rows hold metadata.
+		let rows: Vec<Vec<Vec<String>>> = rows.iter().filter_map(|row| match *row {
+			json::Value::Array(ref items) => Some(items.iter().filter_map(|item| match *item {
+				json::Value::String(ref legend) => Some(kle_legends(legend)),
+				_ => None,
+			}).collect()),
+			_ => None,
+		}).collect();
+		let chars: Vec<Vec<Option<(char, char)>>> = rows.iter()
+			.map(|row| row.iter().map(|legends| kle_key_chars(legends, pairs)).collect())
+			.collect();
+		let alpha_row = |r: &usize| chars[*r].iter().filter(|c| c.is_some()).count() >= 10;
+		let first = match (0..rows.len().saturating_sub(2)).rev().find(|r| (*r..(r + 3)).all(|r| alpha_row(&r))) {
+			Some(first) => first,
+			None => { return Err(KleParseError::NoAlphaBlock) },
+		};
+
+		let mut layers = [['\0'; NUM_KEYS]; 2];
+		let mut warnings = Vec::new();
+		let mut thumb = false;
+		for (r, row) in rows.iter().enumerate() {
+			let mut positions: Vec<usize> = if r >= first && r < first + 3 {
+				let start = [0, 11, 22][r - first];
+				(start..[11, 22, 32][r - first]).collect()
+			} else if r >= first + 3 && !thumb {
+				vec![32]
+			} else {
+				Vec::new()
+			};
+			positions.reverse();
+			for (legends, &kc) in row.iter().zip(chars[r].iter()) {
+				let (lower, upper) = match kc {
+					Some(kc) => kc,
+					None => continue,
+				};
+				match positions.pop() {
+					Some(pos) if board.exists(pos) => {
+						thumb = thumb || pos == 32;
+						layers[0][pos] = lower;
+						layers[1][pos] = upper;
+					},
+					_ => warnings.push(format!("no place for the key {}", legends.join(" / "))),
+				}
+			}
+		}
+		if board.exists(33) {
+			layers[0][33] = ' ';
+			layers[1][33] = ' ';
 		}
-		i += LAYOUT_MASK_SWAP_OFFSETS[i];
-		j += LAYOUT_MASK_SWAP_OFFSETS[j];
 
-		(i, j)
+		Ok((Layout(Layer(KeyMap(layers[0])), Layer(KeyMap(layers[1])), board), warnings))
 	}
-}
 
-impl Layer
-{
-	fn swap(&mut self, i: usize, j: usize)
+	// Places the layout on a different keyboard. Any characters on positions
+	// that the keyboard lacks are reported by `validate`.
+	pub fn set_board(&mut self, board: Board)
 	{
-		let Layer(KeyMap(ref mut layer)) = *self;
-		let temp = layer[i];
-		layer[i] = layer[j];
-		layer[j] = temp;
+		self.2 = board;
 	}
 
-	fn fill_position_map(&self, map: &mut [Option<KeyPress>; 128])
+	// Puts the digits 1 to 0 on the empty keys of the board's number row, with
+	// their pairs from `pairs` above them, so that a layout without a number
+	// row starts from the usual one. Characters the layout already has stay
+	// where they are.
+	pub fn fill_number_row(&mut self, pairs: &ShiftPairs)
 	{
-		let Layer(KeyMap(ref layer)) = *self;
-		let KeyMap(ref fingers) = KEY_FINGERS;
-		let KeyMap(ref hands) = KEY_HANDS;
-		let KeyMap(ref rows) = KEY_ROWS;
-		let KeyMap(ref centers) = KEY_CENTER_COLUMN;
-		for (i, c) in layer.into_iter().enumerate() {
-			if *c < (128 as char) {
-				map[*c as usize] = Some(KeyPress {
-					kc: *c,
-					pos: i,
-					finger: fingers[i],
-					hand: hands[i],
-					row: rows[i],
-					center: centers[i],
-				});
+		let Layout(Layer(KeyMap(ref mut lower)), Layer(KeyMap(ref mut upper)), ref board) = *self;
+		for (pos, digit) in NUMBER_ROW.zip("1234567890".chars()) {
+			let has = |c: char| lower.contains(&c) || upper.contains(&c);
+			if !board.exists(pos) || lower[pos] != '\0' || has(digit) {
+				continue;
+			}
+			let shifted = pairs.upper(digit);
+			if !has(shifted) {
+				upper[pos] = shifted;
 			}
+			lower[pos] = digit;
 		}
 	}
-}
 
-impl LayoutPosMap
-{
-	pub fn get_key_position(&self, kc: char)
-	-> &Option<KeyPress>
+	// Finds characters that are typed from more than one key or from a key the
+	// keyboard doesn't have, lowercase letters whose uppercase isn't on the same
+	// key, and empty swappable keys.
+	pub fn validate(&self)
+	-> Vec<LayoutProblem>
 	{
-		let LayoutPosMap(ref map) = *self;
-		if kc < (128 as char) {
-			&map[kc as usize]
-		} else {
-			&KP_NONE
+		let Layout(Layer(KeyMap(ref lower)), Layer(KeyMap(ref upper)), ref board) = *self;
+		let mut problems = Vec::new();
+
+		for &(name, layer) in [("lower", lower), ("upper", upper)].iter() {
+			for i in 0..NUM_KEYS {
+				if layer[i] == '\0' {
+					continue;
+				}
+				if let Some(j) = (0..i).find(|&j| layer[j] == layer[i]) {
+					problems.push(LayoutProblem::DuplicateChar { layer: name, kc: layer[i], first: j, second: i });
+				}
+			}
+		}
+
+		problems.extend(self.layer_collisions());
+
+		for i in 0..NUM_KEYS {
+			if lower[i].is_ascii_lowercase() && upper[i] != lower[i].to_ascii_uppercase() {
+				problems.push(LayoutProblem::Unshifted { kc: lower[i], pos: i });
+			}
+		}
+
+		for pos in board.swappable() {
+			if lower[pos] == '\0' {
+				problems.push(LayoutProblem::Hole { pos });
+			}
+		}
+
+		for pos in (0..NUM_KEYS).filter(|&pos| !board.exists(pos)) {
+			if let Some(&kc) = [lower[pos], upper[pos]].iter().find(|&&c| c != '\0') {
+				problems.push(LayoutProblem::Missing { kc, pos });
+			}
 		}
+
+		problems
 	}
-}
 
-impl LayoutPermutations
-{
-	pub fn new(layout: &Layout, depth: usize)
-	-> LayoutPermutations
+	// The keys whose upper layer character isn't the pair of their lower layer
+	// character. Characters without a pair aren't checked.
+	pub fn validate_pairs(&self, pairs: &ShiftPairs)
+	-> Vec<LayoutProblem>
+	{
+		let Layout(Layer(KeyMap(ref lower)), Layer(KeyMap(ref upper)), _) = *self;
+		(0..NUM_KEYS).filter_map(|i| match pairs.0.get(&lower[i]) {
+			Some(&expected) if upper[i] != expected =>
+				Some(LayoutProblem::Unpaired { kc: lower[i], pos: i, found: upper[i], expected }),
+			_ => None,
+		}).collect()
+	}
+
+	pub fn shuffle(&mut self, times: usize)
+	-> Vec<Swap>
 	{
-		let mut swaps = Vec::with_capacity(depth * 2);
-		for _ in 0..(depth * 2) {
-			swaps.push(0);
+		let partners = self.2.swap_partners();
+		let movable: Vec<usize> = (0..NUM_KEYS).filter(|&i| !partners[i].is_empty()).collect();
+		let mut swaps = Vec::with_capacity(times);
+		if movable.is_empty() {
+			return swaps;
 		}
-		LayoutPermutations {
-			orig_layout: layout.clone(),
-			swap_idx: swaps,
-			started: false,
+		for _ in 0..times {
+			let (i, j) = Layout::shuffle_position(&movable, &partners);
+			let Layout(ref mut lower, ref mut upper, _) = *self;
+			lower.swap(i, j);
+			upper.swap(i, j);
+			swaps.push((i, j));
 		}
+		swaps
 	}
-}
 
-impl Iterator for LayoutPermutations
-{
-	type Item = Layout;
+	// Swaps the hands: each key moves to the same column and row on the
+	// other hand. Keys without a counterpart stay where they are.
+	pub fn mirrored(&self)
+	-> Layout
+	{
+		let mut mirrored = self.clone();
+		for i in (0..NUM_KEYS).filter(|&i| MIRROR_POSITIONS.0[i] > i) {
+			let Layout(ref mut lower, ref mut upper, _) = mirrored;
+			lower.swap(i, MIRROR_POSITIONS.0[i]);
+			upper.swap(i, MIRROR_POSITIONS.0[i]);
+		}
+		mirrored
+	}
 
-	fn next(&mut self)
-	-> Option<Layout>
-	{
-		let mut some = false;
-		let mut idx = 0;
-		let mut val = 0;
-
-		if self.started {
-			for (i, e) in self.swap_idx.iter_mut().enumerate() {
-				if *e + 1 < LAYOUT_MASK_NUM_SWAPPABLE - i {
-					*e += 1;
-					some = true;
-					idx = i;
-					val = *e;
-					break;
+	// The position of a key named by a character on either layer or by its
+	// position, which must be on the board. A character must be on exactly
+	// one key.
+	pub fn find_key(&self, key: SwapKey)
+	-> Result<usize, SwapError>
+	{
+		let Layout(Layer(KeyMap(ref lower)), Layer(KeyMap(ref upper)), ref board) = *self;
+		match key {
+			SwapKey::Position(pos) if pos < NUM_KEYS && board.exists(pos) => Ok(pos),
+			SwapKey::Position(pos) => Err(SwapError::NoPosition { pos }),
+			SwapKey::Char(kc) => {
+				let found: Vec<usize> = (0..NUM_KEYS).filter(|&i| lower[i] == kc || upper[i] == kc).collect();
+				match found.len() {
+					0 => Err(SwapError::Missing { kc }),
+					1 => Ok(found[0]),
+					count => Err(SwapError::Repeated { kc, count }),
 				}
-			}
-		} else {
-			self.started = true;
-			some = true;
-			idx = 1;
-			val = 0;
+			},
 		}
+	}
 
-		if some {
-			for i in 0..idx {
-				self.swap_idx[i] =  val + idx - i;
-			}
+	// Swaps two keys on both layers, whether or not the board lets the
+	// optimiser move them.
+	pub fn swap_keys(&mut self, i: usize, j: usize)
+	{
+		let Layout(ref mut lower, ref mut upper, _) = *self;
+		lower.swap(i, j);
+		upper.swap(i, j);
+	}
 
-			let mut layout = self.orig_layout.clone();
-			let mut i = 0;
-			while i < self.swap_idx.len() {
-				let ref mut lower = ((layout.0).0).0;
-				let ref mut upper = ((layout.1).0).0;
-				let swap_left = self.swap_idx[i] + LAYOUT_MASK_SWAP_OFFSETS[self.swap_idx[i]];
-				let swap_right = self.swap_idx[i + 1] + LAYOUT_MASK_SWAP_OFFSETS[self.swap_idx[i + 1]];
-				lower.swap(swap_left, swap_right);
-				upper.swap(swap_left, swap_right);
-				i += 2;
-			}
+	// The lower layer characters that `swaps` exchange, in order, starting
+	// from this layout.
+	pub fn swapped_chars(&self, swaps: &[Swap])
+	-> Vec<(char, char)>
+	{
+		let Layout(ref lower, _, _) = *self;
+		let mut lower = lower.clone();
+		swaps.iter().map(|&(i, j)| {
+			let chars = ((lower.0).0[i], (lower.0).0[j]);
+			lower.swap(i, j);
+			chars
+		}).collect()
+	}
 
-			Some(layout)
-		} else {
-			None
-		}
+	// The number of key positions at which two layouts differ.
+	pub fn distance(&self, other: &Layout)
+	-> usize
+	{
+		self.differing_keys(other).len()
 	}
-}
 
-impl fmt::Display for Layout
-{
-	fn fmt(&self, f: &mut fmt::Formatter)
-	-> fmt::Result
+	// The positions at which two layouts differ, with the lower layer
+	// character of each.
+	pub fn differing_keys(&self, other: &Layout)
+	-> Vec<(usize, char, char)>
 	{
-		let Layout(ref lower, _) = *self;
-		lower.fmt(f)
+		let Layout(Layer(KeyMap(ref lower)), Layer(KeyMap(ref upper)), _) = *self;
+		let Layout(Layer(KeyMap(ref other_lower)), Layer(KeyMap(ref other_upper)), _) = *other;
+		(0..NUM_KEYS)
+			.filter(|&i| lower[i] != other_lower[i] || upper[i] != other_upper[i])
+			.map(|i| (i, lower[i], other_lower[i]))
+			.collect()
 	}
-}
 
-impl fmt::Display for Layer
-{
-	fn fmt(&self, f: &mut fmt::Formatter)
-	-> fmt::Result
+	// The lower and upper layer characters at a position.
+	pub fn keys_at(&self, pos: usize)
+	-> (char, char)
 	{
-		let Layer(KeyMap(ref layer)) = *self;
-		write!(f, "{} {} {} {} {} | {} {} {} {} {} {}
-{} {} {} {} {} | {} {} {} {} {} {}
-{} {} {} {} {} | {} {} {} {} {}
-        {} | {}",
-			layer[0], layer[1], layer[2], layer[3], layer[4],
-			layer[5], layer[6], layer[7], layer[8], layer[9], layer[10],
-			layer[11], layer[12], layer[13], layer[14], layer[15],
-			layer[16], layer[17], layer[18], layer[19], layer[20], layer[21],
-			layer[22], layer[23], layer[24], layer[25], layer[26],
-			layer[27], layer[28], layer[29], layer[30], layer[31],
-			layer[32], layer[33])
+		let Layout(Layer(KeyMap(ref lower)), Layer(KeyMap(ref upper)), _) = *self;
+		(lower[pos], upper[pos])
+	}
+
+	// The keys with a character on the lower layer but none on the upper,
+	// such as those of a one-layer layout file with no US QWERTY shift.
+	pub fn unshifted_keys(&self)
+	-> Vec<(usize, char)>
+	{
+		let Layout(Layer(KeyMap(ref lower)), Layer(KeyMap(ref upper)), _) = *self;
+		(0..NUM_KEYS).filter(|&i| lower[i] != '\0' && upper[i] == '\0').map(|i| (i, lower[i])).collect()
+	}
+
+	pub fn board(&self)
+	-> &Board
+	{
+		&self.2
+	}
+
+	// Characters on the upper layer that the lower layer also has at another
+	// position. The position map types them from the lower layer, so the upper
+	// layer key is never scored for them.
+	pub fn layer_collisions(&self)
+	-> Vec<LayoutProblem>
+	{
+		let Layout(Layer(KeyMap(ref lower)), Layer(KeyMap(ref upper)), _) = *self;
+		(0..NUM_KEYS).filter(|&j| upper[j] != '\0').filter_map(|j| {
+			(0..NUM_KEYS).find(|&i| i != j && lower[i] == upper[j])
+				.map(|i| LayoutProblem::CrossLayer { kc: upper[j], lower: i, upper: j })
+		}).collect()
+	}
+
+	// Where each character is typed. Characters on both layers are typed from
+	// the lower one; see `layer_collisions`.
+	pub fn get_position_map(&self)
+	-> LayoutPosMap
+	{
+		self.position_map_for(true)
+	}
+
+	// Where each character is typed when scoring. Only when shift is modelled
+	// are characters on both layers typed from the lower one, without it;
+	// otherwise the upper layer's key is used, as it was before.
+	pub fn position_map_for(&self, model_shift: bool)
+	-> LayoutPosMap
+	{
+		let Layout(ref lower, ref upper, _) = *self;
+		let mut map = [None; 128];
+		if model_shift {
+			upper.fill_position_map(&mut map, true);
+			lower.fill_position_map(&mut map, false);
+		} else {
+			lower.fill_position_map(&mut map, false);
+			upper.fill_position_map(&mut map, true);
+		}
+
+		LayoutPosMap(map, model_shift)
+	}
+
+	// Tags each swappable key as a letter, punctuation or the rest, by its
+	// character on the lower layer, unless `overrides` says otherwise.
+	pub fn set_groups(&mut self, overrides: &HashMap<char, u8>)
+	{
+		let Layout(Layer(KeyMap(ref lower)), _, ref mut board) = *self;
+		for (pos, &c) in lower.iter().enumerate() {
+			board.groups[pos] = match overrides.get(&c) {
+				Some(&group) => group,
+				None if c.is_alphabetic() => 0,
+				None if c.is_ascii_punctuation() => 1,
+				None => 2,
+			};
+		}
+	}
+
+	// Lets only keys of one kind be swapped, by their lower layer character:
+	// letters, or everything but letters. This overrides the default mask, so
+	// even the outer column keys of the kind move. The thumb keys are left
+	// as they are, as are empty keys.
+	pub fn move_only(&mut self, kind: KeyKind)
+	{
+		self.set_swappable_where(|c| c != '\0' && c.is_alphabetic() == (kind == KeyKind::Letters));
+	}
+
+	// Pins the keys that type any of `chars` on either layer.
+	pub fn keep(&mut self, chars: &[char])
+	{
+		let Layout(Layer(KeyMap(lower)), Layer(KeyMap(upper)), ref mut board) = *self;
+		for pos in (0..NUM_KEYS).filter(|&pos| chars.contains(&lower[pos]) || chars.contains(&upper[pos])) {
+			board.set_swappable(pos, false);
+		}
+	}
+
+	// Pins every key on the other hand, so that keys only move within `hand`.
+	pub fn move_only_hand(&mut self, hand: Hand)
+	{
+		let Layout(_, _, ref mut board) = *self;
+		for pos in (0..NUM_KEYS).filter(|&pos| KEY_HANDS.0[pos] != hand) {
+			board.set_swappable(pos, false);
+		}
+	}
+
+	fn set_swappable_where<F>(&mut self, swappable: F)
+	where F: Fn(char) -> bool
+	{
+		let Layout(Layer(KeyMap(lower)), _, ref mut board) = *self;
+		for pos in (0..32).chain(NUMBER_ROW) {
+			board.set_swappable(pos, swappable(lower[pos]));
+		}
+	}
+
+	// Picks a movable key, then one of the keys it may be swapped with.
+	fn shuffle_position(movable: &[usize], partners: &[Vec<usize>])
+	-> (usize, usize)
+	{
+		let i = movable[random::<usize>() % movable.len()];
+		let j = partners[i][random::<usize>() % partners[i].len()];
+
+		(i, j)
+	}
+}
+
+impl Board
+{
+	pub fn from_name(name: &str)
+	-> Option<Board>
+	{
+		match name {
+			"matrix34" => Some(MATRIX34_BOARD),
+			"matrix33" => Some(MATRIX33_BOARD),
+			"ansi30"   => Some(ANSI30_BOARD),
+			_ => None,
+		}
+	}
+
+	pub fn exists(&self, pos: usize)
+	-> bool
+	{
+		self.keys[pos]
+	}
+
+	pub fn has_number_row(&self)
+	-> bool
+	{
+		self.keys[NUMBER_ROW].iter().any(|&key| key)
+	}
+
+	// Adds a number row, whose keys may be swapped like the others.
+	pub fn add_number_row(&mut self)
+	{
+		for pos in NUMBER_ROW {
+			self.keys[pos] = true;
+			self.set_swappable(pos, true);
+		}
+	}
+
+	pub fn remove_number_row(&mut self)
+	{
+		for pos in NUMBER_ROW {
+			self.keys[pos] = false;
+		}
+	}
+
+	// Allows or forbids swapping the key at `pos`, if the board has it.
+	pub fn set_swappable(&mut self, pos: usize, swappable: bool)
+	{
+		let LayoutShuffleMask(KeyMap(ref mut mask)) = self.mask;
+		mask[pos] = swappable;
+	}
+
+	// For each position, the other positions its key may be swapped with.
+	fn swap_partners(&self)
+	-> Vec<Vec<usize>>
+	{
+		let swappable = self.swappable();
+		(0..NUM_KEYS).map(|i| if swappable.contains(&i) {
+			swappable.iter().cloned().filter(|&j| j != i && self.groups[j] == self.groups[i]).collect()
+		} else {
+			Vec::new()
+		}).collect()
+	}
+
+	// The positions whose keys may be swapped, in order.
+	pub fn swappable(&self)
+	-> Vec<usize>
+	{
+		let LayoutShuffleMask(KeyMap(ref mask)) = self.mask;
+		(0..NUM_KEYS).filter(|&pos| self.keys[pos] && mask[pos]).collect()
+	}
+}
+
+impl Layer
+{
+	fn swap(&mut self, i: usize, j: usize)
+	{
+		let Layer(KeyMap(ref mut layer)) = *self;
+		layer.swap(i, j);
+	}
+
+	// Characters already in the map are filled in again from this layer; see
+	// `Layout::position_map_for` for which layer goes last.
+	fn fill_position_map(&self, map: &mut [Option<KeyPress>; 128], shifted: bool)
+	{
+		for i in 0..NUM_KEYS {
+			self.fill_position(map, i, shifted);
+		}
+	}
+
+	// Fills in `c` from this layer alone, as `fill_position_map` would.
+	fn fill_char(&self, map: &mut [Option<KeyPress>; 128], c: char, shifted: bool)
+	{
+		let Layer(KeyMap(ref layer)) = *self;
+		for i in (0..NUM_KEYS).filter(|&i| layer[i] == c) {
+			self.fill_position(map, i, shifted);
+		}
+	}
+
+	fn fill_position(&self, map: &mut [Option<KeyPress>; 128], i: usize, shifted: bool)
+	{
+		let Layer(KeyMap(ref layer)) = *self;
+		let c = layer[i];
+		if c < (128 as char) {
+			map[c as usize] = Some(KeyPress {
+				kc: c,
+				pos: i,
+				finger: KEY_FINGERS.0[i],
+				hand: KEY_HANDS.0[i],
+				row: KEY_ROWS.0[i],
+				col: KEY_COLUMNS.0[i],
+				center: KEY_CENTER_COLUMN.0[i],
+				shifted,
+			});
+		}
+	}
+}
+
+impl LayoutPosMap
+{
+	// Produces the position map for `layout`, given that `layout` differs from
+	// the layout this map was built for only by `swaps`. Only the characters on
+	// the swapped positions are looked up again, with the same layer first as
+	// when the map was built.
+	pub fn patched(&self, layout: &Layout, swaps: &[Swap])
+	-> LayoutPosMap
+	{
+		let LayoutPosMap(mut map, lower_first) = *self;
+		let Layout(ref lower, ref upper, _) = *layout;
+		let (first, last) = if lower_first { ((upper, true), (lower, false)) } else { ((lower, false), (upper, true)) };
+		for &(i, j) in swaps {
+			for &pos in [i, j].iter() {
+				for &c in [(lower.0).0[pos], (upper.0).0[pos]].iter() {
+					if c < (128 as char) {
+						map[c as usize] = None;
+						first.0.fill_char(&mut map, c, first.1);
+						last.0.fill_char(&mut map, c, last.1);
+					}
+				}
+			}
+		}
+
+		LayoutPosMap(map, lower_first)
+	}
+
+	// Keeps this map's keys, and adds those for characters only `other` has.
+	pub fn union(&self, other: &LayoutPosMap)
+	-> LayoutPosMap
+	{
+		let LayoutPosMap(mut map, lower_first) = *self;
+		for (kp, other_kp) in map.iter_mut().zip(other.0.iter()) {
+			if kp.is_none() {
+				*kp = *other_kp;
+			}
+		}
+		LayoutPosMap(map, lower_first)
+	}
+
+	pub fn get_key_position(&self, kc: char)
+	-> &Option<KeyPress>
+	{
+		let LayoutPosMap(ref map, _) = *self;
+		if kc < (128 as char) {
+			&map[kc as usize]
+		} else {
+			&KP_NONE
+		}
+	}
+
+	pub fn get_key_position_by_code(&self, code: u8)
+	-> &Option<KeyPress>
+	{
+		let LayoutPosMap(ref map, _) = *self;
+		if code < 128 {
+			&map[code as usize]
+		} else {
+			&KP_NONE
+		}
+	}
+}
+
+impl Geometry
+{
+	// Geometry files are shaped like the lower layer of a layout file, but
+	// hold an `x,y` coordinate for each key, separated by whitespace, or `-` for
+	// a key the keyboard doesn't have. A `|` may separate the hands. Each
+	// finger's home key is marked with a `*`, except for fingers with a single
+	// key:
+	//
+	//     0,0 1,0 2,0 3,0 4,0 | 7,0 8,0 9,0 10,0 11,0 12,0
+	//     0.25,1 1.25,1* 2.25,1* 3.25,1* 4.25,1 | ...
+	//
+	// A fourth line above the others gives the number row, which the
+	// optimiser may then fill.
+	pub fn from_string(s: &str)
+	-> Result<Geometry, GeometryParseError>
+	{
+		let lines: Vec<&str> = s.trim_end_matches(['\n', '\r']).lines().collect();
+		if lines.len() != 3 && lines.len() != 4 {
+			return Err(GeometryParseError::LineCount { found: lines.len() });
+		}
+		let rows = layout_file_lines(lines.len() == 4);
+
+		let mut coords = [(0.0, 0.0); NUM_KEYS];
+		let mut is_home = [false; NUM_KEYS];
+		let mut exists = [true; NUM_KEYS];
+		for pos in NUMBER_ROW {
+			exists[pos] = lines.len() == 4;
+		}
+		for ((l, line), row) in lines.iter().enumerate().zip(rows) {
+			let pos = row.start;
+			let mut found = 0;
+			let mut column = 0;
+			for token in line.split(|c: char| c.is_whitespace()) {
+				column += 1;
+				let start = column;
+				column += token.chars().count();
+				if token.is_empty() || token == "|" {
+					continue;
+				}
+				if token == "-" {
+					if found < row.len() {
+						exists[pos + found] = false;
+					}
+					found += 1;
+					continue;
+				}
+				let bad = || GeometryParseError::BadCoordinate { line: l + 1, column: start, found: token.to_string() };
+				let home = token.ends_with('*');
+				let mut xy = token.trim_end_matches('*').splitn(2, ',').map(|n| n.parse::<f64>());
+				let coord = match (xy.next(), xy.next()) {
+					(Some(Ok(x)), Some(Ok(y))) => (x, y),
+					_ => { return Err(bad()) }
+				};
+				if found < row.len() {
+					coords[pos + found] = coord;
+					is_home[pos + found] = home;
+				}
+				found += 1;
+			}
+			if found != row.len() {
+				return Err(GeometryParseError::KeyCount { line: l + 1, found, expected: row.len() });
+			}
+		}
+
+		// Find the home key of each key's finger.
+		let mut homes = [(0.0, 0.0); NUM_KEYS];
+		for i in (0..NUM_KEYS).filter(|&i| exists[i]) {
+			let same_finger: Vec<usize> = (0..NUM_KEYS)
+				.filter(|&j| exists[j] && KEY_HANDS.0[j] == KEY_HANDS.0[i] && KEY_FINGERS.0[j] == KEY_FINGERS.0[i])
+				.collect();
+			let marked: Vec<usize> = same_finger.iter().cloned().filter(|&j| is_home[j]).collect();
+			homes[i] = match (marked.len(), same_finger.len()) {
+				(1, _) => coords[marked[0]],
+				(0, 1) => coords[i],
+				(found, _) => { return Err(GeometryParseError::HomeCount { hand: KEY_HANDS.0[i], finger: KEY_FINGERS.0[i], found }) }
+			};
+		}
+
+		let mut board = Board { keys: exists, mask: LAYOUT_MASK, groups: [0; NUM_KEYS] };
+		for pos in NUMBER_ROW {
+			board.set_swappable(pos, true);
+		}
+		Ok(Geometry {
+			coords: KeyMap(coords),
+			homes:  KeyMap(homes),
+			board,
+		})
+	}
+
+	// The distance between two keys.
+	pub fn distance(&self, i: usize, j: usize)
+	-> f64
+	{
+		distance(self.coords.0[i], self.coords.0[j])
+	}
+
+	// The distance from a key to the home key of its finger.
+	pub fn travel(&self, i: usize)
+	-> f64
+	{
+		distance(self.coords.0[i], self.homes.0[i])
+	}
+}
+
+fn distance(a: (f64, f64), b: (f64, f64))
+-> f64
+{
+	((a.0 - b.0).powi(2) + (a.1 - b.1).powi(2)).sqrt()
+}
+
+impl Constraint
+{
+	// The constrained characters that are somewhere else on the layout.
+	// Characters that aren't on the layout at all are ignored.
+	pub fn violations(&self, position_map: &LayoutPosMap)
+	-> Vec<char>
+	{
+		self.chars.iter().cloned().filter(|&c| match *position_map.get_key_position(c) {
+			Some(ref kp) => self.hand.is_some_and(|hand| kp.hand != hand) ||
+			                self.finger.is_some_and(|finger| kp.finger != finger) ||
+			                self.row.is_some_and(|row| kp.row != row),
+			None => false,
+		}).collect()
+	}
+
+	pub fn allows(&self, position_map: &LayoutPosMap)
+	-> bool
+	{
+		self.violations(position_map).is_empty()
+	}
+}
+
+// Reads one constraint per line, written as comma-separated TOML-style keys:
+//
+//     char = "e", hand = "left"
+//     chars = "aeiou", hand = "right", row = "home"
+//
+// Blank lines and those starting with `#` are ignored.
+pub fn parse_constraints(s: &str)
+-> Result<Vec<Constraint>, ConstraintParseError>
+{
+	let mut constraints = Vec::new();
+	for (i, line) in s.lines().enumerate() {
+		let line_num = i + 1;
+		let line = line.trim();
+		if line.is_empty() || line.starts_with('#') {
+			continue;
+		}
+
+		let mut constraint = Constraint { line: line_num, chars: Vec::new(), hand: None, finger: None, row: None };
+		for field in split_fields(line) {
+			let mut kv = field.splitn(2, '=');
+			let key = kv.next().unwrap_or("").trim();
+			let value = match kv.next().map(|v| v.trim()) {
+				Some(v) if v.len() >= 2 && v.starts_with('"') && v.ends_with('"') => &v[1..(v.len() - 1)],
+				_ => { return Err(ConstraintParseError::Syntax { line: line_num }) }
+			};
+			let bad = || ConstraintParseError::BadValue { line: line_num, key: key.to_string(), value: value.to_string() };
+			match key {
+				"char" | "chars" => constraint.chars.extend(value.chars()),
+				"hand" => constraint.hand = Some(match value {
+					"left"  => Hand::Left,
+					"right" => Hand::Right,
+					_ => { return Err(bad()) }
+				}),
+				"finger" => constraint.finger = Some(match value {
+					"thumb"  => Finger::Thumb,
+					"index"  => Finger::Index,
+					"middle" => Finger::Middle,
+					"ring"   => Finger::Ring,
+					"pinky"  => Finger::Pinky,
+					_ => { return Err(bad()) }
+				}),
+				"row" => constraint.row = Some(match value {
+					"number" => Row::Number,
+					"top"    => Row::Top,
+					"home"   => Row::Home,
+					"bottom" => Row::Bottom,
+					"thumb"  => Row::Thumb,
+					_ => { return Err(bad()) }
+				}),
+				_ => { return Err(ConstraintParseError::UnknownKey { line: line_num, key: key.to_string() }) }
+			}
+		}
+		if constraint.chars.is_empty() {
+			return Err(ConstraintParseError::MissingChars { line: line_num });
+		}
+		if constraint.hand.is_none() && constraint.finger.is_none() && constraint.row.is_none() {
+			return Err(ConstraintParseError::MissingPlace { line: line_num });
+		}
+		constraints.push(constraint);
+	}
+
+	Ok(constraints)
+}
+
+// Whether every constraint holds on the layout with this position map.
+pub fn satisfies(position_map: &LayoutPosMap, constraints: &[Constraint])
+-> bool
+{
+	constraints.iter().all(|c| c.allows(position_map))
+}
+
+impl fmt::Display for ConstraintParseError
+{
+	fn fmt(&self, f: &mut fmt::Formatter)
+	-> fmt::Result
+	{
+		match *self {
+			ConstraintParseError::Syntax { line } =>
+				write!(f, "line {} is not a list of key = \"value\" pairs", line),
+			ConstraintParseError::UnknownKey { line, ref key } =>
+				write!(f, "line {} has unknown key {:?}, expected char, chars, hand, finger or row", line, key),
+			ConstraintParseError::BadValue { line, ref key, ref value } =>
+				write!(f, "line {} has invalid {} {:?}", line, key, value),
+			ConstraintParseError::MissingChars { line } =>
+				write!(f, "line {} doesn't say which characters it constrains", line),
+			ConstraintParseError::MissingPlace { line } =>
+				write!(f, "line {} needs a hand, finger or row", line),
+		}
+	}
+}
+
+impl LayoutPermutations
+{
+	pub fn new(layout: &Layout, depth: usize)
+	-> LayoutPermutations
+	{
+		let swappable = layout.2.swappable();
+		LayoutPermutations {
+			orig_layout: layout.clone(),
+			groups: swappable.iter().map(|&pos| layout.2.groups[pos]).collect(),
+			swappable,
+			depth,
+			slots: Vec::new(),
+			started: false,
+		}
+	}
+
+	// How many layouts the iterator yields: for each number of swaps, the
+	// ways to split the keys of each group into that many fewer cycles.
+	pub fn total(&self)
+	-> usize
+	{
+		let mut sizes: Vec<usize> = Vec::new();
+		let mut seen: Vec<u8> = Vec::new();
+		for &group in self.groups.iter() {
+			match seen.iter().position(|&g| g == group) {
+				Some(i) => sizes[i] += 1,
+				None => { seen.push(group); sizes.push(1); },
+			}
+		}
+
+		// ways[j] counts the layouts made by exactly j swaps.
+		let mut ways = vec![0usize; self.depth + 1];
+		ways[0] = 1;
+		for &size in sizes.iter() {
+			// Permutations of the group's keys that need exactly j swaps.
+			let mut perms = vec![0usize; self.depth + 1];
+			perms[0] = 1;
+			for n in 1..size {
+				for j in (1..(self.depth + 1)).rev() {
+					perms[j] += n * perms[j - 1];
+				}
+			}
+			ways = (0..(self.depth + 1)).map(|j| (0..(j + 1)).map(|k| ways[k] * perms[j - k]).sum()).collect();
+		}
+		ways.iter().sum()
+	}
+
+	// Whether the key at index `v` of `swappable` can go in slot `p`, given
+	// the slots before it.
+	fn fits(&self, p: usize, v: usize)
+	-> bool
+	{
+		let t = p / 2;
+		let used = (0..t).any(|s| self.slots[2 * s + 1] == v);
+		if p.is_multiple_of(2) {
+			// A swap starts the same cycle as the last one or a later one, at
+			// a key that no earlier cycle moves.
+			(t == 0 || v >= self.slots[p - 2]) && !used
+		} else {
+			let first = self.slots[p - 1];
+			v > first && self.groups[v] == self.groups[first] && !used
+		}
+	}
+
+	// Fills the slots from `p` on with the next set of swaps in order, trying
+	// `v` first in slot `p`. Returns false when there are no more.
+	fn search(&mut self, mut p: usize, mut v: usize)
+	-> bool
+	{
+		let n = self.swappable.len();
+		loop {
+			while v < n && !self.fits(p, v) {
+				v += 1;
+			}
+			if v < n {
+				self.slots[p] = v;
+				if p + 1 == self.slots.len() {
+					return true;
+				}
+				p += 1;
+				v = if p.is_multiple_of(2) { self.slots[p - 2] } else { v + 1 };
+			} else if p == 0 {
+				return false;
+			} else {
+				p -= 1;
+				v = self.slots[p] + 1;
+			}
+		}
+	}
+
+	// Moves on to the next set of swaps, one swap more once those of the
+	// current length run out. Returns false past `depth`.
+	fn advance(&mut self)
+	-> bool
+	{
+		let len = self.slots.len();
+		if len > 0 && self.search(len - 1, self.slots[len - 1] + 1) {
+			return true;
+		}
+		let swaps = len / 2 + 1;
+		if swaps > self.depth {
+			return false;
+		}
+		self.slots = vec![0; swaps * 2];
+		self.search(0, 0)
+	}
+}
+
+impl Iterator for LayoutPermutations
+{
+	type Item = (Layout, Vec<Swap>);
+
+	fn next(&mut self)
+	-> Option<(Layout, Vec<Swap>)>
+	{
+		if !self.started {
+			self.started = true;
+			return Some((self.orig_layout.clone(), Vec::new()));
+		}
+		if !self.advance() {
+			return None;
+		}
+
+		let mut layout = self.orig_layout.clone();
+		let swaps: Vec<Swap> = self.slots.chunks(2).map(|pair| (self.swappable[pair[0]], self.swappable[pair[1]])).collect();
+		for &(i, j) in swaps.iter() {
+			layout.0.swap(i, j);
+			layout.1.swap(i, j);
+		}
+		Some((layout, swaps))
+	}
+
+	// Skips layouts without making them, so that threads can share the work.
+	fn nth(&mut self, n: usize)
+	-> Option<(Layout, Vec<Swap>)>
+	{
+		for _ in 0..n {
+			if !self.started {
+				self.started = true;
+			} else if !self.advance() {
+				return None;
+			}
+		}
+		self.next()
+	}
+}
+
+// The non-empty legends of a keyboard-layout-editor key, which are separated by
+// newlines and may hold HTML entities.
+fn kle_legends(s: &str)
+-> Vec<String>
+{
+	s.split('\n')
+		.map(|l| l.replace("&lt;", "<").replace("&gt;", ">").replace("&quot;", "\"").replace("&amp;", "&"))
+		.map(|l| l.trim().to_string())
+		.filter(|l| !l.is_empty())
+		.collect()
+}
+
+// The characters that a keyboard-layout-editor key types unshifted and shifted,
+// or None if its legends aren't single characters (e.g. "Tab").
+fn kle_key_chars(legends: &[String], pairs: &ShiftPairs)
+-> Option<(char, char)>
+{
+	let single = |l: &String| {
+		let mut chars = l.chars();
+		match (chars.next(), chars.next()) {
+			(Some(c), None) => Some(c),
+			_ => None,
+		}
+	};
+	match legends.len() {
+		1 => single(&legends[0]).map(|c| {
+			let lower = c.to_ascii_lowercase();
+			(lower, pairs.upper(lower))
+		}),
+		2 => match (single(&legends[1]), single(&legends[0])) {
+			(Some(lower), Some(upper)) => Some((lower, upper)),
+			_ => None,
+		},
+		_ => None,
+	}
+}
+
+// The character that shift gives with a key on US QWERTY.
+pub fn us_shift(c: char)
+-> Option<char>
+{
+	static UNSHIFTED: &str = "`1234567890-=[]\\;',./";
+	static SHIFTED:   &str = "~!@#$%^&*()_+{}|:\"<>?";
+	if c.is_ascii_lowercase() {
+		return Some(c.to_ascii_uppercase());
+	}
+	UNSHIFTED.chars().position(|u| u == c).and_then(|i| SHIFTED.chars().nth(i))
+}
+
+impl ShiftPairs
+{
+	// The pairs of US QWERTY.
+	pub fn us()
+	-> ShiftPairs
+	{
+		ShiftPairs((0u8..128).map(|b| b as char)
+			.filter_map(|c| us_shift(c).map(|shifted| (c, shifted)))
+			.collect())
+	}
+
+	// The upper layer character for a lower layer character when a layout
+	// doesn't say: its pair, or itself for space and empty keys.
+	pub fn upper(&self, c: char)
+	-> char
+	{
+		match c {
+			' ' | '\0' => c,
+			c => self.0.get(&c).cloned().unwrap_or('\0'),
+		}
+	}
+
+	// Replaces each upper layer character in `s` with the lower layer one it
+	// pairs with, so that '<' becomes ',' rather than staying as it is. Where
+	// several pair with the same character, the lowest of them is used.
+	pub fn fold_case(&self, s: &str)
+	-> String
+	{
+		let mut lower: HashMap<char, char> = HashMap::new();
+		for (&l, &u) in self.0.iter() {
+			let entry = lower.entry(u).or_insert(l);
+			*entry = cmp::min(*entry, l);
+		}
+		s.chars().map(|c| lower.get(&c).cloned().unwrap_or(c)).collect()
+	}
+}
+
+impl Compose
+{
+	// Replaces each composed character in `s` with its keystrokes.
+	pub fn expand(&self, s: &str)
+	-> String
+	{
+		let mut expanded = String::with_capacity(s.len());
+		for c in s.chars() {
+			match self.0.get(&c) {
+				Some(keystrokes) => expanded.push_str(keystrokes),
+				None => expanded.push(c),
+			}
+		}
+		expanded
+	}
+
+	// The keystrokes that the layout can't type, in order. A dead key that
+	// isn't on the layout would leave its characters out of the score.
+	pub fn missing(&self, layout: &Layout)
+	-> Vec<char>
+	{
+		let map = layout.get_position_map();
+		let mut missing: Vec<char> = self.0.values()
+			.flat_map(|keystrokes| keystrokes.chars())
+			.filter(|&c| map.get_key_position(c).is_none())
+			.collect();
+		missing.sort();
+		missing.dedup();
+		missing
+	}
+}
+
+// Reads pairs like a TOML table of `"lower" = "upper"` lines, each string
+// holding one character in double or single quotes, on top of the US QWERTY
+// pairs. Table headers, blank lines and `#` comments are ignored.
+pub fn parse_pairs(s: &str)
+-> Result<ShiftPairs, PairsParseError>
+{
+	let mut pairs = ShiftPairs::us();
+	for (i, line) in table_lines(s) {
+		let char_of = |s: &str| {
+			let unquoted = match unquote(s) {
+				Some(unquoted) => unquoted,
+				None => { return Err(PairsParseError::Syntax { line: i + 1 }) },
+			};
+			let mut chars = unquoted.chars();
+			match (chars.next(), chars.next()) {
+				(Some(c), None) => Ok(c),
+				_ => Err(PairsParseError::NotOneChar { line: i + 1, found: unquoted.clone() }),
+			}
+		};
+		let (lower, upper) = match split_assignment(line) {
+			Some(split) => split,
+			None => { return Err(PairsParseError::Syntax { line: i + 1 }) },
+		};
+		pairs.0.insert(char_of(lower)?, char_of(upper)?);
+	}
+	Ok(pairs)
+}
+
+// Reads compose rules like a pairs file, as `"composed" = "keystrokes"` lines
+// that type one character as the characters of a layout, e.g. `"é" = "'e"`
+// for a dead acute accent on the apostrophe key.
+pub fn parse_compose(s: &str)
+-> Result<Compose, ComposeParseError>
+{
+	let mut rules = HashMap::new();
+	for (i, line) in table_lines(s) {
+		let (composed, keystrokes) = match split_assignment(line) {
+			Some((composed, keystrokes)) => match (unquote(composed), unquote(keystrokes)) {
+				(Some(composed), Some(keystrokes)) => (composed, keystrokes),
+				_ => { return Err(ComposeParseError::Syntax { line: i + 1 }) },
+			},
+			None => { return Err(ComposeParseError::Syntax { line: i + 1 }) },
+		};
+		let mut chars = composed.chars();
+		let c = match (chars.next(), chars.next()) {
+			(Some(c), None) => c,
+			_ => { return Err(ComposeParseError::NotOneChar { line: i + 1, found: composed }) },
+		};
+		if keystrokes.is_empty() {
+			return Err(ComposeParseError::NoKeystrokes { line: i + 1 });
+		}
+		rules.insert(c, keystrokes);
+	}
+	Ok(Compose(rules))
+}
+
+// The numbered lines of a TOML-like file that hold something, without table
+// headers, blank lines and `#` comments.
+fn table_lines(s: &str)
+-> impl Iterator<Item = (usize, &str)>
+{
+	s.lines().map(str::trim).enumerate()
+		.filter(|&(_, line)| !(line.is_empty() || line.starts_with('#') || line.starts_with('[')))
+}
+
+// Splits a line at the first `=` outside quotes.
+fn split_assignment(line: &str)
+-> Option<(&str, &str)>
+{
+	let mut quote = None;
+	let mut escaped = false;
+	let split = line.char_indices().find(|&(_, c)| {
+		match quote {
+			Some('"') if escaped => escaped = false,
+			Some('"') if c == '\\' => escaped = true,
+			Some(q) if c == q => quote = None,
+			None if c == '"' || c == '\'' => quote = Some(c),
+			None if c == '=' => return true,
+			_ => (),
+		}
+		false
+	});
+	split.map(|(at, _)| (&line[..at], &line[(at + 1)..]))
+}
+
+// Splits a line of constraints at the commas outside double quotes, so that
+// `chars = ",."` is one field.
+fn split_fields(line: &str)
+-> Vec<&str>
+{
+	let mut fields = Vec::new();
+	let mut start = 0;
+	let mut quoted = false;
+	for (at, c) in line.char_indices() {
+		match c {
+			'"' => quoted = !quoted,
+			',' if !quoted => {
+				fields.push(&line[start..at]);
+				start = at + 1;
+			},
+			_ => (),
+		}
+	}
+	fields.push(&line[start..]);
+	fields
+}
+
+// The contents of a string in double or single quotes, with `\"` and `\\`
+// unescaped in double quotes.
+fn unquote(s: &str)
+-> Option<String>
+{
+	let s = s.trim();
+	if s.len() >= 2 && (s.starts_with('"') && s.ends_with('"') || s.starts_with('\'') && s.ends_with('\'')) {
+		let unquoted = &s[1..(s.len() - 1)];
+		Some(if s.starts_with('"') { unquoted.replace("\\\"", "\"").replace("\\\\", "\\") } else { unquoted.to_string() })
+	} else {
+		None
+	}
+}
+
+// Reads a swap like "a:b" or "3:17". A number names a position, and anything
+// else must be a single character; the first may be ':' itself.
+pub fn parse_swap(s: &str)
+-> Result<(SwapKey, SwapKey), SwapError>
+{
+	let first_len = s.chars().next().map_or(0, |c| c.len_utf8());
+	let idx = match s[first_len..].find(':') {
+		Some(idx) => first_len + idx,
+		None => { return Err(SwapError::Syntax) }
+	};
+	let key = |side: &str| {
+		if !side.is_empty() && side.chars().all(|c| c.is_ascii_digit()) {
+			return side.parse().map(SwapKey::Position).map_err(|_| SwapError::Syntax);
+		}
+		let mut chars = side.chars();
+		match (chars.next(), chars.next()) {
+			(Some(c), None) => Ok(SwapKey::Char(c)),
+			_ => Err(SwapError::Syntax),
+		}
+	};
+	Ok((key(&s[..idx])?, key(&s[(idx + 1)..])?))
+}
+
+// Reads lines of `NAME CHARS`, which put each of CHARS in the group NAME.
+// Blank lines and those starting with `#` are ignored. Characters that aren't
+// listed stay in the `letters`, `punctuation` or `rest` group.
+pub fn parse_groups(s: &str)
+-> Result<HashMap<char, u8>, GroupsParseError>
+{
+	let mut names: Vec<&str> = GROUP_NAMES.to_vec();
+	let mut groups = HashMap::new();
+	for (i, line) in s.lines().enumerate() {
+		let line = line.trim();
+		if line.is_empty() || line.starts_with('#') {
+			continue;
+		}
+		let mut fields = line.splitn(2, char::is_whitespace);
+		let name = fields.next().unwrap_or("");
+		let chars = match fields.next() {
+			Some(chars) if !chars.trim().is_empty() => chars.trim(),
+			_ => { return Err(GroupsParseError::MissingChars { line: i + 1 }) }
+		};
+		let group = match names.iter().position(|&n| n == name) {
+			Some(group) => group,
+			None => {
+				names.push(name);
+				names.len() - 1
+			},
+		};
+		for c in chars.chars().filter(|c| !c.is_whitespace()) {
+			if groups.insert(c, group as u8).is_some() {
+				return Err(GroupsParseError::Duplicate { line: i + 1, kc: c });
+			}
+		}
+	}
+
+	Ok(groups)
+}
+
+impl fmt::Display for KleParseError
+{
+	fn fmt(&self, f: &mut fmt::Formatter)
+	-> fmt::Result
+	{
+		match *self {
+			KleParseError::Json(ref e) =>
+				write!(f, "invalid JSON: {}", e),
+			KleParseError::NotRows =>
+				write!(f, "expected an array of rows of keys"),
+			KleParseError::NoAlphaBlock =>
+				write!(f, "no three rows in a row with at least ten character keys each"),
+		}
+	}
+}
+
+impl fmt::Display for SwapError
+{
+	fn fmt(&self, f: &mut fmt::Formatter)
+	-> fmt::Result
+	{
+		match *self {
+			SwapError::Syntax =>
+				write!(f, "expected two characters or positions separated by a colon"),
+			SwapError::NoPosition { pos } =>
+				write!(f, "the keyboard has no position {}", pos),
+			SwapError::Missing { kc } =>
+				write!(f, "{:?} is not on the layout", kc),
+			SwapError::Repeated { kc, count } =>
+				write!(f, "{:?} is on {} keys", kc, count),
+		}
+	}
+}
+
+impl fmt::Display for PairsParseError
+{
+	fn fmt(&self, f: &mut fmt::Formatter)
+	-> fmt::Result
+	{
+		match *self {
+			PairsParseError::Syntax { line } =>
+				write!(f, "line {} is not of the form \"lower\" = \"upper\"", line),
+			PairsParseError::NotOneChar { line, ref found } =>
+				write!(f, "line {} has {:?} where a single character belongs", line, found),
+		}
+	}
+}
+
+impl fmt::Display for ComposeParseError
+{
+	fn fmt(&self, f: &mut fmt::Formatter)
+	-> fmt::Result
+	{
+		match *self {
+			ComposeParseError::Syntax { line } =>
+				write!(f, "line {} is not of the form \"composed\" = \"keystrokes\"", line),
+			ComposeParseError::NotOneChar { line, ref found } =>
+				write!(f, "line {} composes {:?} rather than a single character", line, found),
+			ComposeParseError::NoKeystrokes { line } =>
+				write!(f, "line {} has no keystrokes", line),
+		}
+	}
+}
+
+impl fmt::Display for GroupsParseError
+{
+	fn fmt(&self, f: &mut fmt::Formatter)
+	-> fmt::Result
+	{
+		match *self {
+			GroupsParseError::MissingChars { line } =>
+				write!(f, "line {} has no characters after the group name", line),
+			GroupsParseError::Duplicate { line, kc } =>
+				write!(f, "line {} puts {:?} in a second group", line, kc),
+		}
+	}
+}
+
+impl fmt::Display for LayoutParseError
+{
+	fn fmt(&self, f: &mut fmt::Formatter)
+	-> fmt::Result
+	{
+		match *self {
+			LayoutParseError::LineCount { found } =>
+				write!(f, "layout has {} lines, expected at most 6, or 8 with a number row", found),
+			LayoutParseError::KeyCount { line, found, expected } =>
+				write!(f, "line {} has {} keys, expected {}", line, found, expected),
+			LayoutParseError::LayerMismatch { lower, upper } =>
+				write!(f, "lower layer has {} keys but upper layer has {}", lower, upper),
+			LayoutParseError::MissingSeparator { line } =>
+				write!(f, "line {} has no | between the hands", line),
+			LayoutParseError::LongKey { line, ref key } =>
+				write!(f, "line {} has {:?} where a single character belongs", line, key),
+		}
+	}
+}
+
+impl fmt::Display for GeometryParseError
+{
+	fn fmt(&self, f: &mut fmt::Formatter)
+	-> fmt::Result
+	{
+		match *self {
+			GeometryParseError::LineCount { found } =>
+				write!(f, "geometry has {} lines, expected 3, or 4 with a number row", found),
+			GeometryParseError::KeyCount { line, found, expected } =>
+				write!(f, "line {} has {} keys, expected {}", line, found, expected),
+			GeometryParseError::BadCoordinate { line, column, ref found } =>
+				write!(f, "line {}, column {}: invalid coordinate {:?}, expected x,y", line, column, found),
+			GeometryParseError::HomeCount { hand, finger, found } =>
+				write!(f, "{} finger has {} home keys marked with *, expected 1",
+					format!("{:?} {:?}", hand, finger).to_lowercase(), found),
+		}
+	}
+}
+
+impl LayoutProblem
+{
+	// Duplicates make the penalty meaningless, since only one of the keys is
+	// ever scored. The other problems are merely suspicious.
+	pub fn is_fatal(&self)
+	-> bool
+	{
+		matches!(*self, LayoutProblem::DuplicateChar { .. } | LayoutProblem::Missing { .. })
+	}
+}
+
+impl fmt::Display for LayoutProblem
+{
+	fn fmt(&self, f: &mut fmt::Formatter)
+	-> fmt::Result
+	{
+		match *self {
+			LayoutProblem::DuplicateChar { layer, kc, first, second } =>
+				write!(f, "duplicate character {:?} at positions {} and {} of the {} layer", kc, first, second, layer),
+			LayoutProblem::CrossLayer { kc, lower, upper } =>
+				write!(f, "character {:?} is on the lower layer at position {} and the upper layer at position {}; it is scored at position {}", kc, lower, upper, lower),
+			LayoutProblem::Unshifted { kc, pos } =>
+				write!(f, "character {:?} at position {} is not shifted to {:?} in the upper layer", kc, pos, kc.to_ascii_uppercase()),
+			LayoutProblem::Hole { pos } =>
+				write!(f, "swappable position {} has no character", pos),
+			LayoutProblem::Missing { kc, pos } =>
+				write!(f, "character {:?} is at position {}, which the keyboard doesn't have", kc, pos),
+			LayoutProblem::Unpaired { kc, pos, found, expected } =>
+				write!(f, "character {:?} at position {} is shifted to {:?} instead of its pair {:?}", kc, pos, found, expected),
+		}
+	}
+}
+
+impl fmt::Display for Layout
+{
+	fn fmt(&self, f: &mut fmt::Formatter)
+	-> fmt::Result
+	{
+		let Layout(ref lower, ref upper, ref board) = *self;
+		writeln!(f, "lower:")?;
+		lower.fmt_on(f, board)?;
+		write!(f, "\nupper:\n")?;
+		upper.fmt_on(f, board)
+	}
+}
+
+impl Layer
+{
+	// Writes the keys the board has, starting with the number row if it has
+	// one and leaving out the thumb row if it has no thumb keys.
+	fn fmt_on(&self, f: &mut fmt::Formatter, board: &Board)
+	-> fmt::Result
+	{
+		let Layer(KeyMap(ref layer)) = *self;
+		let keys = |range: Range<usize>| range
+			.filter(|&i| board.exists(i))
+			.map(|i| layer[i].to_string())
+			.collect::<Vec<String>>()
+			.join(" ");
+		if board.has_number_row() {
+			writeln!(f, "{} | {}", keys(34..39), keys(39..NUM_KEYS))?;
+		}
+		writeln!(f, "{} | {}", keys(0..5), keys(5..11))?;
+		writeln!(f, "{} | {}", keys(11..16), keys(16..22))?;
+		write!(f, "{} | {}", keys(22..27), keys(27..32))?;
+		if board.exists(32) || board.exists(33) {
+			write!(f, "\n{:>9} | {}", keys(32..33), keys(33..34))?;
+		}
+		Ok(())
+	}
+}
+
+#[cfg(test)]
+mod tests
+{
+	use super::*;
+
+	static ORTHO: &str = "\
+		0,0 1,0 2,0 3,0 4,0 | 7,0 8,0 9,0 10,0 11,0 12,0\n\
+		0,1* 1,1* 2,1* 3,1* 4,1 | 7,1 8,1* 9,1* 10,1* 11,1* 12,1\n\
+		0,2 1,2 2,2 3,2 4,2 | 7,2 8,2 9,2 10,2 11,2 4,3 8,3\n";
+
+	#[test]
+	fn number_row()
+	{
+		let mut board = MATRIX34_BOARD;
+		board.add_number_row();
+		let four = "12345 67890\njcyfk zl,uq=\nrsthd mnaio'\n/vgpb xw.;-e \n";
+		let layout = Layout::from_string(four, board).unwrap();
+		assert_eq!(layout.keys_at(34), ('1', '!'));
+		assert_eq!(layout.keys_at(43), ('0', ')'));
+		assert_eq!(layout.keys_at(0), ('j', 'J'));
+		assert!(layout.validate().is_empty());
+		assert!(board.swappable().contains(&34) && !MATRIX34_BOARD.swappable().contains(&34));
+
+		// It's printed first, and read back.
+		let printed = layout.to_string();
+		assert!(printed.starts_with("lower:\n1 2 3 4 5 | 6 7 8 9 0\nj c y f k"));
+		assert!(Layout::from_string(&printed, board).unwrap() == layout);
+
+		// A three-row file leaves it empty, for the digits to fill.
+		let mut three = Layout::from_string(&four[12..], board).unwrap();
+		assert_eq!(three.keys_at(34), ('\0', '\0'));
+		three.fill_number_row(&ShiftPairs::us());
+		assert!(three == layout);
+		let mut one = Layout::from_string("1cyfk zl,uq=\nrsthd mnaio'\n/vgpb xw.;-e \n", board).unwrap();
+		one.fill_number_row(&ShiftPairs::us());
+		assert_eq!((one.keys_at(34), one.keys_at(35)), (('\0', '\0'), ('2', '@')));
+
+		// A board without the row has nowhere to type it from.
+		let problems = Layout::from_string(four, MATRIX34_BOARD).unwrap().validate();
+		assert!(problems.iter().any(|p| p.is_fatal()));
+		assert!(Layout::from_string(&format!("{}{}", four, four), board).is_ok());
+		assert!(Layout::from_string(&format!("{}{}", four, &four[12..]), board).is_err());
+	}
+
+	#[test]
+	fn patched_maps_match_rebuilt_ones()
+	{
+		// 'a' is also on the upper layer of Q, and ';' twice on the lower.
+		let mut layout = QWERTY_LAYOUT.clone();
+		(layout.1).0 .0[0] = 'a';
+		(layout.0).0 .0[31] = ';';
+		let positions = |map: &LayoutPosMap| map.0.iter()
+			.map(|kp| kp.map(|kp| (kp.pos, kp.shifted)))
+			.collect::<Vec<_>>();
+		for &model_shift in [true, false].iter() {
+			let mut layout = layout.clone();
+			let mut map = layout.position_map_for(model_shift);
+			for &swap in [(0, 5), (11, 0), (20, 31), (0, 11), (20, 2)].iter() {
+				layout.swap_keys(swap.0, swap.1);
+				map = map.patched(&layout, &[swap]);
+				assert_eq!(positions(&map), positions(&layout.position_map_for(model_shift)));
+			}
+		}
+
+		// Only with shift modelled is 'a' typed without it.
+		assert_eq!(layout.position_map_for(true).get_key_position('a').unwrap().pos, 11);
+		assert_eq!(layout.position_map_for(false).get_key_position('a').unwrap().pos, 0);
+	}
+
+	#[test]
+	fn layouts_on_smaller_boards()
+	{
+		let short = "qwert yuiop\nasdfg hjkl;\nzxcvb nm,./ \nQWERT YUIOP\nASDFG HJKL:\nZXCVB NM<>? \n";
+		let layout = Layout::from_string(short, ANSI30_BOARD).unwrap();
+		assert!(layout.validate().is_empty());
+		assert_eq!(layout.distance(&QWERTY_LAYOUT), 2);
+		assert!(LayoutPermutations::new(&layout, 1).all(|(l, swaps)| {
+			swaps.iter().all(|&(i, j)| ANSI30_BOARD.exists(i) && ANSI30_BOARD.exists(j)) && l.validate().is_empty()
+		}));
+
+		// Keys the board doesn't have must be empty.
+		let mut qwerty = QWERTY_LAYOUT.clone();
+		qwerty.set_board(ANSI30_BOARD);
+		let missing: Vec<usize> = qwerty.validate().iter().filter_map(|p| match *p {
+			LayoutProblem::Missing { pos, .. } => Some(pos),
+			_ => None,
+		}).collect();
+		assert_eq!(missing, vec![10, 21]);
+	}
+
+	#[test]
+	fn custom_shift_pairs()
+	{
+		let pairs = parse_pairs("# Programmer pairs\n[pairs]\n\",\" = \";\"\n'.' = ':'\n\"\\\"\" = '!'\n").unwrap();
+		let layout = Layout::from_string_with_pairs("jcyfk zl,uq=\nrsthd mnaio\"\n/vgpb xw.;-e \n", MATRIX34_BOARD, &pairs).unwrap();
+		assert_eq!(layout.keys_at(7), (',', ';'));
+		assert_eq!(layout.keys_at(21), ('"', '!'));
+		assert_eq!(layout.keys_at(0), ('j', 'J'));
+		assert!(layout.validate_pairs(&pairs).is_empty());
+
+		let problems: Vec<String> = INIT_LAYOUT.validate_pairs(&pairs).iter().map(|p| p.to_string()).collect();
+		assert_eq!(problems, vec![
+			"character ',' at position 7 is shifted to '<' instead of its pair ';'",
+			"character '.' at position 29 is shifted to '>' instead of its pair ':'",
+		]);
+
+		assert_eq!(pairs.fold_case("Hi! <a>"), "hi\" <a>");
+		assert_eq!(ShiftPairs::us().fold_case("Hi! <a>"), "hi1 ,a.");
+
+		let errors: Vec<String> = ["\",\" \";\"", "\",\" = \";;\"", ", = ;"].iter()
+			.map(|s| parse_pairs(s).err().unwrap().to_string())
+			.collect();
+		assert_eq!(errors, vec![
+			"line 1 is not of the form \"lower\" = \"upper\"",
+			"line 1 has \";;\" where a single character belongs",
+			"line 1 is not of the form \"lower\" = \"upper\"",
+		]);
+	}
+
+	#[test]
+	fn position_names()
+	{
+		let names: Vec<String> = [0, 4, 10, 13, 27, 32, 33, 34].iter().map(|&pos| position_name(pos)).collect();
+		assert_eq!(names, vec![
+			"left pinky top", "left inner index top", "right outer pinky top", "left middle home",
+			"right inner index bottom", "left thumb", "right thumb", "left pinky number",
+		]);
+	}
+
+	#[test]
+	fn compose_rules()
+	{
+		let compose = parse_compose("[compose]\n# Dead acute.\n\"é\" = \"'e\"\n'É' = \"'E\"\n\"ß\" = \"ss\"\n").unwrap();
+		assert_eq!(compose.expand("Schlüß, Élève"), "Schlüss, 'Elève");
+		assert!(compose.missing(&QWERTY_LAYOUT).is_empty());
+
+		// A dead key the layout doesn't have.
+		let compose = parse_compose("\"ñ\" = \"~n\"\n\"ã\" = \"~a\"\n\"ø\" = \"ø\"\n").unwrap();
+		let layout = Layout::from_string("jcyfk zl,uq=\nrsthd mnaio'\n/vgpb xw.;-e \n", MATRIX34_BOARD).unwrap();
+		assert_eq!(compose.missing(&layout), vec!['~', 'ø']);
+		assert!(compose.missing(&COLEMAK_LAYOUT).contains(&'ø'));
+		let errors: Vec<String> = ["\"é\" 'e", "\"ée\" = \"'e\"", "\"é\" = \"\""].iter()
+			.map(|s| parse_compose(s).err().unwrap().to_string())
+			.collect();
+		assert_eq!(errors, vec![
+			"line 1 is not of the form \"composed\" = \"keystrokes\"",
+			"line 1 composes \"ée\" rather than a single character",
+			"line 1 has no keystrokes",
+		]);
+	}
+
+	#[test]
+	fn one_layer_files()
+	{
+		let lower = "jcyfk zl,uq=\nrsthd mnaio'\n/vgpb xw.;-e \n";
+		assert!(Layout::from_string(lower, MATRIX34_BOARD).unwrap() == INIT_LAYOUT);
+
+		// Characters with no shift keep an empty upper layer.
+		let layout = Layout::from_string("jcyfk zl,uq\u{e9}\nrsthd mnaio'\n/vgpb xw.;-e\n", MATRIX34_BOARD).unwrap();
+		assert_eq!(layout.keys_at(10), ('\u{e9}', '\0'));
+		assert_eq!(layout.keys_at(32), ('e', 'E'));
+		assert_eq!(layout.keys_at(33), ('\0', '\0'));
+		assert_eq!(layout.unshifted_keys(), vec![(10, '\u{e9}')]);
+		assert!(layout.validate().iter().all(|p| !p.is_fatal()));
+
+		// An explicit upper layer still wins, and must match the lower.
+		let two = "jcyfk zl,uq=\nrsthd mnaio'\n/vgpb xw.;-e \nJCYFK ZL;UQ+\nRSTHD MNAIO\"\n?VGPB XW>:_E \n";
+		assert_eq!(Layout::from_string(two, MATRIX34_BOARD).unwrap().keys_at(7), (',', ';'));
+		assert!(Layout::from_string(&two[..(two.len() - 14)], MATRIX34_BOARD).is_err());
+	}
+
+	#[test]
+	fn printed_layouts_read_back()
+	{
+		for &(name, layout) in REFERENCE_LAYOUTS.iter() {
+			let printed = layout.to_string();
+			let read = Layout::from_string(&printed, *layout.board()).unwrap();
+			assert!(read == *layout, "{}:\n{}\n{}", name, printed, read);
+			assert_eq!(read.to_string(), printed);
+		}
+
+		let lower_only = "lower:\nq w e r t | y u i o p -\na s d f g | h j k l ; '\nz x c v b | n m , . /\n          |  \n";
+		let read = Layout::from_string(lower_only, MATRIX33_BOARD).unwrap();
+		assert_eq!(read.keys_at(0), ('q', 'Q'));
+		assert_eq!(read.keys_at(10), ('-', '_'));
+		assert_eq!(read.keys_at(33), (' ', ' '));
+		let read = Layout::from_string(lower_only, MATRIX34_BOARD).unwrap();
+		assert_eq!(read.keys_at(32), ('\0', '\0'));
+
+		let errors: Vec<String> = ["lower:\nq w e r t y u i o p -\n", "lower:\nq w e r t | y u i o p\n", "lower:\nq w e r tt | y u i o p -\n"].iter()
+			.map(|s| Layout::from_string(s, MATRIX33_BOARD).err().unwrap().to_string())
+			.collect();
+		assert_eq!(errors, vec![
+			"line 2 has no | between the hands",
+			"line 2 has 5 keys, expected 6",
+			"line 2 has \"tt\" where a single character belongs",
+		]);
+	}
+
+	#[test]
+	fn kle_import()
+	{
+		let ansi = r##"[{"name": "ANSI"},
+			["~\n`","!\n1","@\n2","#\n3","$\n4","%\n5","^\n6","&\n7","*\n8","(\n9",")\n0","_\n-","+\n=",{w:2},"Backspace"],
+			[{w:1.5},"Tab","Q","W","E","R","T","Y","U","I","O","P","{\n[","}\n]",{w:1.5},"|\n\\"],
+			[{w:1.75},"Caps Lock","A","S","D","F","G","H","J","K","L",":\n;","\"\n'",{w:2.25},"Enter"],
+			[{w:2.25},"Shift","Z","X","C","V","B","N","M","&lt;\n,","&gt;\n.","?\n/",{w:2.75},"Shift"],
+			[{w:1.25},"Ctrl",{w:1.25},"Alt",{w:6.25},"",{w:1.25},"Alt"]
+		]"##;
+		let (layout, warnings) = Layout::from_kle_json(ansi, MATRIX34_BOARD, &ShiftPairs::us()).unwrap();
+		assert_eq!(layout.keys_at(0), ('q', 'Q'));
+		assert_eq!(layout.keys_at(10), ('[', '{'));
+		assert_eq!(layout.keys_at(21), ('\'', '"'));
+		assert_eq!(layout.keys_at(29), (',', '<'));
+		assert_eq!(layout.keys_at(32), ('\0', '\0'));
+		assert_eq!(layout.keys_at(33), (' ', ' '));
+		assert_eq!(warnings.len(), 15);
+		assert_eq!(warnings[0], "no place for the key ~ / `");
+		assert_eq!(warnings[14], "no place for the key | / \\");
+
+		// A split sketch with a thumb key.
+		let split = r#"[["J","C","Y","F","K","Z","L","<\n,","U","Q","+\n="],
+			["R","S","T","H","D","M","N","A","I","O","\"\n'"],
+			["?\n/","V","G","P","B","X","W",">\n.",":\n;","_\n-"],
+			[{x:4},"E",""]]"#;
+		let (layout, warnings) = Layout::from_kle_json(split, MATRIX34_BOARD, &ShiftPairs::us()).unwrap();
+		assert!(warnings.is_empty());
+		assert!(layout == INIT_LAYOUT, "{}", layout);
+
+		// Single legends shift as the given pairs say.
+		let pairs = parse_pairs("\"q\" = \"!\"").unwrap();
+		let (layout, _) = Layout::from_kle_json(split, MATRIX34_BOARD, &pairs).unwrap();
+		assert_eq!(layout.keys_at(0), ('j', 'J'));
+		assert_eq!(layout.keys_at(9), ('q', '!'));
+
+		assert_eq!(Layout::from_kle_json("[[\"Q\"]]", MATRIX34_BOARD, &ShiftPairs::us()).err().unwrap().to_string(),
+			"no three rows in a row with at least ten character keys each");
+	}
+
+	#[test]
+	fn layouts_with_one_thumb_key()
+	{
+		let one_thumb = "jcyfk zl,uq=\nrsthd mnaio'\n/vgpb xw.;-e\nJCYFK ZL<UQ+\nRSTHD MNAIO\"\n?VGPB XW>:_E\n";
+		let layout = Layout::from_string(one_thumb, MATRIX34_BOARD).unwrap();
+		assert_eq!(layout.differing_keys(&INIT_LAYOUT), vec![(33, '\0', ' ')]);
+	}
+
+	#[test]
+	fn swap_thumb()
+	{
+		let moves_thumb = |layout: &Layout| LayoutPermutations::new(layout, 1)
+			.any(|(_, swaps)| swaps.iter().any(|&(i, j)| i == 32 || j == 32));
+		let mut layout = INIT_LAYOUT.clone();
+		assert!(!moves_thumb(&layout));
+		assert_eq!(layout.2.swappable().len(), 31);
+
+		let mut board = MATRIX34_BOARD;
+		board.set_swappable(32, true);
+		layout.set_board(board);
+		assert!(moves_thumb(&layout));
+		assert_eq!(LayoutPermutations::new(&layout, 1).count(), 32 * 31 / 2 + 1);
+	}
+
+	#[test]
+	fn move_only_one_kind()
+	{
+		let mut layout = INIT_LAYOUT.clone();
+		layout.move_only(KeyKind::Punctuation);
+		assert_eq!(layout.2.swappable(), vec![7, 10, 21, 22, 29, 30, 31]);
+		layout.keep(&[';']);
+		assert_eq!(layout.2.swappable(), vec![7, 10, 21, 22, 29, 31]);
+
+		let mut layout = INIT_LAYOUT.clone();
+		layout.move_only(KeyKind::Letters);
+		assert_eq!(layout.2.swappable().len(), 25);
+		assert!(LayoutPermutations::new(&layout, 1).all(|(l, _)| l.keys_at(7).0 == ','));
+	}
+
+	#[test]
+	fn explicit_swaps()
+	{
+		assert_eq!(parse_swap("a:b").unwrap(), (SwapKey::Char('a'), SwapKey::Char('b')));
+		assert_eq!(parse_swap("3:17").unwrap(), (SwapKey::Position(3), SwapKey::Position(17)));
+		assert_eq!(parse_swap("::;").unwrap(), (SwapKey::Char(':'), SwapKey::Char(';')));
+		assert!(parse_swap("ab:c").is_err());
+		assert!(parse_swap("a").is_err());
+
+		let mut layout = QWERTY_LAYOUT.clone();
+		let (a, b) = (layout.find_key(SwapKey::Char('e')).unwrap(), layout.find_key(SwapKey::Char('I')).unwrap());
+		layout.swap_keys(a, b);
+		assert_eq!(layout.keys_at(2), ('i', 'I'));
+		assert_eq!(layout.keys_at(7), ('e', 'E'));
+		assert!(layout.find_key(SwapKey::Position(33)).is_ok());
+		assert!(layout.find_key(SwapKey::Position(34)).is_err());
+		assert!(layout.find_key(SwapKey::Char('1')).is_err());
+	}
+
+	#[test]
+	fn mirror_swaps_hands()
+	{
+		let mirrored = QWERTY_LAYOUT.mirrored();
+		assert_eq!(mirrored.keys_at(0), ('p', 'P'));
+		assert_eq!(mirrored.keys_at(15), ('h', 'H'));
+		assert_eq!(mirrored.keys_at(22), ('/', '?'));
+		assert_eq!(mirrored.keys_at(10), QWERTY_LAYOUT.keys_at(10));
+		assert_eq!(mirrored.keys_at(32), QWERTY_LAYOUT.keys_at(32));
+		for &(_, layout) in REFERENCE_LAYOUTS.iter() {
+			assert!(layout.mirrored().mirrored() == *layout);
+			assert_eq!(layout.mirrored().validate().len(), layout.validate().len());
+		}
+	}
+
+	#[test]
+	fn move_only_one_hand()
+	{
+		let mut layout = INIT_LAYOUT.clone();
+		layout.move_only_hand(Hand::Right);
+		assert!(layout.2.swappable().iter().all(|&pos| KEY_HANDS.0[pos] == Hand::Right));
+		assert_eq!(layout.2.swappable().len(), 16);
+		assert!(LayoutPermutations::new(&layout, 2).all(|(l, _)| (0..5).all(|pos| l.keys_at(pos) == INIT_LAYOUT.keys_at(pos))));
+	}
+
+	#[test]
+	fn permutations_are_distinct()
+	{
+		// Only five keys move.
+		let mut layout = INIT_LAYOUT.clone();
+		for pos in (0..34).filter(|&pos| !(5..10).contains(&pos)) {
+			layout.2.set_swappable(pos, false);
+		}
+		assert_eq!(layout.2.swappable().len(), 5);
+
+		let lower = |l: &Layout| (0..34).map(|pos| l.keys_at(pos).0).collect::<String>();
+		for depth in 0..5 {
+			let permutations = LayoutPermutations::new(&layout, depth);
+			let total = permutations.total();
+			let layouts: Vec<(Layout, Vec<Swap>)> = permutations.collect();
+			assert!(layouts[0].0 == layout && layouts[0].1.is_empty());
+			assert_eq!(layouts.len(), total);
+			let mut seen: Vec<String> = layouts.iter().map(|(l, _)| lower(l)).collect();
+			seen.sort();
+			seen.dedup();
+			assert_eq!(seen.len(), total);
+			assert!(layouts.iter().all(|(_, swaps)| swaps.len() <= depth && swaps.iter().all(|&(i, j)| i != j)));
+		}
+		// 1, then 10 swaps, 20 three-cycles and 15 pairs of swaps, and so on
+		// up to all 5! orders.
+		let totals: Vec<usize> = (0..5).map(|depth| LayoutPermutations::new(&layout, depth).total()).collect();
+		assert_eq!(totals, vec![1, 11, 46, 96, 120]);
+	}
+
+	#[test]
+	fn groups_constrain_swaps()
+	{
+		let overrides = parse_groups("# apostrophes are letters\nletters '\n").unwrap();
+		let mut layout = INIT_LAYOUT.clone();
+		layout.set_groups(&overrides);
+		let class = |c: char| if c.is_alphabetic() || c == '\'' { 0 } else { 1 };
+		let same_class = |l: &Layout, swaps: &[Swap]| swaps.iter().all(|&(i, j)| {
+			let Layout(Layer(KeyMap(ref lower)), _, _) = *l;
+			class(lower[i]) == class(lower[j])
+		});
+
+		assert!(LayoutPermutations::new(&layout, 1).all(|(l, swaps)| same_class(&l, &swaps)));
+		let permutations = LayoutPermutations::new(&layout, 2);
+		assert_eq!(permutations.total(), permutations.count());
+		let every_third: Vec<Vec<Swap>> = LayoutPermutations::new(&layout, 2).map(|(_, swaps)| swaps).skip(1).step_by(3).collect();
+		let stepped: Vec<Vec<Swap>> = LayoutPermutations::new(&layout, 2).skip(1).step_by(3).map(|(_, swaps)| swaps).collect();
+		assert!(every_third == stepped);
+		for _ in 0..100 {
+			let mut shuffled = layout.clone();
+			let swaps = shuffled.shuffle(3);
+			assert!(same_class(&shuffled, &swaps));
+		}
+	}
+
+	#[test]
+	fn groups_file_errors()
+	{
+		match parse_groups("letters abc\nvowels a\n") {
+			Err(GroupsParseError::Duplicate { line: 2, kc: 'a' }) => (),
+			_ => panic!("expected a duplicate"),
+		}
+		match parse_groups("letters\n") {
+			Err(GroupsParseError::MissingChars { line: 1 }) => (),
+			_ => panic!("expected missing characters"),
+		}
+	}
+
+	#[test]
+	fn reference_layouts_are_valid()
+	{
+		// Some have holes, but none types a character twice or lacks an upper
+		// case letter.
+		for &(name, layout) in REFERENCE_LAYOUTS.iter() {
+			let problems = layout.validate();
+			assert!(!problems.iter().any(|p| match *p {
+				LayoutProblem::Unshifted { .. } => true,
+				ref p => p.is_fatal(),
+			}), "{} has problems", name);
+		}
+	}
+
+	#[test]
+	fn reference_layouts_have_no_collisions()
+	{
+		for &(name, layout) in REFERENCE_LAYOUTS.iter() {
+			let collisions: Vec<String> = layout.layer_collisions().iter().map(|p| p.to_string()).collect();
+			assert!(collisions.is_empty(), "{}: {}", name, collisions.join("; "));
+		}
+
+		let mut layout = QWERTY_LAYOUT.clone();
+		{
+			let Layout(_, Layer(KeyMap(ref mut upper)), _) = layout;
+			upper[10] = ',';
+		}
+		match layout.layer_collisions()[..] {
+			[LayoutProblem::CrossLayer { kc: ',', lower: 29, upper: 10 }] => (),
+			_ => panic!("expected a collision"),
+		}
+		assert!(layout.validate().iter().all(|p| !p.is_fatal()));
+	}
+
+	#[test]
+	fn reference_layouts_by_name()
+	{
+		assert!(reference_layout("colemak").unwrap() == &COLEMAK_LAYOUT);
+		assert!(reference_layout("Dvorak").unwrap() == &DVORAK_LAYOUT);
+		assert!(reference_layout("colemak.layout").is_none());
+	}
+
+	#[test]
+	fn constraints_on_qwerty()
+	{
+		let constraints = parse_constraints("# vowels\nchars = \"aeiou\", hand = \"right\"\n\nchar = \"f\", finger = \"index\", row = \"home\"\n").unwrap();
+		assert_eq!(constraints.len(), 2);
+		assert_eq!(constraints[0].line, 2);
+
+		let pos_map = QWERTY_LAYOUT.get_position_map();
+		assert_eq!(constraints[0].violations(&pos_map), vec!['a', 'e']);
+		assert!(constraints[1].allows(&pos_map));
+		assert!(!satisfies(&pos_map, &constraints));
+		assert!(satisfies(&pos_map, &constraints[1..]));
+
+		// Commas in quotes are characters, not separators.
+		let constraints = parse_constraints("chars = \",.\", hand = \"right\", row = \"bottom\"\n").unwrap();
+		assert_eq!(constraints[0].chars, vec![',', '.']);
+		assert!(constraints[0].allows(&pos_map));
+	}
+
+	#[test]
+	fn constraints_file_errors()
+	{
+		match parse_constraints("char = \"e\", hand = \"middle\"\n") {
+			Err(ConstraintParseError::BadValue { line: 1, .. }) => (),
+			_ => panic!("expected a bad value"),
+		}
+		match parse_constraints("char = \"e\"\n") {
+			Err(ConstraintParseError::MissingPlace { line: 1 }) => (),
+			_ => panic!("expected a missing hand, finger or row"),
+		}
+		match parse_constraints("char = e, hand = \"left\"\n") {
+			Err(ConstraintParseError::Syntax { line: 1 }) => (),
+			_ => panic!("expected a syntax error"),
+		}
+	}
+
+	#[test]
+	fn geometry_distances()
+	{
+		let geometry = Geometry::from_string(ORTHO).unwrap();
+		// Top row to home row, and the index finger reaching into the centre
+		// column diagonally.
+		assert_eq!(geometry.travel(2), 1.0);
+		assert_eq!(geometry.travel(26), 2f64.sqrt());
+		// Thumbs rest on their only key.
+		assert_eq!(geometry.travel(32), 0.0);
+		assert_eq!(geometry.distance(2, 24), 2.0);
+		assert!(!geometry.board.has_number_row());
+
+		// A fourth line puts the number row above the others.
+		let numbers = "0,-1 1,-1 2,-1 3,-1 4,-1 | 7,-1 8,-1 9,-1 10,-1 -\n";
+		let geometry = Geometry::from_string(&format!("{}{}", numbers, ORTHO)).unwrap();
+		assert_eq!(geometry.travel(36), 2.0);
+		assert!(geometry.board.has_number_row() && !geometry.board.exists(43));
+		assert!(geometry.board.swappable().contains(&34));
+	}
+
+	#[test]
+	fn geometry_needs_one_home_per_finger()
+	{
+		let geometry = ORTHO.replacen("0,1*", "0,1", 1);
+		match Geometry::from_string(&geometry) {
+			Err(GeometryParseError::HomeCount { hand: Hand::Left, finger: Finger::Pinky, found: 0 }) => (),
+			_ => panic!("expected a missing home key"),
+		}
 	}
 }
@@ -0,0 +1,27 @@
+//! Keyboard layout scoring and optimisation.
+//!
+//! A typical use reads a layout with `layout::Layout::from_string`, turns a
+//! corpus into quartads with `penalty::prepare_quartad_list`, and scores the
+//! layout with `penalty::calculate_penalty`. `simulator::simulate` searches for
+//! better layouts, collecting them in a list of `simulator::BestLayoutsEntry`
+//! and reporting its progress as `simulator::Event`s rather than printing
+//! anything; `simulator::format_result` formats a result the way the command
+//! line tool does.
+
+// Module headers are `///` comments followed by a blank line.
+#![allow(clippy::empty_line_after_doc_comments)]
+
+pub mod layout;
+pub mod penalty;
+pub mod annealing;
+pub mod simulator;
+pub mod rng;
+pub mod trace;
+pub mod json;
+pub mod wasm;
+pub mod heatmap;
+pub mod svg;
+pub mod export;
+pub mod hash;
+pub mod checkpoint;
+pub mod verify;
@@ -1,202 +1,2104 @@
- #![feature(linked_list_cursors)]
+// Module headers are `///` comments followed by a blank line.
+#![allow(clippy::empty_line_after_doc_comments)]
 
-mod layout;
-mod penalty;
-mod annealing;
-mod simulator;
+mod cli;
+mod config;
+mod error;
+mod progress;
+mod tui;
 
 extern crate getopts;
+extern crate keygen;
 
 use std::env;
+use std::fs;
 use std::fs::File;
-use std::io::Read;
-use getopts::Options;
+use std::io;
+use std::io::{IsTerminal, Read, Write};
+use std::process;
+use std::thread;
+use std::cmp;
+use std::cmp::Ordering;
+use std::collections::HashMap;
+use std::hash::Hasher;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use keygen::{annealing, checkpoint, export, hash, heatmap, json, layout, penalty, rng, simulator, svg, trace, verify};
 
-fn main()
+// How often --watch checks whether the layout file has changed.
+const WATCH_INTERVAL: Duration = Duration::from_millis(500);
+// Where verify finds its corpus and scores unless given another directory: the
+// fixtures in the source tree the binary was built from, wherever it's run.
+const DEFAULT_FIXTURES: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/fixtures/verify");
+
+// A corpus as read: its text, its weight against the other corpora, the length
+// it was read with to scale penalties by, and whether it's an n-gram table.
+struct Corpus<'a>
+{
+	filename: &'a str,
+	text:     String,
+	weight:   f64,
+	len:      usize,
+	ngrams:   bool,
+}
+
+// What run carries from round to round besides its best layouts: how many
+// rounds to run, or 0 for no limit, where to write traces and checkpoints, and
+// where to resume from.
+struct Session<'s>
+{
+	rounds:     usize,
+	traces:     &'s mut [trace::Trace],
+	checkpoint: Option<checkpoint::Checkpoint>,
+	resume:     Option<checkpoint::Saved>,
+}
+
+// How a search shows what it finds: whether to print every step, how many of
+// the costliest keys to list, whether to add hand statistics, draw a progress
+// line or write JSON, and the file to write the result to, if any.
+struct Output
 {
-	let mut opts = Options::new();
-	opts.optflag("h", "help", "print this help menu");
-	opts.optflag("d", "debug", "show debug logging");
-	opts.optopt("t", "top", "number of top layouts to print (default: 1)", "TOP_LAYOUTS");
-	opts.optopt("s", "swaps-per-iteration", "maximum number of swaps per iteration (default: 3)", "SWAPS");
+	debug:      bool,
+	keys:       usize,
+	hand_stats: bool,
+	progress:   bool,
+	json:       bool,
+	file:       Option<String>,
+}
+
+// What run-ref prints about each layout besides its score: the costliest keys,
+// a heatmap, the costliest words and runs on one hand, the last two typed
+// with the given space mode and reset characters.
+struct Extras<'e>
+{
+	keys:      usize,
+	heatmap:   bool,
+	words:     &'e hash::FastHashMap<&'e str, usize>,
+	run_texts: &'e [&'e str],
+	space:     penalty::SpaceMode,
+	reset:     &'e [char],
+}
 
+fn main()
+{
 	let args: Vec<String> = env::args().collect();
 	let progname = &args[0];
-	if args.len() < 2 {
-		print_usage(progname, opts);
+	if args.len() < 2 || args[1] == "help" && args.len() == 2 {
+		print!("{}", cli::command_list(progname));
+		return;
+	}
+	let (command, help) = if args[1] == "help" { (&args[2], true) } else { (&args[1], false) };
+	let cmd = match cli::command(command) {
+		Some(cmd) => cmd,
+		None => {
+			eprint!("{}", cli::command_list(progname));
+			fail(error::Error::UnknownCommand(command.clone()));
+		},
+	};
+	if help {
+		print_usage(progname, cmd);
 		return;
 	}
-	let command = &args[1];
-	let matches = match opts.parse(&args[2..]) {
+	// Parse with every option, so that one given to the wrong command can be
+	// named as such rather than as unknown.
+	let opts = cli::options(None);
+	// Defaults from a config file, which the command line overrides. Options
+	// that don't apply to this command are left for the others.
+	let args = match config::path(&args[2..]) {
+		Some(path) => {
+			let config_str = match read_file(&path) {
+				Ok(s) => s,
+				Err(e) => fail(error::Error::ReadConfig(path, e)),
+			};
+			let merged = config::parse_config(&config_str[..])
+				.map(|options| options.into_iter().filter(|option| cli::applies(cmd, &option.name)).collect::<Vec<_>>())
+				.and_then(|options| config::merge(&opts, &options, &args[2..]));
+			match merged {
+				Ok(merged) => merged,
+				Err(e) => fail(error::Error::ParseConfig(path, e)),
+			}
+		},
+		None => args[2..].to_vec(),
+	};
+	let matches = match opts.parse(&args) {
 		Ok(m) => { m }
-		Err(f) => { panic!(f.to_string()) }
+		Err(f) => fail(error::Error::Options(f)),
 	};
 
 	// --help
 	if matches.opt_present("h") {
-		print_usage(progname, opts);
+		print_usage(progname, cmd);
+		return;
+	}
+	if let Some(name) = cli::misplaced(&matches, cmd) {
+		fail(error::Error::OptionNotForCommand(name.to_string(), command.clone()));
+	}
+	let settings = match cli::settings(&matches, cmd) {
+		Ok(settings) => settings,
+		Err(e) => fail(error::Error::OptionValue(e)),
+	};
+
+	// Verifying scores with the default model, whatever the options.
+	if command == "verify" {
+		if matches.free.len() > 1 {
+			print_usage(progname, cmd);
+			return;
+		}
+		verify(matches.free.first().map_or(DEFAULT_FIXTURES, |dir| &dir[..]), matches.opt_present("bless"));
 		return;
 	}
 
-	// Read corpus.
-	let corpus_filename = match matches.free.get(0) {
-		Some(f) => f,
+	// Adjust the penalty model before anything is scored with it.
+	// JSON output and exports are meant for other programs, so leave out
+	// everything else, as do commands that don't score anything.
+	let json = matches.opt_present("json");
+	let scoring = command != "export" && command != "mirror" && command != "calibrate" && (command != "swap" || matches.opt_present("corpus") || matches.opt_present("ngrams"));
+	let preset_name = matches.opt_str("preset").unwrap_or("prose".to_string());
+	let preset = match penalty::preset(&preset_name) {
+		Some(preset) => preset,
 		None => {
-			print_usage(progname, opts);
-			return;
+			let names = penalty::PRESETS.iter().map(|preset| preset.name.to_string()).collect();
+			fail(error::Error::UnknownPreset(preset_name, names))
 		},
 	};
-	let mut f = match File::open(corpus_filename) {
-		Ok(f) => f,
-		Err(e) => {
-			println!("Error: {}", e);
-			panic!("could not read corpus");
+	if !json && scoring && matches.opt_present("preset") {
+		println!("Preset: {}", preset.name);
+	}
+	let weights = matches.opt_str("weights").map(|filename| read_weights(&filename));
+	let penalties = configure_penalties(&matches, preset, &weights);
+	if !json && scoring {
+		print_weights(&penalties);
+	}
+	let mut effort = match matches.opt_str("effort") {
+		Some(filename) => read_effort(&filename),
+		None => *preset.effort,
+	};
+	if let Some(arg) = matches.opt_str("dominant-hand") {
+		let factor = settings.off_hand_factor;
+		effort = penalty::favour_hand(&effort, parse_hand(&arg), factor);
+		if !json && scoring {
+			println!("Dominant hand: {}, other hand's base penalties x{}", arg, factor);
+		}
+	}
+	let context = settings.context;
+	if !json && scoring && context != penalty::DEFAULT_CONTEXT {
+		println!("Context: {} characters per keystroke", context);
+	}
+	let same_finger_rows = settings.same_finger_rows;
+	if !json && scoring && same_finger_rows != penalty::DEFAULT_SAME_FINGER_ROWS {
+		println!("Same finger penalty per row between keys: {}", same_finger_rows);
+	}
+	let bigrams = matches.opt_str("bigram-penalties").map(|filename| read_bigram_penalties(&filename));
+	let (mut board, geometry) = match (matches.opt_str("board"), matches.opt_str("geometry")) {
+		(Some(_), Some(_)) => fail(error::Error::OptionConflict("board", "geometry")),
+		(Some(name), None) => match layout::Board::from_name(&name) {
+			Some(board) => (board, None),
+			None => fail(error::Error::UnknownBoard(name)),
+		},
+		(None, Some(filename)) => {
+			let geometry = read_geometry(&filename);
+			(geometry.board, Some(geometry))
 		},
+		(None, None) => (layout::MATRIX34_BOARD, None),
+	};
+	// Key coordinates can't be made up for a number row the geometry lacks.
+	match settings.rows {
+		Some(4) if geometry.is_some() && !board.has_number_row() =>
+			fail(error::Error::NoNumberRow(matches.opt_str("geometry").unwrap())),
+		Some(4) => board.add_number_row(),
+		Some(_) => board.remove_number_row(),
+		None => (),
+	}
+	if matches.opt_present("d") {
+		println!("Base penalties:");
+		print_effort(&effort, board.has_number_row());
+	}
+	if matches.opt_present("swap-thumb") {
+		board.set_swappable(32, true);
+	}
+	if preset.swap_corner {
+		board.set_swappable(10, true);
+	}
+	let pairs = match matches.opt_str("pairs") {
+		Some(filename) => read_pairs(&filename),
+		None => layout::ShiftPairs::us(),
+	};
+	let mut model = penalty::Model {
+		model_shift: matches.opt_present("model-shift"),
+		space: settings.space,
+		hand_balance: settings.hand_balance,
+		same_finger_rows,
+		effort,
+		geometry,
+		bigrams,
+		..penalty::Model::default()
 	};
-	let mut corpus = String::new();
-	match f.read_to_string(&mut corpus) {
-		Ok(_) => (),
-		Err(e) => {
-			println!("Error: {}", e);
-			panic!("could not read corpus");
-		}
-	};
-
-	// Read layout, if applicable.
-	let _layout;
-	let layout = match matches.free.get(1) {
-		None => &layout::INIT_LAYOUT,
-		Some(layout_filename) => {
-			let mut f = match File::open(layout_filename) {
-				Ok(f) => f,
-				Err(e) => {
-					println!("Error: {}", e);
-					panic!("could not read layout");
+
+	// Explaining an n-gram needs no corpus.
+	if command == "explain" {
+		let explainable = |ngram: &String| (2..5).contains(&ngram.chars().count());
+		if matches.free.len() != 2 || !explainable(&matches.free[1]) {
+			print_usage(progname, cmd);
+			return;
+		}
+		explain(&matches.free[0], &matches.free[1], &penalties, &model, board, &pairs, context);
+		return;
+	}
+
+	// Nor does typing some text, given or from standard input.
+	if command == "type" {
+		if matches.free.is_empty() || matches.free.len() > 2 {
+			print_usage(progname, cmd);
+			return;
+		}
+		let text = match matches.free.get(1) {
+			Some(text) if text != "-" => text.clone(),
+			_ => {
+				let mut text = String::new();
+				if let Err(e) = io::stdin().read_to_string(&mut text) {
+					fail(error::Error::ReadCorpus("standard input".to_string(), e));
 				}
-			};
-			let mut layout_str = String::new();
-			match f.read_to_string(&mut layout_str) {
-				Ok(_) => (),
-				Err(e) => {
-					println!("Error: {}", e);
-					panic!("could not read layout");
+				text.trim_end_matches(['\n', '\r']).to_string()
+			},
+		};
+		type_text(&matches.free[0], &text, &penalties, &model, board, &pairs, context);
+		return;
+	}
+
+	// Neither does exporting a layout.
+	if command == "export" {
+		if matches.free.len() != 2 {
+			print_usage(progname, cmd);
+			return;
+		}
+		export(&matches.free[0], &matches.free[1], board, &pairs, &matches, matches.opt_present("f"));
+		return;
+	}
+
+	// Nor does calibrating base penalties, which start from the configured ones.
+	if command == "calibrate" {
+		if matches.free.len() != 2 || !matches.opt_present("o") {
+			print_usage(progname, cmd);
+			return;
+		}
+		calibrate(&matches.free[0], &matches.free[1], &model.effort, board, &pairs, &matches.opt_str("o").unwrap());
+		return;
+	}
+
+	if command == "mirror" {
+		if matches.free.len() != 1 {
+			print_usage(progname, cmd);
+			return;
+		}
+		mirror(&matches.free[0], board, &pairs, matches.opt_str("o"));
+		return;
+	}
+
+	// Swapping only needs corpora to compare penalties.
+	let swapped = if command == "swap" {
+		if matches.free.len() < 2 {
+			print_usage(progname, cmd);
+			return;
+		}
+		let swapped = swap(&matches.free[0], &matches.free[1..], board, &pairs, matches.opt_str("o"));
+		if !scoring {
+			return;
+		}
+		Some(swapped)
+	} else {
+		None
+	};
+
+	// Read corpora and layouts. Every argument with a :WEIGHT suffix names a
+	// corpus, as does the first unless --corpus or --ngrams gives one; any
+	// other argument names a layout. swap takes its corpora from --corpus and
+	// --ngrams only.
+	let mut corpus_args = Vec::new();
+	let mut layout_filenames = Vec::new();
+	let corpus_opts = matches.opt_strs("corpus");
+	let ngram_opts = matches.opt_strs("ngrams");
+	for arg in corpus_opts.iter() {
+		let (filename, weight) = split_weight(arg).unwrap_or((&arg[..], 1.0));
+		corpus_args.push((filename, weight, false));
+	}
+	for arg in ngram_opts.iter() {
+		let (filename, weight) = split_weight(arg).unwrap_or((&arg[..], 1.0));
+		corpus_args.push((filename, weight, true));
+	}
+	if command != "swap" {
+		for (i, arg) in matches.free.iter().enumerate() {
+			match split_weight(arg) {
+				Some((filename, weight)) => corpus_args.push((filename, weight, false)),
+				None if i == 0 && corpus_opts.is_empty() && ngram_opts.is_empty() => corpus_args.push((&arg[..], 1.0, false)),
+				None => layout_filenames.push(&arg[..]),
+			}
+		}
+	}
+	let num_layouts = match command.as_ref() {
+		"compare" => 2,
+		"run-ref" => usize::MAX,
+		_ => 1,
+	};
+	if corpus_args.is_empty() || layout_filenames.len() > num_layouts ||
+	   (command == "compare" && layout_filenames.len() != 2) ||
+	   (command == "rank" && layout_filenames.len() != 1) ||
+	   ((command == "render" || command == "count") && !matches.opt_present("o")) ||
+	   (matches.opt_present("watch") && layout_filenames.len() != 1) ||
+	   (command == "tui" && layout_filenames.len() != 1) {
+		print_usage(progname, cmd);
+		return;
+	}
+	// Folding case leaves only lower layer characters to score, and to report
+	// the coverage of. Saved quartad counts are already counted.
+	// Typographic characters are replaced by the ASCII ones typed for them,
+	// and compose rules then type characters as several keystrokes. Quartads
+	// borrow from the text, so it's changed here, and keeps the length it was
+	// read with to scale penalties by.
+	let mut replacements: HashMap<char, String> = if matches.opt_present("no-normalize") {
+		HashMap::new()
+	} else {
+		penalty::TYPOGRAPHIC.iter().map(|&(c, s)| (c, s.to_string())).collect()
+	};
+	for arg in matches.opt_strs("normalize") {
+		match parse_replacement(&arg) {
+			Some((c, s)) => { replacements.insert(c, s); },
+			None => fail(error::Error::Normalize(arg.clone())),
+		}
+	}
+	let fold_case = matches.opt_present("fold-case");
+	let compose = matches.opt_str("compose").map(|filename| read_compose(&filename));
+	let corpora: Vec<Corpus> = corpus_args.iter().map(|&(filename, weight, ngrams)| {
+		let s = match read_file(filename) {
+			Ok(s) => s,
+			Err(e) => fail(error::Error::ReadCorpus(filename.to_string(), e)),
+		};
+		let len = s.len();
+		let corpus = |text| Corpus { filename, text, weight, len, ngrams };
+		if filename.ends_with(".qd") {
+			if fold_case && !json {
+				println!("Not folding case in {}, which is already counted", filename);
+			}
+			if compose.is_some() && !json {
+				println!("Not composing {}, which is already counted", filename);
+			}
+			return corpus(s);
+		}
+		let s = if replacements.is_empty() || ngrams {
+			s
+		} else {
+			let (normalized, replaced) = penalty::normalize(&s, &replacements);
+			if replaced > 0 && !json {
+				println!("Replaced {} typographic characters in {}", replaced, filename);
+			}
+			normalized
+		};
+		let s = if fold_case { pairs.fold_case(&s) } else { s };
+		match compose {
+			Some(_) if ngrams => {
+				if !json {
+					println!("Not composing {}, whose n-grams are already split", filename);
 				}
-			};
-			_layout = layout::Layout::from_string(&layout_str[..]);
-			&_layout
-		},
+				corpus(s)
+			},
+			Some(ref compose) => corpus(compose.expand(&s)),
+			None => corpus(s),
+		}
+	}).collect();
+
+	// Ranking reads every layout in a directory, skipping those it can't use.
+	let force = matches.opt_present("f");
+	let ranked = if command == "rank" {
+		read_layout_dir(layout_filenames.remove(0), board, &pairs, force)
+	} else {
+		Vec::new()
 	};
 
+	// Read layouts, if applicable.
+	let mut layouts: Vec<(&str, layout::Layout)> = layout_filenames.iter()
+		.map(|&filename| (filename, read_layout(filename, board, &pairs)))
+		.collect();
+	if let Some((before, after)) = swapped {
+		layouts.push((&matches.free[0][..], before));
+		layouts.push(("swapped", after));
+	}
+	if layouts.is_empty() {
+		let mut layout = layout::INIT_LAYOUT.clone();
+		layout.set_board(board);
+		layout.fill_number_row(&pairs);
+		layouts.push(("INITIAL", layout));
+	}
+
+	// Refuse to score layouts that type a character from two keys or from a
+	// key the keyboard doesn't have.
+	for &(name, ref layout) in layouts.iter() {
+		if matches.opt_present("d") {
+			for (pos, kc) in layout.unshifted_keys() {
+				println!("Note: layout {} has no shifted character for {:?} at position {}", name, kc, pos);
+			}
+		}
+		let mut problems = layout.validate();
+		if matches.opt_present("pairs") {
+			problems.extend(layout.validate_pairs(&pairs));
+		}
+		print_problems(&problems, force);
+		if problems.iter().any(|p| p.is_fatal()) && !force {
+			fail(error::Error::InvalidLayout(name.to_string()));
+		}
+		let missing = compose.as_ref().map_or(Vec::new(), |compose| compose.missing(layout));
+		if !missing.is_empty() && !force {
+			fail(error::Error::ComposeMissing(name.to_string(), missing));
+		}
+	}
+
+	// Only swap keys within their groups, if asked to.
+	if matches.opt_present("groups") {
+		let overrides = match matches.opt_str("groups") {
+			Some(filename) => read_groups(&filename),
+			None => HashMap::new(),
+		};
+		for &mut (_, ref mut layout) in layouts.iter_mut() {
+			layout.set_groups(&overrides);
+		}
+	}
+
+	// Pin keys by kind or by character, if asked to.
+	let only = if matches.opt_present("only-punctuation") {
+		Some(layout::KeyKind::Punctuation)
+	} else if matches.opt_present("only-letters") {
+		Some(layout::KeyKind::Letters)
+	} else {
+		None
+	};
+	let hand = matches.opt_str("hand").map(|arg| parse_hand(&arg));
+	let keep = unescape(&matches.opt_str("keep").unwrap_or(String::new()));
+	for &mut (_, ref mut layout) in layouts.iter_mut() {
+		if let Some(kind) = only {
+			layout.move_only(kind);
+		}
+		if let Some(hand) = hand {
+			layout.move_only_hand(hand);
+		}
+		layout.keep(&keep);
+	}
+	match hand {
+		_ if json => (),
+		Some(layout::Hand::Left)  => println!("Only moving keys on the left hand"),
+		Some(layout::Hand::Right) => println!("Only moving keys on the right hand"),
+		None => (),
+	}
+	let layout = &layouts[0].1;
+
+	// The layouts that run-ref compares: those named by --refs or all built-in
+	// layouts, and any given as arguments. Counting keeps the characters of all
+	// of them, so the counts suit any layout that might be scored later.
+	let references: Vec<(String, layout::Layout)> = if command == "run-ref" || command == "count" {
+		let mut references: Vec<(String, layout::Layout)> = match matches.opt_str("refs") {
+			Some(refs) => refs.split(',').map(|name| name.trim()).filter(|name| !name.is_empty()).map(|name| {
+				match layout::reference_layout(name) {
+					Some(layout) => (name.to_uppercase(), builtin_layout(layout, board, &pairs)),
+					None => {
+						let layout = read_layout(name, board, &pairs);
+						let problems = layout.validate();
+						print_problems(&problems, force);
+						if problems.iter().any(|p| p.is_fatal()) && !force {
+							fail(error::Error::InvalidLayout(name.to_string()));
+						}
+						(name.to_string(), layout)
+					},
+				}
+			}).collect(),
+			None => layout::REFERENCE_LAYOUTS.iter().map(|&(name, l)| (name.to_string(), builtin_layout(l, board, &pairs))).collect(),
+		};
+		if !layout_filenames.is_empty() {
+			references.extend(layouts.iter().map(|&(name, ref l)| (name.to_string(), l.clone())));
+		}
+		references
+	} else {
+		Vec::new()
+	};
+
+	// Keep characters where the constraints say, warning about layouts that
+	// already break them.
+	let constraints = match matches.opt_str("constraints") {
+		Some(filename) => read_constraints(&filename),
+		None => Vec::new(),
+	};
+	for &(name, ref layout) in layouts.iter() {
+		let pos_map = layout.get_position_map();
+		for constraint in constraints.iter() {
+			let chars: String = constraint.violations(&pos_map).into_iter().collect();
+			if !chars.is_empty() {
+				eprintln!("Warning: layout {} breaks constraint on line {} with {:?}", name, constraint.line, chars);
+			}
+		}
+	}
+
 	// Parse options.
 	let debug = matches.opt_present("d");
-	let top   = numopt(matches.opt_str("t"), 1usize);
-	let swaps = numopt(matches.opt_str("s"), 3usize);
+	let top   = settings.top;
+	let keys  = settings.keys;
+	let runs = settings.runs;
+	let search = simulator::SearchOptions {
+		strategy:       settings.strategy,
+		max_failures:   settings.max_failures,
+		reheat:         settings.reheat,
+		patience:       settings.patience,
+		deadline:       settings.max_seconds.map(|s| Instant::now() + Duration::from_secs_f64(s)),
+		chains:         settings.chains,
+		exchange_every: settings.exchange_every,
+		top_layouts:    top,
+		min_distance:   settings.min_distance,
+		num_swaps:      settings.swaps,
+		distribution:   settings.distribution,
+		refine_swaps:   settings.refine_swaps.unwrap_or(settings.swaps),
+		threads:        settings.threads,
+		constraints:    &constraints,
+	};
+	let mut traces = Vec::new();
+	if let Some(path) = matches.opt_str("trace") {
+		match trace::Trace::create(&path) {
+			Ok(trace) => traces.push(trace),
+			Err(e) => fail(error::Error::WriteTrace(path.clone(), e)),
+		}
+	}
+	if matches.opt_present("progress-json") {
+		let path = matches.opt_str("progress-json");
+		match trace::Trace::events(path.as_ref().map(|p| &p[..]), settings.progress_every) {
+			Ok(trace) => traces.push(trace),
+			Err(e) => fail(error::Error::WriteTrace(path.unwrap_or(String::new()), e)),
+		}
+	}
 
+	// Checkpoints save the state of a single annealing chain, and record what
+	// a resumed run must repeat: the corpora and the options.
+	let resume_path = matches.opt_str("resume");
+	let checkpoint_path = matches.opt_str("checkpoint").or(resume_path.clone());
+	if checkpoint_path.is_some() {
+		let given = if resume_path.is_some() { "resume" } else { "checkpoint" };
+		if search.chains > 0 {
+			fail(error::Error::OptionConflict(given, "tempering"));
+		}
+		if runs > 0 {
+			fail(error::Error::OptionConflict(given, "runs"));
+		}
+	}
+	let mut corpus_hasher = hash::FnvHasher::default();
+	for corpus in corpora.iter() {
+		corpus_hasher.write(corpus.text.as_bytes());
+		corpus_hasher.write_u64(corpus.weight.to_bits());
+		corpus_hasher.write_u8(corpus.ngrams as u8);
+	}
+	let (options_hash, corpus_hash) = (cli::search_hash(&matches, cmd), corpus_hasher.finish());
+	let checkpoint = checkpoint_path.map(|path| checkpoint::Checkpoint::new(&path, settings.checkpoint_every, options_hash, corpus_hash));
+	let resume = resume_path.map(|path| {
+		let saved = match read_file(&path) {
+			Ok(s) => match checkpoint::parse_checkpoint(&s, board) {
+				Ok(saved) => saved,
+				Err(e) => fail(error::Error::ParseCheckpoint(path.clone(), e)),
+			},
+			Err(e) => fail(error::Error::ReadCheckpoint(path.clone(), e)),
+		};
+		if saved.corpus != corpus_hash && !force {
+			fail(error::Error::CheckpointMismatch(path.clone(), "corpora"));
+		}
+		if saved.options != options_hash && !force {
+			fail(error::Error::CheckpointMismatch(path.clone(), "options"));
+		}
+		let iteration = saved.state.as_ref().map_or(0, |state| state.iteration);
+		if !json {
+			println!("Resuming from {}: round {}, iteration {}", path, saved.round, iteration);
+		}
+		saved
+	});
+	if let Some(seed) = settings.seed {
+		rng::seed(seed);
+	}
+	let space = model.space;
+	let reset = unescape(&matches.opt_str("reset-on").unwrap_or(String::new()));
+	if !reset.is_empty() && !json {
+		println!("Resetting context on: {:?}", reset);
+	}
+
+	// Prepare the corpora for scoring, weighting them against each other if
+	// there are several. Only characters on the layouts being scored are kept.
+	let prep_pos_map = if command == "run-ref" || command == "count" {
+		if debug {
+			println!("Preparing corpora with the characters of all reference layouts");
+		}
+		references.iter()
+			.map(|(_, l)| l.get_position_map())
+			.fold(layout.get_position_map(), |map, other| map.union(&other))
+	} else {
+		if debug {
+			let names: Vec<&str> = layouts.iter().map(|&(name, _)| name).collect();
+			println!("Preparing corpora with the characters of layout {}", names.join(" and "));
+		}
+		layouts.iter()
+			.map(|(_, l)| l.get_position_map())
+			.chain(ranked.iter().map(|(_, l)| l.get_position_map()))
+			.fold(layout.get_position_map(), |map, other| map.union(&other))
+	};
+	let strict = settings.strict_coverage;
+	let finger_load = matches.opt_str("finger-load").map(|s| match penalty::parse_finger_load(&s) {
+		Some(load) => load,
+		None => fail(error::Error::FingerLoad(s.clone())),
+	}).or_else(|| weights.as_ref().and_then(|weights| weights.finger_load));
+	if let Some(load) = finger_load {
+		if !json {
+			println!("Finger load target: {:?}", load);
+		}
+		model.set_finger_load(load);
+	}
+	let hand_balance = model.hand_balance;
+	if hand_balance != 0.5 && !json {
+		println!("Hand balance target: {:.1}% left, {:.1}% right", hand_balance * 100.0, (1.0 - hand_balance) * 100.0);
+	}
+	let prepare_start = Instant::now();
+	let mut coverages = Vec::new();
+	let mut lists: Vec<(penalty::QuartadList, usize, f64)> = corpora.iter().map(|&Corpus { filename, text: ref corpus, weight, len, ngrams }| {
+		// Counts saved by the count command are already prepared.
+		if filename.ends_with(".qd") {
+			let (quartads, len) = match penalty::parse_quartad_counts(&corpus[..], &prep_pos_map, &model, &reset) {
+				Ok(q) => q,
+				Err(e) => fail(error::Error::ParseCounts(filename.to_string(), e)),
+			};
+			if quartads.context() != context {
+				fail(error::Error::CountsContext(filename.to_string(), quartads.context(), context));
+			}
+			if matches.opt_present("penalty-runs") && !json {
+				println!("Not scoring long runs in {}, which has no text", filename);
+			}
+			return (quartads, len, weight);
+		}
+		let (mut quartads, len, coverage) = if ngrams {
+			match penalty::parse_ngram_list(&corpus[..], &prep_pos_map, &model, &reset, context) {
+				Ok(q) => q,
+				Err(e) => fail(error::Error::ParseNgrams(filename.to_string(), e)),
+			}
+		} else {
+			let (quartads, coverage) = penalty::prepare_quartad_list_with_context(&corpus[..], &prep_pos_map, &model, &reset, context);
+			(quartads, len, coverage)
+		};
+
+		if !json {
+			print_coverage(filename, &coverage, debug);
+		}
+		if coverage.ratio() < strict {
+			fail(error::Error::Coverage(filename.to_string(), coverage.ratio(), strict));
+		}
+		coverages.push(coverage);
+		if matches.opt_present("penalty-runs") {
+			if ngrams {
+				if !json {
+					println!("Not scoring long runs in {}, which has no text", filename);
+				}
+			} else {
+				quartads.set_long_runs(&corpus[..], &reset);
+			}
+		}
+		(quartads, len, weight)
+	}).collect();
+	let mut combined = if lists.len() == 1 { None } else { Some(penalty::combine_quartad_lists(&lists)) };
+
+	// Give detailed scores as a percentage of the baseline layout's, scored
+	// once here.
+	let baseline_name = matches.opt_str("baseline").unwrap_or("QWERTY".to_string());
+	let baseline = read_layout(&baseline_name, board, &pairs);
+	{
+		let (quartads, len) = match combined {
+			Some((ref mut quartads, len)) => (quartads, len),
+			None => {
+				let len = lists[0].1;
+				(&mut lists[0].0, len)
+			},
+		};
+		let scaled = penalty::calculate_penalty(quartads, len, &baseline, &penalties, false).scaled;
+		quartads.set_baseline(&baseline_name, scaled);
+	}
+	let (quartads, len) = match combined {
+		Some((ref quartads, len)) => (quartads, len),
+		None => (&lists[0].0, lists[0].1),
+	};
+	let prepare_time = prepare_start.elapsed();
+	let names: Vec<&str> = corpora.iter().map(|corpus| corpus.filename).collect();
+
+	// Words and runs on one hand come from the text of the corpora, which saved
+	// quartad counts and n-gram tables no longer have.
+	let texts: Vec<&str> = if matches.opt_present("words") || matches.opt_present("hand-runs") {
+		corpora.iter().filter_map(|corpus| {
+			if corpus.filename.ends_with(".qd") || corpus.ngrams {
+				if !json {
+					println!("Not reading the text of {}", corpus.filename);
+				}
+				None
+			} else {
+				Some(&corpus.text[..])
+			}
+		}).collect()
+	} else {
+		Vec::new()
+	};
+	let mut words = hash::FastHashMap::default();
+	if matches.opt_present("words") {
+		for text in texts.iter() {
+			penalty::count_words(text, &mut words);
+		}
+	}
+	let run_texts = if matches.opt_present("hand-runs") { &texts[..] } else { &[] };
+
+	// Show progress on a terminal, unless debugging or JSON output would get
+	// in its way.
+	let output = Output {
+		debug,
+		keys,
+		hand_stats: hand.is_some(),
+		progress:   io::stdout().is_terminal() && !debug && !json,
+		json,
+		file:       matches.opt_str("o"),
+	};
+	let mut session = Session { rounds: settings.rounds, traces: &mut traces, checkpoint, resume };
 	match command.as_ref() {
-		"run" => run(&corpus[..], layout, debug, top, swaps),
-		"run-ref" => run_ref(&corpus[..]),
-		"refine" => refine(&corpus[..], layout, debug, top, swaps),
-		_ => print_usage(progname, opts),
+		"run" if runs > 0 => batch(quartads, len, layout, &penalties, &search, runs, &output),
+		"run" => { run(quartads, len, layout, &penalties, &search, &mut session, &output); },
+		"run-ref" => {
+			let extras = Extras { keys, heatmap: matches.opt_present("heatmap"), words: &words, run_texts, space, reset: &reset };
+			run_ref(quartads, len, &names, &lists, &references, &penalties, &extras);
+			if matches.opt_present("watch") {
+				watch(quartads, len, layout_filenames[0], layout, &penalties, &pairs, force);
+			}
+		},
+		"refine" => { refine(quartads, len, layout, &penalties, &search, &output); },
+		"optimize" => optimize(quartads, len, layout, &penalties, &search, &mut session, &output),
+		"swap" => compare_swapped(quartads, len, &layouts[0].1, &layouts[1].1, &penalties),
+		"compare" => compare(quartads, len, &layouts[0], &layouts[1], &penalties),
+		"rank" => rank(quartads, len, &ranked, &penalties, json),
+		"suggest" => suggest(quartads, len, layout, &penalties, &constraints, top),
+		"check-symmetry" => check_symmetry(quartads, len, layout, &penalties),
+		"corpus-stats" => corpus_stats(quartads, len, &coverages, layouts[0].0, json),
+		"bench" => bench(quartads, len, layout, &penalties, prepare_time, corpora.iter().map(|c| c.text.len()).sum(), json),
+		"tui" => edit(quartads, len, layout, &penalties, layout_filenames[0], matches.opt_str("o")),
+		"render" => render(quartads, len, layout, &penalties, &model.geometry, &matches.opt_str("o").unwrap()),
+		"count" => count(quartads, len, &reset, &matches.opt_str("o").unwrap()),
+		_ => print_usage(progname, cmd),
 	};
 }
 
-fn run(s: &str, layout: &layout::Layout, debug: bool, top: usize, swaps: usize)
+fn run(quartads: &penalty::QuartadList, len: usize, layout: &layout::Layout, penalties: &Vec<penalty::KeyPenalty>, search: &simulator::SearchOptions, session: &mut Session, output: &Output)
+-> Vec<simulator::BestLayoutsEntry>
 {
-	let penalties = penalty::init();
-	let init_pos_map = layout::INIT_LAYOUT.get_position_map();
-	let quartads = penalty::prepare_quartad_list(s, &init_pos_map);
-	let len = s.len();
+	let (rounds, deadline, chains, strategy) = (session.rounds, search.deadline, search.chains, search.strategy);
+	let (debug, keys) = (output.debug, output.keys);
+	let traces = &mut *session.traces;
+	let checkpoint = &mut session.checkpoint;
+
+	// Keep the best layouts across rounds, so a bad round can't make us forget
+	// a good layout from an earlier one.
+	let (first_round, mut total_iterations, mut best_layouts, mut state) = match session.resume.take() {
+		Some(saved) => (saved.round, saved.iterations, saved.best, saved.state),
+		None => (1, 0, Vec::new(), None),
+	};
+	let scheduled = annealing::get_simulation_range().len();
+	let known_length = chains > 0 || strategy == simulator::Strategy::Anneal;
+	for round in first_round.. {
+		if let Some(ref mut checkpoint) = *checkpoint {
+			checkpoint.round = round;
+			checkpoint.iterations = total_iterations;
+		}
+		let progress = Mutex::new(if output.progress {
+			Some(progress::Progress::new(round, if known_length { Some(scheduled) } else { None }))
+		} else {
+			None
+		});
+		let report = |event: simulator::Event| match event {
+			simulator::Event::Progress { iteration, temperature, penalty, best } => {
+				if let Some(ref mut progress) = *progress.lock().unwrap() {
+					progress.update(iteration, temperature, penalty, best);
+				}
+			},
+			event => if debug { print_event(&event) },
+		};
+		for trace in traces.iter_mut() {
+			trace.start_round(round, if known_length { Some(scheduled) } else { None });
+		}
+		if debug {
+			println!("Initial layout:");
+			print_result(layout, &penalty::calculate_penalty(quartads, len, layout, penalties, true), keys, None);
+		}
+		let sinks = simulator::Sinks { best_layouts: &mut best_layouts, traces, checkpoint: checkpoint.as_mut(), report: &report };
+		let (iterations, stop) = if chains > 0 {
+			simulator::temper(quartads, len, layout, penalties, search, sinks)
+		} else {
+			simulator::simulate(quartads, len, layout, penalties, search, state.take(), sinks)
+		};
+		if let Some(ref mut progress) = *progress.lock().unwrap() {
+			progress.clear();
+		}
+		total_iterations += iterations;
+		if known_length && stop != simulator::Stop::Finished {
+			println!("Stopped early after {} of {} iterations: {}", iterations, scheduled, stop);
+		}
+		for entry in best_layouts.iter() {
+			let penalty = penalty::calculate_penalty(quartads, len, &entry.layout, penalties, true);
+			println!();
+			print_result(&entry.layout, &penalty, keys, Some(layout));
+			print_history(&entry.history);
+			if output.hand_stats {
+				print_hand_balance(&penalty);
+			}
+		}
+
+		// Write out each round's trace as it finishes, since run is usually
+		// interrupted rather than left to end.
+		let results: Vec<(String, f64)> = best_layouts.iter().map(|entry| (entry.layout.to_string(), entry.penalty)).collect();
+		for trace in traces.iter_mut() {
+			trace.finish_round(iterations, &stop.to_string(), &results);
+			if let Err(e) = trace.flush() {
+				fail(error::Error::WriteTrace(trace.path.clone(), e));
+			}
+		}
 
+		// A round cut short by the deadline has saved where it stopped;
+		// otherwise the next round starts afresh.
+		if let Some(ref mut checkpoint) = *checkpoint {
+			if stop != simulator::Stop::Deadline {
+				checkpoint.round = round + 1;
+				checkpoint.iterations = total_iterations;
+				checkpoint.save(None, &best_layouts);
+			}
+			if let Err(e) = checkpoint.status() {
+				fail(error::Error::WriteCheckpoint(checkpoint.path.clone(), e));
+			}
+		}
+
+		// Steepest descent would only repeat itself, and another round can't
+		// start once time is up.
+		let out_of_time = deadline.is_some_and(|d| Instant::now() >= d);
+		let reason = if out_of_time {
+			simulator::Stop::Deadline.to_string()
+		} else if rounds > 0 && round >= rounds {
+			format!("ran {} rounds", rounds)
+		} else if chains == 0 && strategy == simulator::Strategy::SteepestDescent {
+			stop.to_string()
+		} else {
+			continue;
+		};
+		println!();
+		println!("Stopped after {} iterations over {} rounds: {}", total_iterations, round, reason);
+		for trace in traces.iter_mut() {
+			trace.finish(round, total_iterations, &reason);
+			if let Err(e) = trace.flush() {
+				fail(error::Error::WriteTrace(trace.path.clone(), e));
+			}
+		}
+		break;
+	}
+	best_layouts
+}
+
+// Anneals, then refines the best layout found, for a single round unless
+// --rounds says otherwise.
+fn optimize(quartads: &penalty::QuartadList, len: usize, layout: &layout::Layout, penalties: &Vec<penalty::KeyPenalty>, search: &simulator::SearchOptions, session: &mut Session, output: &Output)
+{
+	println!("Annealing:");
+	session.rounds = cmp::max(session.rounds, 1);
+	let annealed = run(quartads, len, layout, penalties, search, session, output);
+	let annealed = match annealed.into_iter().next() {
+		Some(best) => best.layout,
+		None => layout.clone(),
+	};
+
+	println!();
+	println!("Refining:");
+	let refined = refine(quartads, len, &annealed, penalties, search, output);
+	let penalty = penalty::calculate_penalty(quartads, len, &refined, penalties, false);
+
+	let annealed_penalty = penalty::calculate_penalty(quartads, len, &annealed, penalties, false).scaled;
+	println!();
+	println!("After annealing: {}", annealed_penalty);
+	println!("After refining:  {} ({:+})", penalty.scaled, penalty.scaled - annealed_penalty);
+	if let Some(ref filename) = output.file {
+		if let Err(e) = fs::write(filename, format!("{}\n", refined)) {
+			fail(error::Error::WriteLayout(filename.clone(), e));
+		}
+		println!("Wrote {}", filename);
+	}
+}
+
+// Runs independent optimisations from the same layout, each seeded from the
+// main generator so that --seed repeats the batch, and summarises how their
+// best penalties vary.
+fn batch(quartads: &penalty::QuartadList, len: usize, layout: &layout::Layout, penalties: &Vec<penalty::KeyPenalty>, search: &simulator::SearchOptions, runs: usize, output: &Output)
+{
+	// Each run keeps only its best layout.
+	let search = &simulator::SearchOptions { top_layouts: 1, min_distance: 1, ..*search };
+	let threads = search.threads;
+	let seeds: Vec<u64> = (0..runs).map(|_| rng::random()).collect();
+	let seeds = &seeds;
+
+	// Thread t takes runs t, t + threads, and so on.
+	let mut results: Vec<(usize, u64, simulator::BestLayoutsEntry, usize, simulator::Stop)> = thread::scope(|scope| {
+		let handles: Vec<_> = (0..threads.min(runs)).map(|t| {
+			scope.spawn(move || {
+				(t..runs).step_by(threads).filter_map(|r| {
+					rng::seed(seeds[r]);
+					let mut best_layouts = Vec::new();
+					let sinks = simulator::Sinks { best_layouts: &mut best_layouts, traces: &mut [], checkpoint: None, report: &|_| () };
+					let (iterations, stop) = if search.chains > 0 {
+						simulator::temper(quartads, len, layout, penalties, search, sinks)
+					} else {
+						simulator::simulate(quartads, len, layout, penalties, search, None, sinks)
+					};
+					best_layouts.into_iter().next().map(|best| (r + 1, seeds[r], best, iterations, stop))
+				}).collect::<Vec<_>>()
+			})
+		}).collect();
+		handles.into_iter().flat_map(|h| h.join().unwrap()).collect()
+	});
+	results.sort_by_key(|r| r.0);
+	// Runs that kept no layout, e.g. when every swap broke a constraint.
+	let empty = runs - results.len();
+
+	let mut penalties_found: Vec<f64> = results.iter().map(|r| r.2.penalty).collect();
+	penalties_found.sort_by(|a, b| a.partial_cmp(b).unwrap_or(Ordering::Equal));
+	let (min, max) = (penalties_found.first().cloned().unwrap_or(0.0), penalties_found.last().cloned().unwrap_or(0.0));
+	let median = match penalties_found.len() {
+		0 => 0.0,
+		n if n % 2 == 0 => (penalties_found[n / 2 - 1] + penalties_found[n / 2]) / 2.0,
+		n => penalties_found[n / 2],
+	};
+	let mean = penalties_found.iter().sum::<f64>() / penalties_found.len().max(1) as f64;
+	let best = results.iter().min_by(|a, b| a.2.penalty.partial_cmp(&b.2.penalty).unwrap_or(Ordering::Equal));
+
+	let (baseline_name, baseline) = quartads.baseline().unwrap_or(("", 0.0));
+	let of_baseline = |scaled: f64| scaled / baseline * 100.0;
+
+	if output.json {
+		let rows: Vec<String> = results.iter().map(|&(run, seed, ref entry, iterations, stop)| {
+			format!("{{\"run\": {}, \"seed\": {}, \"scaled\": {}, \"percent_of_baseline\": {}, \"iterations\": {}, \"stop\": {}, \"layout\": {}}}",
+				run, seed, json::number(entry.penalty), json::number(of_baseline(entry.penalty)), iterations, json::string(&stop.to_string()), json::string(&entry.layout.to_string()))
+		}).collect();
+		println!("{{\"runs\": [{}], \"empty\": {}, \"min\": {}, \"median\": {}, \"mean\": {}, \"max\": {}, \"best\": {}}}",
+			rows.join(", "), empty, json::number(min), json::number(median), json::number(mean), json::number(max),
+			best.map_or(String::from("null"), |b| b.0.to_string()));
+		return;
+	}
+
+	for &(run, seed, ref entry, iterations, stop) in results.iter() {
+		println!("Run {} (seed {}): scaled {} ({:.1}% of {}) after {} iterations, {}", run, seed, entry.penalty, of_baseline(entry.penalty), baseline_name, iterations, stop);
+	}
+	println!();
+	if empty > 0 {
+		println!("{} of {} runs found no layout.", empty, runs);
+	}
+	println!("Best scaled penalty over {} runs: min {}, median {}, mean {}, max {}", results.len(), min, median, mean, max);
+	if let Some(&(run, _, ref entry, _, _)) = best {
+		let penalty = penalty::calculate_penalty(quartads, len, &entry.layout, penalties, true);
+		println!();
+		println!("Best layout, from run {}:", run);
+		print_result(&entry.layout, &penalty, output.keys, Some(layout));
+	}
+}
+
+fn run_ref(quartads: &penalty::QuartadList, len: usize, names: &[&str], lists: &[(penalty::QuartadList, usize, f64)], references: &[(String, layout::Layout)], penalties: &Vec<penalty::KeyPenalty>, extras: &Extras)
+{
+	let Extras { keys, heatmap, words, run_texts, space, reset } = *extras;
+	// Score each layout on its own thread, along with the trade-offs between
+	// corpora.
+	let mut results: Vec<(&str, &layout::Layout, _, Vec<f64>)> = thread::scope(|scope| {
+		let handles: Vec<_> = references.iter().map(|(name, layout)| {
+			scope.spawn(move || {
+				let penalty = penalty::calculate_penalty(quartads, len, layout, penalties, true);
+				let by_corpus: Vec<f64> = if lists.len() > 1 {
+					lists.iter().map(|&(ref quartads, len, _)| penalty::calculate_penalty(quartads, len, layout, penalties, false).scaled).collect()
+				} else {
+					Vec::new()
+				};
+				(&name[..], layout, penalty, by_corpus)
+			})
+		}).collect();
+		handles.into_iter().map(|h| h.join().unwrap()).collect()
+	});
+	results.sort_by(|a, b| a.2.scaled.partial_cmp(&b.2.scaled).unwrap_or(Ordering::Equal));
+
+	for (i, &(name, layout, ref penalty, ref by_corpus)) in results.iter().enumerate() {
+		if i > 0 {
+			println!();
+		}
+		println!("Reference: {}", name);
+		if cfg!(debug_assertions) {
+			print_problems(&layout.validate(), false);
+		}
+		print_result(layout, penalty, keys, None);
+		for (name, (&(_, _, weight), scaled)) in names.iter().zip(lists.iter().zip(by_corpus.iter())) {
+			println!("{} (weight {}): scaled: {}", name, weight, scaled);
+		}
+		if heatmap {
+			print_heatmap(layout, penalty);
+		}
+		if !words.is_empty() {
+			print_words(&penalty::penalty_by_word(words, quartads, layout, penalties));
+		}
+		if !run_texts.is_empty() {
+			let position_map = quartads.position_map(layout);
+			let mut runs = penalty::HandRuns::new();
+			for text in run_texts.iter() {
+				penalty::count_hand_runs(text, &position_map, space, reset, &mut runs);
+			}
+			print_hand_runs(&runs);
+		}
+	}
+}
+
+// Scores a layout file again whenever its modification time changes, until
+// interrupted, printing how the score moved since the last version. The corpus
+// was only prepared with the characters of the layouts given at the start.
+fn watch(quartads: &penalty::QuartadList, len: usize, filename: &str, layout: &layout::Layout, penalties: &Vec<penalty::KeyPenalty>, pairs: &layout::ShiftPairs, force: bool)
+{
+	let board = *layout.board();
+	let modified = || fs::metadata(filename).and_then(|m| m.modified()).ok();
+	let chars = |layout: &layout::Layout| -> Vec<char> {
+		(0..layout::NUM_KEYS).flat_map(|pos| { let (lower, upper) = layout.keys_at(pos); vec![lower, upper] }).filter(|&c| c != '\0').collect()
+	};
+	let known = chars(layout);
+	let mut last_modified = modified();
+	let mut last = penalty::calculate_penalty(quartads, len, layout, penalties, true);
+	println!();
+	println!("Watching {} for changes", filename);
 	loop {
-		simulator::simulate(&quartads, len, layout, &penalties, debug, top, swaps);
+		thread::sleep(WATCH_INTERVAL);
+		let now = modified();
+		if now == last_modified {
+			continue;
+		}
+		last_modified = now;
+
+		let layout_str = match read_file(filename) {
+			Ok(s) => s,
+			Err(e) => {
+				eprintln!("Error: {}", error::Error::ReadLayout(filename.to_string(), e));
+				continue;
+			},
+		};
+		let layout = match layout::Layout::from_string_with_pairs(&layout_str[..], board, pairs) {
+			Ok(l) => l,
+			Err(e) => {
+				eprintln!("Error: {}", error::Error::ParseLayout(filename.to_string(), e));
+				continue;
+			},
+		};
+		let problems = layout.validate();
+		print_problems(&problems, force);
+		if problems.iter().any(|p| p.is_fatal()) && !force {
+			continue;
+		}
+		let new_chars: String = chars(&layout).into_iter().filter(|c| !known.contains(c)).collect();
+		if !new_chars.is_empty() {
+			println!("Note: {:?} weren't on the layout when the corpus was read, so aren't scored", new_chars);
+		}
+
+		let penalty = penalty::calculate_penalty(quartads, len, &layout, penalties, true);
+		println!();
+		print_delta(&last, &penalty);
+		last = penalty;
 	}
 }
 
-fn run_ref(s: &str)
+// The change in total and scaled penalty, and the three penalties that changed
+// the most.
+fn print_delta(before: &penalty::PenaltyResult, after: &penalty::PenaltyResult)
 {
-	let penalties = penalty::init();
-	let init_pos_map = layout::INIT_LAYOUT.get_position_map();
-	let quartads = penalty::prepare_quartad_list(s, &init_pos_map);
-	let len = s.len();
+	println!("total: {} ({:+}); scaled: {} ({:+})", after.total, after.total - before.total, after.scaled, after.scaled - before.scaled);
+	let mut changes: Vec<(&str, f64, f64)> = after.breakdown.iter().map(|result| {
+		let was = before.breakdown.iter().find(|b| b.name == result.name).map_or(0.0, |b| b.total);
+		(result.name, result.total, result.total - was)
+	}).filter(|&(_, _, change)| change != 0.0).collect();
+	changes.sort_by(|a, b| b.2.abs().partial_cmp(&a.2.abs()).unwrap_or(Ordering::Equal));
+	for &(name, total, change) in changes.iter().take(3) {
+		println!("  {}: {} ({:+})", name, total, change);
+	}
+}
 
-	let penalty = penalty::calculate_penalty(&quartads, len, &layout::QWERTY_LAYOUT, &penalties, true);
-	println!("Reference: QWERTY");
-	simulator::print_result(&layout::QWERTY_LAYOUT, &penalty);
-	println!("");
+// Swaps keys by hand on a raw terminal, scoring the layout after each change.
+// Saving writes to `output`, or back to the layout file if it is one.
+fn edit(quartads: &penalty::QuartadList, len: usize, layout: &layout::Layout, penalties: &Vec<penalty::KeyPenalty>, layout_name: &str, output: Option<String>)
+{
+	let output = output.or_else(|| if Path::new(layout_name).exists() { Some(layout_name.to_string()) } else { None });
+	let mut session = tui::Session::new(layout);
+	let original = penalty::calculate_penalty(quartads, len, layout, penalties, true);
+	let mut previous = original.clone();
+	let mut score = original.clone();
+	let mut status = String::new();
 
-	let penalty = penalty::calculate_penalty(&quartads, len, &layout::DVORAK_LAYOUT, &penalties, true);
-	println!("Reference: DVORAK");
-	simulator::print_result(&layout::DVORAK_LAYOUT, &penalty);
-	println!("");
+	let raw = match tui::RawMode::enable() {
+		Ok(raw) => raw,
+		Err(e) => fail(error::Error::Terminal(e)),
+	};
+	loop {
+		print!("{}", tui::render(&session, &score, &previous, &original, &status));
+		let _ = io::stdout().flush();
+		let keys = match tui::read_keys() {
+			Ok(keys) => keys,
+			Err(_) => vec![tui::Key::Quit],
+		};
+		for key in keys {
+			status.clear();
+			match key {
+				tui::Key::Quit => {
+					drop(raw);
+					println!("{}", session.layout);
+					return;
+				},
+				tui::Key::Save => status = match output {
+					Some(ref filename) => match fs::write(filename, format!("{}\n", session.layout)) {
+						Ok(()) => format!("Wrote {}", filename),
+						Err(e) => format!("Error: {}", error::Error::WriteLayout(filename.clone(), e)),
+					},
+					None => format!("{} is a built-in layout; give -o FILE to save", layout_name),
+				},
+				key => if session.press(key) {
+					previous = score;
+					score = penalty::calculate_penalty(quartads, len, &session.layout, penalties, true);
+				},
+			}
+		}
+	}
+}
 
-	let penalty = penalty::calculate_penalty(&quartads, len, &layout::COLEMAK_LAYOUT, &penalties, true);
-	println!("Reference: COLEMAK");
-	simulator::print_result(&layout::COLEMAK_LAYOUT, &penalty);
-	println!("");
+// A histogram of the runs on each hand, by length.
+fn print_hand_runs(runs: &penalty::HandRuns)
+{
+	println!("Runs on one hand: average {:.2} left, {:.2} right", runs.mean(layout::Hand::Left), runs.mean(layout::Hand::Right));
+	println!("  length     left    right");
+	for i in 0..cmp::max(runs.left.len(), runs.right.len()) {
+		let count = |runs: &Vec<usize>| runs.get(i).cloned().unwrap_or(0);
+		println!("  {:>6} {:>8} {:>8}", i + 1, count(&runs.left), count(&runs.right));
+	}
+}
 
-	let penalty = penalty::calculate_penalty(&quartads, len, &layout::QGMLWY_LAYOUT, &penalties, true);
-	println!("Reference: QGMLWY");
-	simulator::print_result(&layout::QGMLWY_LAYOUT, &penalty);
-	println!("");
+// The words that cost the most over the corpus and for their length.
+fn print_words(scored: &[penalty::WordPenalty])
+{
+	let mut by_total: Vec<&penalty::WordPenalty> = scored.iter().collect();
+	by_total.sort_by(|a, b| b.total().partial_cmp(&a.total()).unwrap_or(Ordering::Equal).then(a.word.cmp(b.word)));
+	println!("Costliest words:");
+	for word in by_total.iter().take(25) {
+		println!("  {}: {:.1} ({} × {:.2})", word.word, word.total(), word.count, word.penalty);
+	}
 
-	let penalty = penalty::calculate_penalty(&quartads, len, &layout::WORKMAN_LAYOUT, &penalties, true);
-	println!("Reference: WORKMAN");
-	simulator::print_result(&layout::WORKMAN_LAYOUT, &penalty);
-	println!("");
+	let mut by_char = by_total;
+	by_char.sort_by(|a, b| b.per_char().partial_cmp(&a.per_char()).unwrap_or(Ordering::Equal).then(a.word.cmp(b.word)));
+	println!("Costliest words per character:");
+	for word in by_char.iter().take(25) {
+		println!("  {}: {:.2} (count {})", word.word, word.per_char(), word.count);
+	}
+}
+
+fn rank(quartads: &penalty::QuartadList, len: usize, layouts: &[(String, layout::Layout)], penalties: &Vec<penalty::KeyPenalty>, json: bool)
+{
+	let mut scores: Vec<(&str, f64, f64)> = layouts.iter().map(|(name, layout)| {
+		let penalty = penalty::calculate_penalty(quartads, len, layout, penalties, false);
+		(&name[..], penalty.total, penalty.scaled)
+	}).collect();
+	scores.sort_by(|a, b| a.2.partial_cmp(&b.2).unwrap_or(Ordering::Equal));
+	let best = scores.first().map_or(0.0, |s| s.2);
+	// Layouts that tie with the best are 0% worse even when the best scores 0,
+	// which leaves the others infinitely worse, null in JSON.
+	let relative = |scaled: f64| if scaled == best { 0.0 } else { (scaled - best) / best * 100.0 };
+	let (baseline_name, baseline) = quartads.baseline().unwrap_or(("", 0.0));
+	let of_baseline = |scaled: f64| scaled / baseline * 100.0;
 
-	let penalty = penalty::calculate_penalty(&quartads, len, &layout::MALTRON_LAYOUT, &penalties, true);
-	println!("Reference: MALTRON");
-	simulator::print_result(&layout::MALTRON_LAYOUT, &penalty);
-	println!("");
+	if json {
+		let rows: Vec<String> = scores.iter().map(|&(name, total, scaled)| {
+			format!("{{\"layout\": {}, \"total\": {}, \"scaled\": {}, \"relative\": {}, \"percent_of_baseline\": {}}}",
+				json::string(name), json::number(total), json::number(scaled), json::number(relative(scaled)), json::number(of_baseline(scaled)))
+		}).collect();
+		println!("[{}]", rows.join(", "));
+		return;
+	}
 
-	let penalty = penalty::calculate_penalty(&quartads, len, &layout::MTGAP_LAYOUT, &penalties, true);
-	println!("Reference: MTGAP");
-	simulator::print_result(&layout::MTGAP_LAYOUT, &penalty);
-	println!("");
+	let width = scores.iter().map(|s| s.0.len()).max().unwrap_or(0).max(6);
+	let of = format!("of {}", baseline_name);
+	println!("{:>4}  {:<w$}  {:>12}  {:>10}  {:>9}  {:>9}", "rank", "layout", "total", "scaled", "vs best", of, w = width);
+	for (i, &(name, total, scaled)) in scores.iter().enumerate() {
+		println!("{:>4}  {:<w$}  {:>12.2}  {:>10.6}  {:>+8.2}%  {:>8.1}%", i + 1, name, total, scaled, relative(scaled), of_baseline(scaled), w = width);
+	}
+}
 
-	let penalty = penalty::calculate_penalty(&quartads, len, &layout::CAPEWELL_LAYOUT, &penalties, true);
-	println!("Reference: CAPEWELL");
-	simulator::print_result(&layout::CAPEWELL_LAYOUT, &penalty);
-	println!("");
+// Lists how often the characters, bigrams and trigrams of the corpora are
+// typed, from the quartads, along with how much of the text the layout covers.
+// JSON output has every n-gram rather than the most frequent.
+fn corpus_stats(quartads: &penalty::QuartadList, len: usize, coverages: &[penalty::Coverage], layout_name: &str, json: bool)
+{
+	const TOP: usize = 30;
 
-	let penalty = penalty::calculate_penalty(&quartads, len, &layout::ARENSITO_LAYOUT, &penalties, true);
-	println!("Reference: ARENSITO");
-	simulator::print_result(&layout::ARENSITO_LAYOUT, &penalty);
-	println!("");
+	let coverage = penalty::Coverage::sum(coverages);
+	let tables: Vec<_> = ["characters", "bigrams", "trigrams"].iter().enumerate()
+		.map(|(i, &name)| {
+			let table = penalty::ngram_frequencies(quartads, i + 1);
+			let total: f64 = table.iter().map(|&(_, n)| n).sum();
+			(name, table, total)
+		})
+		.collect();
 
-	let penalty = penalty::calculate_penalty(&quartads, len, &layout::INIT_LAYOUT, &penalties, true);
-	println!("Reference: INITIAL");
-	simulator::print_result(&layout::INIT_LAYOUT, &penalty);
+	if json {
+		let tables: Vec<String> = tables.iter().map(|&(name, ref table, total)| {
+			let rows: Vec<String> = table.iter()
+				.map(|&(ngram, count)| format!("{{\"ngram\": {}, \"count\": {}, \"percent\": {}}}", json::string(ngram), json::number(count), json::number(100.0 * count / total)))
+				.collect();
+			format!("\"{}\": [{}]", name, rows.join(", "))
+		}).collect();
+		let skipped: Vec<String> = coverage.skipped_by_frequency().iter()
+			.map(|&(c, n)| format!("{{\"char\": {}, \"count\": {}}}", json::string(&c.to_string()), n))
+			.collect();
+		println!("{{\"layout\": {}, \"length\": {}, \"total\": {}, \"typed\": {}, \"coverage\": {}, \"quartads\": {}, {}, \"skipped\": [{}]}}",
+			json::string(layout_name), len, coverage.total, coverage.consumed(), json::number(coverage.ratio()), quartads.len(), tables.join(", "), skipped.join(", "));
+		return;
+	}
+	if coverages.is_empty() {
+		println!("Total: {} characters, already counted", len);
+	} else {
+		println!("Total: {} characters, {} typed on {} ({:.2}%)", coverage.total, coverage.consumed(), layout_name, coverage.ratio() * 100.0);
+	}
+	println!("Quartads: {}", quartads.len());
+	for &(name, ref table, total) in tables.iter() {
+		if name == "characters" {
+			println!("\nCharacters:");
+		} else {
+			println!("\nTop {} {}:", cmp::min(TOP, table.len()), name);
+		}
+		let shown = if name == "characters" { table.len() } else { TOP };
+		for &(ngram, count) in table.iter().take(shown) {
+			println!("  {:<6} {:>7.3}%  {:.0}", format!("{:?}", ngram), 100.0 * count / total, count);
+		}
+	}
 }
 
-fn refine(s: &str, layout: &layout::Layout, debug: bool, top: usize, swaps: usize)
+// Times the stages of scoring: preparing the corpus, scoring once with and
+// without the detailed breakdown, and then scoring repeatedly for a fixed
+// window, as the optimiser does.
+fn bench(quartads: &penalty::QuartadList, len: usize, layout: &layout::Layout, penalties: &Vec<penalty::KeyPenalty>, prepare_time: Duration, corpus_bytes: usize, json: bool)
 {
-	let penalties = penalty::init();
-	let init_pos_map = layout::INIT_LAYOUT.get_position_map();
-	let quartads = penalty::prepare_quartad_list(s, &init_pos_map);
-	let len = s.len();
+	const WINDOW: Duration = Duration::from_secs(5);
+
+	let start = Instant::now();
+	penalty::calculate_penalty(quartads, len, layout, penalties, true);
+	let detailed_time = start.elapsed();
+	let start = Instant::now();
+	penalty::calculate_penalty(quartads, len, layout, penalties, false);
+	let penalty_time = start.elapsed();
 
-	simulator::refine(&quartads, len, layout, &penalties, debug, top, swaps);
+	let position_map = quartads.position_map(layout);
+	let start = Instant::now();
+	let mut evaluations = 0usize;
+	while start.elapsed() < WINDOW {
+		penalty::calculate_penalty_for_map(quartads, len, &position_map, penalties, false);
+		evaluations += 1;
+	}
+	let window = start.elapsed().as_secs_f64();
+	let rate = evaluations as f64 / window;
+
+	if json {
+		println!("{{\"corpus_bytes\": {}, \"quartads\": {}, \"prepare_seconds\": {}, \"detailed_seconds\": {}, \"penalty_seconds\": {}, \"evaluations\": {}, \"window_seconds\": {}, \"evaluations_per_second\": {}}}",
+			corpus_bytes, quartads.len(), json::number(prepare_time.as_secs_f64()), json::number(detailed_time.as_secs_f64()),
+			json::number(penalty_time.as_secs_f64()), evaluations, json::number(window), json::number(rate));
+		return;
+	}
+	println!("Corpus: {} bytes, {} quartads", corpus_bytes, quartads.len());
+	println!("Preparing quartads: {:.3} ms", prepare_time.as_secs_f64() * 1000.0);
+	println!("Scoring with breakdown: {:.3} ms", detailed_time.as_secs_f64() * 1000.0);
+	println!("Scoring: {:.3} ms", penalty_time.as_secs_f64() * 1000.0);
+	println!("Throughput: {:.1} evaluations per second ({} in {:.1} s)", rate, evaluations, window);
+}
+
+fn render(quartads: &penalty::QuartadList, len: usize, layout: &layout::Layout, penalties: &Vec<penalty::KeyPenalty>, geometry: &Option<layout::Geometry>, filename: &str)
+{
+	let penalty = penalty::calculate_penalty(quartads, len, layout, penalties, true);
+	let picture = svg::render(layout, &penalty.positions.presses, geometry.as_ref());
+	if let Err(e) = fs::write(filename, picture) {
+		fail(error::Error::WriteSvg(filename.to_string(), e));
+	}
+	println!("Wrote {}", filename);
+}
+
+fn count(quartads: &penalty::QuartadList, len: usize, reset: &[char], filename: &str)
+{
+	let (counts, left_out) = penalty::write_quartad_counts(quartads, len, reset);
+	if let Err(e) = fs::write(filename, counts) {
+		fail(error::Error::WriteCounts(filename.to_string(), e));
+	}
+	if left_out > 0 {
+		println!("Left out {} n-grams containing a line break", left_out);
+	}
+	println!("Wrote {}", filename);
+}
+
+fn refine(quartads: &penalty::QuartadList, len: usize, layout: &layout::Layout, penalties: &Vec<penalty::KeyPenalty>, search: &simulator::SearchOptions, output: &Output)
+-> layout::Layout
+{
+	let (debug, keys) = (output.debug, output.keys);
+	println!("Initial layout:");
+	print_result(layout, &penalty::calculate_penalty(quartads, len, layout, penalties, true), keys, None);
+
+	let report = |event: simulator::Event| match event {
+		simulator::Event::Trying { layouts, depth } => {
+			println!();
+			println!("Trying {} layouts within {} swap{}", layouts, depth, if depth == 1 { "" } else { "s" });
+		},
+		simulator::Event::Tried { layouts, seconds } =>
+			println!("Tried {} layouts in {:.1}s ({:.0} per second)", layouts, seconds, layouts as f64 / seconds.max(1e-9)),
+		simulator::Event::Best(entries) => {
+			for entry in entries.iter() {
+				println!();
+				print_result(&entry.layout, &penalty::calculate_penalty(quartads, len, &entry.layout, penalties, true), keys, Some(layout));
+				print_history(&entry.history);
+			}
+		},
+		simulator::Event::Moved { depth, penalty } => {
+			println!();
+			println!("Accepted a layout at depth {} with penalty {}", depth, penalty);
+		},
+		event => if debug { print_event(&event) },
+	};
+	let winner = simulator::refine(quartads, len, layout, penalties, search, &report);
+	println!();
+	println!("Ultimate winner:");
+	println!("{}", winner);
+	if output.hand_stats {
+		print_hand_balance(&penalty::calculate_penalty(quartads, len, &winner, penalties, true));
+	}
+	winner
 }
 
-fn print_usage(progname: &String, opts: Options)
+fn export(format: &str, layout_name: &str, board: layout::Board, pairs: &layout::ShiftPairs, matches: &getopts::Matches, force: bool)
 {
-	let brief = format!("Usage: {} (run|run-ref) <corpus> [OPTIONS]", progname);
-	print!("{}", opts.usage(&brief));
+	let layout = read_layout(layout_name, board, pairs);
+	let mut problems = layout.validate();
+	if matches.opt_present("pairs") {
+		problems.extend(layout.validate_pairs(pairs));
+	}
+	print_problems(&problems, force);
+	if problems.iter().any(|p| p.is_fatal()) && !force {
+		fail(error::Error::InvalidLayout(layout_name.to_string()));
+	}
+
+	let output = match format {
+		"qmk" => {
+			let name = matches.opt_str("template").unwrap_or(String::from("keymap"));
+			let template = match export::QmkTemplate::from_name(&name) {
+				Some(t) => t,
+				None => fail(error::Error::ExportTemplate(name)),
+			};
+			let thumb_key = matches.opt_str("thumb-key").unwrap_or(String::from("KC_NO"));
+			export::qmk(&layout, &template, &thumb_key)
+		},
+		"xkb" => {
+			let name = Path::new(layout_name).file_stem().map_or(layout_name.to_string(), |s| s.to_string_lossy().into_owned());
+			export::xkb(&layout, &name)
+		},
+		"kanata" => export::kanata(&layout),
+		_ => fail(error::Error::ExportFormat(format.to_string())),
+	};
+	match matches.opt_str("o") {
+		Some(filename) => if let Err(e) = fs::write(&filename, output) {
+			fail(error::Error::WriteExport(filename.clone(), e));
+		},
+		None => print!("{}", output),
+	}
+}
+
+fn mirror(layout_name: &str, board: layout::Board, pairs: &layout::ShiftPairs, output: Option<String>)
+{
+	let layout = read_layout(layout_name, board, pairs).mirrored();
+	match output {
+		Some(filename) => if let Err(e) = fs::write(&filename, format!("{}\n", layout)) {
+			fail(error::Error::WriteLayout(filename.clone(), e));
+		},
+		None => println!("{}", layout),
+	}
+}
+
+// Scores the reference layouts on the fixture corpus in `dir`, then either
+// saves the scores there or reports each that differs from those saved.
+fn verify(dir: &str, bless: bool)
+{
+	let path = Path::new(dir);
+	let corpus_name = path.join("corpus.txt").to_string_lossy().into_owned();
+	let scores_name = path.join("scores.tsv").to_string_lossy().into_owned();
+	let corpus = match read_file(&corpus_name) {
+		Ok(s) => s,
+		Err(e) => fail(error::Error::ReadFixtures(corpus_name, e)),
+	};
+	let actual = verify::scores(&corpus);
+	if bless {
+		if let Err(e) = fs::write(&scores_name, verify::format_scores(&actual)) {
+			fail(error::Error::WriteScores(scores_name, e));
+		}
+		println!("Saved {} scores to {}", actual.len(), scores_name);
+		return;
+	}
+
+	let scores_str = match read_file(&scores_name) {
+		Ok(s) => s,
+		Err(e) => fail(error::Error::ReadFixtures(scores_name, e)),
+	};
+	let expected = match verify::parse_scores(&scores_str) {
+		Ok(scores) => scores,
+		Err(e) => fail(error::Error::ParseScores(scores_name, e)),
+	};
+	let changed = verify::differences(&expected, &actual);
+	if !changed.is_empty() {
+		for line in changed.iter() {
+			println!("{}", line);
+		}
+		fail(error::Error::ScoresChanged(changed.len()));
+	}
+	println!("All {} scores match {}", expected.len(), scores_name);
+}
+
+// Writes base penalties measured from key press timings typed on `layout_name`,
+// listing the keys that had none.
+fn calibrate(timings_name: &str, layout_name: &str, effort: &layout::KeyMap<f64>, board: layout::Board, pairs: &layout::ShiftPairs, filename: &str)
+{
+	let layout = read_layout(layout_name, board, pairs);
+	let timings_str = match read_file(timings_name) {
+		Ok(s) => s,
+		Err(e) => fail(error::Error::ReadTimings(timings_name.to_string(), e)),
+	};
+	let timings = match penalty::parse_timings(&timings_str[..]) {
+		Ok(timings) => timings,
+		Err(e) => fail(error::Error::ParseTimings(timings_name.to_string(), e)),
+	};
+	let (calibrated, missing) = penalty::calibrate_effort(&timings, &layout, effort);
+	let grid = penalty::write_effort(&calibrated, board.has_number_row());
+	if let Err(e) = fs::write(filename, &grid) {
+		fail(error::Error::WriteEffort(filename.to_string(), e));
+	}
+	print!("{}", grid);
+	let missing: Vec<String> = missing.iter().filter(|&&pos| board.exists(pos)).map(|&pos| {
+		match layout.keys_at(pos).0 {
+			'\0' => format!("{}", pos),
+			c => format!("{} ({:?})", pos, c),
+		}
+	}).collect();
+	if !missing.is_empty() {
+		println!("Not calibrated, kept at their base penalty: {}", missing.join(", "));
+	}
+}
+
+// Applies swaps like "a:b" or "3:17" in order, printing the result and writing
+// it to `output` if given. Returns the layouts before and after.
+fn swap(layout_name: &str, swaps: &[String], board: layout::Board, pairs: &layout::ShiftPairs, output: Option<String>)
+-> (layout::Layout, layout::Layout)
+{
+	let layout = read_layout(layout_name, board, pairs);
+	let mut swapped = layout.clone();
+	for arg in swaps {
+		let found = layout::parse_swap(arg).and_then(|(a, b)| Ok((swapped.find_key(a)?, swapped.find_key(b)?)));
+		match found {
+			Ok((i, j)) => swapped.swap_keys(i, j),
+			Err(e) => fail(error::Error::Swap(arg.clone(), e)),
+		}
+	}
+	println!("{}", swapped);
+	if let Some(filename) = output {
+		if let Err(e) = fs::write(&filename, format!("{}\n", swapped)) {
+			fail(error::Error::WriteLayout(filename.clone(), e));
+		}
+	}
+	(layout, swapped)
 }
 
-fn numopt<T>(s: Option<String>, default: T)
--> T
-where T: std::str::FromStr + std::fmt::Display
+fn compare_swapped(quartads: &penalty::QuartadList, len: usize, before: &layout::Layout, after: &layout::Layout, penalties: &Vec<penalty::KeyPenalty>)
 {
-	match s {
-		None => default,
-		Some(num) => match num.parse::<T>() {
-			Ok(n) => n,
+	let before = penalty::calculate_penalty(quartads, len, before, penalties, false);
+	let after = penalty::calculate_penalty(quartads, len, after, penalties, false);
+	println!("Before: total: {}; scaled: {}", before.total, before.scaled);
+	println!("After: total: {}; scaled: {} ({:+})", after.total, after.scaled, after.scaled - before.scaled);
+}
+
+fn explain(layout_name: &str, ngram: &str, penalties: &Vec<penalty::KeyPenalty>, model: &penalty::Model, board: layout::Board, pairs: &layout::ShiftPairs, context: usize)
+{
+	let layout = &read_layout(layout_name, board, pairs);
+	let position_map = layout.position_map_for(model.model_shift);
+
+	for c in ngram.chars() {
+		match *position_map.get_key_position(c) {
+			Some(ref kp) => println!("{:?}: {:?} hand, {:?} finger, {:?} row, position {}{}{}",
+				c, kp.hand, kp.finger, kp.row, kp.pos,
+				if kp.center { ", centre column" } else { "" },
+				if kp.shifted { ", shifted" } else { "" }),
+			None => println!("{:?}: not on the layout", c),
+		}
+	}
+
+	let mut total = 0.0;
+	for (quartad, result) in penalty::explain(ngram, layout, penalties, model, context) {
+		println!();
+		println!("{:?}:", quartad);
+		for penalty in result.iter() {
+			println!("  {}", penalty);
+			total += penalty.total;
+		}
+	}
+	println!();
+	println!("total: {}", total);
+}
+
+// Annotates each keystroke of the text with the key that types it and the
+// penalties that apply when it's pressed, then totals each penalty.
+fn type_text(layout_name: &str, text: &str, penalties: &Vec<penalty::KeyPenalty>, model: &penalty::Model, board: layout::Board, pairs: &layout::ShiftPairs, context: usize)
+{
+	let layout = &read_layout(layout_name, board, pairs);
+	let position_map = layout.position_map_for(model.model_shift);
+	let mut totals: Vec<(&str, f64)> = penalties.iter().map(|p| (p.name, 0.0)).collect();
+
+	let explanation = penalty::explain(text, layout, penalties, model, context);
+	for (i, (c, (_, result))) in text.chars().zip(explanation.iter()).enumerate() {
+		let key = match *position_map.get_key_position(c) {
+			Some(ref kp) => format!("{:?} {:?}, {:?} row{}", kp.hand, kp.finger, kp.row, if kp.shifted { ", shifted" } else { "" }),
+			None => "not on the layout".to_string(),
+		};
+		let fired: Vec<String> = result.iter().map(|p| p.to_string()).collect();
+		println!("{:>4} {:<6} {:<28} {}", i + 1, format!("{:?}", c), key, fired.join("; "));
+		for penalty in result.iter() {
+			if let Some(total) = totals.iter_mut().find(|t| t.0 == penalty.name) {
+				total.1 += penalty.total;
+			}
+		}
+	}
+
+	println!();
+	for &(name, total) in totals.iter().filter(|t| t.1 != 0.0) {
+		println!("{}: {}", name, total);
+	}
+	println!("total: {}", totals.iter().map(|t| t.1).sum::<f64>());
+}
+
+fn suggest(quartads: &penalty::QuartadList, len: usize, layout: &layout::Layout, penalties: &Vec<penalty::KeyPenalty>, constraints: &[layout::Constraint], top: usize)
+{
+	let (init_penalty, improvements) = simulator::suggest(quartads, len, layout, penalties, constraints, top);
+
+	println!("Current layout: {}", init_penalty);
+	if improvements.is_empty() {
+		println!("No single swap improves the layout.");
+		return;
+	}
+	for &(penalty, ref improved) in improvements.iter() {
+		let swapped = layout.differing_keys(improved);
+		let keys: Vec<String> = swapped.iter().map(|&(_, c, _)| format!("{:?}", c)).collect();
+		let positions: Vec<String> = swapped.iter().map(|&(pos, _, _)| pos.to_string()).collect();
+		println!("swap {} (positions {}): {} -> {} ({:+.2}%)",
+			keys.join(" <-> "), positions.join(", "), init_penalty, penalty,
+			(penalty - init_penalty) / init_penalty * 100.0);
+	}
+}
+
+// Lists the penalties that differ between a layout and its mirror image, on
+// the n-grams typed without the outer right column or the thumbs.
+fn check_symmetry(quartads: &penalty::QuartadList, len: usize, layout: &layout::Layout, penalties: &Vec<penalty::KeyPenalty>)
+{
+	let asymmetries = penalty::asymmetries(quartads, len, layout, penalties);
+	if asymmetries.is_empty() {
+		println!("Every penalty is the same for the layout and its mirror image.");
+		return;
+	}
+	println!("Penalties (layout -> mirrored):");
+	for asymmetry in asymmetries.iter() {
+		println!("  {}: {} -> {} ({:+})", asymmetry.name, asymmetry.layout, asymmetry.mirrored, asymmetry.mirrored - asymmetry.layout);
+	}
+}
+
+fn compare(quartads: &penalty::QuartadList, len: usize, a: &(&str, layout::Layout), b: &(&str, layout::Layout), penalties: &Vec<penalty::KeyPenalty>)
+{
+	let (name_a, ref layout_a) = *a;
+	let (name_b, ref layout_b) = *b;
+	let penalty_a = penalty::calculate_penalty(quartads, len, layout_a, penalties, true);
+	let penalty_b = penalty::calculate_penalty(quartads, len, layout_b, penalties, true);
+
+	println!("A: {}", name_a);
+	println!("{}", layout_a);
+	println!("B: {}", name_b);
+	println!("{}", layout_b);
+	println!();
+
+	println!("Keys that differ:");
+	for (pos, a, b) in layout_a.differing_keys(layout_b) {
+		println!("  position {}: {:?} -> {:?}", pos, a, b);
+	}
+	println!();
+
+	println!("Penalties (A -> B):");
+	for (a, b) in penalty_a.breakdown.iter().zip(penalty_b.breakdown.iter()) {
+		println!("  {}: {} -> {} ({:+})", a.name, a.total, b.total, b.total - a.total);
+	}
+	println!();
+
+	// Attribute the difference to individual quartads.
+	let by_quartad_a = penalty::penalty_by_quartad(quartads, layout_a, penalties);
+	let by_quartad_b = penalty::penalty_by_quartad(quartads, layout_b, penalties);
+	let mut deltas: Vec<(&str, f64)> = by_quartad_a.iter()
+		.map(|(quartad, a)| (*quartad, by_quartad_b.get(quartad).unwrap_or(&0.0) - a))
+		.filter(|&(_, delta)| delta != 0.0)
+		.collect();
+	deltas.sort_by(|a, b| match b.1.partial_cmp(&a.1) {
+		Some(Ordering::Equal) | None => a.0.cmp(b.0),
+		Some(ord) => ord,
+	});
+	println!("Most increased quartads:");
+	for &(quartad, delta) in deltas.iter().filter(|&&(_, d)| d > 0.0).take(10) {
+		println!("  {:?}: {:+}", quartad, delta);
+	}
+	println!("Most decreased quartads:");
+	for &(quartad, delta) in deltas.iter().rev().filter(|&&(_, d)| d < 0.0).take(10) {
+		println!("  {:?}: {:+}", quartad, delta);
+	}
+	println!();
+
+	println!("scaled: {} -> {} ({:+.2}%)", penalty_a.scaled, penalty_b.scaled, (penalty_b.scaled - penalty_a.scaled) / penalty_a.scaled * 100.0);
+}
+
+// The preset's weights, then those from the weights file, then the options:
+// --hand-balance turns its penalty on, those named by --disable-penalty are
+// turned off and those named by --penalty-weight re-weighted.
+fn configure_penalties(matches: &getopts::Matches, preset: &penalty::Preset, weights: &Option<penalty::Weights>)
+-> Vec<penalty::KeyPenalty<'static>>
+{
+	let mut penalties = penalty::init();
+	for &(kind, weight) in preset.weights {
+		penalties[kind as usize].weight = weight;
+	}
+	if let Some(ref weights) = *weights {
+		for &(kind, weight) in weights.weights.iter() {
+			penalties[kind as usize].weight = weight;
+		}
+	}
+	// A hand balance target turns its penalty on, unless it already has a
+	// weight.
+	let hand_balance = &mut penalties[penalty::PenaltyKind::HandBalance as usize];
+	if matches.opt_present("hand-balance") && hand_balance.weight == 0.0 {
+		hand_balance.weight = penalty::HAND_BALANCE_WEIGHT;
+	}
+	let mut set_weight = |name: &str, weight: f64| {
+		match name.parse::<penalty::PenaltyKind>() {
+			Ok(kind) => penalties[kind as usize].weight = weight,
 			Err(_) => {
-				println!("Error: invalid option value {}. Using default value {}.", num, default);
-				default
+				let names = penalty::PENALTY_KINDS.iter().map(|kind| kind.name().to_string()).collect();
+				fail(error::Error::UnknownPenalty(name.to_string(), names))
 			},
-		},
+		}
+	};
+
+	for name in matches.opt_strs("disable-penalty") {
+		set_weight(&name, 0.0);
+	}
+	for arg in matches.opt_strs("penalty-weight") {
+		let idx = match arg.rfind('=') {
+			Some(idx) => idx,
+			None => fail(error::Error::PenaltyWeight(arg.clone())),
+		};
+		match arg[(idx + 1)..].trim().parse::<f64>() {
+			Ok(weight) => set_weight(arg[..idx].trim(), weight),
+			Err(_) => fail(error::Error::PenaltyWeight(arg.clone())),
+		}
 	}
-}
\ No newline at end of file
+
+	penalties
+}
+
+fn print_weights(penalties: &[penalty::KeyPenalty])
+{
+	let weights: Vec<String> = penalties.iter()
+		.map(|p| format!("{}={}", p.name, p.weight))
+		.collect();
+	println!("Penalty weights: {}", weights.join(", "));
+}
+
+fn print_effort(effort: &layout::KeyMap<f64>, number_row: bool)
+{
+	let row = |keys: &[f64]| keys.iter().map(|x| format!("{:>4}", x)).collect::<Vec<String>>().join(" ");
+	let layout::KeyMap(ref effort) = *effort;
+	if number_row {
+		println!("{} | {}", row(&effort[34..39]), row(&effort[39..44]));
+	}
+	println!("{} | {}", row(&effort[0..5]), row(&effort[5..11]));
+	println!("{} | {}", row(&effort[11..16]), row(&effort[16..22]));
+	println!("{} | {}", row(&effort[22..27]), row(&effort[27..32]));
+	println!("{:>24} | {}", row(&effort[32..33]), row(&effort[33..34]));
+}
+
+fn print_coverage(filename: &str, coverage: &penalty::Coverage, debug: bool)
+{
+	println!("{}: {} characters, {} typed ({:.2}%)",
+		filename, coverage.total, coverage.consumed(), coverage.ratio() * 100.0);
+
+	let skipped = coverage.skipped_by_frequency();
+	if skipped.is_empty() {
+		return;
+	}
+	let shown = if debug { skipped.len() } else { 10 };
+	print!("skipped:");
+	for &(c, n) in skipped.iter().take(shown) {
+		print!(" {:?}: {};", c, n);
+	}
+	if skipped.len() > shown {
+		print!(" ({} more)", skipped.len() - shown);
+	}
+	println!();
+}
+
+// Draws where a layout's keystrokes and penalties fall, in colour if stdout is
+// a terminal.
+fn print_heatmap(layout: &layout::Layout, penalty: &penalty::PenaltyResult)
+{
+	let color = io::stdout().is_terminal();
+	println!("Keystrokes:\n{}", heatmap::render(layout, &penalty.positions.presses, color));
+	println!("Penalty by key:\n{}", heatmap::render(layout, &penalty.positions.penalty, color));
+}
+
+// Prints a layout and its penalties as `simulator::format_result` lays them out.
+fn print_result(layout: &layout::Layout, penalty: &penalty::PenaltyResult, keys: usize, baseline: Option<&layout::Layout>)
+{
+	print!("{}", simulator::format_result(layout, penalty, keys, baseline));
+}
+
+// Prints how a layout was reached, if it took any swaps.
+fn print_history(history: &[(char, char)])
+{
+	if !history.is_empty() {
+		println!("Reached via {} swaps: {}", history.len(), simulator::format_history(history));
+	}
+}
+
+// Prints what a search reports along the way, for --debug.
+fn print_event(event: &simulator::Event)
+{
+	match *event {
+		simulator::Event::Resumed { iteration, penalty } =>
+			println!("Resuming after iteration {} with penalty {}", iteration, penalty),
+		simulator::Event::Accepted { iteration, penalty, swapped } =>
+			println!("Iteration {} accepted with penalty {} after swapping {}", iteration, penalty, simulator::format_history(swapped)),
+		simulator::Event::GaveUp { failures } =>
+			println!("Stopped after {} iterations without improvement", failures),
+		simulator::Event::LostPatience { idle } =>
+			println!("Stopping: neither the accepted penalty nor the best layouts improved in the last {} iterations", idle),
+		simulator::Event::Reheated { iteration, from, to, stale } =>
+			println!("Iteration {}: reheating from schedule step {} to {} after {} iterations without improvement", iteration, from, to, stale),
+		simulator::Event::Acceptance(phases) => print_acceptance(phases),
+		simulator::Event::Chains(temperatures) => println!("Chain temperatures: {:?}", temperatures),
+		simulator::Event::Exchanged { iteration, chains, penalties } =>
+			println!("Iteration {}: exchanged chains {} and {} with penalties {} and {}", iteration, chains.0, chains.1, penalties.0, penalties.1),
+		simulator::Event::Scored { index, layouts, penalty } =>
+			println!("Iteration {}/{}: {}", index, layouts, penalty),
+		_ => (),
+	}
+}
+
+// Prints how many candidates were accepted in each phase of annealing, and how
+// often worse layouts were accepted.
+fn print_acceptance(phases: &[simulator::PhaseStats])
+{
+	let iterations = annealing::get_simulation_range().len();
+	println!();
+	println!("Acceptance by phase:");
+	println!("  {:>13} {:>12} {:>10} {:>10} {:>10} {:>8}", "iterations", "temperature", "improving", "worse", "rejected", "uphill");
+	for (p, phase) in phases.iter().enumerate() {
+		let start = p * iterations / phases.len() + 1;
+		let end = (p + 1) * iterations / phases.len();
+		let uphill = phase.worsening + phase.rejected;
+		let rate = if uphill == 0 { 0.0 } else { phase.worsening as f64 / uphill as f64 * 100.0 };
+		println!("  {:>13} {:>12.6} {:>10} {:>10} {:>10} {:>7.2}%",
+			format!("{}-{}", start, end), annealing::temperature(start),
+			phase.improving, phase.worsening, phase.rejected, rate);
+	}
+}
+
+fn print_hand_balance(penalty: &penalty::PenaltyResult)
+{
+	let (left, right) = penalty.positions.hand_shares();
+	println!("Hand balance: {:.1}% left, {:.1}% right", left * 100.0, right * 100.0);
+}
+
+fn print_problems(problems: &[layout::LayoutProblem], force: bool)
+{
+	for problem in problems {
+		let level = if problem.is_fatal() && !force { "Error" } else { "Warning" };
+		eprintln!("{}: {}", level, problem);
+	}
+}
+
+// Expands \n, \t, \r and \\ so that control characters can be passed on the
+// command line.
+fn unescape(s: &str)
+-> Vec<char>
+{
+	let mut chars = Vec::new();
+	let mut iter = s.chars();
+	while let Some(c) = iter.next() {
+		if c != '\\' {
+			chars.push(c);
+			continue;
+		}
+		match iter.next() {
+			Some('n') => chars.push('\n'),
+			Some('t') => chars.push('\t'),
+			Some('r') => chars.push('\r'),
+			Some(c) => chars.push(c),
+			None => chars.push('\\'),
+		}
+	}
+	chars
+}
+
+fn parse_hand(arg: &str)
+-> layout::Hand
+{
+	match arg {
+		"left"  => layout::Hand::Left,
+		"right" => layout::Hand::Right,
+		_ => fail(error::Error::Hand(arg.to_string())),
+	}
+}
+
+// Reads CHAR=TEXT, where the character may be '=' itself and the text may be
+// empty.
+fn parse_replacement(arg: &str)
+-> Option<(char, String)>
+{
+	let mut chars = arg.chars();
+	let c = chars.next()?;
+	let rest = chars.as_str();
+	rest.strip_prefix('=').map(|rest| (c, rest.to_string()))
+}
+
+// Splits FILE:WEIGHT into its parts, if the argument has a weight.
+fn split_weight(arg: &str)
+-> Option<(&str, f64)>
+{
+	let idx = arg.rfind(':')?;
+	match arg[(idx + 1)..].parse::<f64>() {
+		Ok(weight) if weight > 0.0 => Some((&arg[..idx], weight)),
+		_ => None,
+	}
+}
+
+// Reads a layout file, or keyboard-layout-editor JSON if its name ends in
+// .json, or finds a built-in layout by name if there's no such file. On a
+// board with a number row, the digits fill whatever of it the layout leaves
+// empty.
+fn read_layout(filename: &str, board: layout::Board, pairs: &layout::ShiftPairs)
+-> layout::Layout
+{
+	if !Path::new(filename).exists() {
+		if let Some(layout) = layout::reference_layout(filename) {
+			return builtin_layout(layout, board, pairs);
+		}
+	}
+	let layout_str = match read_file(filename) {
+		Ok(s) => s,
+		Err(e) => fail(error::Error::ReadLayout(filename.to_string(), e)),
+	};
+	if filename.ends_with(".json") {
+		let mut layout = match layout::Layout::from_kle_json(&layout_str[..], board, pairs) {
+			Ok((l, warnings)) => {
+				for warning in warnings {
+					eprintln!("Warning: {}: {}", filename, warning);
+				}
+				l
+			},
+			Err(e) => fail(error::Error::ParseKle(filename.to_string(), e)),
+		};
+		layout.fill_number_row(pairs);
+		return layout;
+	}
+	let mut layout = match layout::Layout::from_string_with_pairs(&layout_str[..], board, pairs) {
+		Ok(l) => l,
+		Err(e) => fail(error::Error::ParseLayout(filename.to_string(), e)),
+	};
+	layout.fill_number_row(pairs);
+	layout
+}
+
+// A copy of a built-in layout, which keeps its own keyboard, with the number
+// row added and filled with the digits if `board` has one.
+fn builtin_layout(layout: &layout::Layout, board: layout::Board, pairs: &layout::ShiftPairs)
+-> layout::Layout
+{
+	let mut layout = layout.clone();
+	if board.has_number_row() {
+		let mut own = *layout.board();
+		own.add_number_row();
+		layout.set_board(own);
+		layout.fill_number_row(pairs);
+	}
+	layout
+}
+
+// Reads every *.layout file in a directory, in name order. Files that can't be
+// read or parsed, or that have fatal problems, are reported and skipped.
+fn read_layout_dir(dirname: &str, board: layout::Board, pairs: &layout::ShiftPairs, force: bool)
+-> Vec<(String, layout::Layout)>
+{
+	let entries = match fs::read_dir(dirname) {
+		Ok(entries) => entries,
+		Err(e) => fail(error::Error::ReadLayout(dirname.to_string(), e)),
+	};
+	let mut paths: Vec<PathBuf> = entries
+		.filter_map(|entry| entry.ok().map(|e| e.path()))
+		.filter(|path| path.extension().is_some_and(|ext| ext == "layout"))
+		.collect();
+	paths.sort();
+
+	let mut layouts = Vec::new();
+	for path in paths {
+		let name = path.file_name().unwrap().to_string_lossy().into_owned();
+		let filename = path.to_string_lossy();
+		let layout_str = match read_file(&filename) {
+			Ok(s) => s,
+			Err(e) => {
+				eprintln!("Skipping: {}", error::Error::ReadLayout(filename.to_string(), e));
+				continue;
+			},
+		};
+		let mut layout = match layout::Layout::from_string_with_pairs(&layout_str[..], board, pairs) {
+			Ok(l) => l,
+			Err(e) => {
+				eprintln!("Skipping: {}", error::Error::ParseLayout(filename.to_string(), e));
+				continue;
+			},
+		};
+		layout.fill_number_row(pairs);
+		let problems = layout.validate();
+		if problems.iter().any(|p| p.is_fatal()) && !force {
+			eprintln!("Skipping: {}", error::Error::InvalidLayout(filename.to_string()));
+			continue;
+		}
+		layouts.push((name, layout));
+	}
+	layouts
+}
+
+fn read_groups(filename: &str)
+-> HashMap<char, u8>
+{
+	let groups_str = match read_file(filename) {
+		Ok(s) => s,
+		Err(e) => fail(error::Error::ReadGroups(filename.to_string(), e)),
+	};
+	match layout::parse_groups(&groups_str[..]) {
+		Ok(groups) => groups,
+		Err(e) => fail(error::Error::ParseGroups(filename.to_string(), e)),
+	}
+}
+
+fn read_pairs(filename: &str)
+-> layout::ShiftPairs
+{
+	let pairs_str = match read_file(filename) {
+		Ok(s) => s,
+		Err(e) => fail(error::Error::ReadPairs(filename.to_string(), e)),
+	};
+	match layout::parse_pairs(&pairs_str[..]) {
+		Ok(pairs) => pairs,
+		Err(e) => fail(error::Error::ParsePairs(filename.to_string(), e)),
+	}
+}
+
+fn read_compose(filename: &str)
+-> layout::Compose
+{
+	let compose_str = match read_file(filename) {
+		Ok(s) => s,
+		Err(e) => fail(error::Error::ReadCompose(filename.to_string(), e)),
+	};
+	match layout::parse_compose(&compose_str[..]) {
+		Ok(compose) => compose,
+		Err(e) => fail(error::Error::ParseCompose(filename.to_string(), e)),
+	}
+}
+
+fn read_constraints(filename: &str)
+-> Vec<layout::Constraint>
+{
+	let constraints_str = match read_file(filename) {
+		Ok(s) => s,
+		Err(e) => fail(error::Error::ReadConstraints(filename.to_string(), e)),
+	};
+	match layout::parse_constraints(&constraints_str[..]) {
+		Ok(constraints) => constraints,
+		Err(e) => fail(error::Error::ParseConstraints(filename.to_string(), e)),
+	}
+}
+
+fn read_effort(filename: &str)
+-> layout::KeyMap<f64>
+{
+	let effort_str = match read_file(filename) {
+		Ok(s) => s,
+		Err(e) => fail(error::Error::ReadEffort(filename.to_string(), e)),
+	};
+	match penalty::parse_effort(&effort_str[..]) {
+		Ok(effort) => effort,
+		Err(e) => fail(error::Error::ParseEffort(filename.to_string(), e)),
+	}
+}
+
+fn read_geometry(filename: &str)
+-> layout::Geometry
+{
+	let geometry_str = match read_file(filename) {
+		Ok(s) => s,
+		Err(e) => fail(error::Error::ReadGeometry(filename.to_string(), e)),
+	};
+	match layout::Geometry::from_string(&geometry_str[..]) {
+		Ok(geometry) => geometry,
+		Err(e) => fail(error::Error::ParseGeometry(filename.to_string(), e)),
+	}
+}
+
+fn read_weights(filename: &str)
+-> penalty::Weights
+{
+	let weights_str = match read_file(filename) {
+		Ok(s) => s,
+		Err(e) => fail(error::Error::ReadWeights(filename.to_string(), e)),
+	};
+	match penalty::parse_weights(&weights_str[..]) {
+		Ok(weights) => weights,
+		Err(e) => fail(error::Error::ParseWeights(filename.to_string(), e)),
+	}
+}
+
+fn read_bigram_penalties(filename: &str)
+-> penalty::BigramPenalties
+{
+	let bigrams_str = match read_file(filename) {
+		Ok(s) => s,
+		Err(e) => fail(error::Error::ReadBigrams(filename.to_string(), e)),
+	};
+	match penalty::parse_bigram_penalties(&bigrams_str[..]) {
+		Ok(bigrams) => bigrams,
+		Err(e) => fail(error::Error::ParseBigrams(filename.to_string(), e)),
+	}
+}
+
+fn read_file(filename: &str)
+-> io::Result<String>
+{
+	let mut f = File::open(filename)?;
+	let mut s = String::new();
+	f.read_to_string(&mut s)?;
+	Ok(s)
+}
+
+fn fail(e: error::Error)
+-> !
+{
+	eprintln!("Error: {}", e);
+	process::exit(1);
+}
+
+fn print_usage(progname: &str, cmd: &cli::Command)
+{
+	print!("{}", cli::usage(progname, cmd));
+}
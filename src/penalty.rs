@@ -4,30 +4,585 @@
 use std::vec::Vec;
 use std::ops::Range;
 use std::collections::HashMap;
+use std::cmp::Ordering;
 use std::fmt;
+use std::str::FromStr;
 
+use hash::FastHashMap;
 use layout::Layout;
 use layout::LayoutPosMap;
 use layout::KeyMap;
+use layout::Geometry;
 use layout::KeyPress;
 use layout::Finger;
+use layout::Hand;
 use layout::Row;
 use layout::KP_NONE;
+use layout::KEY_FINGERS;
+use layout::KEY_HANDS;
+use layout::NUM_KEYS;
+use layout::layout_file_lines;
+use layout::mirror_position;
 
 pub struct KeyPenalty<'a>
 {
-	name:      &'a str,
+	pub kind:   PenaltyKind,
+	pub name:   &'a str,
+	// Multiplies every occurrence of the penalty; 0 disables it.
+	pub weight:     f64,
 }
 
+// Each penalty, in the order that `init` lists them and a result's breakdown
+// reports them, so that `kind as usize` finds one in either.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum PenaltyKind
+{
+	Base,
+	SameFinger,
+	LongJumpHand,
+	LongJump,
+	LongJumpConsecutive,
+	PinkyRingTwist,
+	RollReversal,
+	SameHand,
+	AlternatingHand,
+	RollOut,
+	RollIn,
+	LongJumpSandwich,
+	Twist,
+	Scissor,
+	LateralStretch,
+	FingerLoad,
+	HandBalance,
+	Travel,
+	LongRun,
+	SameHandFive,
+	Repeat,
+	Redirect,
+	CentreColumn,
+	CentreBigram,
+	SameFingerCentre,
+	KeyPair,
+	Shift,
+}
+
+pub static PENALTY_KINDS: [PenaltyKind; 27] = [
+	PenaltyKind::Base,
+	PenaltyKind::SameFinger,
+	PenaltyKind::LongJumpHand,
+	PenaltyKind::LongJump,
+	PenaltyKind::LongJumpConsecutive,
+	PenaltyKind::PinkyRingTwist,
+	PenaltyKind::RollReversal,
+	PenaltyKind::SameHand,
+	PenaltyKind::AlternatingHand,
+	PenaltyKind::RollOut,
+	PenaltyKind::RollIn,
+	PenaltyKind::LongJumpSandwich,
+	PenaltyKind::Twist,
+	PenaltyKind::Scissor,
+	PenaltyKind::LateralStretch,
+	PenaltyKind::FingerLoad,
+	PenaltyKind::HandBalance,
+	PenaltyKind::Travel,
+	PenaltyKind::LongRun,
+	PenaltyKind::SameHandFive,
+	PenaltyKind::Repeat,
+	PenaltyKind::Redirect,
+	PenaltyKind::CentreColumn,
+	PenaltyKind::CentreBigram,
+	PenaltyKind::SameFingerCentre,
+	PenaltyKind::KeyPair,
+	PenaltyKind::Shift,
+];
+
 #[derive(Clone)]
 pub struct KeyPenaltyResult<'a>
 {
 	pub name:  &'a str,
 	pub total:     f64,
-	pub high_keys: HashMap<&'a str, f64>,
+	pub high_keys: FastHashMap<&'a str, f64>,
+}
+
+// A layout's score: its total penalty, that total scaled by the length of the
+// corpus, and the contribution of each penalty.
+#[derive(Clone)]
+pub struct PenaltyResult<'a>
+{
+	pub total:     f64,
+	pub scaled:    f64,
+	pub breakdown: Vec<KeyPenaltyResult<'a>>,
+	pub positions: PositionStats,
+	// The baseline layout and its scaled penalty, in detailed results from
+	// quartads that have one.
+	pub baseline:  Option<(String, f64)>,
+}
+
+// Keystrokes and penalties by the position of the key that incurs them, for
+// heatmaps. Like the breakdown, only filled in for detailed results; the finger
+// load and hand balance penalties belong to no one key and are left out.
+#[derive(Clone, Copy)]
+pub struct PositionStats
+{
+	pub presses: KeyMap<f64>,
+	pub penalty: KeyMap<f64>,
+}
+
+impl PositionStats
+{
+	pub fn new()
+	-> PositionStats
+	{
+		PositionStats { presses: KeyMap([0.0; NUM_KEYS]), penalty: KeyMap([0.0; NUM_KEYS]) }
+	}
+
+	// The shares of keystrokes typed by the left and right hands, leaving out
+	// the thumbs like the hand balance penalty does.
+	pub fn hand_shares(&self)
+	-> (f64, f64)
+	{
+		let mut left = 0.0;
+		let mut right = 0.0;
+		for pos in (0..NUM_KEYS).filter(|&pos| KEY_FINGERS.0[pos] != Finger::Thumb) {
+			match KEY_HANDS.0[pos] {
+				Hand::Left  => left += self.presses.0[pos],
+				Hand::Right => right += self.presses.0[pos],
+			}
+		}
+		let sum = left + right;
+		if sum == 0.0 {
+			return (0.0, 0.0);
+		}
+		(left / sum, right / sum)
+	}
+
+	// The `n` positions with the highest penalty, highest first, leaving out
+	// those with none.
+	pub fn worst(&self, n: usize)
+	-> Vec<(usize, f64)>
+	{
+		let mut worst: Vec<(usize, f64)> = self.penalty.0.iter().cloned().enumerate()
+			.filter(|&(_, penalty)| penalty > 0.0)
+			.collect();
+		worst.sort_by(|a, b| b.1.partial_cmp(&a.1).unwrap_or(Ordering::Equal).then(a.0.cmp(&b.0)));
+		worst.truncate(n);
+		worst
+	}
+}
+
+impl Default for PositionStats
+{
+	fn default()
+	-> PositionStats
+	{
+		PositionStats::new()
+	}
+}
+
+#[derive(Clone, Copy)]
+pub struct Quartad<'a>
+{
+	string: &'a str,
+	codes:  [u8; MAX_CONTEXT],
+	len:        usize,
+	count:      f64,
+	// Only penalties over keystrokes that type exactly this many characters
+	// apply, or all of them if 0.
+	arity:      usize,
+}
+
+#[derive(Debug)]
+pub enum EffortParseError
+{
+	LineCount { found: usize },
+	KeyCount { line: usize, found: usize, expected: usize },
+	BadNumber { line: usize, column: usize, found: String },
+}
+
+#[derive(Debug)]
+pub enum NgramParseError
+{
+	MissingCount { line: usize },
+	BadCount { line: usize, count: String },
+	Length { line: usize, found: usize },
+	NoUnigrams,
+}
+
+#[derive(Debug)]
+pub enum CountsParseError
+{
+	MissingHeader,
+	BadLine { line: usize },
+	Setting { name: &'static str, found: String, expected: String },
+}
+
+#[derive(Debug)]
+pub enum BigramParseError
+{
+	BadLine { line: usize },
+	BadPosition { line: usize, found: String },
+	BadNumber { line: usize, found: String },
+}
+
+#[derive(Debug)]
+pub enum WeightsParseError
+{
+	BadLine { line: usize },
+	UnknownPenalty { line: usize, name: String },
+	BadWeight { line: usize, found: String },
+	BadFingerLoad { line: usize, found: String },
+}
+
+#[derive(Debug)]
+pub enum TimingsParseError
+{
+	BadLine { line: usize },
+	BadTime { line: usize, found: String },
+}
+
+// An extra penalty for typing one position right after another, indexed by the
+// earlier position and then the later one.
+#[derive(Clone)]
+pub struct BigramPenalties(pub [[f64; NUM_KEYS]; NUM_KEYS]);
+
+pub struct QuartadList<'a>
+{
+	quartads:    Vec<Quartad<'a>>,
+	model:       Model,
+	// Stretches of the corpus between resets, with how often each occurs, for
+	// scoring runs on one hand longer than a quartad.
+	runs:        Vec<(&'a str, f64)>,
+	// How many characters each keystroke is scored with, itself included.
+	context:     usize,
+	// The layout that detailed results are compared with, and its scaled
+	// penalty.
+	baseline:    Option<(String, f64)>,
+}
+
+// The share of keystrokes typed by each finger other than the thumbs, from the
+// left pinky to the right pinky.
+pub type FingerLoad = [f64; 8];
+
+// How keystrokes are typed and what they cost, apart from the weight of each
+// penalty. A quartad list is prepared and scored with one.
+#[derive(Clone)]
+pub struct Model
+{
+	pub model_shift: bool,
+	pub space:       SpaceMode,
+	// The target share of keystrokes typed by each finger, adding up to 1.
+	pub finger_load: FingerLoad,
+	// The target share of keystrokes typed by the left hand.
+	pub hand_balance: f64,
+	// How much each row between the keys adds to the same finger penalty.
+	pub same_finger_rows: f64,
+	pub effort:      KeyMap<f64>,
+	pub geometry:    Option<Geometry>,
+	pub bigrams:     Option<BigramPenalties>,
+}
+
+// How much of a corpus can be typed on the layout it was prepared for.
+pub struct Coverage
+{
+	pub total:   usize,
+	// Characters dropped because the layout doesn't have them, as opposed to
+	// those deliberately used to reset the context.
+	pub skipped: HashMap<char, usize>,
+}
+
+// How the space bar is typed.
+#[derive(Clone, Copy, PartialEq)]
+pub enum SpaceMode
+{
+	// Wherever the layout puts it.
+	Key,
+	// With one thumb.
+	Left,
+	Right,
+	// With the thumb opposite the hand of the previous keystroke. With none
+	// before it, at the start of a text or after a character the layout
+	// doesn't have, the right thumb presses it, as most typists' does.
+	Alternate,
+	// Not at all; space resets the context like unknown characters do.
+	Reset,
+}
+
+impl Coverage
+{
+	fn new()
+	-> Coverage
+	{
+		Coverage {
+			total:   0,
+			skipped: HashMap::new(),
+		}
+	}
+
+	// The coverage of several corpora together.
+	pub fn sum(coverages: &[Coverage])
+	-> Coverage
+	{
+		let mut sum = Coverage::new();
+		for coverage in coverages.iter() {
+			sum.total += coverage.total;
+			for (&c, &n) in coverage.skipped.iter() {
+				*sum.skipped.entry(c).or_insert(0) += n;
+			}
+		}
+		sum
+	}
+
+	pub fn consumed(&self)
+	-> usize
+	{
+		self.total - self.skipped.values().sum::<usize>()
+	}
+
+	pub fn ratio(&self)
+	-> f64
+	{
+		if self.total == 0 {
+			1.0
+		} else {
+			self.consumed() as f64 / self.total as f64
+		}
+	}
+
+	// Most common first, ties broken by character.
+	pub fn skipped_by_frequency(&self)
+	-> Vec<(char, usize)>
+	{
+		let mut skipped: Vec<(char, usize)> = self.skipped.iter().map(|(c, n)| (*c, *n)).collect();
+		skipped.sort_by(|a, b| b.1.cmp(&a.1).then(a.0.cmp(&b.0)));
+		skipped
+	}
+}
+
+impl Default for Model
+{
+	fn default()
+	-> Model
+	{
+		Model {
+			model_shift: false,
+			space:       SpaceMode::Key,
+			finger_load: DEFAULT_FINGER_LOAD,
+			hand_balance: 0.5,
+			same_finger_rows: DEFAULT_SAME_FINGER_ROWS,
+			effort:      BASE_PENALTY,
+			geometry:    None,
+			bigrams:     None,
+		}
+	}
+}
+
+impl Model
+{
+	// Sets the target share of each finger, scaled to add up to 1.
+	pub fn set_finger_load(&mut self, target: FingerLoad)
+	{
+		let sum: f64 = target.iter().sum();
+		for (load, t) in self.finger_load.iter_mut().zip(target.iter()) {
+			*load = t / sum;
+		}
+	}
 }
 
-pub struct QuartadList<'a>(HashMap<&'a str, usize>);
+impl <'a> QuartadList<'a>
+{
+	// Where the characters of `layout` are typed, as this list scores them.
+	pub fn position_map(&self, layout: &Layout)
+	-> LayoutPosMap
+	{
+		layout.position_map_for(self.model.model_shift)
+	}
+
+	// The number of distinct n-grams to score.
+	pub fn len(&self)
+	-> usize
+	{
+		self.quartads.len()
+	}
+
+	// Whether there are no n-grams to score.
+	pub fn is_empty(&self)
+	-> bool
+	{
+		self.quartads.is_empty()
+	}
+
+	// How the quartads are typed and scored.
+	pub fn model(&self)
+	-> &Model
+	{
+		&self.model
+	}
+
+	pub fn context(&self)
+	-> usize
+	{
+		self.context
+	}
+
+	// Compares detailed results with a layout's scaled penalty on these
+	// quartads, as a percentage of it.
+	pub fn set_baseline(&mut self, name: &str, scaled: f64)
+	{
+		self.baseline = Some((name.to_string(), scaled));
+	}
+
+	pub fn baseline(&self)
+	-> Option<(&str, f64)>
+	{
+		self.baseline.as_ref().map(|&(ref name, scaled)| (&name[..], scaled))
+	}
+
+	// Scores long runs on one hand in the text the quartads were counted from,
+	// which must be split at the same characters.
+	pub fn set_long_runs(&mut self, string: &'a str, reset_on: &[char])
+	{
+		let space = self.model.space;
+		let mut counts: FastHashMap<&str, f64> = FastHashMap::default();
+		let stretches = string.split(|c| reset_on.contains(&c) || (c == ' ' && space == SpaceMode::Reset));
+		for stretch in stretches.filter(|s| s.len() >= LONG_RUN) {
+			*counts.entry(stretch).or_insert(0.0) += 1.0;
+		}
+		self.runs = counts.into_iter().collect();
+		self.runs.sort_by(|a, b| a.0.cmp(b.0));
+	}
+}
+
+impl FromStr for SpaceMode
+{
+	type Err = ();
+
+	fn from_str(s: &str)
+	-> Result<SpaceMode, ()>
+	{
+		match s {
+			"key"       => Ok(SpaceMode::Key),
+			"left"      => Ok(SpaceMode::Left),
+			"right"     => Ok(SpaceMode::Right),
+			"alternate" => Ok(SpaceMode::Alternate),
+			"reset"     => Ok(SpaceMode::Reset),
+			_ => Err(()),
+		}
+	}
+}
+
+impl fmt::Display for SpaceMode
+{
+	fn fmt(&self, f: &mut fmt::Formatter)
+	-> fmt::Result
+	{
+		let name = match *self {
+			SpaceMode::Key       => "key",
+			SpaceMode::Left      => "left",
+			SpaceMode::Right     => "right",
+			SpaceMode::Alternate => "alternate",
+			SpaceMode::Reset     => "reset",
+		};
+		write!(f, "{}", name)
+	}
+}
+
+impl fmt::Display for EffortParseError
+{
+	fn fmt(&self, f: &mut fmt::Formatter)
+	-> fmt::Result
+	{
+		match *self {
+			EffortParseError::LineCount { found } =>
+				write!(f, "effort grid has {} lines, expected 3, or 4 with a number row", found),
+			EffortParseError::KeyCount { line, found, expected } =>
+				write!(f, "line {} has {} numbers, expected {}", line, found, expected),
+			EffortParseError::BadNumber { line, column, ref found } =>
+				write!(f, "line {}, column {}: invalid number {:?}", line, column, found),
+		}
+	}
+}
+
+impl fmt::Display for WeightsParseError
+{
+	fn fmt(&self, f: &mut fmt::Formatter)
+	-> fmt::Result
+	{
+		match *self {
+			WeightsParseError::BadLine { line } =>
+				write!(f, "line {}: expected NAME = WEIGHT", line),
+			WeightsParseError::UnknownPenalty { line, ref name } =>
+				write!(f, "line {}: unknown penalty {:?}", line, name),
+			WeightsParseError::BadWeight { line, ref found } =>
+				write!(f, "line {}: invalid weight {:?}", line, found),
+			WeightsParseError::BadFingerLoad { line, ref found } =>
+				write!(f, "line {}: invalid finger load {:?}, expected 8 comma-separated shares", line, found),
+		}
+	}
+}
+
+impl fmt::Display for BigramParseError
+{
+	fn fmt(&self, f: &mut fmt::Formatter)
+	-> fmt::Result
+	{
+		match *self {
+			BigramParseError::BadLine { line } =>
+				write!(f, "line {}: expected two positions and a penalty", line),
+			BigramParseError::BadPosition { line, ref found } =>
+				write!(f, "line {}: invalid position {:?}, expected 0 to {}", line, found, NUM_KEYS - 1),
+			BigramParseError::BadNumber { line, ref found } =>
+				write!(f, "line {}: invalid number {:?}", line, found),
+		}
+	}
+}
+
+impl fmt::Display for TimingsParseError
+{
+	fn fmt(&self, f: &mut fmt::Formatter)
+	-> fmt::Result
+	{
+		match *self {
+			TimingsParseError::BadLine { line } =>
+				write!(f, "line {}: expected a character and a time in milliseconds", line),
+			TimingsParseError::BadTime { line, ref found } =>
+				write!(f, "line {}: invalid time {:?}", line, found),
+		}
+	}
+}
+
+impl fmt::Display for NgramParseError
+{
+	fn fmt(&self, f: &mut fmt::Formatter)
+	-> fmt::Result
+	{
+		match *self {
+			NgramParseError::MissingCount { line } =>
+				write!(f, "line {} has no tab-separated count", line),
+			NgramParseError::BadCount { line, ref count } =>
+				write!(f, "line {} has invalid count {:?}", line, count),
+			NgramParseError::Length { line, found } =>
+				write!(f, "line {} has a {}-gram, expected 1 to 4 characters", line, found),
+			NgramParseError::NoUnigrams =>
+				write!(f, "no typeable unigrams to scale penalties by"),
+		}
+	}
+}
+
+impl fmt::Display for CountsParseError
+{
+	fn fmt(&self, f: &mut fmt::Formatter)
+	-> fmt::Result
+	{
+		match *self {
+			CountsParseError::MissingHeader =>
+				write!(f, "not a quartad count file from keygen count"),
+			CountsParseError::BadLine { line } =>
+				write!(f, "line {} is not of the form count<TAB>arity<TAB>n-gram", line),
+			CountsParseError::Setting { name, ref found, ref expected } =>
+				write!(f, "made with {} {}, not {}", name, found, expected),
+		}
+	}
+}
 
 impl <'a> fmt::Display for KeyPenaltyResult<'a>
 {
@@ -36,214 +591,1652 @@ impl <'a> fmt::Display for KeyPenaltyResult<'a>
 	}
 }
 
-static BASE_PENALTY: KeyMap<f64> = KeyMap([
+// Prints the totals and each penalty's share of them. The precision, if any, is
+// how many of the costliest n-grams to list per penalty, e.g. "{:.5}".
+impl <'a> fmt::Display for PenaltyResult<'a>
+{
+	fn fmt(&self, f: &mut fmt::Formatter)
+	-> fmt::Result
+	{
+		let show_keys = f.precision().unwrap_or(0);
+		write!(f, "total: {}; scaled: {}", self.total, self.scaled)?;
+		if let Some((ref name, scaled)) = self.baseline {
+			write!(f, " ({:.1}% of {})", self.scaled / scaled * 100.0, name)?;
+		}
+		for penalty in self.breakdown.iter() {
+			// A layout can score nothing, e.g. on a corpus it can't type.
+			let share = if self.total != 0.0 { penalty.total / self.total * 100.0 } else { 0.0 };
+			write!(f, "\n{} ({:.2}%)", penalty, share)?;
+			if show_keys == 0 || penalty.high_keys.is_empty() {
+				continue;
+			}
+			write!(f, "  / ")?;
+			let mut high_keys: Vec<(&str, f64)> = penalty.high_keys.iter().map(|x| (*x.0, *x.1)).collect();
+			high_keys.sort_by(|a, b|
+				match b.1.abs().partial_cmp(&a.1.abs()) {
+					Some(Ordering::Equal) | None => a.0.cmp(b.0),
+					Some(c) => c,
+				});
+			for &(k, v) in high_keys.iter().take(show_keys) {
+				write!(f, " {}: {};", k, v)?;
+			}
+		}
+		Ok(())
+	}
+}
+
+pub static DEFAULT_FINGER_LOAD: FingerLoad = [
+	0.08, 0.11, 0.15, 0.16,    0.16, 0.15, 0.11, 0.08];
+
+static FINGER_NAMES: [&str; 8] = [
+	"left pinky", "left ring", "left middle", "left index",
+	"right index", "right middle", "right ring", "right pinky"];
+
+static COUNTS_HEADER: &str = "keygen quartads";
+
+// How much each row between the keys adds to the same finger penalty, relative
+// to its weight.
+pub const DEFAULT_SAME_FINGER_ROWS: f64 = 0.5;
+
+// The hand balance penalty's weight when --hand-balance turns it on.
+pub const HAND_BALANCE_WEIGHT: f64 = 20.0;
+
+// How many characters of n-gram each keystroke is scored with by default, and
+// the range that can be chosen instead.
+pub const DEFAULT_CONTEXT: usize = 4;
+pub const MIN_CONTEXT: usize = 3;
+pub const MAX_CONTEXT: usize = 5;
+
+// The shortest run on one hand that the long run penalty scores.
+const LONG_RUN: usize = 5;
+
+pub static BASE_PENALTY: KeyMap<f64> = KeyMap([
 	3.0, 1.0, 1.0, 1.5, 3.0,    3.0, 1.5, 1.0, 1.0, 3.0, 4.0,
 	0.5, 0.5, 0.0, 0.0, 1.5,    1.5, 0.0, 0.0, 0.5, 0.5, 2.0,
 	2.0, 2.0, 1.5, 1.5, 2.5,    2.5, 1.5, 1.5, 2.0, 2.0,
-	                    0.0,    0.0]);
+	                    0.0,    0.0,
+	4.0, 3.0, 3.0, 3.0, 4.0,    4.0, 3.0, 3.0, 3.0, 4.0]);
+
+// For code, which types brackets and other symbols from the outer columns far
+// more often than prose: the pinky's outer keys cost more.
+pub static CODE_PENALTY: KeyMap<f64> = KeyMap([
+	3.5, 1.0, 1.0, 1.5, 3.0,    3.0, 1.5, 1.0, 1.0, 3.5, 5.0,
+	0.5, 0.5, 0.0, 0.0, 1.5,    1.5, 0.0, 0.0, 0.5, 0.5, 3.0,
+	2.5, 2.0, 1.5, 1.5, 2.5,    2.5, 1.5, 1.5, 2.0, 2.5,
+	                    0.0,    0.0,
+	4.5, 3.0, 3.0, 3.0, 4.0,    4.0, 3.0, 3.0, 3.0, 4.5]);
+
+// A bundled set of changes to the penalty model for one kind of corpus.
+pub struct Preset
+{
+	pub name:        &'static str,
+	// Penalty weights that differ from the defaults.
+	pub weights:     &'static [(PenaltyKind, f64)],
+	pub effort:      &'static KeyMap<f64>,
+	// Whether the top right corner, kept for a symbol, moves too.
+	pub swap_corner: bool,
+}
+
+pub static PRESETS: [Preset; 2] = [
+	Preset {
+		name:        "prose",
+		weights:     &[],
+		effort:      &BASE_PENALTY,
+		swap_corner: false,
+	},
+	Preset {
+		name:        "code",
+		weights:     &[(PenaltyKind::SameFinger, 6.0), (PenaltyKind::Shift, 1.0)],
+		effort:      &CODE_PENALTY,
+		swap_corner: true,
+	},
+];
+
+pub fn preset(name: &str)
+-> Option<&'static Preset>
+{
+	PRESETS.iter().find(|preset| preset.name == name)
+}
+
+// Virtual shift keys, pressed with the pinky at the outer bottom-row position
+// of each hand.
+static LEFT_SHIFT: Option<KeyPress> = Some(KeyPress {
+	kc: '\0',
+	pos: 22,
+	finger: Finger::Pinky,
+	hand: Hand::Left,
+	row: Row::Bottom,
+	col: 0,
+	center: false,
+	shifted: false,
+});
+static RIGHT_SHIFT: Option<KeyPress> = Some(KeyPress {
+	kc: '\0',
+	pos: 31,
+	finger: Finger::Pinky,
+	hand: Hand::Right,
+	row: Row::Bottom,
+	col: 9,
+	center: false,
+	shifted: false,
+});
 
+// Virtual thumb presses for the space bar.
+static LEFT_THUMB: Option<KeyPress> = Some(KeyPress {
+	kc: ' ',
+	pos: 32,
+	finger: Finger::Thumb,
+	hand: Hand::Left,
+	row: Row::Thumb,
+	col: 4,
+	center: false,
+	shifted: false,
+});
+static RIGHT_THUMB: Option<KeyPress> = Some(KeyPress {
+	kc: ' ',
+	pos: 33,
+	finger: Finger::Thumb,
+	hand: Hand::Right,
+	row: Row::Thumb,
+	col: 5,
+	center: false,
+	shifted: false,
+});
+
+impl PenaltyKind
+{
+	pub fn name(self)
+	-> &'static str
+	{
+		match self {
+			PenaltyKind::Base                 => "base",
+			PenaltyKind::SameFinger           => "same finger",
+			PenaltyKind::LongJumpHand         => "long jump hand",
+			PenaltyKind::LongJump             => "long jump",
+			PenaltyKind::LongJumpConsecutive  => "long jump consecutive",
+			PenaltyKind::PinkyRingTwist       => "pinky/ring twist",
+			PenaltyKind::RollReversal         => "roll reversal",
+			PenaltyKind::SameHand             => "same hand",
+			PenaltyKind::AlternatingHand      => "alternating hand",
+			PenaltyKind::RollOut              => "roll out",
+			PenaltyKind::RollIn               => "roll in",
+			PenaltyKind::LongJumpSandwich     => "long jump sandwich",
+			PenaltyKind::Twist                => "twist",
+			PenaltyKind::Scissor              => "scissor",
+			PenaltyKind::LateralStretch       => "lateral stretch",
+			PenaltyKind::FingerLoad           => "finger load",
+			PenaltyKind::HandBalance          => "hand balance",
+			PenaltyKind::Travel               => "travel",
+			PenaltyKind::LongRun              => "long run",
+			PenaltyKind::SameHandFive         => "same hand five",
+			PenaltyKind::Repeat               => "repeat",
+			PenaltyKind::Redirect             => "redirect",
+			PenaltyKind::CentreColumn         => "centre column",
+			PenaltyKind::CentreBigram         => "centre bigram",
+			PenaltyKind::SameFingerCentre     => "same finger centre",
+			PenaltyKind::KeyPair              => "key pair",
+			PenaltyKind::Shift                => "shift",
+		}
+	}
+}
+
+impl FromStr for PenaltyKind
+{
+	type Err = ();
+
+	fn from_str(s: &str)
+	-> Result<PenaltyKind, ()>
+	{
+		PENALTY_KINDS.iter().find(|kind| kind.name() == s).cloned().ok_or(())
+	}
+}
+
+impl<'a> KeyPenalty<'a>
+{
+	pub fn new(kind: PenaltyKind, weight: f64)
+	-> KeyPenalty<'a>
+	{
+		KeyPenalty { kind, name: kind.name(), weight }
+	}
+}
+
+// Pushed one at a time so that each weight sits under its explanation.
+#[allow(clippy::vec_init_then_push)]
 pub fn init<'a>()
 -> Vec<KeyPenalty<'a>>
 {
 	let mut penalties = Vec::new();
 
 	// Base penalty.
-	penalties.push(KeyPenalty {
-		name: "base",
-	});
+	penalties.push(KeyPenalty::new(PenaltyKind::Base, 1.0));
 
-	// Penalise 5 points for using the same finger twice on different keys.
-	// An extra 5 points for using the centre column.
-	penalties.push(KeyPenalty {
-		name: "same finger",
-	});
+	// Penalise 5 points for using the same finger twice on different keys,
+	// plus half that for each row between them (see `set_same_finger_rows`).
+	// The centre column costs extra, see same finger centre.
+	penalties.push(KeyPenalty::new(PenaltyKind::SameFinger, 5.0));
 
 	// Penalise 1 point for jumping from top to bottom row or from bottom to
 	// top row on the same hand.
-	penalties.push(KeyPenalty {
-		name: "long jump hand",
-	});
+	penalties.push(KeyPenalty::new(PenaltyKind::LongJumpHand, 1.0));
 
 	// Penalise 10 points for jumping from top to bottom row or from bottom to
 	// top row on the same finger.
-	penalties.push(KeyPenalty {
-		name: "long jump",
-	});
+	penalties.push(KeyPenalty::new(PenaltyKind::LongJump, 10.0));
 
 	// Penalise 5 points for jumping from top to bottom row or from bottom to
 	// top row on consecutive fingers, except for middle finger-top row ->
 	// index finger-bottom row.
-	penalties.push(KeyPenalty {
-		name: "long jump consecutive",
-	});
+	penalties.push(KeyPenalty::new(PenaltyKind::LongJumpConsecutive, 5.0));
 
 	// Penalise 10 points for awkward pinky/ring combination where the pinky
 	// reaches above the ring finger, e.g. QA/AQ, PL/LP, ZX/XZ, ;./.; on Qwerty.
-	penalties.push(KeyPenalty {
-		name: "pinky/ring twist",
-	});
+	penalties.push(KeyPenalty::new(PenaltyKind::PinkyRingTwist, 10.0));
 
 	// Penalise 20 points for reversing a roll at the end of the hand, i.e.
 	// using the ring, pinky, then middle finger of the same hand, or the
 	// middle, pinky, then ring of the same hand.
-	penalties.push(KeyPenalty {
-		name: "roll reversal",
-	});
+	penalties.push(KeyPenalty::new(PenaltyKind::RollReversal, 20.0));
 
 	// Penalise 0.5 points for using the same hand four times in a row.
-	penalties.push(KeyPenalty {
-		name: "same hand",
-	});
+	penalties.push(KeyPenalty::new(PenaltyKind::SameHand, 0.5));
 
 	// Penalise 0.5 points for alternating hands three times in a row.
-	penalties.push(KeyPenalty {
-		name: "alternating hand",
-	});
+	penalties.push(KeyPenalty::new(PenaltyKind::AlternatingHand, 0.5));
 
 	// Penalise 0.125 points for rolling outwards.
-	penalties.push(KeyPenalty {
-		name: "roll out",
-	});
+	penalties.push(KeyPenalty::new(PenaltyKind::RollOut, 0.125));
 
-	// Award 0.125 points for rolling inwards.
-	penalties.push(KeyPenalty {
-		name: "roll in",
-	});
+	// Award 0.125 points for rolling inwards to the next column along the
+	// same row, and half that for rolls that skip a column or change rows.
+	penalties.push(KeyPenalty::new(PenaltyKind::RollIn, -0.125));
 
 	// Penalise 3 points for jumping from top to bottom row or from bottom to
 	// top row on the same finger with a keystroke in between.
-	penalties.push(KeyPenalty {
-		name: "long jump sandwich",
-	});
+	penalties.push(KeyPenalty::new(PenaltyKind::LongJumpSandwich, 3.0));
 
 	// Penalise 10 points for three consecutive keystrokes going up or down the
 	// three rows of the keyboard in a roll.
-	penalties.push(KeyPenalty {
-		name: "twist",
-	});
+	penalties.push(KeyPenalty::new(PenaltyKind::Twist, 10.0));
+
+	// Penalise 5 points for a scissor, i.e. jumping from top to bottom row or
+	// from bottom to top row on adjacent fingers of the same hand, e.g. EX and
+	// CR on Qwerty. Only half as much for the index and middle fingers, which
+	// stretch apart more easily.
+	penalties.push(KeyPenalty::new(PenaltyKind::Scissor, 5.0));
+
+	// Penalise 2 points for a lateral stretch, i.e. using the ring or pinky
+	// finger straight after the index finger reaches into the centre column of
+	// the same hand, e.g. GA and TS on Qwerty.
+	penalties.push(KeyPenalty::new(PenaltyKind::LateralStretch, 2.0));
+
+	// Penalise each keystroke by the sum of the squared differences between
+	// each finger's share of the keystrokes and its target share, times the
+	// weight. Unlike the others, this is scored over the whole corpus, and is
+	// off unless a weights file or --penalty-weight gives it one, e.g. 20.
+	penalties.push(KeyPenalty::new(PenaltyKind::FingerLoad, 0.0));
+
+	// Penalise each keystroke by the squared difference between the left
+	// hand's share of the keystrokes and its target share, times the weight.
+	// This is also scored over the whole corpus, and is off unless
+	// --hand-balance turns it on with `HAND_BALANCE_WEIGHT` or a weights file
+	// or --penalty-weight gives it a weight.
+	penalties.push(KeyPenalty::new(PenaltyKind::HandBalance, 0.0));
+
+	// Penalise 1 point per key width between a key and the home key of its
+	// finger. Only scored with a geometry, which also gives the rows between
+	// the two keys of the same finger penalty.
+	penalties.push(KeyPenalty::new(PenaltyKind::Travel, 1.0));
+
+	// Penalise runs of five or more keystrokes on one hand by the square of
+	// the keystrokes past three, e.g. 4 points for five and 16 for seven. Only
+	// scored for quartad lists given their corpus with `set_long_runs`.
+	penalties.push(KeyPenalty::new(PenaltyKind::LongRun, 1.0));
+
+	// Penalise 1 point for using the same hand five times in a row, on top of
+	// the same hand penalty for each four of them. Only scored with a context
+	// of five characters.
+	penalties.push(KeyPenalty::new(PenaltyKind::SameHandFive, 1.0));
+
+	// Penalise pressing the same key twice in a row, e.g. LL, nothing by
+	// default since the base penalty already charges for each press. The
+	// penalties over several keys treat a repeat as one movement.
+	penalties.push(KeyPenalty::new(PenaltyKind::Repeat, 0.0));
+
+	// Penalise a roll on one hand that changes direction, e.g. rolling in
+	// then out, and twice as much when no index finger is involved. Nothing by
+	// default, since the roll reversal penalty already covers the worst of
+	// these; 2 is a reasonable weight.
+	penalties.push(KeyPenalty::new(PenaltyKind::Redirect, 0.0));
+
+	// Penalise each keystroke in the centre column, nothing by default since
+	// the base penalty already charges for reaching there.
+	penalties.push(KeyPenalty::new(PenaltyKind::CentreColumn, 0.0));
+
+	// Penalise each centre column key of a bigram on one hand, nothing by
+	// default.
+	penalties.push(KeyPenalty::new(PenaltyKind::CentreBigram, 0.0));
+
+	// Penalise 5 points for each centre column key of a same finger bigram,
+	// on top of the same finger penalty.
+	penalties.push(KeyPenalty::new(PenaltyKind::SameFingerCentre, 5.0));
+
+	// Penalise a pair of keys on either hand by the extra penalty given for it
+	// with `set_bigram_penalties`, for awkward motions that only a particular
+	// keyboard has.
+	penalties.push(KeyPenalty::new(PenaltyKind::KeyPair, 1.0));
+
+	// Penalise each press of a modelled shift key, nothing by default on top
+	// of its base penalty.
+	penalties.push(KeyPenalty::new(PenaltyKind::Shift, 0.0));
 
 	penalties
 }
 
+// Reads a base penalty for each key from three lines of whitespace-separated
+// numbers, shaped like the lower layer of a layout file. A `|` may separate the
+// hands, and the third line ends with the two thumb keys:
+//
+//     3 1 1 1.5 3 | 3 1.5 1 1 3 4
+//     ...
+//
+// A fourth line above the others sets the number row's; otherwise it keeps
+// those of `BASE_PENALTY`.
+pub fn parse_effort(s: &str)
+-> Result<KeyMap<f64>, EffortParseError>
+{
+	let lines: Vec<&str> = s.trim_end_matches(['\n', '\r']).lines().collect();
+	if lines.len() != 3 && lines.len() != 4 {
+		return Err(EffortParseError::LineCount { found: lines.len() });
+	}
+
+	let KeyMap(mut effort) = BASE_PENALTY;
+	for ((l, line), row) in lines.iter().enumerate().zip(layout_file_lines(lines.len() == 4)) {
+		let pos = row.start;
+		let mut found = 0;
+		let mut column = 0;
+		for token in line.split(|c: char| c.is_whitespace()) {
+			column += 1;
+			let start = column;
+			column += token.chars().count();
+			if token.is_empty() || token == "|" {
+				continue;
+			}
+			let n = match token.parse::<f64>() {
+				Ok(n) => n,
+				Err(_) => { return Err(EffortParseError::BadNumber { line: l + 1, column: start, found: token.to_string() }) }
+			};
+			if found < row.len() {
+				effort[pos + found] = n;
+			}
+			found += 1;
+		}
+		if found != row.len() {
+			return Err(EffortParseError::KeyCount { line: l + 1, found, expected: row.len() });
+		}
+	}
+
+	Ok(KeyMap(effort))
+}
+
+// Reads extra penalties for pairs of positions, one pair a line. A pair is
+// penalised in both directions unless a `>` separates its positions, and blank
+// lines and lines starting with `#` are skipped:
+//
+//     14 27 2.5
+//     # Only from the left thumb to Z.
+//     32 > 22 1
+pub fn parse_bigram_penalties(s: &str)
+-> Result<BigramPenalties, BigramParseError>
+{
+	let mut bigrams = [[0.0; NUM_KEYS]; NUM_KEYS];
+	for (l, line) in s.lines().enumerate() {
+		let line = line.trim();
+		if line.is_empty() || line.starts_with('#') {
+			continue;
+		}
+		let mut tokens: Vec<&str> = line.split_whitespace().collect();
+		let symmetric = !(tokens.len() == 4 && tokens[1] == ">");
+		if !symmetric {
+			tokens.remove(1);
+		}
+		if tokens.len() != 3 {
+			return Err(BigramParseError::BadLine { line: l + 1 });
+		}
+		let position = |token: &str| match token.parse::<usize>() {
+			Ok(pos) if pos < NUM_KEYS => Ok(pos),
+			_ => Err(BigramParseError::BadPosition { line: l + 1, found: token.to_string() }),
+		};
+		let from = position(tokens[0])?;
+		let to = position(tokens[1])?;
+		let extra = match tokens[2].parse::<f64>() {
+			Ok(n) => n,
+			Err(_) => { return Err(BigramParseError::BadNumber { line: l + 1, found: tokens[2].to_string() }) }
+		};
+		bigrams[from][to] = extra;
+		if symmetric {
+			bigrams[to][from] = extra;
+		}
+	}
+
+	Ok(BigramPenalties(bigrams))
+}
+
+// Penalty weights read from a file, and the finger load target if it sets one.
+pub struct Weights
+{
+	pub weights:     Vec<(PenaltyKind, f64)>,
+	pub finger_load: Option<FingerLoad>,
+}
+
+// Reads the weights of penalties, one `NAME = WEIGHT` a line, and the target
+// share of each finger as `finger load target = SHARES`. Blank lines and lines
+// starting with `#` are skipped:
+//
+//     finger load = 20
+//     finger load target = 8, 11, 15, 16, 16, 15, 11, 8
+//     # Favour rolls in more.
+//     roll in = -0.5
+pub fn parse_weights(s: &str)
+-> Result<Weights, WeightsParseError>
+{
+	let mut weights = Weights { weights: Vec::new(), finger_load: None };
+	for (l, line) in s.lines().enumerate() {
+		let line = line.trim();
+		if line.is_empty() || line.starts_with('#') {
+			continue;
+		}
+		let (name, value) = match line.find('=') {
+			Some(idx) => (line[..idx].trim(), line[(idx + 1)..].trim()),
+			None => { return Err(WeightsParseError::BadLine { line: l + 1 }) }
+		};
+		if name == "finger load target" {
+			match parse_finger_load(value) {
+				Some(load) => weights.finger_load = Some(load),
+				None => { return Err(WeightsParseError::BadFingerLoad { line: l + 1, found: value.to_string() }) }
+			}
+			continue;
+		}
+		let kind = match name.parse::<PenaltyKind>() {
+			Ok(kind) => kind,
+			Err(_) => { return Err(WeightsParseError::UnknownPenalty { line: l + 1, name: name.to_string() }) }
+		};
+		match value.parse::<f64>() {
+			Ok(weight) => weights.weights.push((kind, weight)),
+			Err(_) => { return Err(WeightsParseError::BadWeight { line: l + 1, found: value.to_string() }) }
+		}
+	}
+
+	Ok(weights)
+}
+
+// Parses eight comma-separated, non-negative shares, not all zero.
+pub fn parse_finger_load(s: &str)
+-> Option<FingerLoad>
+{
+	let shares: Vec<f64> = match s.split(',').map(|x| x.trim().parse::<f64>()).collect() {
+		Ok(shares) => shares,
+		Err(_) => { return None }
+	};
+	if shares.len() != 8 || shares.iter().any(|&x| x < 0.0) || shares.iter().all(|&x| x == 0.0) {
+		return None;
+	}
+	let mut load = [0.0; 8];
+	load.copy_from_slice(&shares);
+	Some(load)
+}
+
+// Writes base penalties the way `parse_effort` reads them, with the number
+// row's if asked.
+pub fn write_effort(effort: &KeyMap<f64>, number_row: bool)
+-> String
+{
+	let KeyMap(ref effort) = *effort;
+	let row = |keys: &[f64]| keys.iter().map(|x| ((x * 100.0).round() / 100.0).to_string()).collect::<Vec<String>>().join(" ");
+	let number_row = if number_row {
+		format!("{} | {}\n", row(&effort[34..39]), row(&effort[39..44]))
+	} else {
+		String::new()
+	};
+	number_row + &format!("{} | {}\n{} | {}\n{} | {} | {}\n",
+		row(&effort[0..5]), row(&effort[5..11]),
+		row(&effort[11..16]), row(&effort[16..22]),
+		row(&effort[22..27]), row(&effort[27..32]), row(&effort[32..34]))
+}
+
+// Reads how long each press of a key took, one `char,milliseconds` a line as a
+// typing trainer exports them. Blank lines are skipped, as is a header.
+pub fn parse_timings(s: &str)
+-> Result<Vec<(char, f64)>, TimingsParseError>
+{
+	let mut timings = Vec::new();
+	for (l, line) in s.lines().enumerate() {
+		let line = line.trim_end_matches('\r');
+		if line.trim().is_empty() {
+			continue;
+		}
+		let (key, time) = match line.rfind(',') {
+			Some(idx) => (&line[..idx], line[(idx + 1)..].trim()),
+			None => { return Err(TimingsParseError::BadLine { line: l + 1 }) }
+		};
+		let mut chars = key.chars();
+		let c = match (chars.next(), chars.next()) {
+			(Some(c), None) => c,
+			_ if l == 0 => continue,
+			_ => { return Err(TimingsParseError::BadLine { line: l + 1 }) }
+		};
+		match time.parse::<f64>() {
+			Ok(ms) if ms >= 0.0 => timings.push((c, ms)),
+			_ if l == 0 => continue,
+			_ => { return Err(TimingsParseError::BadTime { line: l + 1, found: time.to_string() }) }
+		}
+	}
+
+	Ok(timings)
+}
+
+// Base penalties from the median time of each key's presses on `layout`,
+// scaled so that the fastest key costs 0 and the slowest 4, the range of
+// `BASE_PENALTY`. Keys without timings keep their penalty from `defaults`, and
+// are returned too.
+pub fn calibrate_effort(timings: &[(char, f64)], layout: &Layout, defaults: &KeyMap<f64>)
+-> (KeyMap<f64>, Vec<usize>)
+{
+	let mut times: Vec<Vec<f64>> = vec![Vec::new(); NUM_KEYS];
+	for &(c, ms) in timings {
+		if c == '\0' {
+			continue;
+		}
+		if let Some(pos) = (0..NUM_KEYS).find(|&pos| { let (lower, upper) = layout.keys_at(pos); lower == c || upper == c }) {
+			times[pos].push(ms);
+		}
+	}
+
+	let medians: Vec<Option<f64>> = times.iter_mut().map(|times| {
+		if times.is_empty() {
+			return None;
+		}
+		times.sort_by(|a, b| a.partial_cmp(b).unwrap_or(Ordering::Equal));
+		let mid = times.len() / 2;
+		Some(if times.len() % 2 == 0 { (times[mid - 1] + times[mid]) / 2.0 } else { times[mid] })
+	}).collect();
+	let known: Vec<f64> = medians.iter().filter_map(|&m| m).collect();
+	let fastest = known.iter().cloned().fold(f64::INFINITY, f64::min);
+	let slowest = known.iter().cloned().fold(f64::NEG_INFINITY, f64::max);
+
+	let mut effort = *defaults;
+	let mut missing = Vec::new();
+	for (pos, &median) in medians.iter().enumerate() {
+		match median {
+			Some(median) if slowest > fastest => effort.0[pos] = 4.0 * (median - fastest) / (slowest - fastest),
+			Some(_) => effort.0[pos] = 0.0,
+			None => missing.push(pos),
+		}
+	}
+
+	(effort, missing)
+}
+
+// Scales the base penalty of every finger key on the hand that isn't `dominant`,
+// so that a model tuned for either hand can favour one of them.
+pub fn favour_hand(effort: &KeyMap<f64>, dominant: Hand, factor: f64)
+-> KeyMap<f64>
+{
+	let mut favoured = *effort;
+	for pos in (0..NUM_KEYS).filter(|&pos| KEY_HANDS.0[pos] != dominant && KEY_FINGERS.0[pos] != Finger::Thumb) {
+		favoured.0[pos] *= factor;
+	}
+	favoured
+}
+
+// How many runs of each length the corpus types on the same hand, the first
+// entry counting single keystrokes between the other hand's.
+#[derive(Default)]
+pub struct HandRuns
+{
+	pub left:  Vec<usize>,
+	pub right: Vec<usize>,
+}
+
+impl HandRuns
+{
+	pub fn new()
+	-> HandRuns
+	{
+		HandRuns { left: Vec::new(), right: Vec::new() }
+	}
+
+	fn add(&mut self, hand: Hand, len: usize)
+	{
+		let runs = if hand == Hand::Left { &mut self.left } else { &mut self.right };
+		if runs.len() < len {
+			runs.resize(len, 0);
+		}
+		runs[len - 1] += 1;
+	}
+
+	// The average length of a run on one hand, or 0 if it has none.
+	pub fn mean(&self, hand: Hand)
+	-> f64
+	{
+		let runs = if hand == Hand::Left { &self.left } else { &self.right };
+		let count: usize = runs.iter().sum();
+		let keys: usize = runs.iter().enumerate().map(|(i, &n)| (i + 1) * n).sum();
+		if count == 0 { 0.0 } else { keys as f64 / count as f64 }
+	}
+}
+
+// Adds up the runs of keystrokes on the same hand in the corpus, which the
+// quartads are too short to see past four keys of. Thumb keys count for their
+// hand and characters that can't be typed end a run, as in the penalties.
+pub fn count_hand_runs(
+	string:       &str,
+	position_map: &LayoutPosMap,
+	space:            SpaceMode,
+	reset_on:         &[char],
+	runs:         &mut HandRuns)
+{
+	let mut run: Option<(Hand, usize)> = None;
+	let mut prev = 0u8;
+	for c in string.chars() {
+		let code = if c.is_ascii() { c as u8 } else { 0 };
+		let hand = if reset_on.contains(&c) { None } else { hand_of(code, prev, position_map, space) };
+		prev = code;
+		run = match (hand, run) {
+			(Some(hand), Some((run_hand, len))) if hand == run_hand => Some((hand, len + 1)),
+			(hand, run) => {
+				if let Some((hand, len)) = run {
+					runs.add(hand, len);
+				}
+				hand.map(|hand| (hand, 1))
+			},
+		};
+	}
+	if let Some((hand, len)) = run {
+		runs.add(hand, len);
+	}
+}
+
+// The hand that types a character, if the layout has it, given the one before.
+fn hand_of(code: u8, prev: u8, position_map: &LayoutPosMap, space: SpaceMode)
+-> Option<Hand>
+{
+	let kp = if code == b' ' {
+		if space == SpaceMode::Reset {
+			return None;
+		}
+		space_position(space, position_map, &[prev])
+	} else {
+		position_map.get_key_position_by_code(code)
+	};
+	kp.as_ref().map(|kp| kp.hand)
+}
+
+// Typographic characters that no layout has, and the ASCII characters to type
+// in their place.
+pub static TYPOGRAPHIC: [(char, &str); 13] = [
+	('\u{2018}', "'"), ('\u{2019}', "'"), ('\u{201a}', "'"),
+	('\u{201c}', "\""), ('\u{201d}', "\""), ('\u{201e}', "\""),
+	('\u{2010}', "-"), ('\u{2013}', "-"), ('\u{2014}', "-"), ('\u{2212}', "-"),
+	('\u{2026}', "..."),
+	('\u{a0}', " "), ('\u{202f}', " "),
+];
+
+// Replaces each character of the corpus that `table` has, so that curly quotes
+// don't break up the quartads around them. Also gives how many were replaced.
+pub fn normalize(corpus: &str, table: &HashMap<char, String>)
+-> (String, usize)
+{
+	let mut normalized = String::with_capacity(corpus.len());
+	let mut replaced = 0;
+	for c in corpus.chars() {
+		match table.get(&c) {
+			Some(replacement) => {
+				normalized.push_str(replacement);
+				replaced += 1;
+			},
+			None => normalized.push(c),
+		}
+	}
+	(normalized, replaced)
+}
+
+// Counts every n-gram of up to four characters in the corpus that can be typed
+// on the layout with this position map, along with how much of the corpus that
+// covers.
 pub fn prepare_quartad_list<'a>(
 	string:       &'a str,
-	position_map: &'a LayoutPosMap)
--> QuartadList<'a>
+	position_map: &   LayoutPosMap,
+	model:        &   Model,
+	reset_on:         &[char])
+-> (QuartadList<'a>, Coverage)
+{
+	prepare_quartad_list_with_context(string, position_map, model, reset_on, DEFAULT_CONTEXT)
+}
+
+// Like `prepare_quartad_list`, but with n-grams of up to `context` characters,
+// from 3 to 5. Longer n-grams take more memory and time to score.
+pub fn prepare_quartad_list_with_context<'a>(
+	string:       &'a str,
+	position_map: &   LayoutPosMap,
+	model:        &   Model,
+	reset_on:         &[char],
+	context:          usize)
+-> (QuartadList<'a>, Coverage)
 {
 	let mut range: Range<usize> = 0..0;
-	let mut quartads: HashMap<&str, usize> = HashMap::new();
-	for (i, c) in string.chars().enumerate() {
-		match *position_map.get_key_position(c) {
+	// Distinct n-grams grow roughly with the square root of the length of
+	// English text, at about 40 per square root character.
+	let mut counts: FastHashMap<&str, usize> = FastHashMap::default();
+	counts.reserve(40 * (string.len() as f64).sqrt() as usize);
+	let mut coverage = Coverage::new();
+	let space = model.space;
+	for (i, c) in string.char_indices() {
+		let resets = reset_on.contains(&c) || (c == ' ' && space == SpaceMode::Reset);
+		let kp = if resets {
+			&KP_NONE
+		} else if c == ' ' && space != SpaceMode::Key {
+			&LEFT_THUMB
+		} else {
+			position_map.get_key_position(c)
+		};
+		coverage.total += 1;
+		if kp.is_none() && !resets {
+			*coverage.skipped.entry(c).or_insert(0) += 1;
+		}
+		match *kp {
 			Some(_) => {
 				range.end = i + 1;
-				if range.end > 3 && range.start < range.end - 4 {
-					range.start = range.end - 4;
+				if range.end >= context && range.start < range.end - context {
+					range.start = range.end - context;
 				}
 				let quartad = &string[range.clone()];
-				let entry = quartads.entry(quartad).or_insert(0);
+				let entry = counts.entry(quartad).or_insert(0);
 				*entry += 1;
 			},
 			None => {
-				range = (i + 1)..(i + 1);
+				range = (i + c.len_utf8())..(i + c.len_utf8());
+			}
+		}
+	}
+
+	let mut quartads = counts.into_iter().map(|(quartad, count)| {
+		Quartad {
+			string: quartad,
+			codes:  codes(quartad),
+			len:    quartad.len(),
+			count:  count as f64,
+			arity:  0,
+		}
+	}).collect();
+	sort_quartads(&mut quartads);
+
+	(QuartadList {
+		quartads,
+		model:       model.clone(),
+		runs:        Vec::new(),
+		context,
+		baseline:    None,
+	}, coverage)
+}
+
+// Builds a quartad list from lines of `ngram<TAB>count` for 1- to 5-grams,
+// leaving out those longer than the context. Each n-gram only triggers the
+// penalties over n keys, since its prefixes are counted separately. Also
+// returns the total unigram count, which takes the place of the corpus length,
+// and the coverage of the unigrams.
+pub fn parse_ngram_list<'a>(
+	string:       &'a str,
+	position_map: &   LayoutPosMap,
+	model:        &   Model,
+	reset_on:         &[char],
+	context:          usize)
+-> Result<(QuartadList<'a>, usize, Coverage), NgramParseError>
+{
+	let mut counts: FastHashMap<&str, usize> = FastHashMap::default();
+	let mut len = 0;
+	let mut coverage = Coverage::new();
+	for (i, line) in string.lines().enumerate() {
+		let line_num = i + 1;
+		if line.is_empty() {
+			continue;
+		}
+		let mut fields = line.rsplitn(2, '\t');
+		let count_str = fields.next().unwrap_or("");
+		let ngram = match fields.next() {
+			Some(ngram) => ngram,
+			None => { return Err(NgramParseError::MissingCount { line: line_num }) }
+		};
+		let count = match count_str.trim().parse::<usize>() {
+			Ok(count) => count,
+			Err(_) => { return Err(NgramParseError::BadCount { line: line_num, count: count_str.to_string() }) }
+		};
+		let n = ngram.chars().count();
+		if !(1..=MAX_CONTEXT).contains(&n) {
+			return Err(NgramParseError::Length { line: line_num, found: n });
+		}
+		if n > context {
+			continue;
+		}
+
+		// Skip n-grams that can't be typed as a whole.
+		let resets = |c: char| reset_on.contains(&c) || (c == ' ' && model.space == SpaceMode::Reset);
+		let typeable = ngram.chars().all(|c| !resets(c) && position_map.get_key_position(c).is_some());
+		if n == 1 {
+			let c = ngram.chars().next().unwrap();
+			coverage.total += count;
+			if !typeable && !resets(c) {
+				*coverage.skipped.entry(c).or_insert(0) += count;
+			}
+		}
+		if !typeable {
+			continue;
+		}
+
+		if n == 1 {
+			len += count;
+		}
+		*counts.entry(ngram).or_insert(0) += count;
+	}
+	if len == 0 {
+		return Err(NgramParseError::NoUnigrams);
+	}
+
+	let mut quartads = counts.into_iter().map(|(ngram, count)| {
+		Quartad {
+			string: ngram,
+			codes:  codes(ngram),
+			len:    ngram.len(),
+			count:  count as f64,
+			arity:  ngram.chars().count(),
+		}
+	}).collect();
+	sort_quartads(&mut quartads);
+
+	Ok((QuartadList {
+		quartads,
+		model:       model.clone(),
+		runs:        Vec::new(),
+		context,
+		baseline:    None,
+	}, len, coverage))
+}
+
+// The settings that change how a corpus is counted, as the header lines of a
+// quartad count file name them: the space mode, whether shift is modelled,
+// and the characters that reset the hand.
+fn counts_settings(model: &Model, reset_on: &[char])
+-> [(&'static str, String); 3]
+{
+	let mut reset: Vec<char> = reset_on.to_vec();
+	reset.sort();
+	reset.dedup();
+	[
+		("space", model.space.to_string()),
+		("model-shift", model.model_shift.to_string()),
+		("reset-on", format!("{:?}", reset.into_iter().collect::<String>())),
+	]
+}
+
+// Writes a quartad list and the length of the corpus it came from so that it
+// can be read back instead of counting the corpus again, as a header followed
+// by lines of `count<TAB>arity<TAB>n-gram`. The header also records the
+// settings the corpus was counted with. N-grams containing a line break can't
+// be written; returns how many were left out.
+pub fn write_quartad_counts(list: &QuartadList, len: usize, reset_on: &[char])
+-> (String, usize)
+{
+	let mut out = format!("{}\nlength {}\ncontext {}\n", COUNTS_HEADER, len, list.context);
+	for &(name, ref value) in counts_settings(&list.model, reset_on).iter() {
+		out.push_str(&format!("{} {}\n", name, value));
+	}
+	let mut left_out = 0;
+	for quartad in list.quartads.iter() {
+		if quartad.string.contains(['\n', '\r']) {
+			left_out += 1;
+			continue;
+		}
+		out.push_str(&format!("{}\t{}\t{}\n", quartad.count, quartad.arity, quartad.string));
+	}
+	(out, left_out)
+}
+
+// Reads back a file from `write_quartad_counts`, keeping the n-grams that can
+// be typed with this position map. Also returns the corpus length. The list
+// keeps the context the counts were made with, but the other settings in the
+// header must match the model and reset characters given.
+pub fn parse_quartad_counts<'a>(
+	string:       &'a str,
+	position_map: &   LayoutPosMap,
+	model:        &   Model,
+	reset_on:     &   [char])
+-> Result<(QuartadList<'a>, usize), CountsParseError>
+{
+	let mut lines = string.split('\n');
+	if lines.next().map(|l| l.trim_end_matches('\r')) != Some(COUNTS_HEADER) {
+		return Err(CountsParseError::MissingHeader);
+	}
+	let len = match lines.next().and_then(|l| l.trim_end_matches('\r').strip_prefix("length ")).and_then(|n| n.parse::<usize>().ok()) {
+		Some(len) => len,
+		None => { return Err(CountsParseError::BadLine { line: 2 }) }
+	};
+	let context = match lines.next().and_then(|l| l.trim_end_matches('\r').strip_prefix("context ")).and_then(|n| n.parse::<usize>().ok()) {
+		Some(context) if (MIN_CONTEXT..=MAX_CONTEXT).contains(&context) => context,
+		_ => { return Err(CountsParseError::BadLine { line: 3 }) }
+	};
+	let settings = counts_settings(model, reset_on);
+	for (i, (name, expected)) in settings.iter().cloned().enumerate() {
+		let found = match lines.next().and_then(|l| l.trim_end_matches('\r').strip_prefix(name)).and_then(|v| v.strip_prefix(' ')) {
+			Some(found) => found,
+			None => { return Err(CountsParseError::BadLine { line: i + 4 }) }
+		};
+		if found != expected {
+			return Err(CountsParseError::Setting { name, found: found.to_string(), expected });
+		}
+	}
+	let first_line = 4 + settings.len();
+
+	let mut quartads = Vec::new();
+	for (i, line) in lines.enumerate() {
+		if line.is_empty() {
+			continue;
+		}
+		let mut fields = line.splitn(3, '\t');
+		let parsed = match (fields.next(), fields.next(), fields.next()) {
+			(Some(count), Some(arity), Some(ngram)) => match (count.parse::<f64>(), arity.parse::<usize>()) {
+				(Ok(count), Ok(arity)) if !ngram.is_empty() && ngram.chars().count() <= context => Some((count, arity, ngram)),
+				_ => None,
+			},
+			_ => None,
+		};
+		let (count, arity, ngram) = match parsed {
+			Some(p) => p,
+			None => { return Err(CountsParseError::BadLine { line: i + first_line }) }
+		};
+		if !ngram.chars().all(|c| position_map.get_key_position(c).is_some()) {
+			continue;
+		}
+
+		quartads.push(Quartad {
+			string: ngram,
+			codes:  codes(ngram),
+			len:    ngram.len(),
+			count,
+			arity,
+		});
+	}
+	sort_quartads(&mut quartads);
+
+	Ok((QuartadList {
+		quartads,
+		model:       model.clone(),
+		runs:        Vec::new(),
+		context,
+		baseline:    None,
+	}, len))
+}
+
+// Merges the quartad lists of several corpora, given with their lengths and
+// weights. Counts are scaled so that each corpus contributes in proportion to
+// its weight rather than its length, and the scaled penalty of the merged list
+// is the weighted average of the corpora's scaled penalties. Also returns the
+// combined length.
+pub fn combine_quartad_lists<'a>(lists: &[(QuartadList<'a>, usize, f64)])
+-> (QuartadList<'a>, usize)
+{
+	let total_len: usize = lists.iter().map(|&(_, len, _)| len).sum();
+	let total_weight: f64 = lists.iter().map(|&(_, _, weight)| weight).sum();
+	let mut model = Model::default();
+	let mut context = DEFAULT_CONTEXT;
+	let mut merged_runs: FastHashMap<&str, f64> = FastHashMap::default();
+
+	let mut merged: FastHashMap<(&str, usize), Quartad<'a>> = FastHashMap::default();
+	for &(ref list, len, weight) in lists {
+		let scale = weight / total_weight * (total_len as f64) / (len as f64);
+		model = list.model.clone();
+		context = list.context;
+		for &(stretch, count) in list.runs.iter() {
+			*merged_runs.entry(stretch).or_insert(0.0) += count * scale;
+		}
+		for quartad in list.quartads.iter() {
+			let entry = merged.entry((quartad.string, quartad.arity)).or_insert(Quartad {
+				count: 0.0,
+				..*quartad
+			});
+			entry.count += quartad.count * scale;
+		}
+	}
+
+	let mut quartads = merged.into_values().collect();
+	sort_quartads(&mut quartads);
+	let mut runs: Vec<(&str, f64)> = merged_runs.into_iter().collect();
+	runs.sort_by(|a, b| a.0.cmp(b.0));
+
+	(QuartadList {
+		quartads,
+		model,
+		runs,
+		context,
+		baseline:    None,
+	}, total_len)
+}
+
+// Resolves an n-gram into character codes, most recent keystroke first, so that
+// evaluation can index straight into the position map.
+fn codes(ngram: &str)
+-> [u8; MAX_CONTEXT]
+{
+	let mut codes = [0u8; MAX_CONTEXT];
+	for (i, b) in ngram.bytes().rev().take(MAX_CONTEXT).enumerate() {
+		codes[i] = b;
+	}
+	codes
+}
+
+// Fixes the order in which quartads are summed, so that repeated runs give
+// identical totals.
+fn sort_quartads(quartads: &mut Vec<Quartad>)
+{
+	quartads.sort_by(|a, b| a.string.cmp(b.string).then(a.arity.cmp(&b.arity)));
+}
+
+// Scores a layout. Only with `detailed` is there a breakdown of the penalties,
+// each listing the n-grams that cost the most.
+pub fn calculate_penalty<'a>(
+	quartads:  &   QuartadList<'a>,
+	len:           usize,
+	layout:    &   Layout,
+	penalties: &'a Vec<KeyPenalty>,
+	detailed:      bool)
+-> PenaltyResult<'a>
+{
+	let position_map = quartads.position_map(layout);
+	calculate_penalty_for_map(quartads, len, &position_map, penalties, detailed)
+}
+
+// Like `calculate_penalty`, but for callers that already hold the layout's
+// position map (e.g. one patched from a neighbouring layout).
+pub fn calculate_penalty_for_map<'a>(
+	quartads:     &   QuartadList<'a>,
+	len:              usize,
+	position_map: &   LayoutPosMap,
+	penalties:    &'a Vec<KeyPenalty>,
+	detailed:         bool)
+-> PenaltyResult<'a>
+{
+	let QuartadList { ref quartads, ref model, ref runs, ref baseline, .. } = *quartads;
+	let mut result: Vec<KeyPenaltyResult> = Vec::new();
+	let mut total = 0.0;
+	let mut tally = Tally::new();
+
+	if detailed {
+		for penalty in penalties {
+			result.push(KeyPenaltyResult {
+				name: penalty.name,
+				total: 0.0,
+				high_keys: FastHashMap::default(),
+			});
+		}
+	}
+
+	for quartad in quartads {
+		total += penalty_for_quartad(quartad, position_map, model, penalties, &mut tally, &mut result, detailed);
+	}
+	total += finger_load_penalty(&tally.loads, &model.finger_load, len, penalties, &mut result, detailed);
+	total += hand_balance_penalty(&tally.loads, model.hand_balance, len, penalties, &mut result, detailed);
+	total += long_run_penalty(runs, position_map, model.space, penalties, &mut result, detailed);
+
+	let baseline = if detailed { baseline.clone() } else { None };
+	PenaltyResult { total, scaled: total / (len as f64), breakdown: result, positions: tally.positions, baseline }
+}
+
+// Scores a single n-gram keystroke by keystroke, returning the penalties that
+// apply to each keystroke given the ones before it.
+pub fn explain<'a>(
+	ngram:     &'a str,
+	layout:    &   Layout,
+	penalties: &'a Vec<KeyPenalty>,
+	model:     &   Model,
+	context:       usize)
+-> Vec<(&'a str, Vec<KeyPenaltyResult<'a>>)>
+{
+	let position_map = layout.position_map_for(model.model_shift);
+	let mut explanation = Vec::new();
+	for (end, c) in ngram.char_indices() {
+		let end = end + c.len_utf8();
+		let start = ngram[..end].char_indices().rev().nth(context - 1).map(|(i, _)| i).unwrap_or(0);
+		let quartad = &ngram[start..end];
+
+		let mut result: Vec<KeyPenaltyResult> = penalties.iter().map(|penalty| KeyPenaltyResult {
+			name: penalty.name,
+			total: 0.0,
+			high_keys: FastHashMap::default(),
+		}).collect();
+		if quartad.is_ascii() {
+			let quartad = Quartad {
+				string: quartad,
+				codes:  codes(quartad),
+				len:    quartad.len(),
+				count:  1.0,
+				arity:  0,
+			};
+			penalty_for_quartad(&quartad, &position_map, model, penalties, &mut Tally::new(), &mut result, true);
+		}
+		result.retain(|penalty| penalty.total != 0.0);
+		explanation.push((quartad, result));
+	}
+
+	explanation
+}
+
+// A word's penalty on its own, as if typed after a pause.
+pub struct WordPenalty<'a>
+{
+	pub word:    &'a str,
+	pub count:       usize,
+	pub penalty:     f64,
+}
+
+impl <'a> WordPenalty<'a>
+{
+	// What the word costs over the whole corpus.
+	pub fn total(&self)
+	-> f64
+	{
+		self.penalty * self.count as f64
+	}
+
+	pub fn per_char(&self)
+	-> f64
+	{
+		self.penalty / self.word.chars().count() as f64
+	}
+}
+
+// How often each n-gram of `n` characters is typed, most often first. Each
+// quartad read from text ends with the keystroke it was counted for, so its
+// last `n` characters count once; one read from an n-gram table counts if it
+// has `n` characters.
+pub fn ngram_frequencies<'b>(list: &'b QuartadList, n: usize)
+-> Vec<(&'b str, f64)>
+{
+	let mut counts: FastHashMap<&str, f64> = FastHashMap::default();
+	for quartad in list.quartads.iter() {
+		let ngram = if quartad.arity == 0 {
+			match quartad.string.char_indices().rev().nth(n - 1) {
+				Some((i, _)) => &quartad.string[i..],
+				None => continue,
 			}
+		} else if quartad.arity == n {
+			quartad.string
+		} else {
+			continue;
+		};
+		*counts.entry(ngram).or_insert(0.0) += quartad.count;
+	}
+	let mut frequencies: Vec<(&str, f64)> = counts.into_iter().collect();
+	frequencies.sort_by(|a, b| b.1.partial_cmp(&a.1).unwrap_or(Ordering::Equal).then(a.0.cmp(b.0)));
+	frequencies
+}
+
+// Counts the distinct whitespace-separated words of a corpus.
+pub fn count_words<'a>(corpus: &'a str, counts: &mut FastHashMap<&'a str, usize>)
+{
+	for word in corpus.split_whitespace() {
+		*counts.entry(word).or_insert(0) += 1;
+	}
+}
+
+// Scores each word by running it through the penalties from a fresh start,
+// one n-gram per keystroke like `explain`. Words that can't be typed on the
+// layout are left out.
+pub fn penalty_by_word<'a>(
+	words:     &FastHashMap<&'a str, usize>,
+	quartads:  &QuartadList,
+	layout:    &Layout,
+	penalties: &Vec<KeyPenalty>)
+-> Vec<WordPenalty<'a>>
+{
+	let QuartadList { ref model, context, .. } = *quartads;
+	let position_map = quartads.position_map(layout);
+	let mut result: Vec<KeyPenaltyResult> = Vec::with_capacity(penalties.len());
+	words.iter()
+		.filter(|&(word, _)| word.is_ascii() && word.chars().all(|c| position_map.get_key_position(c).is_some()))
+		.map(|(&word, &count)| {
+			let mut penalty = 0.0;
+			for end in 1..(word.len() + 1) {
+				let quartad = &word[end.saturating_sub(context)..end];
+				let quartad = Quartad {
+					string: quartad,
+					codes:  codes(quartad),
+					len:    quartad.len(),
+					count:  1.0,
+					arity:  0,
+				};
+				penalty += penalty_for_quartad(&quartad, &position_map, model, penalties, &mut Tally::new(), &mut result, false);
+			}
+			WordPenalty { word, count, penalty }
+		})
+		.collect()
+}
+
+// The penalty of each quartad on its own, for attributing the difference
+// between two layouts.
+pub fn penalty_by_quartad<'a>(
+	quartads:  &QuartadList<'a>,
+	layout:    &Layout,
+	penalties: &Vec<KeyPenalty>)
+-> FastHashMap<&'a str, f64>
+{
+	let position_map = quartads.position_map(layout);
+	let QuartadList { ref quartads, ref model, .. } = *quartads;
+	let mut result: Vec<KeyPenaltyResult> = Vec::with_capacity(penalties.len());
+	let mut by_quartad = FastHashMap::default();
+	for quartad in quartads {
+		let penalty = penalty_for_quartad(quartad, &position_map, model, penalties, &mut Tally::new(), &mut result, false);
+		*by_quartad.entry(quartad.string).or_insert(0.0) += penalty;
+	}
+
+	by_quartad
+}
+
+// A penalty that a layout and its mirror image incur differently.
+pub struct Asymmetry<'a>
+{
+	pub name:     &'a str,
+	pub layout:   f64,
+	pub mirrored: f64,
+}
+
+// Scores a layout and its mirror image on the quartads and runs typed wholly
+// on keys with a counterpart on the other hand, returning each penalty whose
+// totals differ. The default model treats the hands alike, so any that do
+// point to a rule that doesn't, or to effort, geometry or bigram penalties
+// that aren't symmetric.
+pub fn asymmetries<'a>(
+	quartads:  &   QuartadList<'a>,
+	len:           usize,
+	layout:    &   Layout,
+	penalties: &'a Vec<KeyPenalty>)
+-> Vec<Asymmetry<'a>>
+{
+	let position_map = quartads.position_map(layout);
+	let mirrorable = |s: &str| s.chars().all(|c| match *position_map.get_key_position(c) {
+		Some(ref kp) => mirror_position(kp.pos).is_some(),
+		None => false,
+	});
+	let symmetric = QuartadList {
+		quartads:    quartads.quartads.iter().filter(|quartad| mirrorable(quartad.string)).cloned().collect(),
+		model:       quartads.model.clone(),
+		runs:        quartads.runs.iter().filter(|&&(run, _)| mirrorable(run)).cloned().collect(),
+		baseline:    None,
+		..*quartads
+	};
+
+	let result = calculate_penalty(&symmetric, len, layout, penalties, true);
+	let mirrored = calculate_penalty(&symmetric, len, &layout.mirrored(), penalties, true);
+	result.breakdown.iter().zip(mirrored.breakdown.iter())
+		.filter(|&(a, b)| (a.total - b.total).abs() > 1e-9 * a.total.abs().max(b.total.abs()).max(1.0))
+		.map(|(a, b)| Asymmetry { name: a.name, layout: a.total, mirrored: b.total })
+		.collect()
+}
+
+// What scoring quartads adds up besides their penalties: the keystrokes typed
+// by each finger and, for detailed results, the position statistics.
+struct Tally
+{
+	loads:     FingerLoad,
+	positions: PositionStats,
+}
+
+impl Tally
+{
+	fn new()
+	-> Tally
+	{
+		Tally { loads: [0.0; 8], positions: PositionStats::new() }
+	}
+}
+
+// A keystroke to score along with the keystrokes before it. `slices` holds the
+// n-grams that end with it, shortest first.
+struct Stroke<'a, 'k>
+{
+	slices: [&'a str; MAX_CONTEXT],
+	count:  f64,
+	arity:  usize,
+	curr:   &'k KeyPress,
+	old:    [&'k Option<KeyPress>; MAX_CONTEXT - 1],
+	repeat: bool,
+}
+
+fn penalty_for_quartad<'a, 'b>(
+	quartad:      &'b Quartad<'a>,
+	position_map: &'b LayoutPosMap,
+	model:        &'b Model,
+	penalties:    &'b [KeyPenalty],
+	tally:        &'b mut Tally,
+	result:       &'b mut [KeyPenaltyResult<'a>],
+	detailed:         bool)
+-> f64
+{
+	let Quartad { string, ref codes, len, count, arity } = *quartad;
+	let Model { model_shift, space, .. } = *model;
+
+	// Expand the quartad into keystrokes, most recent first, along with the
+	// index of the character that each keystroke types. With shift modelled,
+	// a character from the upper layer takes two keystrokes.
+	let mut strokes: [(&Option<KeyPress>, usize); 2 * MAX_CONTEXT] = [(&KP_NONE, 0); 2 * MAX_CONTEXT];
+	let mut num_strokes = 0;
+	for i in 0..len {
+		let kp = if codes[i] == b' ' {
+			space_position(space, position_map, &codes[(i + 1)..len])
+		} else {
+			position_map.get_key_position_by_code(codes[i])
+		};
+		strokes[num_strokes] = (kp, i);
+		num_strokes += 1;
+		if let Some(ref kp) = *kp {
+			if model_shift && kp.shifted {
+				let shift = if kp.hand == Hand::Left { &RIGHT_SHIFT } else { &LEFT_SHIFT };
+				strokes[num_strokes] = (shift, i);
+				num_strokes += 1;
+			}
+		}
+	}
+
+	// Score every keystroke of the most recent character.
+	let mut total = 0.0;
+	for s in 0..num_strokes {
+		if strokes[s].1 != 0 {
+			break;
+		}
+		let curr = match *strokes[s].0 {
+			Some(ref kp) => kp,
+			None => { return total }
+		};
+
+		// Pressing the same key again continues the movement rather than
+		// starting a new one, so the older keystrokes skip repeats.
+		let same_key = |a: &Option<KeyPress>, b: &Option<KeyPress>| match (a, b) {
+			(Some(a), Some(b)) => a.pos == b.pos,
+			_ => false,
+		};
+		let repeat = s + 1 < num_strokes && same_key(strokes[s].0, strokes[s + 1].0);
+		let mut slices = [""; MAX_CONTEXT];
+		let mut old = [&KP_NONE; MAX_CONTEXT - 1];
+		slices[0] = &string[(len - 1 - strokes[s].1)..len];
+		let mut k = 1;
+		for t in (s + 1)..num_strokes {
+			if k == MAX_CONTEXT {
+				break;
+			}
+			if same_key(strokes[t].0, strokes[t - 1].0) {
+				continue;
+			}
+			slices[k] = &string[(len - 1 - strokes[t].1)..len];
+			old[k - 1] = strokes[t].0;
+			k += 1;
+		}
+		// A repeat is scored on the key it repeats, which the older
+		// keystrokes skipped.
+		if repeat {
+			slices[1] = &string[(len - 1 - strokes[s + 1].1)..len];
+		}
+
+		// Count the keystroke towards its finger's load.
+		if arity <= 1 {
+			if let Some(i) = finger_index(curr) {
+				tally.loads[i] += count;
+			}
+			if detailed {
+				tally.positions.presses.0[curr.pos] += count;
+			}
+		}
+
+		let stroke = Stroke { slices, count, arity, curr, old, repeat };
+		let penalty = penalize(&stroke, model, penalties, result, detailed);
+		if detailed {
+			tally.positions.penalty.0[curr.pos] += penalty;
+		}
+		total += penalty;
+	}
+
+	total
+}
+
+// Scores how far the share of keystrokes typed by each finger is from the
+// target. The squared differences are multiplied by the corpus length, so that
+// the scaled penalty is independent of it like the per-quartad penalties.
+fn finger_load_penalty<'a>(
+	loads:     &    FingerLoad,
+	target:    &    FingerLoad,
+	len:            usize,
+	penalties: &    [KeyPenalty],
+	result:    &mut [KeyPenaltyResult<'a>],
+	detailed:       bool)
+-> f64
+{
+	let weight = penalties[PenaltyKind::FingerLoad as usize].weight;
+	let sum: f64 = loads.iter().sum();
+	if weight == 0.0 || sum == 0.0 {
+		return 0.0;
+	}
+
+	let mut total = 0.0;
+	for i in 0..8 {
+		let diff = loads[i] / sum - target[i];
+		let penalty = weight * diff * diff * (len as f64);
+		if detailed {
+			*result[PenaltyKind::FingerLoad as usize].high_keys.entry(FINGER_NAMES[i]).or_insert(0.0) += penalty;
+			result[PenaltyKind::FingerLoad as usize].total += penalty;
 		}
+		total += penalty;
 	}
 
-	QuartadList(quartads)
+	total
 }
 
-pub fn calculate_penalty<'a>(
-	quartads:  &   QuartadList<'a>,
-	len:           usize,
-	layout:    &   Layout,
-	penalties: &'a Vec<KeyPenalty>,
-	detailed:      bool)
--> (f64, f64, Vec<KeyPenaltyResult<'a>>)
+// Scores each run of at least five keystrokes on one hand by the square of how
+// far it goes past three, listing the runs themselves as high keys.
+fn long_run_penalty<'a>(
+	runs:         &    [(&'a str, f64)],
+	position_map: &    LayoutPosMap,
+	space:             SpaceMode,
+	penalties:    &    [KeyPenalty],
+	result:       &mut [KeyPenaltyResult<'a>],
+	detailed:          bool)
+-> f64
 {
-	let QuartadList(ref quartads) = *quartads;
-	let mut result: Vec<KeyPenaltyResult> = Vec::new();
+	let weight = penalties[PenaltyKind::LongRun as usize].weight;
 	let mut total = 0.0;
-
-	if detailed {
-		for penalty in penalties {
-			result.push(KeyPenaltyResult {
-				name: penalty.name,
-				total: 0.0,
-				high_keys: HashMap::new(),
-			});
+	let mut score = |stretch: &'a str, start: usize, end: usize, count: f64| {
+		let over = (end - start - 3) as f64;
+		let penalty = weight * over * over * count;
+		if detailed {
+			*result[PenaltyKind::LongRun as usize].high_keys.entry(&stretch[start..end]).or_insert(0.0) += penalty;
+			result[PenaltyKind::LongRun as usize].total += penalty;
 		}
+		total += penalty;
+	};
+
+	// Look up each character's hand once rather than for every keystroke, as
+	// 0 for none, 1 for left and 2 for right, leaving a space that alternates
+	// to be worked out from the one before.
+	let mut hands = [0u8; 256];
+	for code in 0..128u8 {
+		hands[code as usize] = match hand_of(code, 0, position_map, space) {
+			None => 0,
+			Some(Hand::Left) => 1,
+			Some(Hand::Right) => 2,
+		};
 	}
+	let alternate = space == SpaceMode::Alternate;
 
-	let position_map = layout.get_position_map();
-	for (string, count) in quartads {
-		total += penalty_for_quartad(string, *count, &position_map, &mut result, detailed);
+	for &(stretch, count) in runs {
+		let bytes = stretch.as_bytes();
+		let mut run_hand = 0;
+		let mut start = 0;
+		for (i, &code) in bytes.iter().enumerate() {
+			let hand = if code == b' ' && alternate {
+				if run_hand == 2 { 1 } else { 2 }
+			} else {
+				hands[code as usize]
+			};
+			if hand != run_hand || hand == 0 {
+				if i - start >= LONG_RUN && run_hand != 0 {
+					score(stretch, start, i, count);
+				}
+				run_hand = hand;
+				start = i;
+			}
+		}
+		if bytes.len() - start >= LONG_RUN && run_hand != 0 {
+			score(stretch, start, bytes.len(), count);
+		}
 	}
 
-	(total, total / (len as f64), result)
+	total
 }
 
-fn penalty_for_quartad<'a, 'b>(
-	string:       &'a str,
-	count:            usize,
-	position_map: &'b LayoutPosMap,
-	result:       &'b mut Vec<KeyPenaltyResult<'a>>,
-	detailed:         bool)
+// Scores how far the share of keystrokes typed by the left hand is from the
+// target, scaled like the finger load penalty. Being a single number, it has no
+// high keys.
+fn hand_balance_penalty<'a>(
+	loads:     &    FingerLoad,
+	target:         f64,
+	len:            usize,
+	penalties: &    [KeyPenalty],
+	result:    &mut [KeyPenaltyResult<'a>],
+	detailed:       bool)
 -> f64
 {
-	let mut chars = string.chars().into_iter().rev();
-	let opt_curr = chars.next();
-	let opt_old1 = chars.next();
-	let opt_old2 = chars.next();
-	let opt_old3 = chars.next();
+	let sum: f64 = loads.iter().sum();
+	if sum == 0.0 {
+		return 0.0;
+	}
 
-	let curr = match opt_curr {
-		Some(c) => match position_map.get_key_position(c) {
-			&Some(ref kp) => kp,
-			&None => { return 0.0 }
-		},
-		None => panic!("unreachable")
-	};
-	let old1 = match opt_old1 {
-		Some(c) => position_map.get_key_position(c),
-		None => &KP_NONE
-	};
-	let old2 = match opt_old2 {
-		Some(c) => position_map.get_key_position(c),
-		None => &KP_NONE
-	};
-	let old3 = match opt_old3 {
-		Some(c) => position_map.get_key_position(c),
-		None => &KP_NONE
+	let left: f64 = loads[..4].iter().sum();
+	let diff = left / sum - target;
+	let penalty = penalties[PenaltyKind::HandBalance as usize].weight * diff * diff * (len as f64);
+	if detailed {
+		result[PenaltyKind::HandBalance as usize].total += penalty;
+	}
+
+	penalty
+}
+
+// The position of a key's finger in a FingerLoad, or None for the thumbs.
+fn finger_index(kp: &KeyPress)
+-> Option<usize>
+{
+	let i = match kp.finger {
+		Finger::Thumb  => { return None },
+		Finger::Pinky  => 0,
+		Finger::Ring   => 1,
+		Finger::Middle => 2,
+		Finger::Index  => 3,
 	};
+	Some(if kp.hand == Hand::Left { i } else { 7 - i })
+}
 
-	penalize(string, count, &curr, old1, old2, old3, result, detailed)
+// Where the space bar is pressed, given the older characters before it. An
+// alternating space with no keystroke before it goes to the right thumb.
+fn space_position<'a>(
+	space:        SpaceMode,
+	position_map: &'a LayoutPosMap,
+	older:        &   [u8])
+-> &'a Option<KeyPress>
+{
+	match space {
+		SpaceMode::Key | SpaceMode::Reset => position_map.get_key_position_by_code(b' '),
+		SpaceMode::Left  => &LEFT_THUMB,
+		SpaceMode::Right => &RIGHT_THUMB,
+		SpaceMode::Alternate => {
+			let prev = older.first().map(|&c| position_map.get_key_position_by_code(c));
+			match prev {
+				Some(Some(kp)) if kp.hand == Hand::Right => &LEFT_THUMB,
+				_ => &RIGHT_THUMB,
+			}
+		},
+	}
 }
 
-fn penalize<'a, 'b>(
-	string: &'a     str,
-	count:          usize,
-	curr:   &              KeyPress,
-	old1:   &       Option<KeyPress>,
-	old2:   &       Option<KeyPress>,
-	old3:   &       Option<KeyPress>,
-	result: &'b mut Vec<KeyPenaltyResult<'a>>,
+// Each penalty keeps its own block, even where it could share a condition.
+#[allow(clippy::collapsible_if)]
+fn penalize<'a>(
+	stroke:    &    Stroke<'a, '_>,
+	model:     &    Model,
+	penalties: &    [KeyPenalty],
+	result:    &mut [KeyPenaltyResult<'a>],
 	detailed:       bool)
 -> f64
 {
-	let len = string.len();
-	let count = count as f64;
+	let Stroke { ref slices, count, arity, curr, old, repeat } = *stroke;
+	let Model { same_finger_rows, ref effort, ref geometry, ref bigrams, .. } = *model;
 	let mut total = 0.0;
+	// A penalty over some keystrokes applies to the n-gram of the characters
+	// they type, which is fewer than the keystrokes when shift is modelled.
+	let scores = |keys: usize| arity == 0 || slices[keys - 1].chars().count() == arity;
+	let weight = |kind: PenaltyKind| penalties[kind as usize].weight;
 
 	// One key penalties.
-	let slice1 = &string[(len - 1)..len];
+	let slice1 = slices[0];
 
-	// 0: Base penalty.
-	let base = BASE_PENALTY.0[curr.pos] * count;
-	if detailed {
-		*result[0].high_keys.entry(slice1).or_insert(0.0) += base;
-		result[0].total += base;
+	// Base penalty.
+	if scores(1) {
+		let base = weight(PenaltyKind::Base) * effort.0[curr.pos] * count;
+		if detailed {
+			*result[PenaltyKind::Base as usize].high_keys.entry(slice1).or_insert(0.0) += base;
+			result[PenaltyKind::Base as usize].total += base;
+		}
+		total += base;
+
+		// Travel.
+		if let Some(ref geometry) = *geometry {
+			let penalty = weight(PenaltyKind::Travel) * geometry.travel(curr.pos) * count;
+			if detailed {
+				*result[PenaltyKind::Travel as usize].high_keys.entry(slice1).or_insert(0.0) += penalty;
+				result[PenaltyKind::Travel as usize].total += penalty;
+			}
+			total += penalty;
+		}
+
+		// Shift. Only the virtual shift keys type nothing.
+		if curr.kc == '\0' && weight(PenaltyKind::Shift) != 0.0 {
+			let penalty = weight(PenaltyKind::Shift) * count;
+			if detailed {
+				*result[PenaltyKind::Shift as usize].high_keys.entry(slice1).or_insert(0.0) += penalty;
+				result[PenaltyKind::Shift as usize].total += penalty;
+			}
+			total += penalty;
+		}
+
+		// Centre column.
+		if curr.center && weight(PenaltyKind::CentreColumn) != 0.0 {
+			let penalty = weight(PenaltyKind::CentreColumn) * count;
+			if detailed {
+				*result[PenaltyKind::CentreColumn as usize].high_keys.entry(slice1).or_insert(0.0) += penalty;
+				result[PenaltyKind::CentreColumn as usize].total += penalty;
+			}
+			total += penalty;
+		}
+	}
+
+	// Repeat. Pressing the key again is no new movement, so nothing else
+	// applies.
+	if repeat {
+		if scores(2) && weight(PenaltyKind::Repeat) != 0.0 {
+			let penalty = weight(PenaltyKind::Repeat) * count;
+			if detailed {
+				*result[PenaltyKind::Repeat as usize].high_keys.entry(slices[1]).or_insert(0.0) += penalty;
+				result[PenaltyKind::Repeat as usize].total += penalty;
+			}
+			total += penalty;
+		}
+		return total;
 	}
-	total += base;
+
+	let [old1, old2, old3, old4] = old;
 
 	// Two key penalties.
 	let old1 = match *old1 {
@@ -251,97 +2244,151 @@ fn penalize<'a, 'b>(
 		None => { return total }
 	};
 
-	if curr.hand == old1.hand {
-		let slice2 = &string[(len - 2)..len];
+	// Key pair.
+	if let Some(BigramPenalties(ref bigrams)) = *bigrams {
+		let extra = bigrams[old1.pos][curr.pos];
+		if extra != 0.0 && scores(2) {
+			let penalty = weight(PenaltyKind::KeyPair) * extra * count;
+			if detailed {
+				*result[PenaltyKind::KeyPair as usize].high_keys.entry(slices[1]).or_insert(0.0) += penalty;
+				result[PenaltyKind::KeyPair as usize].total += penalty;
+			}
+			total += penalty;
+		}
+	}
+
+	if curr.hand == old1.hand && scores(2) {
+		let slice2 = slices[1];
 
-		// 1: Same finger.
+		// Same finger.
 		if curr.finger == old1.finger && curr.pos != old1.pos {
-			let penalty = 5.0 + if curr.center { 5.0 } else { 0.0 }
-			                  + if old1.center { 5.0 } else { 0.0 };
-			let penalty = penalty * count;
+			let rows = match *geometry {
+				Some(ref geometry) => (geometry.coords.0[curr.pos].1 - geometry.coords.0[old1.pos].1).abs(),
+				None => row_distance(curr.row, old1.row),
+			};
+			let penalty = weight(PenaltyKind::SameFinger) * (1.0 + rows * same_finger_rows) * count;
+			if detailed {
+				*result[PenaltyKind::SameFinger as usize].high_keys.entry(slice2).or_insert(0.0) += penalty;
+				result[PenaltyKind::SameFinger as usize].total += penalty;
+			}
+			total += penalty;
+
+			// Same finger centre.
+			let centre = curr.center as u8 + old1.center as u8;
+			if centre > 0 {
+				let penalty = weight(PenaltyKind::SameFingerCentre) * centre as f64 * count;
+				if detailed {
+					*result[PenaltyKind::SameFingerCentre as usize].high_keys.entry(slice2).or_insert(0.0) += penalty;
+					result[PenaltyKind::SameFingerCentre as usize].total += penalty;
+				}
+				total += penalty;
+			}
+		}
+
+		// Centre bigram.
+		let centre = curr.center as u8 + old1.center as u8;
+		if centre > 0 && weight(PenaltyKind::CentreBigram) != 0.0 {
+			let penalty = weight(PenaltyKind::CentreBigram) * centre as f64 * count;
 			if detailed {
-				*result[1].high_keys.entry(slice2).or_insert(0.0) += penalty;
-				result[1].total += penalty;
+				*result[PenaltyKind::CentreBigram as usize].high_keys.entry(slice2).or_insert(0.0) += penalty;
+				result[PenaltyKind::CentreBigram as usize].total += penalty;
 			}
 			total += penalty;
 		}
 
-		// 2: Long jump hand.
-		if curr.row == Row::Top && old1.row == Row::Bottom ||
-		   curr.row == Row::Bottom && old1.row == Row::Top {
-			let penalty = count;
+		// Long jump hand.
+		if is_long_jump(curr.row, old1.row) {
+			let penalty = weight(PenaltyKind::LongJumpHand) * count;
 			if detailed {
-				*result[2].high_keys.entry(slice2).or_insert(0.0) += penalty;
-				result[2].total += penalty;
+				*result[PenaltyKind::LongJumpHand as usize].high_keys.entry(slice2).or_insert(0.0) += penalty;
+				result[PenaltyKind::LongJumpHand as usize].total += penalty;
 			}
 			total += penalty;
 		}
 
-		// 3: Long jump.
+		// Long jump.
 		if curr.hand == old1.hand && curr.finger == old1.finger {
-			if curr.row == Row::Top && old1.row == Row::Bottom ||
-			   curr.row == Row::Bottom && old1.row == Row::Top {
-				let penalty = 10.0 * count;
+			if is_long_jump(curr.row, old1.row) {
+				let penalty = weight(PenaltyKind::LongJump) * count;
 				if detailed {
-					*result[3].high_keys.entry(slice2).or_insert(0.0) += penalty;
-					result[3].total += penalty;
+					*result[PenaltyKind::LongJump as usize].high_keys.entry(slice2).or_insert(0.0) += penalty;
+					result[PenaltyKind::LongJump as usize].total += penalty;
 				}
 				total += penalty;
 			}
 		}
 
-		// 4: Long jump consecutive.
-		if curr.row == Row::Top && old1.row == Row::Bottom ||
-		   curr.row == Row::Bottom && old1.row == Row::Top {
+		// Long jump consecutive.
+		if is_long_jump(curr.row, old1.row) {
 			if curr.finger == Finger::Ring   && old1.finger == Finger::Pinky  ||
 			   curr.finger == Finger::Pinky  && old1.finger == Finger::Ring   ||
 			   curr.finger == Finger::Middle && old1.finger == Finger::Ring   ||
 			   curr.finger == Finger::Ring   && old1.finger == Finger::Middle ||
 			  (curr.finger == Finger::Index  && (old1.finger == Finger::Middle ||
 			                                     old1.finger == Finger::Ring) &&
-			   curr.row == Row::Top && old1.row == Row::Bottom) {
-				let penalty = 5.0 * count;
+			   row_index(curr.row) < row_index(old1.row)) {
+				let penalty = weight(PenaltyKind::LongJumpConsecutive) * count;
 				if detailed {
-					*result[4].high_keys.entry(slice2).or_insert(0.0) += penalty;
-					result[4].total += penalty;
+					*result[PenaltyKind::LongJumpConsecutive as usize].high_keys.entry(slice2).or_insert(0.0) += penalty;
+					result[PenaltyKind::LongJumpConsecutive as usize].total += penalty;
 				}
 				total += penalty;
 			}
 		}
 
-		// 5: Pinky/ring twist.
-		if (curr.finger == Finger::Ring && old1.finger == Finger::Pinky &&
-		    (curr.row == Row::Home && old1.row == Row::Top ||
-		     curr.row == Row::Bottom && old1.row == Row::Top)) ||
-		   (curr.finger == Finger::Pinky && old1.finger == Finger::Ring &&
-		    (curr.row == Row::Top && old1.row == Row::Home ||
-		     curr.row == Row::Top && old1.row == Row::Bottom)) {
-			let penalty = 10.0 * count;
+		// Pinky/ring twist.
+		let pinky_above = |pinky: Row, ring: Row| (pinky == Row::Top || pinky == Row::Number) && row_index(ring) > row_index(pinky);
+		if (curr.finger == Finger::Ring && old1.finger == Finger::Pinky && pinky_above(old1.row, curr.row)) ||
+		   (curr.finger == Finger::Pinky && old1.finger == Finger::Ring && pinky_above(curr.row, old1.row)) {
+			let penalty = weight(PenaltyKind::PinkyRingTwist) * count;
 			if detailed {
-				*result[5].high_keys.entry(slice2).or_insert(0.0) += penalty;
-				result[5].total += penalty;
+				*result[PenaltyKind::PinkyRingTwist as usize].high_keys.entry(slice2).or_insert(0.0) += penalty;
+				result[PenaltyKind::PinkyRingTwist as usize].total += penalty;
 			}
 			total += penalty;
 		}
 
-		// 9: Roll out.
+		// Roll out.
 		if curr.hand == old1.hand &&
 		   old1.finger != Finger::Thumb &&
 		   is_roll_out(curr.finger, old1.finger) {
-			let penalty = 0.125 * count;
+			let penalty = weight(PenaltyKind::RollOut) * count;
 			if detailed {
-				*result[9].high_keys.entry(slice2).or_insert(0.0) += penalty;
-				result[9].total += penalty;
+				*result[PenaltyKind::RollOut as usize].high_keys.entry(slice2).or_insert(0.0) += penalty;
+				result[PenaltyKind::RollOut as usize].total += penalty;
 			}
 			total += penalty;
 		}
 
-		// 10: Roll in.
+		// Roll in.
 		if curr.hand == old1.hand && is_roll_in(curr.finger, old1.finger) {
-			let penalty = -0.125 * count;
+			let thumb = curr.finger == Finger::Thumb || old1.finger == Finger::Thumb;
+			let adjacent = curr.row == old1.row && (curr.col as i32 - old1.col as i32).abs() == 1;
+			let penalty = weight(PenaltyKind::RollIn) * if thumb || adjacent { 1.0 } else { 0.5 } * count;
+			if detailed {
+				*result[PenaltyKind::RollIn as usize].high_keys.entry(slice2).or_insert(0.0) += penalty;
+				result[PenaltyKind::RollIn as usize].total += penalty;
+			}
+			total += penalty;
+		}
+
+		// Scissor.
+		if is_long_jump(curr.row, old1.row) && is_adjacent(curr.finger, old1.finger) {
+			let index_middle = curr.finger == Finger::Index || old1.finger == Finger::Index;
+			let penalty = weight(PenaltyKind::Scissor) * if index_middle { 0.5 } else { 1.0 } * count;
+			if detailed {
+				*result[PenaltyKind::Scissor as usize].high_keys.entry(slice2).or_insert(0.0) += penalty;
+				result[PenaltyKind::Scissor as usize].total += penalty;
+			}
+			total += penalty;
+		}
+
+		// Lateral stretch.
+		if old1.center && (curr.finger == Finger::Ring || curr.finger == Finger::Pinky) {
+			let penalty = weight(PenaltyKind::LateralStretch) * count;
 			if detailed {
-				*result[10].high_keys.entry(slice2).or_insert(0.0) += penalty;
-				result[10].total += penalty;
+				*result[PenaltyKind::LateralStretch as usize].high_keys.entry(slice2).or_insert(0.0) += penalty;
+				result[PenaltyKind::LateralStretch as usize].total += penalty;
 			}
 			total += penalty;
 		}
@@ -353,43 +2400,58 @@ fn penalize<'a, 'b>(
 		None => { return total },
 	};
 
-	if curr.hand == old1.hand && old1.hand == old2.hand {
-		// 6: Roll reversal.
+	if curr.hand == old1.hand && old1.hand == old2.hand && scores(3) {
+		// Roll reversal.
 		if (curr.finger == Finger::Middle && old1.finger == Finger::Pinky && old2.finger == Finger::Ring) ||
 		    curr.finger == Finger::Ring && old1.finger == Finger::Pinky && old2.finger == Finger::Middle {
-			let slice3 = &string[(len - 3)..len];
-			let penalty = 20.0 * count;
+			let slice3 = slices[2];
+			let penalty = weight(PenaltyKind::RollReversal) * count;
 			if detailed {
-				*result[6].high_keys.entry(slice3).or_insert(0.0) += penalty;
-				result[6].total += penalty;
+				*result[PenaltyKind::RollReversal as usize].high_keys.entry(slice3).or_insert(0.0) += penalty;
+				result[PenaltyKind::RollReversal as usize].total += penalty;
 			}
 			total += penalty;
 		}
 
-		// 12: Twist.
-		if ((curr.row == Row::Top && old1.row == Row::Home && old2.row == Row::Bottom) ||
-		    (curr.row == Row::Bottom && old1.row == Row::Home && old2.row == Row::Top)) &&
+		// Redirect.
+		if weight(PenaltyKind::Redirect) != 0.0 {
+			if let (Some(first), Some(second)) = (roll_direction(old1.finger, old2.finger), roll_direction(curr.finger, old1.finger)) {
+				if first != second {
+					let index = curr.finger == Finger::Index || old1.finger == Finger::Index || old2.finger == Finger::Index;
+					let slice3 = slices[2];
+					let penalty = weight(PenaltyKind::Redirect) * if index { 1.0 } else { 2.0 } * count;
+					if detailed {
+						*result[PenaltyKind::Redirect as usize].high_keys.entry(slice3).or_insert(0.0) += penalty;
+						result[PenaltyKind::Redirect as usize].total += penalty;
+					}
+					total += penalty;
+				}
+			}
+		}
+
+		// Twist.
+		let (a, b, c) = (row_index(curr.row), row_index(old1.row), row_index(old2.row));
+		if (a < b && b < c || a > b && b > c) && curr.row != Row::Thumb && old2.row != Row::Thumb &&
 		   ((is_roll_out(curr.finger, old1.finger) && is_roll_out(old1.finger, old2.finger)) ||
 		   	(is_roll_in(curr.finger, old1.finger) && is_roll_in(old1.finger, old2.finger))) {
-			let slice3 = &string[(len - 3)..len];
-			let penalty = 10.0 * count;
+			let slice3 = slices[2];
+			let penalty = weight(PenaltyKind::Twist) * count;
 			if detailed {
-				*result[12].high_keys.entry(slice3).or_insert(0.0) += penalty;
-				result[12].total += penalty;
+				*result[PenaltyKind::Twist as usize].high_keys.entry(slice3).or_insert(0.0) += penalty;
+				result[PenaltyKind::Twist as usize].total += penalty;
 			}
 			total += penalty;
 		}
 	}
 
-	// 11: Long jump sandwich.
-	if curr.hand == old2.hand && curr.finger == old2.finger {
-		if curr.row == Row::Top && old2.row == Row::Bottom ||
-		   curr.row == Row::Bottom && old2.row == Row::Top {
-			let penalty = 3.0 * count;
+	// Long jump sandwich.
+	if curr.hand == old2.hand && curr.finger == old2.finger && scores(3) {
+		if is_long_jump(curr.row, old2.row) {
+			let penalty = weight(PenaltyKind::LongJumpSandwich) * count;
 			if detailed {
-				let slice3 = &string[(len - 3)..len];
-				*result[11].high_keys.entry(slice3).or_insert(0.0) += penalty;
-				result[11].total += penalty;
+				let slice3 = slices[2];
+				*result[PenaltyKind::LongJumpSandwich as usize].high_keys.entry(slice3).or_insert(0.0) += penalty;
+				result[PenaltyKind::LongJumpSandwich as usize].total += penalty;
 			}
 			total += penalty;
 		}
@@ -401,22 +2463,42 @@ fn penalize<'a, 'b>(
 		None => { return total },
 	};
 
-	if curr.hand == old1.hand && old1.hand == old2.hand && old2.hand == old3.hand {
-		// 7: Same hand.
-		let slice4 = &string[(len - 4)..len];
-		let penalty = 0.5 * count;
-		if detailed {
-			*result[7].high_keys.entry(slice4).or_insert(0.0) += penalty;
-			result[7].total += penalty;
+	let same_hand = curr.hand == old1.hand && old1.hand == old2.hand && old2.hand == old3.hand;
+	if scores(4) {
+		if same_hand {
+			// Same hand.
+			let slice4 = slices[3];
+			let penalty = weight(PenaltyKind::SameHand) * count;
+			if detailed {
+				*result[PenaltyKind::SameHand as usize].high_keys.entry(slice4).or_insert(0.0) += penalty;
+				result[PenaltyKind::SameHand as usize].total += penalty;
+			}
+			total += penalty;
+		} else if curr.hand != old1.hand && old1.hand != old2.hand && old2.hand != old3.hand {
+			// Alternating hand.
+			let slice4 = slices[3];
+			let penalty = weight(PenaltyKind::AlternatingHand) * count;
+			if detailed {
+				*result[PenaltyKind::AlternatingHand as usize].high_keys.entry(slice4).or_insert(0.0) += penalty;
+				result[PenaltyKind::AlternatingHand as usize].total += penalty;
+			}
+			total += penalty;
 		}
-		total += penalty;
-	} else if curr.hand != old1.hand && old1.hand != old2.hand && old2.hand != old3.hand {
-		// 8: Alternating hand.
-		let slice4 = &string[(len - 4)..len];
-		let penalty = 0.5 * count;
+	}
+
+	// Five key penalties, only with a context of five.
+	let old4 = match *old4 {
+		Some(ref o) => o,
+		None => { return total },
+	};
+
+	// Same hand five.
+	if same_hand && old3.hand == old4.hand && scores(5) {
+		let slice5 = slices[4];
+		let penalty = weight(PenaltyKind::SameHandFive) * count;
 		if detailed {
-			*result[8].high_keys.entry(slice4).or_insert(0.0) += penalty;
-			result[8].total += penalty;
+			*result[PenaltyKind::SameHandFive as usize].high_keys.entry(slice5).or_insert(0.0) += penalty;
+			result[PenaltyKind::SameHandFive as usize].total += penalty;
 		}
 		total += penalty;
 	}
@@ -424,6 +2506,46 @@ fn penalize<'a, 'b>(
 	total
 }
 
+// How far down the board a row is, counting the thumb keys as a row below the
+// bottom row.
+fn row_index(row: Row)
+-> i32
+{
+	match row {
+		Row::Number => 0,
+		Row::Top    => 1,
+		Row::Home   => 2,
+		Row::Bottom => 3,
+		Row::Thumb  => 4,
+	}
+}
+
+// How many rows apart two keys are.
+fn row_distance(a: Row, b: Row)
+-> f64
+{
+	(row_index(a) - row_index(b)).abs() as f64
+}
+
+// Whether moving between two rows jumps over another, such as from the top row
+// to the bottom row or from the number row to the home row. The thumbs move on
+// their own, so they never jump.
+fn is_long_jump(a: Row, b: Row)
+-> bool
+{
+	a != Row::Thumb && b != Row::Thumb && row_distance(a, b) >= 2.0
+}
+
+fn is_adjacent(a: Finger, b: Finger) -> bool {
+	match a {
+		Finger::Thumb  => false,
+		Finger::Index  => b == Finger::Middle,
+		Finger::Middle => b == Finger::Index || b == Finger::Ring,
+		Finger::Ring   => b == Finger::Middle || b == Finger::Pinky,
+		Finger::Pinky  => b == Finger::Ring,
+	}
+}
+
 fn is_roll_out(curr: Finger, prev: Finger) -> bool {
 	match curr {
 		Finger::Thumb  => false,
@@ -434,6 +2556,16 @@ fn is_roll_out(curr: Finger, prev: Finger) -> bool {
 	}
 }
 
+// Whether moving from `prev` to `curr` rolls in, towards the index finger, or
+// out. None for the same finger or a thumb, which don't roll.
+fn roll_direction(curr: Finger, prev: Finger) -> Option<bool> {
+	if curr == prev || curr == Finger::Thumb || prev == Finger::Thumb {
+		None
+	} else {
+		Some(is_roll_in(curr, prev))
+	}
+}
+
 fn is_roll_in(curr: Finger, prev: Finger) -> bool {
 	match curr {
 		Finger::Thumb  => prev != Finger::Thumb,
@@ -443,3 +2575,673 @@ fn is_roll_in(curr: Finger, prev: Finger) -> bool {
 		Finger::Pinky  => false,
 	}
 }
+
+#[cfg(test)]
+mod tests
+{
+	use layout;
+	use super::*;
+
+	// The total of one penalty over every keystroke of `ngram` on Qwerty.
+	fn explained(ngram: &str, kind: PenaltyKind)
+	-> f64
+	{
+		explained_with(&layout::QWERTY_LAYOUT, ngram, kind, &init(), &Model::default())
+	}
+
+	// The total of one penalty over the keystrokes of an n-gram, as `explain`
+	// lists them for this layout, weights and model.
+	fn explained_with(layout: &Layout, ngram: &str, kind: PenaltyKind, penalties: &Vec<KeyPenalty>, model: &Model)
+	-> f64
+	{
+		explain(ngram, layout, penalties, model, DEFAULT_CONTEXT).iter()
+			.flat_map(|(_, result)| result.iter())
+			.filter(|penalty| penalty.name == kind.name())
+			.map(|penalty| penalty.total)
+			.sum()
+	}
+
+	#[test]
+	fn bigram_penalties()
+	{
+		let bigrams = parse_bigram_penalties("# Q to A.\n0 11 2.5\n\n32 > 22 1\n").unwrap();
+		assert_eq!((bigrams.0[0][11], bigrams.0[11][0]), (2.5, 2.5));
+		assert_eq!((bigrams.0[32][22], bigrams.0[22][32]), (1.0, 0.0));
+		match parse_bigram_penalties("0 44 1") {
+			Err(BigramParseError::BadPosition { line: 1, .. }) => (),
+			_ => panic!("expected a bad position"),
+		}
+		match parse_bigram_penalties("0 1\n") {
+			Err(BigramParseError::BadLine { line: 1 }) => (),
+			_ => panic!("expected a bad line"),
+		}
+
+		// Scored for every pair of keystrokes, whichever hands type them.
+		let penalties = init();
+		let model = Model { bigrams: Some(parse_bigram_penalties("0 11 2.5\n0 > 16 1").unwrap()), ..Model::default() };
+		let key_pair = |ngram| explained_with(&layout::QWERTY_LAYOUT, ngram, PenaltyKind::KeyPair, &penalties, &model);
+		assert_eq!(key_pair("qa"), 2.5);
+		assert_eq!(key_pair("aq"), 2.5);
+		assert_eq!(key_pair("qh"), 1.0);
+		assert_eq!(key_pair("hq"), 0.0);
+	}
+
+	#[test]
+	fn calibrate()
+	{
+		let timings = parse_timings("key,ms\na,100\na,140\na,300\ns,200\nD,400\n,,250\n").unwrap();
+		assert_eq!(timings.len(), 6);
+		match parse_timings("a,100\nb,slow\n") {
+			Err(TimingsParseError::BadTime { line: 2, .. }) => (),
+			_ => panic!("expected a bad time"),
+		}
+
+		let (effort, missing) = calibrate_effort(&timings, &layout::QWERTY_LAYOUT, &BASE_PENALTY);
+		// Medians of 140, 200, 400 and 250 milliseconds.
+		assert_eq!(effort.0[11], 0.0);
+		assert_eq!(effort.0[13], 4.0);
+		assert!((effort.0[12] - 4.0 * 60.0 / 260.0).abs() < 1e-9);
+		assert!((effort.0[29] - 4.0 * 110.0 / 260.0).abs() < 1e-9);
+		assert_eq!(effort.0[0], BASE_PENALTY.0[0]);
+		assert!(missing.contains(&0) && !missing.contains(&11));
+		assert_eq!(missing.len(), 40);
+
+		let written = write_effort(&effort, false);
+		let read = parse_effort(&written).unwrap();
+		assert_eq!(read.0[13], 4.0);
+		assert_eq!(read.0[0], BASE_PENALTY.0[0]);
+		let mut numbers = effort;
+		numbers.0[34] = 0.5;
+		assert_eq!(written.lines().count() + 1, write_effort(&numbers, true).lines().count());
+		assert_eq!(parse_effort(&write_effort(&numbers, true)).unwrap().0[34], 0.5);
+		assert_eq!(read.0[34], BASE_PENALTY.0[34]);
+	}
+
+	#[test]
+	fn roll_in_columns()
+	{
+		// Next column along the same row.
+		assert_eq!(explained("sd", PenaltyKind::RollIn), -0.125);
+		assert_eq!(explained("lk", PenaltyKind::RollIn), -0.125);
+		// Skipping a column, or changing rows.
+		assert_eq!(explained("sf", PenaltyKind::RollIn), -0.0625);
+		assert_eq!(explained("dg", PenaltyKind::RollIn), -0.0625);
+		assert_eq!(explained("we", PenaltyKind::RollIn), -0.125);
+		assert_eq!(explained("wd", PenaltyKind::RollIn), -0.0625);
+		// Onto the thumb is unchanged.
+		assert_eq!(explained("k ", PenaltyKind::RollIn), -0.125);
+	}
+
+	#[test]
+	fn kinds_match_breakdown_order()
+	{
+		let penalties = init();
+		assert_eq!(penalties.len(), PENALTY_KINDS.len());
+		for (i, (penalty, &kind)) in penalties.iter().zip(PENALTY_KINDS.iter()).enumerate() {
+			assert_eq!((penalty.kind, kind as usize, penalty.name), (kind, i, kind.name()));
+			assert_eq!(kind.name().parse::<PenaltyKind>(), Ok(kind));
+		}
+
+		let layout = &layout::QWERTY_LAYOUT;
+		let (quartads, _) = prepare_quartad_list("the quick brown fox", &layout.get_position_map(), &Model::default(), &[]);
+		let result = calculate_penalty(&quartads, 19, layout, &penalties, true);
+		let names: Vec<&str> = result.breakdown.iter().map(|penalty| penalty.name).collect();
+		assert_eq!(names, PENALTY_KINDS.iter().map(|kind| kind.name()).collect::<Vec<&str>>());
+	}
+
+	#[test]
+	fn presets()
+	{
+		assert!(preset("prose").is_some());
+		assert!(preset("poetry").is_none());
+
+		// Shift is only scored when modelled.
+		let mut penalties = init();
+		penalties[PenaltyKind::Shift as usize].weight = 1.0;
+		let shifts = |model_shift| explained_with(&layout::QWERTY_LAYOUT, "Ab", PenaltyKind::Shift, &penalties, &Model { model_shift, ..Model::default() });
+		assert_eq!(shifts(true), 1.0);
+		assert_eq!(shifts(false), 0.0);
+	}
+
+	#[test]
+	fn redirect()
+	{
+		// Nothing by default.
+		assert_eq!(explained("sad", PenaltyKind::Redirect), 0.0);
+
+		let mut penalties = init();
+		penalties[PenaltyKind::Redirect as usize].weight = 2.0;
+		let explained = |ngram, kind| explained_with(&layout::QWERTY_LAYOUT, ngram, kind, &penalties, &Model::default());
+		// Ring, pinky, middle: out then in with no index finger.
+		assert_eq!(explained("sad", PenaltyKind::Redirect), 4.0);
+		assert_eq!(explained("was", PenaltyKind::Redirect), 4.0);
+		// Index, middle, index.
+		assert_eq!(explained("ref", PenaltyKind::Redirect), 2.0);
+		// Rolls one way, and a same finger pair, don't change direction.
+		assert_eq!(explained("wer", PenaltyKind::Redirect), 0.0);
+		assert_eq!(explained("pol", PenaltyKind::Redirect), 0.0);
+		// Nor do trigrams that leave the hand.
+		assert_eq!(explained("sid", PenaltyKind::Redirect), 0.0);
+	}
+
+	#[test]
+	fn centre_column()
+	{
+		// Nothing by default but the same finger surcharge.
+		assert_eq!(explained("tg", PenaltyKind::SameFingerCentre), 10.0);
+		assert_eq!(explained("tg", PenaltyKind::CentreColumn), 0.0);
+		assert_eq!(explained("tg", PenaltyKind::CentreBigram), 0.0);
+
+		let mut penalties = init();
+		penalties[PenaltyKind::CentreColumn as usize].weight = 1.0;
+		penalties[PenaltyKind::CentreBigram as usize].weight = 2.0;
+		penalties[PenaltyKind::SameFingerCentre as usize].weight = 0.0;
+		let totals = |ngram: &str| -> Vec<f64> {
+			let mut totals = vec![0.0; penalties.len()];
+			for (_, result) in explain(ngram, &layout::QWERTY_LAYOUT, &penalties, &Model::default(), DEFAULT_CONTEXT).iter() {
+				for penalty in result.iter() {
+					let i = penalties.iter().position(|p| p.name == penalty.name).unwrap();
+					totals[i] += penalty.total;
+				}
+			}
+			totals
+		};
+		// Two centre keys, both on one hand.
+		let tg = totals("tg");
+		assert_eq!((tg[PenaltyKind::CentreColumn as usize], tg[PenaltyKind::CentreBigram as usize], tg[PenaltyKind::SameFingerCentre as usize]), (2.0, 4.0, 0.0));
+		// The bigram surcharge needs both keys on one hand.
+		let hg = totals("hg");
+		assert_eq!((hg[PenaltyKind::CentreColumn as usize], hg[PenaltyKind::CentreBigram as usize]), (2.0, 0.0));
+		let ta = totals("ta");
+		assert_eq!((ta[PenaltyKind::CentreColumn as usize], ta[PenaltyKind::CentreBigram as usize]), (1.0, 2.0));
+	}
+
+	#[test]
+	fn same_finger_rows()
+	{
+		// One row apart, then two.
+		assert_eq!(explained("ed", PenaltyKind::SameFinger), 7.5);
+		assert_eq!(explained("ce", PenaltyKind::SameFinger), 10.0);
+		// The centre column surcharge is its own penalty.
+		assert_eq!(explained("fb", PenaltyKind::SameFinger), 7.5);
+		assert_eq!(explained("fb", PenaltyKind::SameFingerCentre), 5.0);
+
+		let flat = Model { same_finger_rows: 0.0, ..Model::default() };
+		assert_eq!(explained_with(&layout::QWERTY_LAYOUT, "ce", PenaltyKind::SameFinger, &init(), &flat), 5.0);
+	}
+
+	#[test]
+	fn scissor()
+	{
+		// Middle to ring finger, top to bottom row.
+		assert_eq!(explained("ex", PenaltyKind::Scissor), 5.0);
+		// Ring to pinky finger, bottom to top row.
+		assert_eq!(explained("xq", PenaltyKind::Scissor), 5.0);
+		// Middle to index finger is half as bad.
+		assert_eq!(explained("cr", PenaltyKind::Scissor), 2.5);
+		// Not for fingers that aren't adjacent, nor for a one-row gap.
+		assert_eq!(explained("qc", PenaltyKind::Scissor), 0.0);
+		assert_eq!(explained("ed", PenaltyKind::Scissor), 0.0);
+		assert_eq!(explained("ez", PenaltyKind::Scissor), 0.0);
+	}
+
+	#[test]
+	fn lateral_stretch()
+	{
+		// From the centre column to the pinky and ring fingers.
+		assert_eq!(explained("ga", PenaltyKind::LateralStretch), 2.0);
+		assert_eq!(explained("ts", PenaltyKind::LateralStretch), 2.0);
+		// Not in the other order, nor onto the middle finger or other hand.
+		assert_eq!(explained("ag", PenaltyKind::LateralStretch), 0.0);
+		assert_eq!(explained("gd", PenaltyKind::LateralStretch), 0.0);
+		assert_eq!(explained("hs", PenaltyKind::LateralStretch), 0.0);
+	}
+
+	#[test]
+	fn parse_effort_grid()
+	{
+		let grid = "3 1 1 1.5 3 | 3 1.5 1 1 3 4\n\
+		            0.5 0.5 0 0 1.5 | 1.5 0 0 0.5 0.5 2\n\
+		            2 2 1.5 1.5 2.5 | 2.5 1.5 1.5 2 2 0 0\n";
+		assert!(parse_effort(grid).unwrap() == BASE_PENALTY);
+
+		match parse_effort("1 2 3 4 5 | 6 7 8 9 10 11\n4 x\n6\n") {
+			Err(EffortParseError::BadNumber { line: 2, column: 3, .. }) => (),
+			e => panic!("unexpected {:?}", e.map(|_| ())),
+		}
+		match parse_effort("1 2 3\n4 5\n6\n") {
+			Err(EffortParseError::KeyCount { line: 1, found: 3, expected: 11 }) => (),
+			e => panic!("unexpected {:?}", e.map(|_| ())),
+		}
+	}
+
+	#[test]
+	fn finger_load()
+	{
+		// Off by default, so it comes from a weights file.
+		let mut penalties = init();
+		assert_eq!(penalties[PenaltyKind::FingerLoad as usize].weight, 0.0);
+		for (kind, weight) in parse_weights("finger load = 20").unwrap().weights {
+			penalties[kind as usize].weight = weight;
+		}
+		let mut result = Vec::new();
+
+		// Any multiple of the target is on target.
+		let on_target: Vec<f64> = DEFAULT_FINGER_LOAD.iter().map(|x| x * 300.0).collect();
+		let mut loads = [0.0; 8];
+		loads.copy_from_slice(&on_target);
+		let penalty = finger_load_penalty(&loads, &DEFAULT_FINGER_LOAD, 100, &penalties, &mut result, false);
+		assert!(penalty.abs() < 1e-9);
+
+		// Everything on the left index finger, whose target is 0.16.
+		let loads = [0.0, 0.0, 0.0, 5.0, 0.0, 0.0, 0.0, 0.0];
+		let squares: f64 = DEFAULT_FINGER_LOAD.iter().map(|x| x * x).sum::<f64>()
+			- 0.16 * 0.16 + 0.84 * 0.84;
+		let penalty = finger_load_penalty(&loads, &DEFAULT_FINGER_LOAD, 100, &penalties, &mut result, false);
+		assert!((penalty - 20.0 * squares * 100.0).abs() < 1e-9);
+	}
+
+	#[test]
+	fn weights_file()
+	{
+		let weights = parse_weights("# Rolls\nroll in = -0.5\n\nfinger load target = 8, 11, 15, 16, 16, 15, 11, 8\n").unwrap();
+		assert_eq!(weights.weights.len(), 1);
+		assert_eq!(weights.weights[0].0 as usize, PenaltyKind::RollIn as usize);
+		assert_eq!(weights.weights[0].1, -0.5);
+		assert_eq!(weights.finger_load, Some([8.0, 11.0, 15.0, 16.0, 16.0, 15.0, 11.0, 8.0]));
+
+		match parse_weights("roll in -0.5") {
+			Err(WeightsParseError::BadLine { line: 1 }) => (),
+			_ => panic!("expected a bad line"),
+		}
+		match parse_weights("\nroll sideways = 1") {
+			Err(WeightsParseError::UnknownPenalty { line: 2, ref name }) if name == "roll sideways" => (),
+			_ => panic!("expected an unknown penalty"),
+		}
+		match parse_weights("finger load = lots") {
+			Err(WeightsParseError::BadWeight { line: 1, ref found }) if found == "lots" => (),
+			_ => panic!("expected a bad weight"),
+		}
+		match parse_weights("finger load target = 1, 2") {
+			Err(WeightsParseError::BadFingerLoad { line: 1, .. }) => (),
+			_ => panic!("expected a bad finger load"),
+		}
+	}
+
+	#[test]
+	fn hand_balance()
+	{
+		// Off by default, so it comes from a weights file.
+		let mut penalties = init();
+		assert_eq!(penalties[PenaltyKind::HandBalance as usize].weight, 0.0);
+		for (kind, weight) in parse_weights("hand balance = 20").unwrap().weights {
+			penalties[kind as usize].weight = weight;
+		}
+		let mut result = Vec::new();
+
+		// Perfectly balanced, however the hands share the load between fingers.
+		let loads = [1.0, 2.0, 3.0, 4.0, 10.0, 0.0, 0.0, 0.0];
+		let penalty = hand_balance_penalty(&loads, 0.5, 100, &penalties, &mut result, false);
+		assert_eq!(penalty, 0.0);
+
+		// Entirely one-handed, against a target of 50/50 and of 55/45.
+		let loads = [0.0, 0.0, 0.0, 0.0, 4.0, 3.0, 2.0, 1.0];
+		let penalty = hand_balance_penalty(&loads, 0.5, 100, &penalties, &mut result, false);
+		assert_eq!(penalty, 20.0 * 0.25 * 100.0);
+		let penalty = hand_balance_penalty(&loads, 0.55, 100, &penalties, &mut result, false);
+		assert!((penalty - 20.0 * 0.55 * 0.55 * 100.0).abs() < 1e-9);
+
+		// A target of 55/45 prefers the left hand.
+		let loads = [3.0, 0.0, 0.0, 8.0, 9.0, 0.0, 0.0, 0.0];
+		let penalty = hand_balance_penalty(&loads, 0.55, 100, &penalties, &mut result, false);
+		assert!(penalty.abs() < 1e-9);
+	}
+
+	#[test]
+	fn number_row_jumps()
+	{
+		let mut layout = layout::QWERTY_LAYOUT.clone();
+		let mut board = *layout.board();
+		board.add_number_row();
+		layout.set_board(board);
+		layout.fill_number_row(&layout::ShiftPairs::us());
+		let penalties = init();
+		let explained = |ngram, kind| explained_with(&layout, ngram, kind, &penalties, &Model::default());
+
+		assert_eq!(explained("1", PenaltyKind::Base), BASE_PENALTY.0[34]);
+		// From the number row over the top row, on one finger.
+		assert!(explained("3d", PenaltyKind::LongJump) > 0.0 && explained("3d", PenaltyKind::LongJumpHand) > 0.0);
+		assert!(explained("3d", PenaltyKind::SameFinger) > explained("ed", PenaltyKind::SameFinger));
+		assert_eq!(explained("3e", PenaltyKind::LongJump), 0.0);
+		assert!(explained("2c", PenaltyKind::LongJumpConsecutive) > 0.0 && explained("3f", PenaltyKind::Scissor) > 0.0);
+		assert!(explained("1w", PenaltyKind::PinkyRingTwist) > 0.0);
+		assert_eq!(explained("w1", PenaltyKind::PinkyRingTwist), explained("1w", PenaltyKind::PinkyRingTwist));
+		assert!(explained("2ef", PenaltyKind::Twist) > 0.0);
+		assert!(explained("3kd", PenaltyKind::LongJumpSandwich) > 0.0);
+	}
+
+	#[test]
+	fn favour_left_hand()
+	{
+		let effort = favour_hand(&BASE_PENALTY, Hand::Left, 2.0);
+		assert_eq!(effort.0[0], BASE_PENALTY.0[0]);
+		assert_eq!(effort.0[9], BASE_PENALTY.0[9] * 2.0);
+		assert_eq!(effort.0[21], BASE_PENALTY.0[21] * 2.0);
+		assert_eq!(effort.0[33], BASE_PENALTY.0[33]);
+
+		// Only the right hand's keystrokes cost more.
+		let penalties = init();
+		let base = |ngram, effort| explained_with(&layout::QWERTY_LAYOUT, ngram, PenaltyKind::Base, &penalties, &Model { effort, ..Model::default() });
+		assert_eq!(base("p", effort), base("p", BASE_PENALTY) * 2.0);
+		assert_eq!(base("q", effort), base("q", BASE_PENALTY));
+	}
+
+	#[test]
+	fn mirrored_layouts_score_alike()
+	{
+		let corpus = "The quick brown fox jumps over the lazy dog. Pack my box with five dozen liquor jugs!\n\
+		              How vexingly quick daft zebras jump; sphinx of black quartz, judge my vow.";
+		let penalties = init();
+		for &(name, layout) in layout::REFERENCE_LAYOUTS.iter() {
+			let (quartads, _) = prepare_quartad_list(corpus, &layout.get_position_map(), &Model { model_shift: true, ..Model::default() }, &[]);
+			let asymmetries: Vec<&str> = asymmetries(&quartads, corpus.len(), layout, &penalties).iter().map(|a| a.name).collect();
+			assert!(asymmetries.is_empty(), "{} scores differently mirrored: {:?}", name, asymmetries);
+		}
+
+		// Favouring a hand is meant to break the symmetry, in the base penalty.
+		let favoured = Model { effort: favour_hand(&BASE_PENALTY, Hand::Left, 1.2), ..Model::default() };
+		let (quartads, _) = prepare_quartad_list(corpus, &layout::QWERTY_LAYOUT.get_position_map(), &favoured, &[]);
+		let asymmetries: Vec<&str> = asymmetries(&quartads, corpus.len(), &layout::QWERTY_LAYOUT, &penalties).iter().map(|a| a.name).collect();
+		assert_eq!(asymmetries, vec!["base"]);
+	}
+
+	#[test]
+	fn hand_shares()
+	{
+		let mut stats = PositionStats::new();
+		assert_eq!(stats.hand_shares(), (0.0, 0.0));
+		stats.presses.0[0] = 3.0;
+		stats.presses.0[20] = 1.0;
+		stats.presses.0[32] = 100.0;
+		assert_eq!(stats.hand_shares(), (0.75, 0.25));
+	}
+
+	#[test]
+	fn penalty_result_display()
+	{
+		let mut high_keys = FastHashMap::default();
+		high_keys.insert("th", 3.0);
+		high_keys.insert("he", -5.0);
+		high_keys.insert("in", 1.0);
+		let result = PenaltyResult {
+			total: 10.0,
+			scaled: 0.5,
+			breakdown: vec![KeyPenaltyResult { name: "base", total: 2.5, high_keys }],
+			positions: PositionStats::new(),
+			baseline:  None,
+		};
+		assert_eq!(format!("{}", result), "total: 10; scaled: 0.5\nbase: 2.5 (25.00%)");
+		assert_eq!(format!("{:.2}", result), "total: 10; scaled: 0.5\nbase: 2.5 (25.00%)  /  he: -5; th: 3;");
+		let result = PenaltyResult { baseline: Some(("QWERTY".to_string(), 0.8)), ..result };
+		assert_eq!(format!("{}", result), "total: 10; scaled: 0.5 (62.5% of QWERTY)\nbase: 2.5 (25.00%)");
+
+		// Nothing scored has no shares to divide.
+		let breakdown = vec![KeyPenaltyResult { name: "base", total: 0.0, high_keys: FastHashMap::default() }];
+		let result = PenaltyResult { total: 0.0, scaled: 0.0, breakdown, baseline: None, ..result };
+		assert_eq!(format!("{}", result), "total: 0; scaled: 0\nbase: 0 (0.00%)");
+	}
+
+	#[test]
+	fn counts_round_trip()
+	{
+		let penalties = init();
+		let layout = &layout::QWERTY_LAYOUT;
+		let pos_map = layout.get_position_map();
+		let corpus = "the quick brown fox jumps over the lazy dog\nthe end";
+		let (quartads, _) = prepare_quartad_list(corpus, &pos_map, &Model::default(), &[]);
+		let (counts, left_out) = write_quartad_counts(&quartads, corpus.len(), &[]);
+		let (read, len) = parse_quartad_counts(&counts, &pos_map, &Model::default(), &[]).unwrap();
+		assert_eq!(len, corpus.len());
+		assert_eq!(read.len() + left_out, quartads.len());
+		assert_eq!(calculate_penalty(&read, len, layout, &penalties, false).total,
+		           calculate_penalty(&quartads, len, layout, &penalties, false).total);
+
+		assert!(parse_quartad_counts("th 3", &pos_map, &Model::default(), &[]).is_err());
+
+		// Counts made another way can't be read back as this run's.
+		let shifted = Model { model_shift: true, ..Model::default() };
+		assert_eq!(parse_quartad_counts(&counts, &pos_map, &shifted, &[]).err().unwrap().to_string(),
+			"made with model-shift false, not true");
+		assert_eq!(parse_quartad_counts(&counts, &pos_map, &Model::default(), &['\n']).err().unwrap().to_string(),
+			"made with reset-on \"\", not \"\\n\"");
+		let (counts, _) = write_quartad_counts(&quartads, corpus.len(), &[' ', '\n', ' ']);
+		assert!(parse_quartad_counts(&counts, &pos_map, &Model::default(), &['\n', ' ']).is_ok());
+	}
+
+	#[test]
+	fn word_penalties()
+	{
+		let penalties = init();
+		let quartads = QuartadList {
+			quartads:    Vec::new(),
+			model:       Model::default(),
+			runs:        Vec::new(),
+			context:     DEFAULT_CONTEXT,
+			baseline:    None,
+		};
+		let mut words = FastHashMap::default();
+		count_words("the cat sat on the mat\n\tthe end é", &mut words);
+		assert_eq!(words["the"], 3);
+
+		let scored = penalty_by_word(&words, &quartads, &layout::QWERTY_LAYOUT, &penalties);
+		assert!(!scored.iter().any(|w| w.word == "é"));
+		let the = scored.iter().find(|w| w.word == "the").unwrap();
+		let explained: f64 = explain("the", &layout::QWERTY_LAYOUT, &penalties, &Model::default(), DEFAULT_CONTEXT).iter()
+			.flat_map(|(_, result)| result.iter())
+			.map(|penalty| penalty.total)
+			.sum();
+		assert!((the.penalty - explained).abs() < 1e-9);
+		assert!((the.total() - 3.0 * explained).abs() < 1e-9);
+	}
+
+	#[test]
+	fn hand_runs()
+	{
+		let position_map = layout::QWERTY_LAYOUT.get_position_map();
+		let mut runs = HandRuns::new();
+		// "sweater" is all left hand on Qwerty; "9" can't be typed and ends
+		// the run of "you".
+		count_hand_runs("sweater\nyou9on", &position_map, SpaceMode::Key, &[], &mut runs);
+		assert_eq!(runs.left, vec![0, 0, 0, 0, 0, 0, 1]);
+		assert_eq!(runs.right, vec![0, 1, 1]);
+		assert_eq!(runs.mean(Hand::Left), 7.0);
+		assert_eq!(runs.mean(Hand::Right), 2.5);
+	}
+
+	#[test]
+	fn long_runs()
+	{
+		let penalties = init();
+		let mut quartads = QuartadList {
+			quartads:    Vec::new(),
+			model:       Model { space: SpaceMode::Reset, ..Model::default() },
+			runs:        Vec::new(),
+			context:     DEFAULT_CONTEXT,
+			baseline:    None,
+		};
+		let text = "sweater\nsweater on you";
+		assert_eq!(calculate_penalty(&quartads, text.len(), &layout::QWERTY_LAYOUT, &penalties, true).total, 0.0);
+
+		// Seven keystrokes on the left hand, twice; "on" and "you" are too
+		// short to count.
+		quartads.set_long_runs(text, &['\n']);
+		let result = calculate_penalty(&quartads, text.len(), &layout::QWERTY_LAYOUT, &penalties, true);
+		assert_eq!(result.total, 32.0);
+		assert_eq!(result.breakdown[PenaltyKind::LongRun as usize].high_keys["sweater"], 32.0);
+	}
+
+	#[test]
+	fn context_lengths()
+	{
+		let penalties = init();
+		let layout = &layout::QWERTY_LAYOUT;
+		let pos_map = layout.get_position_map();
+		let total = |context: usize, name: &str| {
+			let (quartads, _) = prepare_quartad_list_with_context("sweat", &pos_map, &Model::default(), &[], context);
+			let result = calculate_penalty(&quartads, 5, layout, &penalties, true);
+			result.breakdown.iter().find(|p| p.name == name).unwrap().total
+		};
+
+		// Five keystrokes on the left hand: the same hand penalty needs four
+		// characters of context, and its five key version five.
+		assert_eq!(total(3, "same hand"), 0.0);
+		assert_eq!(total(4, "same hand"), 1.0);
+		assert_eq!(total(4, "same hand five"), 0.0);
+		assert_eq!(total(5, "same hand"), 1.0);
+		assert_eq!(total(5, "same hand five"), 1.0);
+	}
+
+	#[test]
+	fn repeated_keys()
+	{
+		let mut penalties = init();
+		penalties[PenaltyKind::Repeat as usize].weight = 1.0;
+		let layout = &layout::QWERTY_LAYOUT;
+		let pos_map = layout.get_position_map();
+		let breakdown = |corpus: &'static str| {
+			let (quartads, _) = prepare_quartad_list(corpus, &pos_map, &Model::default(), &[]);
+			calculate_penalty(&quartads, corpus.len(), layout, &penalties, true).breakdown.iter()
+				.map(|p| (p.name.to_string(), p.total))
+				.collect::<HashMap<String, f64>>()
+		};
+
+		// OO, KK and EE are repeats, each pressed twice at full base penalty.
+		let bookkeeper = breakdown("bookkeeper");
+		assert_eq!(bookkeeper["repeat"], 3.0);
+		assert_eq!(bookkeeper["base"], breakdown("bokeper")["base"] + BASE_PENALTY.0[8] + BASE_PENALTY.0[18] + BASE_PENALTY.0[2]);
+		assert_eq!(bookkeeper["same finger"], breakdown("bokeper")["same finger"]);
+
+		// LOOK is three movements on the right hand, not four, and the roll
+		// from O to K counts as it would from the first O.
+		let look = breakdown("look");
+		assert_eq!(look["same hand"], 0.0);
+		assert_eq!(look["roll in"], breakdown("lok")["roll in"]);
+		assert_eq!(breakdown("deaf")["same hand"], 0.5);
+	}
+
+	#[test]
+	fn typographic_characters()
+	{
+		let mut table: HashMap<char, String> = TYPOGRAPHIC.iter().map(|&(c, s)| (c, s.to_string())).collect();
+		let text = "\u{201c}Don\u{2019}t\u{2026}\u{201d} \u{2013} ten\u{a0}km";
+		assert_eq!(normalize(text, &table), ("\"Don't...\" - ten km".to_string(), 6));
+		assert_eq!(normalize("plain", &table), ("plain".to_string(), 0));
+
+		// The apostrophe keeps "don't" on the quartads it belongs to.
+		let pos_map = layout::QWERTY_LAYOUT.get_position_map();
+		let (_, coverage) = prepare_quartad_list(text, &pos_map, &Model::default(), &[]);
+		assert_eq!(coverage.skipped.values().sum::<usize>(), 6);
+		let (_, coverage) = prepare_quartad_list(&normalize(text, &table).0, &pos_map, &Model::default(), &[]);
+		assert!(coverage.skipped.is_empty());
+
+		table.insert('\u{ab}', "\"".to_string());
+		assert_eq!(normalize("\u{ab}oui\u{2019}", &table).0, "\"oui'");
+	}
+
+	#[test]
+	fn penalties_by_position()
+	{
+		let penalties = init();
+		let layout = &layout::QWERTY_LAYOUT;
+		let corpus = "the quick brown fox jumps over the lazy dog";
+		let (quartads, _) = prepare_quartad_list(corpus, &layout.get_position_map(), &Model::default(), &[]);
+		let result = calculate_penalty(&quartads, corpus.len(), layout, &penalties, true);
+
+		// Every penalty but those over the whole corpus falls on some key.
+		let whole: f64 = result.breakdown.iter()
+			.filter(|p| p.name == "finger load" || p.name == "hand balance")
+			.map(|p| p.total)
+			.sum();
+		let by_key: f64 = result.positions.penalty.0.iter().sum();
+		assert!((by_key - (result.total - whole)).abs() < 1e-9);
+
+		let worst = result.positions.worst(3);
+		assert_eq!(worst.len(), 3);
+		assert!(worst[0].1 >= worst[1].1 && worst[1].1 >= worst[2].1);
+		assert!(result.positions.penalty.0.iter().all(|&p| p <= worst[0].1));
+		assert!(PositionStats::new().worst(3).is_empty());
+	}
+
+	#[test]
+	fn alternating_space()
+	{
+		let pos_map = layout::QWERTY_LAYOUT.get_position_map();
+		let thumb = |older: &[u8]| space_position(SpaceMode::Alternate, &pos_map, older).unwrap().hand;
+		assert_eq!(thumb(b"f"), Hand::Right);
+		assert_eq!(thumb(b"jf"), Hand::Left);
+		assert_eq!(thumb(b""), Hand::Right);
+		assert_eq!(thumb(b"\nj"), Hand::Right);
+	}
+
+	#[test]
+	fn ngram_frequencies_from_quartads()
+	{
+		let pos_map = layout::QWERTY_LAYOUT.get_position_map();
+		let (quartads, _) = prepare_quartad_list("the then them", &pos_map, &Model::default(), &[]);
+		let unigrams = ngram_frequencies(&quartads, 1);
+		assert_eq!(unigrams[..3], [("e", 3.0), ("h", 3.0), ("t", 3.0)]);
+		assert_eq!(unigrams.iter().map(|&(_, n)| n).sum::<f64>(), 13.0);
+		assert_eq!(ngram_frequencies(&quartads, 2)[..3], [("he", 3.0), ("th", 3.0), (" t", 2.0)]);
+		assert_eq!(ngram_frequencies(&quartads, 3)[0], ("the", 3.0));
+
+		// N-gram tables count each length apart.
+		let (quartads, _, _) = parse_ngram_list("th\t5\nthe\t4\nt\t9\n", &pos_map, &Model::default(), &[], DEFAULT_CONTEXT).unwrap();
+		assert_eq!(ngram_frequencies(&quartads, 1), vec![("t", 9.0)]);
+		assert_eq!(ngram_frequencies(&quartads, 2), vec![("th", 5.0)]);
+		assert_eq!(ngram_frequencies(&quartads, 3), vec![("the", 4.0)]);
+	}
+
+	#[test]
+	fn ngram_table_matches_text_with_shift()
+	{
+		let penalties = init();
+		let layout = &layout::QWERTY_LAYOUT;
+		let pos_map = layout.position_map_for(true);
+
+		// "A" takes right shift and a, so the table's bigram covers the three
+		// keystrokes of the text, and p follows shift on the same finger.
+		let model = Model { model_shift: true, ..Model::default() };
+		let (text, _) = prepare_quartad_list("Ap", &pos_map, &model, &[]);
+		let (table, len, _) = parse_ngram_list("A\t1\np\t1\nAp\t1\n", &pos_map, &model, &[], DEFAULT_CONTEXT).unwrap();
+		let text = calculate_penalty(&text, 2, layout, &penalties, false).total;
+		let table = calculate_penalty(&table, len, layout, &penalties, false).total;
+		assert!(text > 0.0);
+		assert!((text - table).abs() < 1e-9, "{} != {}", text, table);
+	}
+
+	#[test]
+	fn compose_dead_keys()
+	{
+		let penalties = init();
+		let layout = &layout::QWERTY_LAYOUT;
+		let pos_map = layout.get_position_map();
+		let compose = layout::parse_compose("\"é\" = \"'e\"").unwrap();
+
+		// Without the rule, é can't be typed and drops out of the score.
+		let text = "café au lait";
+		let (_, coverage) = prepare_quartad_list(text, &pos_map, &Model::default(), &[]);
+		assert_eq!(coverage.skipped[&'é'], 1);
+
+		// With it, é costs the apostrophe and the e, but the score is still
+		// scaled by the text as written.
+		let expanded = compose.expand(text);
+		let (quartads, coverage) = prepare_quartad_list(&expanded, &pos_map, &Model::default(), &[]);
+		assert!(coverage.skipped.is_empty());
+		let base = |result: &PenaltyResult| result.breakdown.iter().find(|p| p.name == "base").unwrap().total;
+		let composed = calculate_penalty(&quartads, text.len(), layout, &penalties, true);
+		let (plain, _) = prepare_quartad_list("cafe au lait", &pos_map, &Model::default(), &[]);
+		let plain = calculate_penalty(&plain, text.len(), layout, &penalties, true);
+		assert_eq!(base(&composed), base(&plain) + BASE_PENALTY.0[21]);
+		assert_eq!(composed.scaled, composed.total / text.len() as f64);
+	}
+}
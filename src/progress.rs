@@ -0,0 +1,59 @@
+/// Shows how a search is going on a terminal.
+
+
+use std::io;
+use std::io::Write;
+use std::time::{Duration, Instant};
+
+// How often to redraw the progress line.
+const PROGRESS_INTERVAL: Duration = Duration::from_millis(200);
+
+// A status line for a terminal, redrawn in place as a search goes on. It is
+// cleared once the search returns so that results print on a clean line.
+pub struct Progress
+{
+	round:  usize,
+	total:  Option<usize>,
+	start:  Instant,
+	drawn:  Option<Instant>,
+}
+
+impl Progress
+{
+	// `total` is the number of iterations the search will run, if known.
+	pub fn new(round: usize, total: Option<usize>)
+	-> Progress
+	{
+		Progress { round, total, start: Instant::now(), drawn: None }
+	}
+
+	pub fn update(&mut self, iteration: usize, temperature: f64, penalty: f64, best: f64)
+	{
+		let now = Instant::now();
+		if self.drawn.is_some_and(|t| now - t < PROGRESS_INTERVAL) {
+			return;
+		}
+		self.drawn = Some(now);
+
+		let elapsed = (now - self.start).as_secs_f64();
+		let (of, eta) = match self.total {
+			Some(total) if iteration > 0 => {
+				let left = total.saturating_sub(iteration) as f64 * elapsed / iteration as f64;
+				(format!("/{}", total), format!("  ETA {}:{:02}", left as u64 / 60, left as u64 % 60))
+			},
+			Some(total) => (format!("/{}", total), String::new()),
+			None => (String::new(), String::new()),
+		};
+		print!("\r\x1b[Kround {}  iteration {}{}  temperature {:.6}  penalty {:.6}  best {:.6}{}",
+			self.round, iteration, of, temperature, penalty, best, eta);
+		let _ = io::stdout().flush();
+	}
+
+	pub fn clear(&mut self)
+	{
+		if self.drawn.take().is_some() {
+			print!("\r\x1b[K");
+			let _ = io::stdout().flush();
+		}
+	}
+}
@@ -0,0 +1,153 @@
+/// Random numbers for the optimiser, which can be seeded to make a run
+/// reproducible.
+///
+/// The generator is a plain xorshift so that it builds for WebAssembly, where
+/// there is no operating system to seed it from; there it starts from a fixed
+/// seed unless `seed` is called.
+
+#[cfg(not(target_arch = "wasm32"))]
+extern crate rand;
+
+use std::cell::RefCell;
+
+thread_local!(static RNG: RefCell<XorShift> = RefCell::new(XorShift::new(entropy())));
+
+#[cfg(not(target_arch = "wasm32"))]
+fn entropy()
+-> u64
+{
+	rand::random()
+}
+
+#[cfg(target_arch = "wasm32")]
+fn entropy()
+-> u64
+{
+	0
+}
+
+// Marsaglia's xorshift128.
+pub struct XorShift
+{
+	x: u32,
+	y: u32,
+	z: u32,
+	w: u32,
+}
+
+impl XorShift
+{
+	fn new(seed: u64)
+	-> XorShift
+	{
+		// XorShift can't start from all zeroes, so mix in some fixed bits.
+		XorShift { x: seed as u32, y: (seed >> 32) as u32, z: 0x9e3779b9, w: 0x7f4a7c15 }
+	}
+
+	fn next_u32(&mut self)
+	-> u32
+	{
+		let t = self.x ^ (self.x << 11);
+		self.x = self.y;
+		self.y = self.z;
+		self.z = self.w;
+		self.w = self.w ^ (self.w >> 19) ^ (t ^ (t >> 8));
+		self.w
+	}
+
+	fn next_u64(&mut self)
+	-> u64
+	{
+		((self.next_u32() as u64) << 32) | self.next_u32() as u64
+	}
+}
+
+// Types that can be drawn from the generator.
+pub trait Random
+{
+	fn random(rng: &mut XorShift) -> Self;
+}
+
+impl Random for u32
+{
+	fn random(rng: &mut XorShift) -> u32 { rng.next_u32() }
+}
+
+impl Random for u64
+{
+	fn random(rng: &mut XorShift) -> u64 { rng.next_u64() }
+}
+
+impl Random for usize
+{
+	fn random(rng: &mut XorShift) -> usize { rng.next_u64() as usize }
+}
+
+// Uniform in [0, 1).
+impl Random for f64
+{
+	fn random(rng: &mut XorShift) -> f64 { (rng.next_u64() >> 11) as f64 / (1u64 << 53) as f64 }
+}
+
+// Restarts the sequence of random numbers from a seed.
+pub fn seed(seed: u64)
+{
+	RNG.with(|rng| *rng.borrow_mut() = XorShift::new(seed));
+}
+
+// The generator's state, for a checkpoint to save.
+pub fn state()
+-> [u32; 4]
+{
+	RNG.with(|rng| {
+		let rng = rng.borrow();
+		[rng.x, rng.y, rng.z, rng.w]
+	})
+}
+
+// Carries on the sequence from a saved state.
+pub fn restore(state: [u32; 4])
+{
+	RNG.with(|rng| *rng.borrow_mut() = XorShift { x: state[0], y: state[1], z: state[2], w: state[3] });
+}
+
+pub fn random<T: Random>()
+-> T
+{
+	RNG.with(|rng| T::random(&mut rng.borrow_mut()))
+}
+
+#[cfg(test)]
+mod tests
+{
+	use super::*;
+
+	#[test]
+	fn seeded_runs_repeat()
+	{
+		seed(42);
+		let a: Vec<usize> = (0..10).map(|_| random()).collect();
+		seed(42);
+		let b: Vec<usize> = (0..10).map(|_| random()).collect();
+		assert_eq!(a, b);
+	}
+
+	#[test]
+	fn restored_state_carries_on()
+	{
+		seed(9);
+		random::<u64>();
+		let saved = state();
+		let a: Vec<usize> = (0..10).map(|_| random()).collect();
+		restore(saved);
+		let b: Vec<usize> = (0..10).map(|_| random()).collect();
+		assert_eq!(a, b);
+	}
+
+	#[test]
+	fn floats_are_in_unit_interval()
+	{
+		seed(7);
+		assert!((0..1000).map(|_| random::<f64>()).all(|r| (0.0..1.0).contains(&r)));
+	}
+}
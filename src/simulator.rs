@@ -1,20 +1,230 @@
 /// Applies the math in annealing.rs to keyboard layouts.
 
 
-extern crate rand;
-
-use self::rand::random;
+use std::cmp;
 use std::cmp::Ordering;
-use std::collections::LinkedList;
+use std::fmt;
+use std::mem;
+use std::str::FromStr;
+use std::thread;
+use std::time::Instant;
 
+use heatmap;
 use layout;
 use penalty;
 use annealing;
+use rng;
+use rng::random;
+use trace::{Trace, TraceRow};
+use checkpoint::Checkpoint;
+
+// How many times to re-roll a shuffle that breaks a constraint before giving
+// up on the iteration.
+const MAX_REROLLS: usize = 1000;
+
+// How many phases of the annealing schedule to report acceptance rates for.
+pub const PHASES: usize = 10;
+
+// How `simulate` moves from one layout to the next.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Strategy
+{
+	// Simulated annealing, which sometimes accepts worse layouts.
+	Anneal,
+	// Accepts the first random swap that improves the layout.
+	HillClimb,
+	// Accepts the best single swap.
+	SteepestDescent,
+}
+
+impl FromStr for Strategy
+{
+	type Err = ();
+
+	fn from_str(s: &str)
+	-> Result<Strategy, ()>
+	{
+		match s {
+			"anneal"     => Ok(Strategy::Anneal),
+			"hill-climb" => Ok(Strategy::HillClimb),
+			"steepest"   => Ok(Strategy::SteepestDescent),
+			_ => Err(()),
+		}
+	}
+}
+
+impl fmt::Display for Strategy
+{
+	fn fmt(&self, f: &mut fmt::Formatter)
+	-> fmt::Result
+	{
+		let name = match *self {
+			Strategy::Anneal          => "anneal",
+			Strategy::HillClimb       => "hill-climb",
+			Strategy::SteepestDescent => "steepest",
+		};
+		write!(f, "{}", name)
+	}
+}
+
+// The most swaps an iteration may make. Beyond a handful, a shuffled layout has
+// little to do with the one it came from and annealing turns into random
+// restarts.
+pub const MAX_SWAPS: usize = 10;
+
+// How many swaps each iteration of `simulate` and `temper` makes, up to
+// --swaps-per-iteration.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum SwapDistribution
+{
+	// Any number from 1 to the maximum, equally likely.
+	Uniform,
+	// One swap half the time, two a quarter of the time and so on, with the
+	// tail folded into the maximum. Searches more locally than Uniform.
+	Geometric,
+}
+
+impl FromStr for SwapDistribution
+{
+	type Err = ();
+
+	fn from_str(s: &str)
+	-> Result<SwapDistribution, ()>
+	{
+		match s {
+			"uniform"   => Ok(SwapDistribution::Uniform),
+			"geometric" => Ok(SwapDistribution::Geometric),
+			_ => Err(()),
+		}
+	}
+}
+
+impl fmt::Display for SwapDistribution
+{
+	fn fmt(&self, f: &mut fmt::Formatter)
+	-> fmt::Result
+	{
+		let name = match *self {
+			SwapDistribution::Uniform   => "uniform",
+			SwapDistribution::Geometric => "geometric",
+		};
+		write!(f, "{}", name)
+	}
+}
+
+impl SwapDistribution
+{
+	// Draws a number of swaps from 1 to `max`.
+	pub fn sample(&self, max: usize)
+	-> usize
+	{
+		let max = cmp::max(max, 1);
+		match *self {
+			SwapDistribution::Uniform => random::<usize>() % max + 1,
+			SwapDistribution::Geometric => {
+				let mut swaps = 1;
+				while swaps < max && random::<f64>() < 0.5 {
+					swaps += 1;
+				}
+				swaps
+			},
+		}
+	}
+}
+
+// Why a search stopped.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Stop
+{
+	// Annealing reached the end of its schedule.
+	Finished,
+	// Annealing went --patience iterations without improving.
+	Patience,
+	// Hill climbing failed to improve too many times in a row.
+	Failures,
+	// No single swap improves on the layout.
+	Converged,
+	// The time limit passed.
+	Deadline,
+}
+
+impl fmt::Display for Stop
+{
+	fn fmt(&self, f: &mut fmt::Formatter)
+	-> fmt::Result
+	{
+		let reason = match *self {
+			Stop::Finished  => "finished the schedule",
+			Stop::Patience  => "ran out of patience",
+			Stop::Failures  => "too many failures in a row",
+			Stop::Converged => "no single swap improves the layout",
+			Stop::Deadline  => "time limit reached",
+		};
+		write!(f, "{}", reason)
+	}
+}
+
+// What a search reports as it goes, for the caller to show or not. Nothing is
+// printed here.
+pub enum Event<'e>
+{
+	// Carrying on from a saved state.
+	Resumed { iteration: usize, penalty: f64 },
+	// An iteration accepted a layout, reached by swapping these characters.
+	Accepted { iteration: usize, penalty: f64, swapped: &'e [(char, char)] },
+	// Hill climbing gave up after this many failures in a row.
+	GaveUp { failures: usize },
+	// Annealing went this many iterations without improving.
+	LostPatience { idle: usize },
+	// Annealing stepped back through its schedule.
+	Reheated { iteration: usize, from: usize, to: usize, stale: usize },
+	// Where a search has got to, for a progress line.
+	Progress { iteration: usize, temperature: f64, penalty: f64, best: f64 },
+	// What became of the candidates in each phase of an annealing schedule,
+	// once it ends.
+	Acceptance(&'e [PhaseStats; PHASES]),
+	// The temperatures of the chains of parallel tempering.
+	Chains(&'e [f64]),
+	// Two neighbouring chains traded layouts.
+	Exchanged { iteration: usize, chains: (usize, usize), penalties: (f64, f64) },
+	// Refine is about to try every layout within `depth` swaps.
+	Trying { layouts: usize, depth: usize },
+	// Refine scored layout `index` of them.
+	Scored { index: usize, layouts: usize, penalty: f64 },
+	// Refine has tried them, taking this long.
+	Tried { layouts: usize, seconds: f64 },
+	// The best of them.
+	Best(&'e [BestLayoutsEntry]),
+	// Refine moved to the best of them, and starts again from one swap away.
+	Moved { depth: usize, penalty: f64 },
+}
+
+// One layout being annealed at a fixed temperature.
+struct Chain
+{
+	temperature: f64,
+	layout:      layout::Layout,
+	pos_map:     layout::LayoutPosMap,
+	penalty:     f64,
+	history:     Vec<(char, char)>,
+}
+
+// What became of the candidate layouts in one phase of annealing.
+#[derive(Clone, Copy, Default)]
+pub struct PhaseStats
+{
+	pub improving: usize,
+	pub worsening: usize,
+	pub rejected:  usize,
+}
 
-struct BestLayoutsEntry
+pub struct BestLayoutsEntry
 {
-	layout:  layout::Layout,
-	penalty: f64,
+	pub layout:  layout::Layout,
+	pub penalty: f64,
+	// The characters swapped, in order, to reach the layout from the initial
+	// one.
+	pub history: Vec<(char, char)>,
 }
 
 impl BestLayoutsEntry
@@ -29,190 +239,874 @@ impl BestLayoutsEntry
 	}
 }
 
+// How `simulate`, `temper` and `refine` search. The defaults are those of the
+// command line.
+#[derive(Clone, Copy)]
+pub struct SearchOptions<'c>
+{
+	pub strategy:       Strategy,
+	// How many failures in a row hill climbing gives up after.
+	pub max_failures:   usize,
+	pub reheat:         Option<annealing::Reheat>,
+	// How many iterations annealing goes without improving before it stops.
+	pub patience:       Option<usize>,
+	pub deadline:       Option<Instant>,
+	// How many chains `temper` runs, and how often they may trade layouts.
+	pub chains:         usize,
+	pub exchange_every: usize,
+	// How many of the best layouts to keep, and how many keys apart they are.
+	pub top_layouts:    usize,
+	pub min_distance:   usize,
+	// The most swaps an iteration makes, and how many it makes up to that.
+	pub num_swaps:      usize,
+	pub distribution:   SwapDistribution,
+	// The most swaps away `refine` looks, and how many threads it scores on.
+	pub refine_swaps:   usize,
+	pub threads:        usize,
+	pub constraints:    &'c [layout::Constraint],
+}
+
+impl <'c> Default for SearchOptions<'c>
+{
+	fn default()
+	-> SearchOptions<'c>
+	{
+		SearchOptions {
+			strategy:       Strategy::Anneal,
+			max_failures:   1000,
+			reheat:         None,
+			patience:       None,
+			deadline:       None,
+			chains:         0,
+			exchange_every: 100,
+			top_layouts:    1,
+			min_distance:   1,
+			num_swaps:      3,
+			distribution:   SwapDistribution::Uniform,
+			refine_swaps:   3,
+			threads:        1,
+			constraints:    &[],
+		}
+	}
+}
+
+// Where a search sends what it finds: the best layouts, a row per iteration
+// for each trace, its state now and then for a checkpoint, and events for
+// `report`.
+pub struct Sinks<'s>
+{
+	pub best_layouts: &'s mut Vec<BestLayoutsEntry>,
+	pub traces:       &'s mut [Trace],
+	pub checkpoint:   Option<&'s mut Checkpoint>,
+	pub report:       &'s (dyn Fn(Event) + Sync),
+}
+
+// Where `simulate` had got to after an iteration: enough for a checkpoint to
+// save and for a resumed search to carry on exactly as it would have. The
+// acceptance rates reported at the end start again.
+pub struct SearchState
+{
+	pub iteration: usize,
+	pub layout:    layout::Layout,
+	pub penalty:   f64,
+	pub history:   Vec<(char, char)>,
+	pub lowest:    f64,
+	pub stale:     usize,
+	pub reheats:   usize,
+	pub rewound:   usize,
+	pub idle:      usize,
+	pub failures:  usize,
+	pub rng:       [u32; 4],
+}
+
+// Searches for better layouts than `init_layout` with the given strategy,
+// inserting those it accepts into the best layouts. Returns how many
+// iterations it ran and why it stopped; it stops after the iteration during
+// which the deadline passes, if there is one. With a checkpoint it saves its
+// state every so often, and with `resume` it starts from a saved state
+// instead of `init_layout`.
 pub fn simulate<'a>(
 	quartads:    &penalty::QuartadList<'a>,
 	len:          usize,
 	init_layout: &layout::Layout,
 	penalties:   &Vec<penalty::KeyPenalty<'a>>,
-	debug:        bool,
-	top_layouts:  usize,
-	num_swaps:    usize)
+	options:     &SearchOptions,
+	resume:       Option<SearchState>,
+	sinks:        Sinks)
+-> (usize, Stop)
 {
-	let penalty = penalty::calculate_penalty(&quartads, len, init_layout, penalties, true);
+	let SearchOptions { strategy, max_failures, reheat, patience, deadline, top_layouts, min_distance, num_swaps, distribution, constraints, .. } = *options;
+	let Sinks { best_layouts, traces, mut checkpoint, report } = sinks;
+	let init_pos_map = quartads.position_map(init_layout);
+	let penalty = penalty::calculate_penalty_for_map(quartads, len, &init_pos_map, penalties, false);
 
-	if debug {
-		println!("Initial layout:");
-		print_result(init_layout, &penalty);
+	let mut iterations_run = 0;
+	let mut stop = Stop::Finished;
+	let mut accepted_layout = init_layout.clone();
+	let mut accepted_pos_map = init_pos_map;
+	let mut accepted_penalty = penalty.scaled;
+	let mut accepted_history = Vec::new();
+	let mut failures = 0;
+	let mut lowest_penalty = accepted_penalty;
+	let mut stale = 0;
+	let mut reheats = 0;
+	let mut rewound = 0;
+	let mut idle = 0;
+	let mut phases = [PhaseStats::default(); PHASES];
+	if let Some(state) = resume {
+		report(Event::Resumed { iteration: state.iteration, penalty: state.penalty });
+		iterations_run = state.iteration;
+		accepted_pos_map = quartads.position_map(&state.layout);
+		accepted_layout = state.layout;
+		accepted_penalty = state.penalty;
+		accepted_history = state.history;
+		failures = state.failures;
+		lowest_penalty = state.lowest;
+		stale = state.stale;
+		reheats = state.reheats;
+		rewound = state.rewound;
+		idle = state.idle;
+		rng::restore(state.rng);
 	}
+	let iterations: Box<dyn Iterator<Item = usize>> = match strategy {
+		Strategy::Anneal => Box::new(annealing::get_simulation_range().skip(iterations_run)),
+		_ => Box::new((iterations_run + 1)..),
+	};
+	for i in iterations {
+		// Save where the previous iteration left off every so often, and when
+		// time is up.
+		let out_of_time = deadline.is_some_and(|d| Instant::now() >= d);
+		if let Some(ref mut checkpoint) = checkpoint {
+			if (i - 1) % checkpoint.every == 0 || out_of_time {
+				checkpoint.save(Some(&SearchState {
+					iteration: i - 1, layout: accepted_layout.clone(), penalty: accepted_penalty, history: accepted_history.clone(),
+					lowest: lowest_penalty, stale, reheats, rewound, idle, failures, rng: rng::state(),
+				}), best_layouts);
+			}
+		}
+		if out_of_time {
+			stop = Stop::Deadline;
+			break;
+		}
+		iterations_run = i;
 
-	// Keep track of the best layouts we've encountered.
-	let mut best_layouts: LinkedList<BestLayoutsEntry> = LinkedList::new();
+		// Steepest descent tries every single swap, keeping the best if it's an
+		// improvement.
+		if strategy == Strategy::SteepestDescent {
+			let best = layout::LayoutPermutations::new(&accepted_layout, 1)
+				.skip(1)
+				.map(|(layout, swaps)| (accepted_pos_map.patched(&layout, &swaps), layout, swaps))
+				.filter(|(pos_map, _, _)| layout::satisfies(pos_map, constraints))
+				.map(|(pos_map, layout, swaps)| {
+					let penalty = penalty::calculate_penalty_for_map(quartads, len, &pos_map, penalties, false);
+					(penalty.scaled, layout, pos_map, swaps)
+				})
+				.min_by(|a, b| a.0.partial_cmp(&b.0).unwrap_or(Ordering::Equal));
+			let candidate = best.as_ref().map(|b| b.0);
+			let improved = candidate.is_some_and(|c| c < accepted_penalty);
+			if let (true, Some((penalty, layout, pos_map, swaps))) = (improved, best) {
+				let swapped = accepted_layout.swapped_chars(&swaps);
+				report(Event::Accepted { iteration: i, penalty, swapped: &swapped });
+				accepted_history.extend(swapped);
+				accepted_layout = layout.clone();
+				accepted_pos_map = pos_map;
+				accepted_penalty = penalty;
+				insert_ordered(best_layouts, BestLayoutsEntry { layout, penalty, history: accepted_history.clone() }, top_layouts, min_distance);
+			}
+			let row = TraceRow {
+				chain: 0, iteration: i, temperature: 0.0, candidate, accepted: improved,
+				penalty: accepted_penalty, best: best_penalty(best_layouts, accepted_penalty),
+			};
+			for trace in traces.iter_mut() {
+				trace.write(&row);
+			}
+			if improved {
+				continue;
+			}
+			stop = Stop::Converged;
+			break;
+		}
 
-	let mut accepted_layout = init_layout.clone();
-	let mut accepted_penalty = penalty.1;
-	for i in annealing::get_simulation_range() {
 		// Copy and shuffle this iteration of the layout.
-		let mut curr_layout = accepted_layout.clone();
-		curr_layout.shuffle(random::<usize>() % num_swaps + 1);
+		idle += 1;
+		let shuffled = shuffle(&accepted_layout, &accepted_pos_map, num_swaps, distribution, constraints);
 
 		// Calculate penalty.
-		let curr_layout_copy = curr_layout.clone();
-		let penalty = penalty::calculate_penalty(&quartads, len, &curr_layout, penalties, false);
-		let scaled_penalty = penalty.1;
-
-		// Probabilistically accept worse transitions; always accept better
-		// transitions.
-		if annealing::accept_transition(scaled_penalty - accepted_penalty, i) {
-			if debug {
-				println!("Iteration {} accepted with penalty {}", i, scaled_penalty);
-			}
+		let candidate = shuffled.map(|(curr_layout, curr_pos_map, swapped)| {
+			let penalty = penalty::calculate_penalty_for_map(quartads, len, &curr_pos_map, penalties, false);
+			(curr_layout, curr_pos_map, penalty.scaled, swapped)
+		});
+
+		// Annealing probabilistically accepts worse transitions; every strategy
+		// accepts better transitions.
+		let candidate_penalty = candidate.as_ref().map(|c| c.2);
+		let improving = candidate_penalty.is_some_and(|c| c < accepted_penalty);
+		let accepted = match candidate {
+			Some((_, _, scaled_penalty, _)) => match strategy {
+				Strategy::Anneal => annealing::accept_transition(scaled_penalty - accepted_penalty, i - rewound),
+				_ => scaled_penalty < accepted_penalty,
+			},
+			None => false,
+		};
+
+		// A rejected candidate's swaps are dropped with it.
+		if let (true, Some((curr_layout, curr_pos_map, scaled_penalty, swapped))) = (accepted, candidate) {
+			report(Event::Accepted { iteration: i, penalty: scaled_penalty, swapped: &swapped });
+			accepted_history.extend(swapped);
 
-			accepted_layout = curr_layout_copy.clone();
+			if improving {
+				idle = 0;
+			}
+			accepted_layout = curr_layout.clone();
+			accepted_pos_map = curr_pos_map;
 			accepted_penalty = scaled_penalty;
+			failures = 0;
 
 			// Insert this layout into best layouts.
 			let new_entry = BestLayoutsEntry {
-				layout: curr_layout_copy,
-				penalty: penalty.1,
+				layout: curr_layout,
+				penalty: scaled_penalty,
+				history: accepted_history.clone(),
 			};
-			best_layouts = list_insert_ordered(best_layouts, new_entry);
+			if insert_ordered(best_layouts, new_entry, top_layouts, min_distance) {
+				idle = 0;
+			}
+		} else {
+			// Hill climbing gives up after enough swaps in a row fail to
+			// improve the layout.
+			failures += 1;
+			if strategy == Strategy::HillClimb && failures >= max_failures {
+				report(Event::GaveUp { failures });
+				stop = Stop::Failures;
+				break;
+			}
+		}
 
-			// Limit best layouts list length.
-			while best_layouts.len() > top_layouts {
-				best_layouts.pop_back();
+		if strategy == Strategy::Anneal {
+			let phase = &mut phases[(i - 1) * PHASES / annealing::get_simulation_range().len()];
+			match (accepted, improving) {
+				(true, true)  => phase.improving += 1,
+				(true, false) => phase.worsening += 1,
+				(false, _)    => phase.rejected += 1,
+			}
+		}
+
+		if accepted_penalty < lowest_penalty {
+			lowest_penalty = accepted_penalty;
+			stale = 0;
+		} else {
+			stale += 1;
+		}
+
+		let temperature = match strategy {
+			Strategy::Anneal => annealing::temperature(i - rewound),
+			_ => 0.0,
+		};
+		let row = TraceRow {
+			chain: 0, iteration: i, temperature, candidate: candidate_penalty, accepted,
+			penalty: accepted_penalty, best: best_penalty(best_layouts, lowest_penalty),
+		};
+		for trace in traces.iter_mut() {
+			trace.write(&row);
+		}
+		report(Event::Progress { iteration: i, temperature, penalty: accepted_penalty, best: best_penalty(best_layouts, lowest_penalty) });
+
+		// Stop annealing early once it has frozen, leaving the rest of the
+		// schedule unused.
+		if let (Strategy::Anneal, Some(patience)) = (strategy, patience) {
+			if idle >= patience {
+				report(Event::LostPatience { idle });
+				stop = Stop::Patience;
+				break;
+			}
+		}
+
+		// Step back through the annealing schedule if it has frozen.
+		if let (Strategy::Anneal, Some(reheat)) = (strategy, reheat) {
+			if stale >= reheat.after && reheats < reheat.max {
+				let step = i - rewound;
+				let reheated = reheat.reheated(step);
+				report(Event::Reheated { iteration: i, from: step, to: reheated, stale });
+				rewound += step - reheated;
+				reheats += 1;
+				stale = 0;
 			}
 		}
 	}
 
-	for entry in best_layouts.into_iter() {
-		let layout = entry.layout;
-		let penalty = penalty::calculate_penalty(&quartads, len, &layout, penalties, true);
-		println!("");
-		print_result(&layout, &penalty);
+	if strategy == Strategy::Anneal {
+		report(Event::Acceptance(&phases));
 	}
+
+	(iterations_run, stop)
 }
 
-pub fn refine<'a>(
+// Parallel tempering: anneals several chains of layouts at once, each at its
+// own fixed temperature, and every so often offers neighbouring chains the
+// chance to trade layouts. Hot chains explore while cold ones polish, and a
+// good layout found while exploring works its way down. The deadline, if any,
+// is checked between exchanges, and no checkpoint is saved. Returns how many
+// iterations each chain ran and why it stopped; progress is reported on the
+// coldest chain.
+pub fn temper<'a>(
 	quartads:    &penalty::QuartadList<'a>,
 	len:          usize,
 	init_layout: &layout::Layout,
 	penalties:   &Vec<penalty::KeyPenalty<'a>>,
-	debug:        bool,
-	top_layouts:  usize,
-	num_swaps:    usize)
+	options:     &SearchOptions,
+	sinks:        Sinks)
+-> (usize, Stop)
 {
-	let penalty = penalty::calculate_penalty(&quartads, len, init_layout, penalties, true);
+	let SearchOptions { chains: num_chains, exchange_every, deadline, top_layouts, min_distance, num_swaps, distribution, constraints, .. } = *options;
+	let Sinks { best_layouts, traces, report, .. } = sinks;
+	let init_pos_map = quartads.position_map(init_layout);
+	let penalty = penalty::calculate_penalty_for_map(quartads, len, &init_pos_map, penalties, false);
+
+	let temperatures = annealing::chain_temperatures(num_chains);
+	report(Event::Chains(&temperatures));
+	let mut chains: Vec<Chain> = temperatures.iter().map(|&temperature| Chain {
+		temperature,
+		layout:      init_layout.clone(),
+		pos_map:     init_pos_map,
+		penalty:     penalty.scaled,
+		history:     Vec::new(),
+	}).collect();
+
+	let exchange_every = cmp::max(exchange_every, 1);
+	let iterations = annealing::get_simulation_range().len();
+	let mut done = 0;
+	while done < iterations {
+		if deadline.is_some_and(|d| Instant::now() >= d) {
+			return (done, Stop::Deadline);
+		}
+		let steps = cmp::min(exchange_every, iterations - done);
+		let start = done;
+		done += steps;
+
+		// Run every chain for a while on its own thread, seeding each from the
+		// main generator so that --seed still repeats the run. Threads collect
+		// their trace rows to be written in order afterwards.
+		let seeds: Vec<u64> = chains.iter().map(|_| random()).collect();
+		let tracing = !traces.is_empty();
+		let best_before = best_penalty(best_layouts, f64::INFINITY);
+		let results: Vec<(Vec<BestLayoutsEntry>, Vec<TraceRow>)> = thread::scope(|scope| {
+			let handles: Vec<_> = chains.iter_mut().enumerate().zip(seeds).map(|((c, chain), seed)| {
+				scope.spawn(move || {
+					rng::seed(seed);
+					let mut accepted = Vec::new();
+					let mut rows = Vec::new();
+					let mut best = best_before.min(chain.penalty);
+					for step in 0..steps {
+						let shuffled = shuffle(&chain.layout, &chain.pos_map, num_swaps, distribution, constraints);
+						let mut candidate = None;
+						let mut accept = false;
+						if let Some((layout, pos_map, swapped)) = shuffled {
+							let penalty = penalty::calculate_penalty_for_map(quartads, len, &pos_map, penalties, false).scaled;
+							candidate = Some(penalty);
+							accept = annealing::accept_transition_at(penalty - chain.penalty, chain.temperature);
+							if accept {
+								chain.layout = layout.clone();
+								chain.pos_map = pos_map;
+								chain.penalty = penalty;
+								chain.history.extend(swapped);
+								best = best.min(penalty);
+								insert_ordered(&mut accepted, BestLayoutsEntry { layout, penalty, history: chain.history.clone() }, top_layouts, min_distance);
+							}
+						}
+						if tracing {
+							rows.push(TraceRow {
+								chain: c, iteration: start + step + 1, temperature: chain.temperature, candidate,
+								accepted: accept, penalty: chain.penalty, best,
+							});
+						}
+					}
+					(accepted, rows)
+				})
+			}).collect();
+			handles.into_iter().map(|h| h.join().unwrap()).collect()
+		});
+		for (accepted, rows) in results.into_iter() {
+			for entry in accepted.into_iter() {
+				insert_ordered(best_layouts, entry, top_layouts, min_distance);
+			}
+			for trace in traces.iter_mut() {
+				for row in rows.iter() {
+					trace.write(row);
+				}
+			}
+		}
 
-	println!("Initial layout:");
-	print_result(init_layout, &penalty);
+		// Offer neighbouring chains the chance to trade layouts.
+		for c in 1..chains.len() {
+			let (hot, cold) = chains.split_at_mut(c);
+			let (a, b) = (&mut hot[c - 1], &mut cold[0]);
+			if annealing::accept_exchange(a.penalty, a.temperature, b.penalty, b.temperature) {
+				report(Event::Exchanged { iteration: done, chains: (c - 1, c), penalties: (a.penalty, b.penalty) });
+				mem::swap(&mut a.layout, &mut b.layout);
+				mem::swap(&mut a.pos_map, &mut b.pos_map);
+				mem::swap(&mut a.penalty, &mut b.penalty);
+				mem::swap(&mut a.history, &mut b.history);
+			}
+		}
+
+		// Report on the coldest chain, which holds the most polished layout.
+		if let Some(coldest) = chains.last() {
+			report(Event::Progress { iteration: done, temperature: coldest.temperature, penalty: coldest.penalty, best: best_penalty(best_layouts, coldest.penalty) });
+		}
+	}
+
+	(done, Stop::Finished)
+}
+
+// Improves `init_layout` by trying every layout up to `refine_swaps` swaps
+// away, moving to the best while any is better, and returns where it ends up.
+// Reports each layout it scores from the threads scoring them.
+pub fn refine<'a>(
+	quartads:    &penalty::QuartadList<'a>,
+	len:          usize,
+	init_layout: &layout::Layout,
+	penalties:   &Vec<penalty::KeyPenalty<'a>>,
+	options:     &SearchOptions,
+	report:      &(dyn Fn(Event) + Sync))
+-> layout::Layout
+{
+	let SearchOptions { top_layouts, min_distance, refine_swaps: num_swaps, threads, constraints, .. } = *options;
+	let penalty = penalty::calculate_penalty(quartads, len, init_layout, penalties, false);
 
 	let mut curr_layout = init_layout.clone();
-	let mut curr_penalty = penalty.1;
+	let mut curr_penalty = penalty.scaled;
+	let mut curr_history = Vec::new();
 
+	// Search one swap away first, and only further when nothing nearer helps.
+	let mut depth = 1;
 	loop {
-		// Test every layout within `num_swaps` swaps of the initial layout.
-		let mut best_layouts: LinkedList<BestLayoutsEntry> = LinkedList::new();
-		let permutations = layout::LayoutPermutations::new(&curr_layout, num_swaps);
-		for (i, layout) in permutations.enumerate() {
-			let penalty = penalty::calculate_penalty(&quartads, len, &layout, penalties, false);
-
-			if debug {
-				println!("Iteration {}: {}", i, penalty.1);
-			}
+		// Test every layout within `depth` swaps of the current layout, itself
+		// included, with each thread taking its own stretch of them in order.
+		let curr_pos_map = quartads.position_map(&curr_layout);
+		let total = layout::LayoutPermutations::new(&curr_layout, depth).total();
+		report(Event::Trying { layouts: total, depth });
+		let start = Instant::now();
+		let results: Vec<(Vec<BestLayoutsEntry>, usize)> = thread::scope(|scope| {
+			let handles: Vec<_> = (0..threads).map(|t| {
+				let (curr_layout, curr_pos_map, curr_history) = (&curr_layout, &curr_pos_map, &curr_history);
+				scope.spawn(move || {
+					let mut best_layouts = Vec::new();
+					let mut tried = 0;
+					let permutations = layout::LayoutPermutations::new(curr_layout, depth);
+					let (start, end) = (total * t / threads, total * (t + 1) / threads);
+					for (i, (layout, swaps)) in permutations.enumerate().skip(start).take(end - start) {
+						let pos_map = curr_pos_map.patched(&layout, &swaps);
+						if !layout::satisfies(&pos_map, constraints) {
+							continue;
+						}
+						let penalty = penalty::calculate_penalty_for_map(quartads, len, &pos_map, penalties, false);
+						tried += 1;
 
-			// Insert this layout into best layouts.
-			let new_entry = BestLayoutsEntry {
-				layout: layout,
-				penalty: penalty.1,
-			};
-			best_layouts = list_insert_ordered(best_layouts, new_entry);
+						report(Event::Scored { index: i, layouts: total, penalty: penalty.scaled });
+						let mut history = curr_history.clone();
+						history.extend(curr_layout.swapped_chars(&swaps));
 
-			// Limit best layouts list length.
-			while best_layouts.len() > top_layouts {
-				best_layouts.pop_back();
-			}
+						// Insert this layout into best layouts.
+						let new_entry = BestLayoutsEntry {
+							layout,
+							penalty: penalty.scaled,
+							history,
+						};
+						insert_ordered(&mut best_layouts, new_entry, top_layouts, min_distance);
+					}
+					(best_layouts, tried)
+				})
+			}).collect();
+			handles.into_iter().map(|h| h.join().unwrap()).collect()
+		});
+
+		// Merge the threads' best layouts in the order they were tried, so that
+		// ties go to the first whatever the number of threads.
+		let elapsed = start.elapsed().as_secs_f64();
+		let tried: usize = results.iter().map(|r| r.1).sum();
+		report(Event::Tried { layouts: tried, seconds: elapsed });
+		let mut best_layouts: Vec<BestLayoutsEntry> = Vec::new();
+		for entry in results.into_iter().flat_map(|r| r.0.into_iter()) {
+			insert_ordered(&mut best_layouts, entry, top_layouts, min_distance);
 		}
 
-		// Print the top layouts.
-		for entry in best_layouts.iter() {
-			let ref layout = entry.layout;
-			let penalty = penalty::calculate_penalty(&quartads, len, &layout, penalties, true);
-			println!("");
-			print_result(&layout, &penalty);
+		// Go deeper if no layout this close is better.
+		let improved = best_layouts.first().is_some_and(|best| best.penalty < curr_penalty);
+		if !improved && depth < num_swaps {
+			depth += 1;
+			continue;
 		}
 
-		// Keep going until swapping doesn't get us any more improvements.
-		let best = best_layouts.pop_front().unwrap();
-		if curr_penalty <= best.penalty {
+		report(Event::Best(&best_layouts));
+
+		// Keep going until swapping doesn't get us any more improvements,
+		// starting again from one swap away after each.
+		if !improved {
 			break;
-		} else {
-			curr_layout = best.layout;
-			curr_penalty = best.penalty;
 		}
+		let best = best_layouts.remove(0);
+		report(Event::Moved { depth, penalty: best.penalty });
+		curr_layout = best.layout;
+		curr_penalty = best.penalty;
+		curr_history = best.history;
+		depth = 1;
 	}
 
-	println!("");
-	println!("Ultimate winner:");
-	println!("{}", curr_layout);
+	curr_layout
 }
 
-pub fn print_result<'a>(
-	layout: &'a layout::Layout,
-	penalty: &'a (f64, f64, Vec<penalty::KeyPenaltyResult<'a>>))
+pub fn suggest<'a>(
+	quartads:    &penalty::QuartadList<'a>,
+	len:          usize,
+	init_layout: &layout::Layout,
+	penalties:   &Vec<penalty::KeyPenalty<'a>>,
+	constraints: &[layout::Constraint],
+	top:          usize)
+-> (f64, Vec<(f64, layout::Layout)>)
 {
-	println!("{}", layout);
+	let init_pos_map = quartads.position_map(init_layout);
+	let init_penalty = penalty::calculate_penalty_for_map(quartads, len, &init_pos_map, penalties, false).scaled;
 
-	let (ref total, ref scaled, ref penalties) = *penalty;
-	println!("total: {}; scaled: {}", total, scaled);
-	for penalty in penalties {
-		print!("{}  / ", penalty);
-		let mut high_keys: Vec<(&str, f64)> = penalty.high_keys.iter().map(|x| (*x.0, *x.1)).collect();
-		high_keys.sort_by(|a, b|
-			match b.1.abs().partial_cmp(&a.1.abs()) {
-				Some(c) => c,
-				None => Ordering::Equal
-			});
-		for key in high_keys.iter().take(5) {
-			let (k, v) = *key;
-			print!(" {}: {};", k, v);
+	// Score every single swap, keeping those that improve the layout.
+	let mut improvements: Vec<(f64, layout::Layout)> = layout::LayoutPermutations::new(init_layout, 1)
+		.skip(1)
+		.map(|(layout, swaps)| (init_pos_map.patched(&layout, &swaps), layout))
+		.filter(|(pos_map, _)| layout::satisfies(pos_map, constraints))
+		.map(|(pos_map, layout)| {
+			let penalty = penalty::calculate_penalty_for_map(quartads, len, &pos_map, penalties, false);
+			(penalty.scaled, layout)
+		})
+		.filter(|&(penalty, _)| penalty < init_penalty)
+		.collect();
+	improvements.sort_by(|a, b| a.0.partial_cmp(&b.0).unwrap_or(Ordering::Equal));
+	improvements.truncate(top);
+
+	(init_penalty, improvements)
+}
+
+// Formats a layout and its penalties, and the keys that changed from
+// `baseline` if there is one. The penalty of each key is shown on the layout as
+// its share of them all, followed by the worst keys.
+pub fn format_result(
+	layout:    &layout::Layout,
+	penalty:   &penalty::PenaltyResult,
+	show_keys:  usize,
+	baseline:   Option<&layout::Layout>)
+-> String
+{
+	let mut out = format!("{}\n", layout);
+	if let Some(baseline) = baseline {
+		let changed = baseline.differing_keys(layout);
+		out.push_str(&format!("Changed keys: {}\n", changed.len()));
+		for (pos, before, after) in changed {
+			out.push_str(&format!("  pos {}: {}→{}\n", pos, before, after));
 		}
-		println!("");
 	}
+	out.push_str(&format!("{:.*}\n", show_keys, penalty));
+	let by_key = &penalty.positions.penalty;
+	let sum: f64 = by_key.0.iter().sum();
+	out.push_str(&format!("Penalty by key:\n{}\n", heatmap::render(layout, by_key, false)));
+	out.push_str("Worst keys:");
+	for (pos, key_penalty) in penalty.positions.worst(3) {
+		let share = if sum != 0.0 { key_penalty / sum * 100.0 } else { 0.0 };
+		out.push_str(&format!(" {:?} at {} ({}): {:.2} ({:.2}%);", layout.keys_at(pos).0, pos, layout::position_name(pos), key_penalty, share));
+	}
+	out.push('\n');
+	out
 }
 
-// Take ownership of the list and give it back as a hack to make the borrow checker happy :^)
+// Lists swapped characters like "e↔o, k↔v".
+pub fn format_history(history: &[(char, char)])
+-> String
+{
+	let swaps: Vec<String> = history.iter().map(|&(a, b)| format!("{}↔{}", a, b)).collect();
+	swaps.join(", ")
+}
 
-fn list_insert_ordered(mut list: LinkedList<BestLayoutsEntry>, entry: BestLayoutsEntry)
--> LinkedList<BestLayoutsEntry>
+// The lowest penalty among the best layouts so far, or `otherwise` if it's
+// lower.
+fn best_penalty(best_layouts: &[BestLayoutsEntry], otherwise: f64)
+-> f64
 {
-	if let Some(first) = list.front() {
-		let cmp = entry.cmp(first);
-		if cmp == Ordering::Less {
-			list.push_front(entry);
-			return list;
-		}
+	best_layouts.first().map_or(otherwise, |e| e.penalty.min(otherwise))
+}
+
+// A shuffled layout, its position map and the characters swapped.
+type Shuffled = (layout::Layout, layout::LayoutPosMap, Vec<(char, char)>);
+
+// Copies and shuffles a layout, re-rolling shuffles that break a constraint,
+// and lists the characters swapped. Gives up after `MAX_REROLLS` tries.
+fn shuffle(
+	layout:      &layout::Layout,
+	pos_map:     &layout::LayoutPosMap,
+	num_swaps:    usize,
+	distribution: SwapDistribution,
+	constraints: &[layout::Constraint])
+-> Option<Shuffled>
+{
+	(0..MAX_REROLLS).map(|_| {
+		let mut curr_layout = layout.clone();
+		let swaps = curr_layout.shuffle(distribution.sample(num_swaps));
+		let curr_pos_map = pos_map.patched(&curr_layout, &swaps);
+		(curr_layout, curr_pos_map, swaps)
+	}).find(|(_, pos_map, _)| layout::satisfies(pos_map, constraints))
+		.map(|(curr_layout, curr_pos_map, swaps)| (curr_layout, curr_pos_map, layout.swapped_chars(&swaps)))
+}
+
+// Inserts `entry` after any entries with an equal or lower penalty, keeping the
+// list sorted and at most `top_layouts` long. Of two layouts that differ in
+// fewer than `min_distance` keys (or are identical), only the one with the
+// lower penalty is kept. Returns whether `entry` made it into the list.
+fn insert_ordered(
+	list:         &mut Vec<BestLayoutsEntry>,
+	entry:             BestLayoutsEntry,
+	top_layouts:       usize,
+	min_distance:      usize)
+-> bool
+{
+	let min_distance = cmp::max(min_distance, 1);
+	let too_close = |e: &BestLayoutsEntry| e.layout.distance(&entry.layout) < min_distance;
+	if list.iter().any(|e| too_close(e) && e.cmp(&entry) != Ordering::Greater) {
+		return false;
+	}
+	list.retain(|e| !too_close(e));
+
+	let idx = list.partition_point(|e| e.cmp(&entry) != Ordering::Greater);
+	list.insert(idx, entry);
+	list.truncate(top_layouts);
+	idx < top_layouts
+}
+
+#[cfg(test)]
+mod tests
+{
+	use std::env;
+	use std::fs;
+	use checkpoint;
+	use layout;
+	use penalty;
+	use super::*;
+
+	static CORPUS: &str = "the quick brown fox jumps over the lazy dog. \
+		pack my box with five dozen liquor jugs. \
+		how vexingly quick daft zebras jump!";
+
+	#[test]
+	fn refine_walks_past_first_pass()
+	{
+		let penalties = penalty::init();
+		let init_pos_map = layout::INIT_LAYOUT.get_position_map();
+		let (quartads, _) = penalty::prepare_quartad_list(CORPUS, &init_pos_map, &penalty::Model::default(), &[]);
+		let len = CORPUS.len();
+
+		// The best layout reachable in a single pass of one swap.
+		let single_pass = layout::LayoutPermutations::new(&layout::INIT_LAYOUT, 1)
+			.map(|(l, _)| penalty::calculate_penalty(&quartads, len, &l, &penalties, false).scaled)
+			.fold(f64::INFINITY, f64::min);
+
+		let options = SearchOptions { refine_swaps: 1, ..SearchOptions::default() };
+		let refined = refine(&quartads, len, &layout::INIT_LAYOUT, &penalties, &options, &|_| ());
+		let refined_penalty = penalty::calculate_penalty(&quartads, len, &refined, &penalties, false).scaled;
+
+		assert!(refined_penalty < single_pass);
+
+		// Sharing the layouts between threads finds the same one.
+		let shared = refine(&quartads, len, &layout::INIT_LAYOUT, &penalties, &SearchOptions { threads: 3, ..options }, &|_| ());
+		assert!(shared == refined);
 	}
+
+	#[test]
+	fn refine_goes_deeper_only_when_stuck()
 	{
-		// Find where to add our new entry to, since the list is sorted.
-		let mut cursor = list.cursor_front_mut();
-		loop {
-			{
-				let opt_next = cursor.peek_next();
-				if let Some(next) = opt_next {
-					let cmp = entry.cmp(next);
-					if cmp == Ordering::Less {
-						break;
-					}
-				} else {
-					break;
-				}
-			}
+		let penalties = penalty::init();
+		let init_pos_map = layout::INIT_LAYOUT.get_position_map();
+		let (quartads, _) = penalty::prepare_quartad_list(CORPUS, &init_pos_map, &penalty::Model::default(), &[]);
+		let len = CORPUS.len();
+		let mut layout = layout::INIT_LAYOUT.clone();
+		layout.move_only_hand(layout::Hand::Right);
+		let score = |l: &layout::Layout| penalty::calculate_penalty(&quartads, len, l, &penalties, false).scaled;
+
+		let shallow = refine(&quartads, len, &layout, &penalties, &SearchOptions { refine_swaps: 1, threads: 2, ..SearchOptions::default() }, &|_| ());
+		let deep = refine(&quartads, len, &layout, &penalties, &SearchOptions { refine_swaps: 2, threads: 2, ..SearchOptions::default() }, &|_| ());
+		assert!(score(&deep) <= score(&shallow));
+
+		// Nothing within two swaps of where it stopped is better.
+		let neighbour = layout::LayoutPermutations::new(&deep, 2)
+			.map(|(l, _)| score(&l))
+			.fold(f64::INFINITY, f64::min);
+		assert!(neighbour >= score(&deep));
+	}
+
+	#[test]
+	fn steepest_descent_ends_at_local_minimum()
+	{
+		let penalties = penalty::init();
+		let init_pos_map = layout::INIT_LAYOUT.get_position_map();
+		let (quartads, _) = penalty::prepare_quartad_list(CORPUS, &init_pos_map, &penalty::Model::default(), &[]);
+		let len = CORPUS.len();
+
+		let mut best_layouts = Vec::new();
+		let options = SearchOptions { strategy: Strategy::SteepestDescent, ..SearchOptions::default() };
+		simulate(&quartads, len, &layout::INIT_LAYOUT, &penalties, &options, None, sinks(&mut best_layouts));
+		let best = &best_layouts[0];
+
+		// No single swap improves on where it stopped.
+		let neighbour = layout::LayoutPermutations::new(&best.layout, 1)
+			.map(|(l, _)| penalty::calculate_penalty(&quartads, len, &l, &penalties, false).scaled)
+			.fold(f64::INFINITY, f64::min);
+		assert!(neighbour >= best.penalty);
+	}
+
+	#[test]
+	fn stops_at_deadline()
+	{
+		let penalties = penalty::init();
+		let init_pos_map = layout::INIT_LAYOUT.get_position_map();
+		let (quartads, _) = penalty::prepare_quartad_list(CORPUS, &init_pos_map, &penalty::Model::default(), &[]);
+		let len = CORPUS.len();
+
+		let mut best_layouts = Vec::new();
+		let options = SearchOptions { deadline: Some(Instant::now()), chains: 2, exchange_every: 10, ..SearchOptions::default() };
+		let stopped = simulate(&quartads, len, &layout::INIT_LAYOUT, &penalties, &options, None, sinks(&mut best_layouts));
+		assert_eq!(stopped, (0, Stop::Deadline));
+		let stopped = temper(&quartads, len, &layout::INIT_LAYOUT, &penalties, &options, sinks(&mut best_layouts));
+		assert_eq!(stopped, (0, Stop::Deadline));
+		assert!(best_layouts.is_empty());
+	}
+
+	#[test]
+	fn history_replays_to_layout()
+	{
+		let penalties = penalty::init();
+		let init_pos_map = layout::INIT_LAYOUT.get_position_map();
+		let (quartads, _) = penalty::prepare_quartad_list(CORPUS, &init_pos_map, &penalty::Model::default(), &[]);
+		let len = CORPUS.len();
 
-			cursor.move_next();
+		rng::seed(3);
+		let mut best_layouts = Vec::new();
+		let options = SearchOptions {
+			strategy: Strategy::HillClimb, max_failures: 100, top_layouts: 3, distribution: SwapDistribution::Geometric,
+			..SearchOptions::default()
+		};
+		simulate(&quartads, len, &layout::INIT_LAYOUT, &penalties, &options, None, sinks(&mut best_layouts));
+		assert!(best_layouts.iter().any(|e| !e.history.is_empty()));
+		for entry in best_layouts.iter() {
+			let mut replayed = layout::INIT_LAYOUT.clone();
+			for &(a, b) in entry.history.iter() {
+				let i = replayed.find_key(layout::SwapKey::Char(a)).unwrap();
+				let j = replayed.find_key(layout::SwapKey::Char(b)).unwrap();
+				replayed.swap_keys(i, j);
+			}
+			assert!(replayed == entry.layout);
 		}
+	}
+
+	#[test]
+	fn resumes_from_checkpoint()
+	{
+		let penalties = penalty::init();
+		let init_pos_map = layout::INIT_LAYOUT.get_position_map();
+		let (quartads, _) = penalty::prepare_quartad_list(CORPUS, &init_pos_map, &penalty::Model::default(), &[]);
+		let len = CORPUS.len();
+		let path = env::temp_dir().join("keygen-resume-test.json");
+		let path = path.to_str().unwrap();
+
+		rng::seed(5);
+		let mut checkpoint = Checkpoint::new(path, 20, 0, 0);
+		let mut best_layouts = Vec::new();
+		let options = SearchOptions { strategy: Strategy::HillClimb, max_failures: 100, top_layouts: 3, num_swaps: 1, ..SearchOptions::default() };
+		let finished = simulate(&quartads, len, &layout::INIT_LAYOUT, &penalties, &options, None,
+			Sinks { checkpoint: Some(&mut checkpoint), ..sinks(&mut best_layouts) });
+		checkpoint.status().unwrap();
+
+		// Carrying on from the last checkpoint ends the same way.
+		let saved = checkpoint::parse_checkpoint(&fs::read_to_string(path).unwrap(), *layout::INIT_LAYOUT.board()).unwrap();
+		fs::remove_file(path).unwrap();
+		let state = saved.state.unwrap();
+		assert!(state.iteration > 0 && state.iteration.is_multiple_of(20) && state.iteration < finished.0);
+		let mut resumed_layouts = saved.best;
+		rng::seed(6);
+		let resumed = simulate(&quartads, len, &layout::INIT_LAYOUT, &penalties, &options, Some(state), sinks(&mut resumed_layouts));
+		assert_eq!(resumed, finished);
+		let penalties = |layouts: &[BestLayoutsEntry]| layouts.iter().map(|e| e.penalty).collect::<Vec<f64>>();
+		assert_eq!(penalties(&resumed_layouts), penalties(&best_layouts));
+		assert_eq!(resumed_layouts[0].history, best_layouts[0].history);
+	}
 
-		// Add to list.
-		cursor.insert_after(entry);
+	// Collects the best layouts, and nothing else.
+	fn sinks<'s>(best_layouts: &'s mut Vec<BestLayoutsEntry>)
+	-> Sinks<'s>
+	{
+		Sinks { best_layouts, traces: &mut [], checkpoint: None, report: &|_| () }
 	}
-	list
+
+	fn entry(layout: &layout::Layout, penalty: f64)
+	-> BestLayoutsEntry
+	{
+		BestLayoutsEntry { layout: layout.clone(), penalty, history: Vec::new() }
+	}
+
+	#[test]
+	fn insert_ordered_sorts_ties_and_truncates()
+	{
+		let mut list = Vec::new();
+		insert_ordered(&mut list, entry(&layout::QWERTY_LAYOUT, 2.0), 3, 0);
+		insert_ordered(&mut list, entry(&layout::DVORAK_LAYOUT, 1.0), 3, 0);
+		insert_ordered(&mut list, entry(&layout::COLEMAK_LAYOUT, 2.0), 3, 0);
+		insert_ordered(&mut list, entry(&layout::WORKMAN_LAYOUT, 3.0), 3, 0);
+		insert_ordered(&mut list, entry(&layout::QGMLWY_LAYOUT, 0.5), 3, 0);
+
+		// Ties keep insertion order, and only the top three survive.
+		let penalties: Vec<f64> = list.iter().map(|e| e.penalty).collect();
+		assert_eq!(penalties, vec![0.5, 1.0, 2.0]);
+		assert!(list[0].layout == layout::QGMLWY_LAYOUT);
+		assert!(list[1].layout == layout::DVORAK_LAYOUT);
+		assert!(list[2].layout == layout::QWERTY_LAYOUT);
+	}
+
+	#[test]
+	fn insert_ordered_skips_duplicates()
+	{
+		let mut list = Vec::new();
+		insert_ordered(&mut list, entry(&layout::QWERTY_LAYOUT, 2.0), 5, 0);
+		insert_ordered(&mut list, entry(&layout::QWERTY_LAYOUT, 2.0), 5, 0);
+		assert_eq!(list.len(), 1);
+
+		// A layout within the minimum distance of a better one is dropped,
+		// and a better one replaces it.
+		let mut near = layout::QWERTY_LAYOUT.clone();
+		near.shuffle(1);
+		insert_ordered(&mut list, entry(&near, 3.0), 5, 3);
+		assert_eq!(list.len(), 1);
+		insert_ordered(&mut list, entry(&near, 1.0), 5, 3);
+		assert_eq!(list.len(), 1);
+		assert!(list[0].layout == near);
+	}
+
+	#[test]
+	fn swap_distributions()
+	{
+		rng::seed(5);
+		for &distribution in [SwapDistribution::Uniform, SwapDistribution::Geometric].iter() {
+			assert!((0..100).all(|_| distribution.sample(1) == 1));
+			let counts = (0..4000).fold([0usize; 5], |mut counts, _| {
+				let swaps = distribution.sample(4);
+				assert!((1..=4).contains(&swaps));
+				counts[swaps] += 1;
+				counts
+			});
+			match distribution {
+				// About 1000 each.
+				SwapDistribution::Uniform => assert!(counts[1..].iter().all(|&n| n > 850 && n < 1150), "{:?}", counts),
+				// About 2000, 1000, 500 and 500.
+				SwapDistribution::Geometric => {
+					assert!(counts[1] > 1850 && counts[1] < 2150, "{:?}", counts);
+					assert!(counts[2] > counts[3] && counts[3] > 400 && counts[4] > 400, "{:?}", counts);
+				},
+			}
+		}
+		assert_eq!("geometric".parse(), Ok(SwapDistribution::Geometric));
+		assert!("poisson".parse::<SwapDistribution>().is_err());
+	}
+
 }
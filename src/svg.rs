@@ -0,0 +1,145 @@
+/// Draws a layout as an SVG picture, each keycap shaded by how often its key is
+/// typed, with the share of each hand and finger underneath.
+
+use layout::{Finger, Geometry, Hand, KeyMap, Layout, KEY_FINGERS, KEY_HANDS, NUM_KEYS};
+
+// Sizes in pixels: the distance between neighbouring keys, the size of a
+// keycap, the margin around the picture and the height of a legend line.
+const PITCH:  f64 = 60.0;
+const CAP:    f64 = 54.0;
+const MARGIN: f64 = 20.0;
+const LINE:   f64 = 24.0;
+
+static FINGERS: [(Finger, &str); 5] = [
+	(Finger::Pinky, "pinky"), (Finger::Ring, "ring"), (Finger::Middle, "middle"),
+	(Finger::Index, "index"), (Finger::Thumb, "thumb")];
+
+// Renders `layout` with keycaps shaded by `presses`, the keystrokes on each
+// position. Keys are placed by `geometry` if there is one, and otherwise on a
+// grid split between the hands like the text output.
+pub fn render(layout: &Layout, presses: &KeyMap<f64>, geometry: Option<&Geometry>)
+-> String
+{
+	let KeyMap(ref presses) = *presses;
+	let board = layout.board();
+	let positions: Vec<usize> = (0..NUM_KEYS).filter(|&i| board.exists(i)).collect();
+	let coords = |i: usize| match geometry {
+		Some(geometry) => geometry.coords.0[i],
+		None => grid_coords(i),
+	};
+	let min_x = positions.iter().map(|&i| coords(i).0).fold(f64::INFINITY, f64::min);
+	let min_y = positions.iter().map(|&i| coords(i).1).fold(f64::INFINITY, f64::min);
+	let max_x = positions.iter().map(|&i| coords(i).0).fold(f64::NEG_INFINITY, f64::max);
+	let max_y = positions.iter().map(|&i| coords(i).1).fold(f64::NEG_INFINITY, f64::max);
+	let max = presses.iter().cloned().fold(0.0, f64::max);
+	let sum: f64 = positions.iter().map(|&i| presses[i]).sum();
+
+	let legend = legend(presses, &positions, sum);
+	let width = (max_x - min_x) * PITCH + CAP + 2.0 * MARGIN;
+	let keys_height = (max_y - min_y) * PITCH + CAP;
+	let height = keys_height + 2.0 * MARGIN + LINE * legend.len() as f64;
+
+	let mut svg = format!("<svg xmlns=\"http://www.w3.org/2000/svg\" width=\"{:.0}\" height=\"{:.0}\" font-family=\"sans-serif\">\n", width, height);
+	svg.push_str(&format!("<rect width=\"{:.0}\" height=\"{:.0}\" fill=\"#ffffff\"/>\n", width, height));
+	for &i in positions.iter() {
+		let (x, y) = coords(i);
+		let x = MARGIN + (x - min_x) * PITCH;
+		let y = MARGIN + (y - min_y) * PITCH;
+		let heat = if max > 0.0 { presses[i] / max } else { 0.0 };
+		let (lower, upper) = layout.keys_at(i);
+		svg.push_str(&format!("<rect x=\"{:.1}\" y=\"{:.1}\" width=\"{}\" height=\"{}\" rx=\"6\" fill=\"{}\" stroke=\"#555555\"/>\n",
+			x, y, CAP, CAP, color(heat)));
+		svg.push_str(&format!("<text x=\"{:.1}\" y=\"{:.1}\" font-size=\"14\" fill=\"#555555\">{}</text>\n",
+			x + 8.0, y + 18.0, escape(upper)));
+		svg.push_str(&format!("<text x=\"{:.1}\" y=\"{:.1}\" font-size=\"20\">{}</text>\n",
+			x + 8.0, y + CAP - 10.0, escape(lower)));
+	}
+	for (l, line) in legend.iter().enumerate() {
+		svg.push_str(&format!("<text x=\"{}\" y=\"{:.1}\" font-size=\"14\">{}</text>\n",
+			MARGIN, MARGIN + keys_height + LINE * (l as f64 + 1.0), line));
+	}
+	svg.push_str("</svg>\n");
+	svg
+}
+
+// Where a position sits on a plain grid, in key widths, with a gap of one key
+// between the hands. The number row goes above the top row.
+fn grid_coords(i: usize)
+-> (f64, f64)
+{
+	let (row, column) = match i {
+		0..=10  => (0, i),
+		11..=21 => (1, i - 11),
+		22..=31 => (2, i - 22),
+		32      => (3, 4),
+		33      => (3, 5),
+		_       => (-1, i - 34),
+	};
+	let gap = if column >= 5 { 1 } else { 0 };
+	((column + gap) as f64, row as f64)
+}
+
+// One line per hand with its share of the keystrokes and each finger's.
+fn legend(presses: &[f64; NUM_KEYS], positions: &[usize], sum: f64)
+-> Vec<String>
+{
+	let share = |hand: Hand, finger: Option<Finger>| {
+		let typed: f64 = positions.iter()
+			.filter(|&&i| KEY_HANDS.0[i] == hand && finger.is_none_or(|f| KEY_FINGERS.0[i] == f))
+			.map(|&i| presses[i])
+			.sum();
+		if sum > 0.0 { typed / sum * 100.0 } else { 0.0 }
+	};
+	[(Hand::Left, "Left hand"), (Hand::Right, "Right hand")].iter().map(|&(hand, name)| {
+		let fingers: Vec<String> = FINGERS.iter()
+			.filter(|&&(finger, _)| positions.iter().any(|&i| KEY_HANDS.0[i] == hand && KEY_FINGERS.0[i] == finger))
+			.map(|&(finger, finger_name)| format!("{} {:.1}%", finger_name, share(hand, Some(finger))))
+			.collect();
+		format!("{}: {:.1}% ({})", name, share(hand, None), fingers.join(", "))
+	}).collect()
+}
+
+// Shades from white for unused keys to red for the most used.
+fn color(heat: f64)
+-> String
+{
+	let mix = |from: f64, to: f64| (from + (to - from) * heat).round() as u8;
+	format!("#{:02x}{:02x}{:02x}", mix(255.0, 215.0), mix(255.0, 48.0), mix(255.0, 39.0))
+}
+
+fn escape(c: char)
+-> String
+{
+	match c {
+		'&'  => String::from("&amp;"),
+		'<'  => String::from("&lt;"),
+		'>'  => String::from("&gt;"),
+		'"'  => String::from("&quot;"),
+		'\'' => String::from("&apos;"),
+		' '  => String::from("␣"),
+		'\0' => String::new(),
+		c    => c.to_string(),
+	}
+}
+
+#[cfg(test)]
+mod tests
+{
+	use layout::QWERTY_LAYOUT;
+	use super::*;
+
+	#[test]
+	fn renders_keys_and_legend()
+	{
+		let mut presses = KeyMap([0.0; NUM_KEYS]);
+		presses.0[11] = 1.0;
+		presses.0[20] = 3.0;
+		let svg = render(&QWERTY_LAYOUT, &presses, None);
+		assert!(svg.starts_with("<svg "));
+		assert!(svg.ends_with("</svg>\n"));
+		assert!(svg.contains(">&lt;</text>"));
+		assert!(svg.contains("fill=\"#d73027\""));
+		assert!(svg.contains(">Left hand: 25.0% (pinky 25.0%, ring 0.0%, middle 0.0%, index 0.0%)</text>"));
+		assert!(svg.contains(">Right hand: 75.0% (pinky 75.0%, ring 0.0%, middle 0.0%, index 0.0%, thumb 0.0%)</text>"));
+	}
+}
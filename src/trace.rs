@@ -0,0 +1,207 @@
+/// Writes the trajectory of an optimisation, either to a CSV file for plotting
+/// or as a stream of JSON events, one object per line, for another program to
+/// follow. The events are documented in cli.rs, where `help run` prints them.
+
+use std::fs::File;
+use std::io;
+use std::io::{BufWriter, Write};
+
+use json;
+
+pub enum Format
+{
+	Csv,
+	// Reports every `every`th iteration and every accepted layout.
+	Events { every: usize },
+}
+
+pub struct Trace
+{
+	out:       BufWriter<Box<dyn Write + Send>>,
+	format:    Format,
+	pub path:  String,
+	pub round: usize,
+	error:     Option<io::Error>,
+}
+
+// One iteration of one chain. `candidate` is None when no shuffle satisfied
+// the constraints.
+pub struct TraceRow
+{
+	pub chain:       usize,
+	pub iteration:   usize,
+	pub temperature: f64,
+	pub candidate:   Option<f64>,
+	pub accepted:    bool,
+	pub penalty:     f64,
+	pub best:        f64,
+}
+
+impl Trace
+{
+	pub fn create(path: &str)
+	-> io::Result<Trace>
+	{
+		let mut trace = Trace::new(Box::new(File::create(path)?), path, Format::Csv);
+		writeln!(trace.out, "round,chain,iteration,temperature,candidate,accepted,penalty,best")?;
+		Ok(trace)
+	}
+
+	// Events go to standard error unless `path` names a file or pipe.
+	pub fn events(path: Option<&str>, every: usize)
+	-> io::Result<Trace>
+	{
+		Ok(match path {
+			Some(path) => Trace::new(Box::new(File::create(path)?), path, Format::Events { every }),
+			None => Trace::new(Box::new(io::stderr()), "standard error", Format::Events { every }),
+		})
+	}
+
+	fn new(out: Box<dyn Write + Send>, path: &str, format: Format)
+	-> Trace
+	{
+		Trace { out: BufWriter::new(out), format, path: path.to_string(), round: 1, error: None }
+	}
+
+	// Writes a row, keeping the first error for `flush` to report so that the
+	// optimiser doesn't have to stop for it.
+	pub fn write(&mut self, row: &TraceRow)
+	{
+		if self.error.is_some() {
+			return;
+		}
+		let result = match self.format {
+			Format::Csv => {
+				let candidate = row.candidate.map(|c| c.to_string()).unwrap_or_default();
+				writeln!(self.out, "{},{},{},{},{},{},{},{}",
+					self.round, row.chain, row.iteration, row.temperature, candidate,
+					row.accepted as u8, row.penalty, row.best)
+			},
+			Format::Events { every } => {
+				let mut result = Ok(());
+				if row.accepted {
+					result = self.row_event("accepted", row);
+				}
+				// Flush with each report so a reader keeps up, but not for
+				// every acceptance.
+				if row.iteration.is_multiple_of(every) {
+					result = result.and_then(|_| self.row_event("iteration", row)).and_then(|_| self.out.flush());
+				}
+				result
+			},
+		};
+		if let Err(e) = result {
+			self.error = Some(e);
+		}
+	}
+
+	fn row_event(&mut self, event: &str, row: &TraceRow)
+	-> io::Result<()>
+	{
+		writeln!(self.out, "{{\"event\":\"{}\",\"round\":{},\"chain\":{},\"iteration\":{},\"temperature\":{},\"penalty\":{},\"best\":{}}}",
+			event, self.round, row.chain, row.iteration, json::number(row.temperature), json::number(row.penalty), json::number(row.best))
+	}
+
+	// Starts a round of `iterations`, if known.
+	pub fn start_round(&mut self, round: usize, iterations: Option<usize>)
+	{
+		self.round = round;
+		let iterations = iterations.map_or(String::from("null"), |n| n.to_string());
+		self.event(format!("{{\"event\":\"round\",\"round\":{},\"iterations\":{}}}", round, iterations));
+	}
+
+	// Ends a round with the best layouts found so far and their penalties.
+	pub fn finish_round(&mut self, iterations: usize, stop: &str, layouts: &[(String, f64)])
+	{
+		let layouts: Vec<String> = layouts.iter()
+			.map(|&(ref layout, penalty)| format!("{{\"penalty\":{},\"layout\":{}}}", json::number(penalty), json::string(layout)))
+			.collect();
+		let event = format!("{{\"event\":\"result\",\"round\":{},\"iterations\":{},\"stop\":{},\"layouts\":[{}]}}",
+			self.round, iterations, json::string(stop), layouts.join(","));
+		self.event(event);
+	}
+
+	pub fn finish(&mut self, rounds: usize, iterations: usize, reason: &str)
+	{
+		self.event(format!("{{\"event\":\"done\",\"rounds\":{},\"iterations\":{},\"reason\":{}}}", rounds, iterations, json::string(reason)));
+	}
+
+	// Writes an event line straight away. CSV has no events.
+	fn event(&mut self, line: String)
+	{
+		if self.error.is_some() {
+			return;
+		}
+		if let Format::Events { .. } = self.format {
+			let result = writeln!(self.out, "{}", line).and_then(|_| self.out.flush());
+			if let Err(e) = result {
+				self.error = Some(e);
+			}
+		}
+	}
+
+	pub fn flush(&mut self)
+	-> io::Result<()>
+	{
+		match self.error.take() {
+			Some(e) => Err(e),
+			None => self.out.flush(),
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests
+{
+	use std::env;
+	use std::fs;
+	use super::*;
+
+	#[test]
+	fn writes_rows_as_csv()
+	{
+		let path = env::temp_dir().join("keygen-trace-test.csv");
+		let path = path.to_str().unwrap();
+		let mut trace = Trace::create(path).unwrap();
+		trace.round = 2;
+		trace.write(&TraceRow { chain: 1, iteration: 3, temperature: 0.5, candidate: Some(1.25), accepted: true, penalty: 1.25, best: 1.0 });
+		trace.write(&TraceRow { chain: 1, iteration: 4, temperature: 0.5, candidate: None, accepted: false, penalty: 1.25, best: 1.0 });
+		trace.flush().unwrap();
+
+		let lines: Vec<String> = fs::read_to_string(path).unwrap().lines().map(|l| l.to_string()).collect();
+		fs::remove_file(path).unwrap();
+		assert_eq!(lines, vec![
+			"round,chain,iteration,temperature,candidate,accepted,penalty,best",
+			"2,1,3,0.5,1.25,1,1.25,1",
+			"2,1,4,0.5,,0,1.25,1",
+		]);
+	}
+
+	#[test]
+	fn writes_events_as_json_lines()
+	{
+		let path = env::temp_dir().join("keygen-events-test.jsonl");
+		let path = path.to_str().unwrap();
+		let mut trace = Trace::events(Some(path), 2).unwrap();
+		trace.start_round(1, Some(4));
+		trace.write(&TraceRow { chain: 0, iteration: 1, temperature: 0.5, candidate: Some(1.25), accepted: true, penalty: 1.25, best: 1.25 });
+		trace.write(&TraceRow { chain: 0, iteration: 2, temperature: 0.5, candidate: None, accepted: false, penalty: 1.25, best: 1.25 });
+		trace.write(&TraceRow { chain: 0, iteration: 3, temperature: 0.5, candidate: Some(2.0), accepted: false, penalty: 1.25, best: 1.25 });
+		trace.finish_round(3, "finished", &[("ab\ncd".to_string(), 1.25)]);
+		trace.finish(1, 3, "ran 1 rounds");
+		trace.flush().unwrap();
+
+		let lines: Vec<String> = fs::read_to_string(path).unwrap().lines().map(|l| l.to_string()).collect();
+		fs::remove_file(path).unwrap();
+		assert_eq!(lines, vec![
+			r#"{"event":"round","round":1,"iterations":4}"#,
+			r#"{"event":"accepted","round":1,"chain":0,"iteration":1,"temperature":0.5,"penalty":1.25,"best":1.25}"#,
+			r#"{"event":"iteration","round":1,"chain":0,"iteration":2,"temperature":0.5,"penalty":1.25,"best":1.25}"#,
+			r#"{"event":"result","round":1,"iterations":3,"stop":"finished","layouts":[{"penalty":1.25,"layout":"ab\u000acd"}]}"#,
+			r#"{"event":"done","rounds":1,"iterations":3,"reason":"ran 1 rounds"}"#,
+		]);
+		for line in lines.iter() {
+			assert!(json::parse(line).is_ok(), "{}", line);
+		}
+	}
+}
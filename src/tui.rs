@@ -0,0 +1,298 @@
+/// An interactive session for editing a layout by hand. The arrow keys (or h,
+/// j, k and l) move between keys, space selects a key and swaps it with the
+/// next one selected, u undoes a swap, r goes back to the original layout, s
+/// saves and q quits. The terminal is put into raw mode with stty, so this
+/// only works where there is one.
+
+use std::io;
+use std::io::{Read, Write};
+use std::process::{Command, Stdio};
+
+use keygen::layout;
+use keygen::penalty;
+
+// The key positions of each row as drawn, from left to right, starting with
+// the number row if the board has one.
+const ROWS: [(usize, usize); 5] = [(34, 44), (0, 11), (11, 22), (22, 32), (32, 34)];
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Key
+{
+	Up,
+	Down,
+	Left,
+	Right,
+	Select,
+	Undo,
+	Reset,
+	Save,
+	Quit,
+}
+
+pub struct Session
+{
+	pub original: layout::Layout,
+	pub layout:   layout::Layout,
+	pub history:  Vec<layout::Swap>,
+	pub cursor:   usize,
+	pub selected: Option<usize>,
+}
+
+impl Session
+{
+	pub fn new(layout: &layout::Layout)
+	-> Session
+	{
+		let cursor = (0..34).find(|&pos| layout.board().exists(pos)).unwrap_or(0);
+		Session { original: layout.clone(), layout: layout.clone(), history: Vec::new(), cursor, selected: None }
+	}
+
+	// Acts on a key press, returning whether the layout changed.
+	pub fn press(&mut self, key: Key)
+	-> bool
+	{
+		match key {
+			Key::Up    => self.step(-1, 0),
+			Key::Down  => self.step(1, 0),
+			Key::Left  => self.step(0, -1),
+			Key::Right => self.step(0, 1),
+			Key::Select => match self.selected {
+				None => self.selected = Some(self.cursor),
+				Some(pos) if pos == self.cursor => self.selected = None,
+				Some(pos) => {
+					self.layout.swap_keys(pos, self.cursor);
+					self.history.push((pos, self.cursor));
+					self.selected = None;
+					return true;
+				},
+			},
+			Key::Undo => if let Some((i, j)) = self.history.pop() {
+				self.layout.swap_keys(i, j);
+				return true;
+			},
+			Key::Reset => if !self.history.is_empty() {
+				self.layout = self.original.clone();
+				self.history.clear();
+				self.selected = None;
+				return true;
+			},
+			Key::Save | Key::Quit => (),
+		}
+		false
+	}
+
+	// Moves the cursor to the next key along its row, or to the nearest key
+	// in the next row up or down, skipping keys the board lacks.
+	fn step(&mut self, rows: isize, cols: isize)
+	{
+		let board = *self.layout.board();
+		let row = ROWS.iter().position(|&(start, end)| self.cursor >= start && self.cursor < end).unwrap_or(0);
+		let col = (self.cursor - ROWS[row].0) as isize;
+		if cols != 0 {
+			let (start, end) = ROWS[row];
+			let mut c = col + cols;
+			while c >= 0 && start + (c as usize) < end {
+				if board.exists(start + c as usize) {
+					self.cursor = start + c as usize;
+					return;
+				}
+				c += cols;
+			}
+		} else {
+			let mut r = row as isize + rows;
+			while r >= 0 && (r as usize) < ROWS.len() {
+				let (start, end) = ROWS[r as usize];
+				let nearest = (start..end)
+					.filter(|&pos| board.exists(pos))
+					.min_by_key(|&pos| ((pos - start) as isize - col).abs());
+				if let Some(pos) = nearest {
+					self.cursor = pos;
+					return;
+				}
+				r += rows;
+			}
+		}
+	}
+}
+
+// Reads key presses from bytes typed on a raw terminal, ignoring the rest.
+pub fn parse_keys(bytes: &[u8])
+-> Vec<Key>
+{
+	let mut keys = Vec::new();
+	let mut i = 0;
+	while i < bytes.len() {
+		let key = match (bytes[i], bytes.get(i + 1), bytes.get(i + 2)) {
+			(0x1b, Some(&b'['), Some(&arrow)) => {
+				i += 2;
+				match arrow {
+					b'A' => Some(Key::Up),
+					b'B' => Some(Key::Down),
+					b'C' => Some(Key::Right),
+					b'D' => Some(Key::Left),
+					_ => None,
+				}
+			},
+			(b'k', _, _) => Some(Key::Up),
+			(b'j', _, _) => Some(Key::Down),
+			(b'l', _, _) => Some(Key::Right),
+			(b'h', _, _) => Some(Key::Left),
+			(b' ', _, _) | (b'\r', _, _) | (b'\n', _, _) => Some(Key::Select),
+			(b'u', _, _) => Some(Key::Undo),
+			(b'r', _, _) => Some(Key::Reset),
+			(b's', _, _) => Some(Key::Save),
+			(b'q', _, _) | (0x03, _, _) | (0x04, _, _) => Some(Key::Quit),
+			_ => None,
+		};
+		keys.extend(key);
+		i += 1;
+	}
+	keys
+}
+
+// The screen for a session: the layout with the cursor in brackets and the
+// selected key in angle brackets, then the score against the original layout
+// and the previous one, and each penalty.
+pub fn render(session: &Session, score: &penalty::PenaltyResult, previous: &penalty::PenaltyResult, original: &penalty::PenaltyResult, status: &str)
+-> String
+{
+	let mut screen = String::from("\x1b[2J\x1b[H");
+	for &(start, end) in ROWS.iter() {
+		if start == 34 && !session.layout.board().has_number_row() {
+			continue;
+		}
+		let mut line = String::new();
+		for pos in start..end {
+			if pos == start + 5 || pos == 33 {
+				line.push_str(" |");
+			}
+			let (lower, _) = session.layout.keys_at(pos);
+			let c = if session.layout.board().exists(pos) { lower } else { ' ' };
+			let (open, close) = if pos == session.cursor {
+				('[', ']')
+			} else if session.selected == Some(pos) {
+				('<', '>')
+			} else {
+				(' ', ' ')
+			};
+			line.push_str(&format!("{}{}{}", open, c, close));
+		}
+		if start == 32 {
+			line = format!("{:>16}", line);
+		}
+		screen.push_str(&line);
+		screen.push_str("\r\n");
+	}
+
+	screen.push_str(&format!("\r\nscaled: {:.6}  vs original: {:+.6}  vs last: {:+.6}\r\n",
+		score.scaled, score.scaled - original.scaled, score.scaled - previous.scaled));
+	for result in score.breakdown.iter() {
+		let was = previous.breakdown.iter().find(|b| b.name == result.name).map_or(0.0, |b| b.total);
+		screen.push_str(&format!("  {:<24} {:>12.1} ({:+.1})\r\n", result.name, result.total, result.total - was));
+	}
+	screen.push_str(&format!("\r\n{} swap(s) from the original. arrows/hjkl: move  space: select/swap  u: undo  r: reset  s: save  q: quit\r\n{}\r\n",
+		session.history.len(), status));
+	screen
+}
+
+// Puts the terminal into raw mode until dropped.
+pub struct RawMode(String);
+
+impl RawMode
+{
+	pub fn enable()
+	-> io::Result<RawMode>
+	{
+		let saved = stty(&["-g"])?;
+		stty(&["raw", "-echo"])?;
+		print!("\x1b[?25l");
+		io::stdout().flush()?;
+		Ok(RawMode(saved.trim().to_string()))
+	}
+}
+
+impl Drop for RawMode
+{
+	fn drop(&mut self)
+	{
+		print!("\x1b[?25h\r\n");
+		let _ = io::stdout().flush();
+		let _ = stty(&[&self.0[..]]);
+	}
+}
+
+fn stty(args: &[&str])
+-> io::Result<String>
+{
+	let output = Command::new("stty").args(args).stdin(Stdio::inherit()).output()?;
+	if !output.status.success() {
+		return Err(io::Error::other(String::from_utf8_lossy(&output.stderr).trim().to_string()));
+	}
+	Ok(String::from_utf8_lossy(&output.stdout).into_owned())
+}
+
+// Waits for some key presses.
+pub fn read_keys()
+-> io::Result<Vec<Key>>
+{
+	let mut buf = [0u8; 16];
+	let n = io::stdin().read(&mut buf)?;
+	if n == 0 {
+		return Ok(vec![Key::Quit]);
+	}
+	Ok(parse_keys(&buf[..n]))
+}
+
+#[cfg(test)]
+mod tests
+{
+	use super::*;
+
+	#[test]
+	fn swap_undo_and_reset()
+	{
+		let mut session = Session::new(&layout::QWERTY_LAYOUT);
+		assert_eq!(session.cursor, 0);
+		assert!(!session.press(Key::Select));
+		session.press(Key::Right);
+		session.press(Key::Down);
+		assert_eq!(session.cursor, 12);
+		assert!(session.press(Key::Select));
+		assert_eq!(session.layout.keys_at(0).0, 's');
+		assert_eq!(session.layout.keys_at(12).0, 'q');
+
+		session.press(Key::Select);
+		session.press(Key::Left);
+		assert!(session.press(Key::Select));
+		assert_eq!(session.history, vec![(0, 12), (12, 11)]);
+		assert!(session.press(Key::Undo));
+		assert_eq!(session.history.len(), 1);
+		assert!(session.press(Key::Reset));
+		assert!(session.layout == layout::QWERTY_LAYOUT);
+		assert!(!session.press(Key::Undo));
+	}
+
+	#[test]
+	fn cursor_stays_on_the_board()
+	{
+		let mut session = Session::new(&layout::QWERTY_LAYOUT);
+		session.press(Key::Left);
+		session.press(Key::Up);
+		assert_eq!(session.cursor, 0);
+		for _ in 0..20 {
+			session.press(Key::Right);
+		}
+		assert_eq!(session.cursor, 10);
+		for _ in 0..5 {
+			session.press(Key::Down);
+		}
+		assert!(session.layout.board().exists(session.cursor) && session.cursor >= 22);
+	}
+
+	#[test]
+	fn reads_arrows_and_letters()
+	{
+		assert_eq!(parse_keys(b"\x1b[A\x1b[Dj u"), vec![Key::Up, Key::Left, Key::Down, Key::Select, Key::Undo]);
+		assert_eq!(parse_keys(b"xq"), vec![Key::Quit]);
+	}
+}
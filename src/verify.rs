@@ -0,0 +1,131 @@
+/// Golden scores: the penalties of a few reference layouts on a fixed corpus,
+/// kept in fixtures/verify so that a change to the model that moves any of
+/// them gets noticed. The `verify` command and tests/verify.rs both check them.
+
+use std::fmt;
+
+use layout;
+use penalty;
+
+pub const LAYOUTS: [&str; 5] = ["QWERTY", "DVORAK", "COLEMAK", "WORKMAN", "RSTHD"];
+
+// How far a score may move, relative to its size, before it counts as changed.
+const EPSILON: f64 = 1e-9;
+
+// One number from the table: a layout's total, its scaled total, or the total
+// of one of its penalties.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Score
+{
+	pub layout: String,
+	pub name:   String,
+	pub value:  f64,
+}
+
+#[derive(Debug)]
+pub struct ScoresParseError
+{
+	pub line: usize,
+}
+
+// Scores each of `LAYOUTS` on `corpus` with the default model.
+pub fn scores(corpus: &str)
+-> Vec<Score>
+{
+	let penalties = penalty::init();
+	let mut scores = Vec::new();
+	for &name in LAYOUTS.iter() {
+		let layout = layout::reference_layout(name).unwrap();
+		let (quartads, _) = penalty::prepare_quartad_list(corpus, &layout.get_position_map(), &penalty::Model::default(), &[]);
+		let result = penalty::calculate_penalty(&quartads, corpus.len(), layout, &penalties, true);
+		let score = |key: &str, value: f64| Score { layout: name.to_string(), name: key.to_string(), value };
+		scores.push(score("total", result.total));
+		scores.push(score("scaled", result.scaled));
+		for penalty in result.breakdown.iter() {
+			scores.push(score(penalty.name, penalty.total));
+		}
+	}
+	scores
+}
+
+// Writes scores one to a line, as the layout, the name and the value separated
+// by tabs.
+pub fn format_scores(scores: &[Score])
+-> String
+{
+	scores.iter().map(|score| format!("{}\t{}\t{}\n", score.layout, score.name, score.value)).collect()
+}
+
+// Reads what `format_scores` writes, skipping blank lines and those starting
+// with `#`.
+pub fn parse_scores(s: &str)
+-> Result<Vec<Score>, ScoresParseError>
+{
+	let mut scores = Vec::new();
+	for (i, line) in s.lines().enumerate() {
+		if line.trim().is_empty() || line.starts_with('#') {
+			continue;
+		}
+		let fields: Vec<&str> = line.split('\t').collect();
+		match (fields.len(), fields.last().and_then(|value| value.trim().parse::<f64>().ok())) {
+			(3, Some(value)) => scores.push(Score { layout: fields[0].to_string(), name: fields[1].to_string(), value }),
+			_ => { return Err(ScoresParseError { line: i + 1 }) },
+		}
+	}
+	Ok(scores)
+}
+
+// Describes each score that moved, appeared or went missing.
+pub fn differences(expected: &[Score], actual: &[Score])
+-> Vec<String>
+{
+	let find = |scores: &[Score], score: &Score| scores.iter()
+		.find(|other| other.layout == score.layout && other.name == score.name)
+		.map(|other| other.value);
+	let mut differences = Vec::new();
+	for score in expected.iter() {
+		match find(actual, score) {
+			Some(value) if (value - score.value).abs() <= EPSILON * score.value.abs().max(1.0) => (),
+			Some(value) => differences.push(format!("{} {}: expected {}, got {} ({:+})", score.layout, score.name, score.value, value, value - score.value)),
+			None => differences.push(format!("{} {}: expected {}, no longer scored", score.layout, score.name, score.value)),
+		}
+	}
+	for score in actual.iter().filter(|score| find(expected, score).is_none()) {
+		differences.push(format!("{} {}: got {}, not in the table", score.layout, score.name, score.value));
+	}
+	differences
+}
+
+impl fmt::Display for ScoresParseError
+{
+	fn fmt(&self, f: &mut fmt::Formatter)
+	-> fmt::Result
+	{
+		write!(f, "line {} is not a layout, a name and a number separated by tabs", self.line)
+	}
+}
+
+#[cfg(test)]
+mod tests
+{
+	use super::*;
+
+	#[test]
+	fn reports_moved_scores()
+	{
+		let scores = scores("the quick brown fox");
+		assert_eq!(parse_scores(&format_scores(&scores)).unwrap(), scores);
+		assert!(differences(&scores, &scores).is_empty());
+
+		let mut moved = scores.clone();
+		moved[0].value += 1e-12;
+		assert!(differences(&scores, &moved).is_empty());
+		moved[0].value += 0.5;
+		moved.pop();
+		let changed = differences(&scores, &moved);
+		assert_eq!(changed.len(), 2);
+		assert!(changed[0].starts_with("QWERTY total: expected"));
+		assert!(changed[1].ends_with("no longer scored"));
+		assert_eq!(parse_scores("QWERTY\tbase\t1\nQWERTY base 2\n").unwrap_err().line, 2);
+	}
+}
@@ -0,0 +1,133 @@
+/// A scoring entry point for JavaScript, through WebAssembly or any other host
+/// that can call C functions. Results and errors come back as JSON.
+
+use std::ffi::CString;
+use std::os::raw::c_char;
+use std::slice;
+use std::str;
+
+use json;
+use layout;
+use penalty;
+
+// Scores a layout, in the format of the layout files, against a corpus with
+// the default penalties. Returns an object with the total and scaled penalty,
+// the corpus coverage and each penalty's total, or an object with an "error".
+pub fn score_layout(layout_str: &str, corpus_str: &str)
+-> String
+{
+	let layout = match layout::Layout::from_string(layout_str, layout::MATRIX34_BOARD) {
+		Ok(l) => l,
+		Err(e) => return error(&e.to_string()),
+	};
+	if let Some(problem) = layout.validate().into_iter().find(|p| p.is_fatal()) {
+		return error(&problem.to_string());
+	}
+
+	let penalties = penalty::init();
+	let pos_map = layout.get_position_map();
+	let (quartads, coverage) = penalty::prepare_quartad_list(corpus_str, &pos_map, &penalty::Model::default(), &[]);
+	let result = penalty::calculate_penalty(&quartads, corpus_str.len(), &layout, &penalties, true);
+
+	let breakdown: Vec<String> = result.breakdown.iter().map(|p| {
+		format!("{{\"name\": {}, \"total\": {}}}", json::string(p.name), json::number(p.total))
+	}).collect();
+	format!("{{\"total\": {}, \"scaled\": {}, \"coverage\": {}, \"penalties\": [{}]}}",
+		json::number(result.total), json::number(result.scaled),
+		json::number(coverage.ratio()), breakdown.join(", "))
+}
+
+fn error(message: &str)
+-> String
+{
+	format!("{{\"error\": {}}}", json::string(message))
+}
+
+// The C interface. The host allocates buffers with `keygen_alloc`, copies the
+// layout and corpus into them as UTF-8, and calls `keygen_score`, which returns
+// a NUL-terminated JSON string to be released with `keygen_free_string`.
+
+#[no_mangle]
+pub extern "C" fn keygen_alloc(len: usize)
+-> *mut u8
+{
+	let mut buf = Vec::<u8>::with_capacity(len);
+	let ptr = buf.as_mut_ptr();
+	::std::mem::forget(buf);
+	ptr
+}
+
+/// Releases a buffer from `keygen_alloc`.
+///
+/// # Safety
+///
+/// `ptr` and `len` must be a pointer returned by `keygen_alloc` and the length
+/// it was called with, and the buffer must not be used or released again.
+#[no_mangle]
+pub unsafe extern "C" fn keygen_free(ptr: *mut u8, len: usize)
+{
+	drop(Vec::from_raw_parts(ptr, 0, len));
+}
+
+/// Scores the layout and corpus in two buffers, returning JSON.
+///
+/// # Safety
+///
+/// Each pointer must be valid for reads of its length in bytes, e.g. a buffer
+/// from `keygen_alloc` of at least that length that has been filled in. The
+/// result must be released with `keygen_free_string`.
+#[no_mangle]
+pub unsafe extern "C" fn keygen_score(
+	layout_ptr: *const u8,
+	layout_len: usize,
+	corpus_ptr: *const u8,
+	corpus_len: usize)
+-> *mut c_char
+{
+	let layout_bytes = slice::from_raw_parts(layout_ptr, layout_len);
+	let corpus_bytes = slice::from_raw_parts(corpus_ptr, corpus_len);
+	let json = match (str::from_utf8(layout_bytes), str::from_utf8(corpus_bytes)) {
+		(Ok(layout_str), Ok(corpus_str)) => score_layout(layout_str, corpus_str),
+		_ => error("input is not UTF-8"),
+	};
+	// Control characters are escaped, so the JSON has no NULs.
+	CString::new(json).unwrap().into_raw()
+}
+
+/// Releases a result of `keygen_score`.
+///
+/// # Safety
+///
+/// `ptr` must be a string returned by `keygen_score` that hasn't been released
+/// yet, and must not be used afterwards.
+#[no_mangle]
+pub unsafe extern "C" fn keygen_free_string(ptr: *mut c_char)
+{
+	drop(CString::from_raw(ptr));
+}
+
+#[cfg(test)]
+mod tests
+{
+	use std::ffi::CStr;
+	use super::*;
+
+	static LAYOUT: &str = "jcyfk zl,uq=\nrsthd mnaio'\n/vgpb xw.;-e \nJCYFK ZL<UQ+\nRSTHD MNAIO\"\n?VGPB XW>:_E \n";
+
+	#[test]
+	fn scores_to_json()
+	{
+		let json = score_layout(LAYOUT, "the quick brown fox");
+		assert!(json.starts_with("{\"total\": "), "{}", json);
+		assert!(json.contains("\"name\": \"same finger\""), "{}", json);
+		assert!(score_layout("qwerty", "abc").starts_with("{\"error\": "));
+
+		unsafe {
+			let corpus = "hello world";
+			let ptr = keygen_score(LAYOUT.as_ptr(), LAYOUT.len(), corpus.as_ptr(), corpus.len());
+			let result = CStr::from_ptr(ptr).to_str().unwrap().to_string();
+			keygen_free_string(ptr);
+			assert_eq!(result, score_layout(LAYOUT, corpus));
+		}
+	}
+}
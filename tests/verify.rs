@@ -0,0 +1,18 @@
+//! Checks the golden scores in fixtures/verify against the current model, as
+//! `keygen verify` does.
+
+extern crate keygen;
+
+use std::fs;
+use std::path::Path;
+use keygen::verify;
+
+#[test]
+fn scores_match_fixtures()
+{
+	let dir = Path::new(env!("CARGO_MANIFEST_DIR")).join("fixtures/verify");
+	let corpus = fs::read_to_string(dir.join("corpus.txt")).unwrap();
+	let expected = verify::parse_scores(&fs::read_to_string(dir.join("scores.tsv")).unwrap()).unwrap();
+	let changed = verify::differences(&expected, &verify::scores(&corpus));
+	assert!(changed.is_empty(), "scores changed; run `keygen verify --bless` if that's intended:\n{}", changed.join("\n"));
+}